@@ -7,8 +7,8 @@ use bitcoin::{
     secp256k1::SecretKey,
     sighash::{self, SighashCache},
     transaction::Version,
-    Address, Amount, OutPoint, PrivateKey, Psbt, ScriptBuf, Sequence, TapSighashType, Transaction,
-    TxIn, TxOut, Witness, XOnlyPublicKey,
+    Address, Amount, CompressedPublicKey, EcdsaSighashType, OutPoint, PrivateKey, Psbt, PublicKey,
+    ScriptBuf, Sequence, TapSighashType, Transaction, TxIn, TxOut, Witness, XOnlyPublicKey,
 };
 use snafu::ResultExt;
 
@@ -57,6 +57,48 @@ pub fn sign_message_bip322(
     signature.to_vec()[0][..64].try_into().unwrap()
 }
 
+/// Signs a BIP-322 simple message for the P2WPKH address of `private_key`, returning the witness.
+pub fn sign_message_bip322_p2wpkh(
+    private_key: &PrivateKey,
+    msg: &[u8],
+    network: bitcoin::Network,
+) -> Witness {
+    let secp = Secp256k1::new();
+    let public_key = CompressedPublicKey::from_private_key(&secp, private_key)
+        .expect("p2wpkh requires a compressed key");
+
+    let address = Address::p2wpkh(&public_key, network);
+
+    let to_spend = create_to_spend(&address, msg).unwrap();
+    let to_sign = create_to_sign(&to_spend, None).unwrap();
+
+    let sighash = SighashCache::new(to_sign.unsigned_tx)
+        .p2wpkh_signature_hash(
+            0,
+            &to_spend.output[0].script_pubkey,
+            Amount::from_sat(0),
+            EcdsaSighashType::All,
+        )
+        .expect("signature hash should compute");
+
+    let signature = secp.sign_ecdsa(
+        &bitcoin::secp256k1::Message::from_digest_slice(sighash.as_ref())
+            .expect("should be cryptographically secure hash"),
+        &private_key.inner,
+    );
+
+    let mut witness = Witness::new();
+    witness.push(
+        bitcoin::ecdsa::Signature {
+            signature,
+            sighash_type: EcdsaSighashType::All,
+        }
+        .to_vec(),
+    );
+    witness.push(public_key.to_bytes());
+    witness
+}
+
 pub fn verify_message_bip322(
     msg: &[u8],
     pubkey: [u8; 32],
@@ -228,6 +270,8 @@ pub fn verify_full(address: &Address, message: &[u8], to_sign: Transaction) -> B
                     .map_err(|_| error::Error::InvalidPublicKey)?;
 
                 verify_full_p2tr(address, message, to_sign, pub_key)
+            } else if witness.version().to_num() == 0 && witness.program().len() == 20 {
+                verify_full_p2wpkh(address, message, to_sign, witness.program().as_bytes())
             } else {
                 Err(error::Error::UnsupportedAddress {
                     address: address.to_string(),
@@ -311,3 +355,84 @@ fn verify_full_p2tr(
         .verify_schnorr(&signature, &message, &pub_key)
         .context(error::SignatureInvalid)
 }
+
+fn verify_full_p2wpkh(
+    address: &Address,
+    message: &[u8],
+    to_sign: Transaction,
+    pubkey_hash: &[u8],
+) -> BIP322Result<()> {
+    use bitcoin::secp256k1::{ecdsa::Signature, Message};
+
+    let to_spend = create_to_spend(address, message)?;
+    let to_sign = create_to_sign(&to_spend, Some(to_sign.input[0].witness.clone()))?;
+
+    let to_spend_outpoint = OutPoint {
+        txid: to_spend.compute_txid(),
+        vout: 0,
+    };
+
+    if to_spend_outpoint != to_sign.unsigned_tx.input[0].previous_output {
+        return Err(error::Error::ToSignInvalid);
+    }
+
+    let Some(witness) = to_sign.inputs[0].final_script_witness.clone() else {
+        return Err(error::Error::WitnessEmpty);
+    };
+
+    // a p2wpkh witness is the DER signature with its sighash byte, then the public key
+    if witness.len() != 2 {
+        return Err(error::Error::InvalidWitness);
+    }
+
+    let encoded_signature = witness.to_vec()[0].clone();
+
+    let Some((&sighash_byte, der_signature)) = encoded_signature.split_last() else {
+        return Err(error::Error::WitnessEmpty);
+    };
+
+    let signature = Signature::from_der(der_signature).context(error::SignatureInvalid)?;
+
+    let sighash_type = EcdsaSighashType::from_standard(u32::from(sighash_byte)).map_err(|_| {
+        error::Error::SigHashTypeUnsupported {
+            sighash_type: sighash_byte.to_string(),
+        }
+    })?;
+
+    if sighash_type != EcdsaSighashType::All {
+        return Err(error::Error::SigHashTypeUnsupported {
+            sighash_type: sighash_type.to_string(),
+        });
+    }
+
+    let pub_key = witness
+        .nth(1)
+        .and_then(|bytes| PublicKey::from_slice(bytes).ok())
+        .ok_or(error::Error::InvalidPublicKey)?;
+
+    let wpubkey_hash = pub_key
+        .wpubkey_hash()
+        .map_err(|_| error::Error::InvalidPublicKey)?;
+
+    if wpubkey_hash.as_byte_array().as_slice() != pubkey_hash {
+        return Err(error::Error::PublicKeyMismatch);
+    }
+
+    let mut sighash_cache = SighashCache::new(to_sign.unsigned_tx);
+
+    let sighash = sighash_cache
+        .p2wpkh_signature_hash(
+            0,
+            &to_spend.output[0].script_pubkey,
+            Amount::from_sat(0),
+            sighash_type,
+        )
+        .expect("signature hash should compute");
+
+    let message = Message::from_digest_slice(sighash.as_ref())
+        .expect("should be cryptographically secure hash");
+
+    Secp256k1::verification_only()
+        .verify_ecdsa(&message, &signature, &pub_key.inner)
+        .context(error::SignatureInvalid)
+}
@@ -0,0 +1,226 @@
+use std::str::FromStr;
+use arch_program::program_error::ProgramError;
+use bitcoin::address::AddressData;
+use bitcoin::hashes::{sha256, Hash as _};
+use bitcoin::{Address, Network, ScriptBuf};
+use crate::error::*;
+use crate::state::{AddressScriptHash, Hash, NetworkType, ADDRESS_SCRIPT_HASH_SIZE, WalletLast4};
+
+pub const WALLET_ADDRESS_SIZE: usize = 42;
+const ADDRESS_TAG_OFFSET: usize = 0;
+const PROGRAM_LENGTH_OFFSET: usize = 1;
+const PROGRAM_OFFSET: usize = 2;
+const MAX_PROGRAM_SIZE: usize = WALLET_ADDRESS_SIZE - PROGRAM_OFFSET;
+
+// the tag has the witness flag in the top bit, the network in the next two bits and either the
+// witness version or the legacy address type in the low five bits
+const WITNESS_FLAG: u8 = 0x80;
+const NETWORK_SHIFT: u8 = 5;
+const NETWORK_MASK: u8 = 0x03;
+const TYPE_MASK: u8 = 0x1f;
+const TYPE_P2PKH: u8 = 1;
+const TYPE_P2SH: u8 = 2;
+const NETWORK_MAINNET: u8 = 0;
+const NETWORK_TESTNETS: u8 = 1;
+const NETWORK_REGTEST: u8 = 2;
+
+/// A wallet address as kept in balances: a tag with the address type and network, followed by
+/// the length of the witness program or hash and the program itself. That is all that is needed
+/// to build the scriptPubKey of a withdrawal, and enough to restore the address string off chain.
+/// An all zero address marks a free balance.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct WalletAddress(pub [u8; WALLET_ADDRESS_SIZE]);
+
+impl WalletAddress {
+    pub const EMPTY: WalletAddress = WalletAddress([0u8; WALLET_ADDRESS_SIZE]);
+
+    pub fn from_address(address: &str) -> Result<Self, ProgramError> {
+        let address = Address::from_str(address)
+            .map_err(|_| ProgramError::Custom(ERROR_INVALID_ADDRESS))?;
+        // legacy test addresses are the same on every test network, segwit ones differ for regtest
+        let network = if address.is_valid_for_network(Network::Bitcoin) {
+            NETWORK_MAINNET
+        } else if address.is_valid_for_network(Network::Testnet) {
+            NETWORK_TESTNETS
+        } else {
+            NETWORK_REGTEST
+        };
+        let network_bits = network << NETWORK_SHIFT;
+        match address.assume_checked().to_address_data() {
+            AddressData::P2pkh { pubkey_hash } => Ok(Self::new(network_bits | TYPE_P2PKH, pubkey_hash.as_byte_array())),
+            AddressData::P2sh { script_hash } => Ok(Self::new(network_bits | TYPE_P2SH, script_hash.as_byte_array())),
+            AddressData::Segwit { witness_program } => Ok(Self::new(
+                WITNESS_FLAG | network_bits | witness_program.version().to_num(),
+                witness_program.program().as_bytes(),
+            )),
+            _ => Err(ProgramError::Custom(ERROR_INVALID_ADDRESS)),
+        }
+    }
+
+    fn new(tag: u8, program: &[u8]) -> Self {
+        let mut bytes = [0u8; WALLET_ADDRESS_SIZE];
+        bytes[ADDRESS_TAG_OFFSET] = tag;
+        bytes[PROGRAM_LENGTH_OFFSET] = program.len() as u8;
+        bytes[PROGRAM_OFFSET..PROGRAM_OFFSET + program.len()].copy_from_slice(program);
+        Self(bytes)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0[ADDRESS_TAG_OFFSET] == 0
+    }
+
+    fn is_witness(&self) -> bool {
+        self.0[ADDRESS_TAG_OFFSET] & WITNESS_FLAG != 0
+    }
+
+    fn network(&self) -> u8 {
+        (self.0[ADDRESS_TAG_OFFSET] >> NETWORK_SHIFT) & NETWORK_MASK
+    }
+
+    fn address_type(&self) -> u8 {
+        self.0[ADDRESS_TAG_OFFSET] & TYPE_MASK
+    }
+
+    fn program(&self) -> &[u8] {
+        let length = (self.0[PROGRAM_LENGTH_OFFSET] as usize).min(MAX_PROGRAM_SIZE);
+        &self.0[PROGRAM_OFFSET..PROGRAM_OFFSET + length]
+    }
+
+    /// The last four bytes of the witness program or hash, which address indexes are checked against.
+    pub fn last4(&self) -> WalletLast4 {
+        let program = self.program();
+        let length = program.len().min(4);
+        let mut last4: WalletLast4 = [0u8; 4];
+        last4[4 - length..].copy_from_slice(&program[program.len() - length..]);
+        last4
+    }
+
+    pub fn hash(&self) -> Hash {
+        sha256::Hash::hash(&self.0).to_byte_array()
+    }
+
+    /// The first bytes of the sha256 of the scriptPubKey, which address indexes commit to.
+    pub fn script_hash(&self) -> Result<AddressScriptHash, ProgramError> {
+        let hash = sha256::Hash::hash(self.script_pubkey()?.as_bytes()).to_byte_array();
+        let mut script_hash: AddressScriptHash = [0u8; ADDRESS_SCRIPT_HASH_SIZE];
+        script_hash.copy_from_slice(&hash[..ADDRESS_SCRIPT_HASH_SIZE]);
+        Ok(script_hash)
+    }
+
+    pub fn is_valid_for_network(&self, network_type: &NetworkType) -> bool {
+        match (self.network(), network_type) {
+            (NETWORK_MAINNET, NetworkType::Bitcoin) => true,
+            (NETWORK_TESTNETS, NetworkType::Testnet | NetworkType::Signet) => true,
+            (NETWORK_TESTNETS, NetworkType::Regtest) => !self.is_witness(),
+            (NETWORK_REGTEST, NetworkType::Regtest) => true,
+            _ => false,
+        }
+    }
+
+    pub fn script_pubkey(&self) -> Result<ScriptBuf, ProgramError> {
+        let program = self.program();
+        let mut script = Vec::with_capacity(program.len() + 5);
+        if self.is_witness() {
+            // OP_0 for version 0, OP_1 to OP_16 for the later ones
+            let version = self.address_type();
+            script.push(if version == 0 { 0x00 } else { 0x50 + version });
+            script.push(program.len() as u8);
+            script.extend_from_slice(program);
+        } else {
+            match self.address_type() {
+                // OP_DUP OP_HASH160 <hash> OP_EQUALVERIFY OP_CHECKSIG
+                TYPE_P2PKH => {
+                    script.extend_from_slice(&[0x76, 0xa9, program.len() as u8]);
+                    script.extend_from_slice(program);
+                    script.extend_from_slice(&[0x88, 0xac]);
+                }
+                // OP_HASH160 <hash> OP_EQUAL
+                TYPE_P2SH => {
+                    script.extend_from_slice(&[0xa9, program.len() as u8]);
+                    script.extend_from_slice(program);
+                    script.push(0x87);
+                }
+                _ => return Err(ProgramError::Custom(ERROR_INVALID_ADDRESS)),
+            }
+        }
+        Ok(ScriptBuf::from_bytes(script))
+    }
+
+    /// Restores the address string, an empty address gives an empty string.
+    pub fn to_address_string(&self) -> Result<String, ProgramError> {
+        if self.is_empty() {
+            return Ok(String::new());
+        }
+        let network = match self.network() {
+            NETWORK_MAINNET => Network::Bitcoin,
+            NETWORK_TESTNETS => Network::Testnet,
+            _ => Network::Regtest,
+        };
+        Address::from_script(&self.script_pubkey()?, network)
+            .map(|address| address.to_string())
+            .map_err(|_| ProgramError::Custom(ERROR_INVALID_ADDRESS))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bitcoin::hashes::Hash as _;
+    use bitcoin::{PubkeyHash, ScriptHash, WPubkeyHash, XOnlyPublicKey};
+    use bitcoin::key::TweakedPublicKey;
+    use crate::address::*;
+
+    #[test]
+    fn test_wallet_address() {
+        let p2wpkh = ScriptBuf::new_p2wpkh(&WPubkeyHash::from_byte_array([1; 20]));
+        let p2tr = ScriptBuf::new_p2tr_tweaked(TweakedPublicKey::dangerous_assume_tweaked(
+            XOnlyPublicKey::from_str("79be667ef9dcbbac55a06295ce870b07029bfcdb2dce28d959f2815b16f81798").unwrap(),
+        ));
+        let p2pkh = ScriptBuf::new_p2pkh(&PubkeyHash::from_byte_array([2; 20]));
+        let p2sh = ScriptBuf::new_p2sh(&ScriptHash::from_byte_array([3; 20]));
+        for (script_pubkey, network, network_type) in [
+            (&p2wpkh, Network::Bitcoin, NetworkType::Bitcoin),
+            (&p2wpkh, Network::Testnet, NetworkType::Signet),
+            (&p2wpkh, Network::Regtest, NetworkType::Regtest),
+            (&p2tr, Network::Bitcoin, NetworkType::Bitcoin),
+            (&p2tr, Network::Regtest, NetworkType::Regtest),
+            (&p2pkh, Network::Bitcoin, NetworkType::Bitcoin),
+            (&p2pkh, Network::Regtest, NetworkType::Testnet),
+            (&p2sh, Network::Bitcoin, NetworkType::Bitcoin),
+            (&p2sh, Network::Testnet, NetworkType::Regtest),
+        ] {
+            let address = Address::from_script(script_pubkey, network).unwrap().to_string();
+            let wallet_address = WalletAddress::from_address(&address).unwrap();
+            assert_eq!(address, wallet_address.to_address_string().unwrap());
+            assert_eq!(*script_pubkey, wallet_address.script_pubkey().unwrap());
+            assert_eq!(
+                sha256::Hash::hash(script_pubkey.as_bytes()).to_byte_array()[..ADDRESS_SCRIPT_HASH_SIZE],
+                wallet_address.script_hash().unwrap(),
+            );
+            assert!(wallet_address.is_valid_for_network(&network_type));
+            assert!(!wallet_address.is_empty());
+        }
+        assert_eq!([1; 4], WalletAddress::from_address(&Address::from_script(&p2wpkh, Network::Regtest).unwrap().to_string()).unwrap().last4());
+        assert_eq!([3; 4], WalletAddress::from_address(&Address::from_script(&p2sh, Network::Bitcoin).unwrap().to_string()).unwrap().last4());
+        for address in [
+            "132F25rTsvBdp9JzLLBHP5mvGY66i1xdiM",
+            "33iFwdLuRpW1uK1RTRqsoi8rR4NpDzk66k",
+            "bc1p5cyxnuxmeuwuvkwfem96lqzszd02n6xdcjrs20cac6yqjjwudpxqkedrcr",
+        ] {
+            assert_eq!(address, WalletAddress::from_address(address).unwrap().to_address_string().unwrap());
+        }
+
+        // segwit addresses are bound to their network, legacy test addresses work on every test network
+        let testnet = WalletAddress::from_address("tb1q4sgwdxx8c3l08chkw2w3rewn5armr9urhe0pfk").unwrap();
+        assert!(testnet.is_valid_for_network(&NetworkType::Testnet));
+        assert!(!testnet.is_valid_for_network(&NetworkType::Regtest));
+        let mainnet = WalletAddress::from_address("bc1qhz5a7xfh5dj00u32x0j5we6jfpa8vgpqhvaqug").unwrap();
+        assert!(!mainnet.is_valid_for_network(&NetworkType::Regtest));
+        let legacy = WalletAddress::from_address(&Address::from_script(&p2pkh, Network::Testnet).unwrap().to_string()).unwrap();
+        assert!(legacy.is_valid_for_network(&NetworkType::Regtest));
+        assert!(!legacy.is_valid_for_network(&NetworkType::Bitcoin));
+
+        assert!(WalletAddress::EMPTY.is_empty());
+        assert_eq!("", WalletAddress::EMPTY.to_address_string().unwrap());
+        assert_eq!(Err(ProgramError::Custom(ERROR_INVALID_ADDRESS)), WalletAddress::from_address("fee"));
+    }
+}
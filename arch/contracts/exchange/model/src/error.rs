@@ -26,3 +26,53 @@ pub const ERROR_WITHDRAWAL_NOT_ALLOWED: u32 = 623;
 pub const ERROR_INVALID_RUNE_ID: u32 = 624;
 pub const ERROR_RUNE_ALREADY_SET: u32 = 625;
 
+pub const ERROR_DEPOSIT_TX_NOT_FOUND: u32 = 626;
+pub const ERROR_DEPOSIT_OUTPUT_MISMATCH: u32 = 627;
+pub const ERROR_DEPOSIT_AMOUNT_MISMATCH: u32 = 628;
+pub const ERROR_DEPOSIT_LEDGER_MISSING: u32 = 629;
+pub const ERROR_DEPOSIT_LEDGER_FULL: u32 = 630;
+pub const ERROR_PAUSED: u32 = 631;
+pub const ERROR_INVALID_ACCOUNT_VERSION: u32 = 632;
+pub const ERROR_EVENT_LOG_MISSING: u32 = 633;
+pub const ERROR_EVENT_LOG_FULL: u32 = 634;
+pub const ERROR_WALLET_INDEX_MISSING: u32 = 635;
+pub const ERROR_WALLET_INDEX_INCOMPLETE: u32 = 636;
+pub const ERROR_WALLET_INDEX_FULL: u32 = 637;
+pub const ERROR_BALANCE_NOT_EMPTY: u32 = 638;
+pub const ERROR_BALANCE_SHARD_MISSING: u32 = 639;
+pub const ERROR_TOKEN_SHARDED: u32 = 640;
+pub const ERROR_ADDRESS_COMMITMENT_MISMATCH: u32 = 641;
+pub const ERROR_ARITHMETIC_OVERFLOW: u32 = 642;
+pub const ERROR_WITHDRAWAL_FEE_POLICY: u32 = 643;
+pub const ERROR_INVALID_FEE_POLICY: u32 = 644;
+pub const ERROR_WITHDRAWAL_LIMIT_EXCEEDED: u32 = 645;
+pub const ERROR_INVALID_WITHDRAWAL_LIMIT: u32 = 646;
+pub const ERROR_WITHDRAWAL_LIMIT_MISSING: u32 = 647;
+pub const ERROR_WITHDRAWAL_LIMIT_FULL: u32 = 648;
+pub const ERROR_FORCED_WITHDRAWALS_MISSING: u32 = 649;
+pub const ERROR_FORCED_WITHDRAWALS_FULL: u32 = 650;
+pub const ERROR_INVALID_SIGNATURE: u32 = 651;
+pub const ERROR_NONCE_USED: u32 = 652;
+pub const ERROR_FORCED_WITHDRAWAL_PENDING: u32 = 653;
+pub const ERROR_NO_FORCED_WITHDRAWAL: u32 = 654;
+pub const ERROR_FORCED_WITHDRAWAL_NOT_DUE: u32 = 655;
+pub const ERROR_WALLET_FROZEN: u32 = 656;
+pub const ERROR_INVALID_FORCED_WITHDRAWAL_TX: u32 = 657;
+pub const ERROR_WITHDRAWAL_NONCES_MISSING: u32 = 658;
+pub const ERROR_WITHDRAWAL_NONCES_FULL: u32 = 659;
+pub const ERROR_WITHDRAWAL_EXPIRED: u32 = 660;
+pub const ERROR_PREVOUT_NOT_FOUND: u32 = 661;
+pub const ERROR_INVALID_UTXO_OWNER: u32 = 662;
+pub const ERROR_INVALID_CHANGE: u32 = 663;
+pub const ERROR_FEE_RATE_OUT_OF_BOUNDS: u32 = 664;
+pub const ERROR_INVALID_FEE_RATE: u32 = 665;
+pub const ERROR_UTXO_REGISTRY_MISSING: u32 = 666;
+pub const ERROR_UTXO_REGISTRY_FULL: u32 = 667;
+pub const ERROR_UTXO_NOT_REGISTERED: u32 = 668;
+pub const ERROR_UTXO_LOCKED: u32 = 669;
+pub const ERROR_NO_SUBMITTED_WITHDRAWAL: u32 = 670;
+pub const ERROR_WITHDRAWAL_TX_NOT_FOUND: u32 = 671;
+pub const ERROR_FEE_NOT_INCREASED: u32 = 672;
+pub const ERROR_FEE_EXCEEDS_AMOUNT: u32 = 673;
+pub const ERROR_WITHDRAWAL_NOT_CONFIRMED: u32 = 674;
+pub const ERROR_WITHDRAW_ACCOUNT_MISSING: u32 = 675;
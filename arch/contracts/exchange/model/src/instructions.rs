@@ -1,4 +1,6 @@
-use crate::state::{NetworkType, WalletLast4};
+use arch_program::program_error::ProgramError;
+use arch_program::pubkey::Pubkey;
+use crate::state::{checked_sub_amount, AddressScriptHash, Hash, NetworkType, SHARD_INDEX_SHIFT, WalletLast4};
 
 #[derive(Clone, PartialEq, Debug)]
 pub enum ProgramInstruction {
@@ -15,6 +17,32 @@ pub enum ProgramInstruction {
     UpdateWithdrawStateUtxo(UpdateWithdrawStateUtxoParams),
     InitRuneReceiverState(),
     SetTokeRuneId(SetTokenRuneIdParams),
+    InitDepositLedger(),
+    UpdateFeeAccount(UpdateFeeAccountParams),
+    UpdateProgramChangeAddress(UpdateProgramChangeAddressParams),
+    UpdateWithdrawAccount(),
+    Pause(PauseParams),
+    Unpause(PauseParams),
+    MigrateAccount(MigrateAccountParams),
+    InitEventLog(),
+    PreparePartialBatchSettlement(SettlementBatchParams),
+    PreparePartialBatchWithdraw(WithdrawBatchParams),
+    InitWalletIndex(),
+    IndexWalletBalances(),
+    CloseWalletBalance(CloseWalletBalanceParams),
+    CompactBalances(CompactBalancesParams),
+    InitBalanceShard(),
+    SetWithdrawalFeePolicy(SetWithdrawalFeePolicyParams),
+    SetWithdrawalLimit(SetWithdrawalLimitParams),
+    InitForcedWithdrawals(InitForcedWithdrawalsParams),
+    RequestForcedWithdrawal(RequestForcedWithdrawalParams),
+    ForcedWithdraw(ForcedWithdrawParams),
+    InitWithdrawalNonces(),
+    SetWithdrawalFeeRate(SetWithdrawalFeeRateParams),
+    InitUtxoRegistry(),
+    RegisterUtxos(RegisterUtxosParams),
+    ConfirmWithdrawBatch(ConfirmWithdrawBatchParams),
+    BumpWithdrawBatch(BumpWithdrawBatchParams),
 }
 
 #[derive(Clone, PartialEq, Debug)]
@@ -24,6 +52,148 @@ pub struct InitProgramStateParams {
     pub network_type: NetworkType,
 }
 
+#[derive(Clone, PartialEq, Debug)]
+pub struct UpdateFeeAccountParams {
+    pub fee_account: String,
+}
+
+#[derive(Clone, PartialEq, Debug)]
+pub struct UpdateProgramChangeAddressParams {
+    pub program_change_address: String,
+}
+
+#[derive(Clone, PartialEq, Debug)]
+pub struct PauseParams {
+    // bitmask of PAUSE_DEPOSITS, PAUSE_WITHDRAWALS and PAUSE_SETTLEMENTS
+    pub pause_flags: u8,
+}
+
+#[derive(Clone, PartialEq, Debug)]
+pub struct MigrateAccountParams {
+    pub account_index: u8,
+}
+
+#[derive(Clone, PartialEq, Debug)]
+pub struct CloseWalletBalanceParams {
+    pub account_index: u8,
+    pub address_indexes: Vec<AddressIndex>,
+}
+
+#[derive(Clone, PartialEq, Debug)]
+pub struct CompactBalancesParams {
+    pub account_index: u8,
+}
+
+#[derive(Clone, PartialEq, Debug)]
+pub struct SetWithdrawalFeePolicyParams {
+    pub account_index: u8,
+    // None removes the policy, leaving withdrawal fees unchecked
+    pub fee_policy: Option<WithdrawalFeePolicyParams>,
+}
+
+#[derive(Clone, PartialEq, Debug)]
+pub struct WithdrawalFeePolicyParams {
+    pub min_fee: u64,
+    pub flat_fee: u64,
+    // the token the fee is charged in, the token itself when None
+    pub fee_account_index: Option<u8>,
+}
+
+#[derive(Clone, PartialEq, Debug)]
+pub struct SetWithdrawalLimitParams {
+    // None removes the limit, leaving withdrawals only bound by the balances
+    pub limit: Option<WithdrawalLimitParams>,
+}
+
+#[derive(Clone, PartialEq, Debug)]
+pub struct WithdrawalLimitParams {
+    // the most a single wallet can withdraw within a window of `window_blocks` bitcoin blocks
+    pub max_amount: u64,
+    pub window_blocks: u64,
+}
+
+#[derive(Clone, PartialEq, Debug)]
+pub struct SetWithdrawalFeeRateParams {
+    // None leaves the fee rate of withdrawal transactions unchecked
+    pub fee_rate: Option<WithdrawalFeeRateParams>,
+}
+
+#[derive(Clone, PartialEq, Debug)]
+pub struct WithdrawalFeeRateParams {
+    // in sat/vB of the signed transaction
+    pub min_fee_rate: u64,
+    pub max_fee_rate: u64,
+}
+
+#[derive(Clone, PartialEq, Debug)]
+pub struct RegisterUtxosParams {
+    // utxos of the program or the rune receiver that were not deposited, e.g. from before the
+    // registry was initialized
+    pub utxos: Vec<FundingOutpoint>,
+}
+
+#[derive(Clone, PartialEq, Debug)]
+pub struct ConfirmWithdrawBatchParams {
+    // the transaction of the batch that confirmed, in display order
+    pub tx_id: Hash,
+}
+
+#[derive(Clone, PartialEq, Debug)]
+pub struct BumpWithdrawBatchParams {
+    // the params the submitted batch was prepared with
+    pub withdraw_batch: WithdrawBatchParams,
+    // the change of the replacement transaction, lower than the one of the transaction it replaces
+    pub change_amount: u64,
+}
+
+#[derive(Clone, PartialEq, Debug)]
+pub struct InitForcedWithdrawalsParams {
+    // how many bitcoin blocks the operator has to serve a forced withdrawal request
+    pub delay_blocks: u64,
+}
+
+#[derive(Clone, PartialEq, Debug)]
+pub struct RequestForcedWithdrawalParams {
+    pub account_index: u8,
+    pub address_index: AddressIndex,
+    pub amount: u64,
+    pub nonce: u64,
+    // consensus encoded BIP322 simple signature witness over `message`
+    pub signature: Vec<u8>,
+}
+
+impl RequestForcedWithdrawalParams {
+    /// The message the wallet signs to request the withdrawal.
+    pub fn message(&self, program_state_account: &Pubkey, token_state_account: &Pubkey, address: &str) -> String {
+        format!(
+            "Forced withdrawal of {} from token {:x} of exchange {:x} to {}, nonce {}",
+            self.amount, token_state_account, program_state_account, address, self.nonce,
+        )
+    }
+}
+
+#[derive(Clone, PartialEq, Debug)]
+pub struct ForcedWithdrawParams {
+    pub account_index: u8,
+    pub address_index: AddressIndex,
+    // the program owned utxos to spend, the outputs are added by the program
+    pub tx_hex: Vec<u8>,
+    // taken from the withdrawn amount
+    pub network_fee: u64,
+    // consensus encoded BIP322 simple signature witness over `message`
+    pub signature: Vec<u8>,
+}
+
+impl ForcedWithdrawParams {
+    /// The message the wallet signs to accept the network fee of the request with `nonce`.
+    pub fn message(&self, program_state_account: &Pubkey, token_state_account: &Pubkey, address: &str, amount: u64, nonce: u64) -> String {
+        format!(
+            "Forced withdrawal of {} from token {:x} of exchange {:x} to {} paying a network fee of {}, nonce {}",
+            amount, token_state_account, program_state_account, address, self.network_fee, nonce,
+        )
+    }
+}
+
 #[derive(Clone, PartialEq, Debug)]
 pub struct InitTokenStateParams {
     pub token_id: String,
@@ -71,7 +241,39 @@ pub struct RollbackWithdrawBatchParams {
 #[derive(Clone, PartialEq, Debug)]
 pub struct AddressIndex {
     pub index: u32,
-    pub last4: WalletLast4,
+    pub commitment: AddressCommitment,
+}
+
+/// What the wallet address of the balance at an address index is checked against.
+#[derive(Clone, PartialEq, Debug)]
+pub enum AddressCommitment {
+    /// The first bytes of the sha256 of the scriptPubKey of the wallet address.
+    ScriptHash(AddressScriptHash),
+    /// Only the last 4 bytes of the witness program or hash, as in batches encoded before
+    /// commitments were introduced.
+    Last4(WalletLast4),
+}
+
+impl AddressIndex {
+    pub fn new(shard: u8, balance_index: usize, commitment: AddressCommitment) -> Self {
+        Self {
+            index: Self::pack(shard, balance_index),
+            commitment,
+        }
+    }
+
+    pub fn pack(shard: u8, balance_index: usize) -> u32 {
+        ((shard as u32) << SHARD_INDEX_SHIFT) | balance_index as u32
+    }
+
+    // 0 for balances kept in the token state itself
+    pub fn shard(&self) -> u8 {
+        (self.index >> SHARD_INDEX_SHIFT) as u8
+    }
+
+    pub fn balance_index(&self) -> usize {
+        (self.index & ((1 << SHARD_INDEX_SHIFT) - 1)) as usize
+    }
 }
 
 #[derive(Clone, PartialEq, Debug)]
@@ -80,6 +282,20 @@ pub struct Adjustment {
     pub amount: u64,
 }
 
+#[derive(Clone, PartialEq, Debug)]
+pub struct FundingOutpoint {
+    // txid bytes in display order, as in UtxoMeta
+    pub tx_id: Hash,
+    pub vout: u32,
+}
+
+#[derive(Clone, PartialEq, Debug)]
+pub struct Deposit {
+    pub address_index: AddressIndex,
+    pub amount: u64,
+    pub funding_outpoint: Option<FundingOutpoint>,
+}
+
 #[derive(Clone, PartialEq, Debug)]
 pub struct TokenStateSetup {
     pub account_index: u8,
@@ -93,12 +309,49 @@ pub struct Withdrawal {
     pub fee_account_index: u8,
     pub fee_address_index: AddressIndex,
     pub fee_amount: u64,
+    // None for withdrawals the operator submits on its own authority
+    pub signature: Option<WithdrawalSignature>,
+}
+
+/// A wallet's authorization of a withdrawal, see `Withdrawal::message`.
+#[derive(Clone, PartialEq, Debug)]
+pub struct WithdrawalSignature {
+    // has to be higher than the last nonce the wallet signed a withdrawal with
+    pub nonce: u64,
+    // the last bitcoin block height at which the withdrawal can be prepared
+    pub expiry: u64,
+    // consensus encoded BIP322 simple signature witness
+    pub witness: Vec<u8>,
+}
+
+impl Withdrawal {
+    /// The amount paid out to the wallet once the fee is taken off.
+    pub fn net_amount(&self) -> Result<u64, ProgramError> {
+        checked_sub_amount(self.amount, self.fee_amount)
+    }
+
+    /// The message the wallet signs to authorize the withdrawal.
+    pub fn message(
+        &self,
+        program_state_account: &Pubkey,
+        token_state_account: &Pubkey,
+        fee_token_state_account: &Pubkey,
+        address: &str,
+        nonce: u64,
+        expiry: u64,
+    ) -> String {
+        format!(
+            "Withdrawal of {} from token {:x} of exchange {:x} to {} with a fee of {} in token {:x}, nonce {}, expiry {}",
+            self.amount, token_state_account, program_state_account, address, self.fee_amount,
+            fee_token_state_account, nonce, expiry,
+        )
+    }
 }
 
 #[derive(Clone, PartialEq, Debug)]
 pub struct TokenDeposits {
     pub account_index: u8,
-    pub deposits: Vec<Adjustment>,
+    pub deposits: Vec<Deposit>,
 }
 
 #[derive(Clone, PartialEq, Debug)]
@@ -1,4 +1,6 @@
 pub mod state;
+pub mod address;
 pub mod error;
 pub mod serialization;
 pub mod instructions;
+pub mod migration;
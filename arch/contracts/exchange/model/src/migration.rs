@@ -0,0 +1,122 @@
+use arch_program::account::AccountInfo;
+use arch_program::program_error::ProgramError;
+use crate::address::{WalletAddress, WALLET_ADDRESS_SIZE};
+use crate::error::*;
+use crate::state::*;
+
+/// Upgrades the layout of `account` by a single version and returns the new version.
+///
+/// Each step only knows how to go from version N to N + 1, so an account that is several
+/// versions behind is brought up to date by calling `MigrateAccount` repeatedly.
+pub fn migrate_account(account: &AccountInfo) -> Result<u32, ProgramError> {
+    let account_type = get_type(account)?;
+    let version = get_version(account)?;
+    if version >= account_type.current_version() {
+        return Err(ProgramError::Custom(ERROR_INVALID_ACCOUNT_VERSION));
+    }
+    match (account_type, version) {
+        (AccountType::Program, 0) => replace_zeroed(account, PAUSE_FLAGS_OFFSET, 0, PAUSE_FLAGS_SIZE)?,
+        (AccountType::Program, 1) => replace_zeroed(account, EVENT_SEQUENCE_OFFSET, 0, EVENT_SEQUENCE_SIZE)?,
+        (AccountType::Program, 2) => migrate_program_state_v2(account)?,
+        (AccountType::Token, 0) => replace_zeroed(account, WALLET_INDEX_OFFSET, 0, PUBKEY_SIZE)?,
+        (AccountType::Token, 1) => replace_zeroed(account, FREE_BALANCE_COUNT_OFFSET, 0, FREE_BALANCE_COUNT_SIZE + FIRST_FREE_BALANCE_SIZE)?,
+        (AccountType::Token, 2) => replace_zeroed(account, BALANCE_SHARD_COUNT_OFFSET, 0, BALANCE_SHARD_COUNT_SIZE + 2 * PUBKEY_SIZE)?,
+        (AccountType::Token, 3) => migrate_balances(account, LEGACY_BALANCE_COUNT_OFFSET)?,
+        (AccountType::Token, 4) => replace_zeroed(account, FEE_POLICY_OFFSET, 0, FEE_POLICY_SIZE)?,
+        (AccountType::Token, 5) => replace_zeroed(account, WITHDRAWAL_LIMIT_OFFSET, 0, PUBKEY_SIZE)?,
+        (AccountType::Withdraw, 0) => migrate_withdraw_state_v0(account)?,
+        (AccountType::BalanceShard, 0) => migrate_balances(account, SHARD_BALANCE_COUNT_OFFSET)?,
+        (AccountType::WalletIndex, 0) => reset_wallet_index(account)?,
+        _ => return Err(ProgramError::Custom(ERROR_INVALID_ACCOUNT_VERSION)),
+    }
+    set_version(account, version + 1)?;
+    Ok(version + 1)
+}
+
+// v3 moved the inline events to event log accounts, the program state only keeps the keys of
+// the first and the current event log. An event log has to be initialized after migrating.
+fn migrate_program_state_v2(account: &AccountInfo) -> Result<(), ProgramError> {
+    replace_zeroed(account, FIRST_EVENT_LOG_OFFSET, LEGACY_EVENT_SIZE * LEGACY_MAX_EVENTS, 2 * PUBKEY_SIZE)?;
+    ProgramState::clear_events(account)
+}
+
+// v1 tracks the status and transactions of the last batch, a batch prepared before migrating
+// can still be submitted
+fn migrate_withdraw_state_v0(account: &AccountInfo) -> Result<(), ProgramError> {
+    replace_zeroed(
+        account,
+        WITHDRAW_STATUS_OFFSET,
+        0,
+        WITHDRAW_STATUS_SIZE + HASH_SIZE + WITHDRAW_SUBMITTED_HEIGHT_SIZE + WITHDRAW_REPLACED_COUNT_SIZE,
+    )?;
+    if WithdrawState::get_hash(account)? != EMPTY_HASH {
+        WithdrawState::set_status(account, WithdrawStatus::Prepared)?;
+    }
+    Ok(())
+}
+
+// token state v4 and balance shard v1 store the wallet address of a balance in its compact form.
+// Records only shrink, so converting them front to back never overwrites one not yet converted.
+// Free balances keep their amount, which links the free list. The balance count is located by the
+// caller since later token state versions moved it.
+fn migrate_balances(account: &AccountInfo, balance_count_offset: usize) -> Result<(), ProgramError> {
+    let balances_offset = balance_count_offset + BALANCE_COUNT_SIZE;
+    if account.data_len() < balances_offset {
+        return Err(ProgramError::InvalidAccountData);
+    }
+    let num_balances = u32::from_le_bytes(
+        account.data.borrow()[balance_count_offset..balances_offset]
+            .try_into()
+            .map_err(|_| ProgramError::InvalidAccountData)?
+    ) as usize;
+    if account.data_len() < balances_offset + num_balances * LEGACY_BALANCE_SIZE {
+        return Err(ProgramError::InvalidAccountData);
+    }
+    for index in 0..num_balances {
+        let legacy_offset = balances_offset + index * LEGACY_BALANCE_SIZE;
+        let address = get_address(account, legacy_offset)?;
+        let wallet_address = if address.is_empty() {
+            WalletAddress::EMPTY
+        } else {
+            WalletAddress::from_address(&address)?
+        };
+        let amount_offset = legacy_offset + MAX_ADDRESS_SIZE;
+        let offset = balances_offset + index * BALANCE_SIZE;
+        let mut data = account.data.try_borrow_mut().map_err(|_| ProgramError::InvalidAccountData)?;
+        data.copy_within(amount_offset..amount_offset + BALANCE_AMOUNT_SIZE, offset + BALANCE_AMOUNT_OFFSET);
+        data[offset..offset + WALLET_ADDRESS_SIZE].copy_from_slice(wallet_address.0.as_slice());
+    }
+    account.realloc(balances_offset + num_balances * BALANCE_SIZE, true)
+}
+
+// v1 hashes the compact wallet address, the entries are dropped and rebuilt by IndexWalletBalances
+fn reset_wallet_index(account: &AccountInfo) -> Result<(), ProgramError> {
+    account.realloc(WALLET_INDEX_ENTRIES_OFFSET, true)?;
+    let mut data = account.data.try_borrow_mut().map_err(|_| ProgramError::InvalidAccountData)?;
+    data[NUM_INDEXED_BALANCES_OFFSET..WALLET_INDEX_ENTRIES_OFFSET].fill(0);
+    Ok(())
+}
+
+// v1 added the pause flags byte after the last settlement batch hash and v2 added the event
+// sequence after the pause flags, both in front of the events. Token state v1 added the wallet
+// index key, v2 the free list, v3 the balance shards, v5 the withdrawal fee policy and v6 the
+// withdrawal limit key in front of the balances.
+fn replace_zeroed(account: &AccountInfo, offset: usize, old_size: usize, new_size: usize) -> Result<(), ProgramError> {
+    let data_len = account.data_len();
+    if data_len < offset + old_size {
+        return Err(ProgramError::InvalidAccountData);
+    }
+    let new_data_len = data_len - old_size + new_size;
+    if new_data_len > data_len {
+        account.realloc(new_data_len, true)?;
+    }
+    {
+        let mut data = account.data.try_borrow_mut().map_err(|_| ProgramError::InvalidAccountData)?;
+        data.copy_within(offset + old_size..data_len, offset + new_size);
+        data[offset..offset + new_size].fill(0);
+    }
+    if new_data_len < data_len {
+        account.realloc(new_data_len, true)?;
+    }
+    Ok(())
+}
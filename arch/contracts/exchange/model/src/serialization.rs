@@ -1,7 +1,8 @@
 use std::io;
 use std::io::{Cursor, Error, Read, Write};
 use arch_program::pubkey::Pubkey;
-use crate::state::{AccountType, Balance, Event, EVENT_SIZE, Hash, MAX_ADDRESS_SIZE, MAX_TOKEN_ID_SIZE, NetworkType, ProgramState, RuneReceiverState, TokenState, WithdrawState};
+use crate::address::{WalletAddress, WALLET_ADDRESS_SIZE};
+use crate::state::{ADDRESS_SCRIPT_HASH_FLAG, ADDRESS_SCRIPT_HASH_SIZE, AccountType, Balance, BalanceShardState, DepositLedgerState, Event, EVENT_LENGTH_SIZE, EventLogState, FEE_POLICY_FLAG_SIZE, FEE_POLICY_SIZE, ForcedWithdrawalEntry, ForcedWithdrawalState, Hash, LEGACY_EVENT_SIZE, MAX_ADDRESS_SIZE, MAX_TOKEN_ID_SIZE, NetworkType, ProgramState, RuneReceiverState, TokenState, UtxoEntry, UtxoRegistryState, WalletIndexEntry, WalletIndexState, WithdrawalFeePolicy, WithdrawalLimitEntry, WithdrawalLimitState, WithdrawalNonceEntry, WithdrawalNonceState, WithdrawState, WithdrawStatus};
 use crate::instructions::*;
 
 pub trait ReadExt: io::Read {
@@ -206,6 +207,32 @@ impl Codable for ProgramInstruction {
             10 => Ok(Self::UpdateWithdrawStateUtxo(UpdateWithdrawStateUtxoParams::decode(reader)?)),
             11 => Ok(Self::InitRuneReceiverState()),
             12 => Ok(Self::SetTokeRuneId(SetTokenRuneIdParams::decode(reader)?)),
+            13 => Ok(Self::InitDepositLedger()),
+            14 => Ok(Self::UpdateFeeAccount(UpdateFeeAccountParams::decode(reader)?)),
+            15 => Ok(Self::UpdateProgramChangeAddress(UpdateProgramChangeAddressParams::decode(reader)?)),
+            16 => Ok(Self::UpdateWithdrawAccount()),
+            17 => Ok(Self::Pause(PauseParams::decode(reader)?)),
+            18 => Ok(Self::Unpause(PauseParams::decode(reader)?)),
+            19 => Ok(Self::MigrateAccount(MigrateAccountParams::decode(reader)?)),
+            20 => Ok(Self::InitEventLog()),
+            21 => Ok(Self::PreparePartialBatchSettlement(SettlementBatchParams::decode(reader)?)),
+            22 => Ok(Self::PreparePartialBatchWithdraw(WithdrawBatchParams::decode(reader)?)),
+            23 => Ok(Self::InitWalletIndex()),
+            24 => Ok(Self::IndexWalletBalances()),
+            25 => Ok(Self::CloseWalletBalance(CloseWalletBalanceParams::decode(reader)?)),
+            26 => Ok(Self::CompactBalances(CompactBalancesParams::decode(reader)?)),
+            27 => Ok(Self::InitBalanceShard()),
+            28 => Ok(Self::SetWithdrawalFeePolicy(SetWithdrawalFeePolicyParams::decode(reader)?)),
+            29 => Ok(Self::SetWithdrawalLimit(SetWithdrawalLimitParams::decode(reader)?)),
+            30 => Ok(Self::InitForcedWithdrawals(InitForcedWithdrawalsParams::decode(reader)?)),
+            31 => Ok(Self::RequestForcedWithdrawal(RequestForcedWithdrawalParams::decode(reader)?)),
+            32 => Ok(Self::ForcedWithdraw(ForcedWithdrawParams::decode(reader)?)),
+            33 => Ok(Self::InitWithdrawalNonces()),
+            34 => Ok(Self::SetWithdrawalFeeRate(SetWithdrawalFeeRateParams::decode(reader)?)),
+            35 => Ok(Self::InitUtxoRegistry()),
+            36 => Ok(Self::RegisterUtxos(RegisterUtxosParams::decode(reader)?)),
+            37 => Ok(Self::ConfirmWithdrawBatch(ConfirmWithdrawBatchParams::decode(reader)?)),
+            38 => Ok(Self::BumpWithdrawBatch(BumpWithdrawBatchParams::decode(reader)?)),
             _ => Err(io::Error::new(io::ErrorKind::Other, "Invalid instruction type"))
         }
     }
@@ -251,6 +278,84 @@ impl Codable for ProgramInstruction {
             Self::SetTokeRuneId(params) => {
                 Ok(writer.write_u8(12)? + params.encode(&mut writer)?)
             }
+            Self::InitDepositLedger() => {
+                Ok(writer.write_u8(13)?)
+            }
+            Self::UpdateFeeAccount(params) => {
+                Ok(writer.write_u8(14)? + params.encode(&mut writer)?)
+            }
+            Self::UpdateProgramChangeAddress(params) => {
+                Ok(writer.write_u8(15)? + params.encode(&mut writer)?)
+            }
+            Self::UpdateWithdrawAccount() => {
+                Ok(writer.write_u8(16)?)
+            }
+            Self::Pause(params) => {
+                Ok(writer.write_u8(17)? + params.encode(&mut writer)?)
+            }
+            Self::Unpause(params) => {
+                Ok(writer.write_u8(18)? + params.encode(&mut writer)?)
+            }
+            Self::MigrateAccount(params) => {
+                Ok(writer.write_u8(19)? + params.encode(&mut writer)?)
+            }
+            Self::InitEventLog() => {
+                Ok(writer.write_u8(20)?)
+            }
+            Self::PreparePartialBatchSettlement(params) => {
+                Ok(writer.write_u8(21)? + params.encode(&mut writer)?)
+            }
+            Self::PreparePartialBatchWithdraw(params) => {
+                Ok(writer.write_u8(22)? + params.encode(&mut writer)?)
+            }
+            Self::InitWalletIndex() => {
+                Ok(writer.write_u8(23)?)
+            }
+            Self::IndexWalletBalances() => {
+                Ok(writer.write_u8(24)?)
+            }
+            Self::CloseWalletBalance(params) => {
+                Ok(writer.write_u8(25)? + params.encode(&mut writer)?)
+            }
+            Self::CompactBalances(params) => {
+                Ok(writer.write_u8(26)? + params.encode(&mut writer)?)
+            }
+            Self::InitBalanceShard() => {
+                Ok(writer.write_u8(27)?)
+            }
+            Self::SetWithdrawalFeePolicy(params) => {
+                Ok(writer.write_u8(28)? + params.encode(&mut writer)?)
+            }
+            Self::SetWithdrawalLimit(params) => {
+                Ok(writer.write_u8(29)? + params.encode(&mut writer)?)
+            }
+            Self::InitForcedWithdrawals(params) => {
+                Ok(writer.write_u8(30)? + params.encode(&mut writer)?)
+            }
+            Self::RequestForcedWithdrawal(params) => {
+                Ok(writer.write_u8(31)? + params.encode(&mut writer)?)
+            }
+            Self::ForcedWithdraw(params) => {
+                Ok(writer.write_u8(32)? + params.encode(&mut writer)?)
+            }
+            Self::InitWithdrawalNonces() => {
+                Ok(writer.write_u8(33)?)
+            }
+            Self::SetWithdrawalFeeRate(params) => {
+                Ok(writer.write_u8(34)? + params.encode(&mut writer)?)
+            }
+            Self::InitUtxoRegistry() => {
+                Ok(writer.write_u8(35)?)
+            }
+            Self::RegisterUtxos(params) => {
+                Ok(writer.write_u8(36)? + params.encode(&mut writer)?)
+            }
+            Self::ConfirmWithdrawBatch(params) => {
+                Ok(writer.write_u8(37)? + params.encode(&mut writer)?)
+            }
+            Self::BumpWithdrawBatch(params) => {
+                Ok(writer.write_u8(38)? + params.encode(&mut writer)?)
+            }
         }
     }
 }
@@ -322,23 +427,39 @@ impl Codable for TokenStateSetup {
     }
 }
 
+// batches encoded before script hash commitments have no flag and a 4 byte last4
 impl Codable for AddressIndex {
     fn decode<R: Read + ?Sized>(reader: &mut R) -> Result<Self, io::Error> {
         let index = reader.read_u32()?;
-        let mut last4 = [0; 4];
-        reader.read_exact(&mut last4)?;
+        let commitment = if index & ADDRESS_SCRIPT_HASH_FLAG != 0 {
+            let mut script_hash = [0; ADDRESS_SCRIPT_HASH_SIZE];
+            reader.read_exact(&mut script_hash)?;
+            AddressCommitment::ScriptHash(script_hash)
+        } else {
+            let mut last4 = [0; 4];
+            reader.read_exact(&mut last4)?;
+            AddressCommitment::Last4(last4)
+        };
 
         Ok(Self {
-            index,
-            last4,
+            index: index & !ADDRESS_SCRIPT_HASH_FLAG,
+            commitment,
         })
     }
 
     fn encode<W: Write + ?Sized>(&self, mut writer: &mut W) -> Result<usize, io::Error> {
-        let mut bytes_written = writer.write_u32(self.index)?;
-        writer.write_all(&self.last4)?;
-        bytes_written += self.last4.len();
-        Ok(bytes_written)
+        let bytes = match &self.commitment {
+            AddressCommitment::ScriptHash(script_hash) => {
+                writer.write_u32(self.index | ADDRESS_SCRIPT_HASH_FLAG)?;
+                script_hash.as_slice()
+            }
+            AddressCommitment::Last4(last4) => {
+                writer.write_u32(self.index)?;
+                last4.as_slice()
+            }
+        };
+        writer.write_all(bytes)?;
+        Ok(4 + bytes.len())
     }
 }
 
@@ -357,28 +478,110 @@ impl Codable for Adjustment {
     }
 }
 
-impl Codable for Withdrawal {
+impl Codable for FundingOutpoint {
     fn decode<R: Read + ?Sized>(reader: &mut R) -> Result<Self, io::Error> {
         Ok(Self {
-            address_index: AddressIndex::decode(reader)?,
-            amount: reader.read_u64()?,
-            fee_account_index: reader.read_u8()?,
-            fee_address_index: AddressIndex::decode(reader)?,
-            fee_amount: reader.read_u64()?,
+            tx_id: reader.read_hash()?,
+            vout: reader.read_u32()?,
         })
     }
 
     fn encode<W: Write + ?Sized>(&self, mut writer: &mut W) -> Result<usize, io::Error> {
         Ok(
-            self.address_index.encode(writer)?
-                + writer.write_u64(self.amount)?
-                + writer.write_u8(self.fee_account_index)?
-                + self.fee_address_index.encode(writer)?
-                + writer.write_u64(self.fee_amount)?
+            writer.write_hash(&self.tx_id)? + writer.write_u32(self.vout)?
         )
     }
 }
 
+impl Codable for Deposit {
+    fn decode<R: Read + ?Sized>(reader: &mut R) -> Result<Self, io::Error> {
+        let address_index = AddressIndex::decode(reader)?;
+        let amount = reader.read_u64()?;
+        let funding_outpoint = match reader.read_u8()? {
+            0 => None,
+            1 => Some(FundingOutpoint::decode(reader)?),
+            _ => return Err(io::Error::new(io::ErrorKind::Other, "Invalid funding outpoint flag"))
+        };
+
+        Ok(Self {
+            address_index,
+            amount,
+            funding_outpoint,
+        })
+    }
+
+    fn encode<W: Write + ?Sized>(&self, mut writer: &mut W) -> Result<usize, io::Error> {
+        let mut bytes_written = self.address_index.encode(writer)? + writer.write_u64(self.amount)?;
+        bytes_written += match &self.funding_outpoint {
+            None => writer.write_u8(0)?,
+            Some(funding_outpoint) => writer.write_u8(1)? + funding_outpoint.encode(writer)?,
+        };
+        Ok(bytes_written)
+    }
+}
+
+impl Codable for Withdrawal {
+    fn decode<R: Read + ?Sized>(reader: &mut R) -> Result<Self, io::Error> {
+        let address_index = AddressIndex::decode(reader)?;
+        let amount = reader.read_u64()?;
+        let fee_account_index = reader.read_u8()?;
+        let fee_address_index = AddressIndex::decode(reader)?;
+        let fee_amount = reader.read_u64()?;
+        let signature = match reader.read_u8()? {
+            0 => None,
+            1 => Some(WithdrawalSignature::decode(reader)?),
+            _ => return Err(io::Error::new(io::ErrorKind::Other, "Invalid withdrawal signature flag"))
+        };
+
+        Ok(Self {
+            address_index,
+            amount,
+            fee_account_index,
+            fee_address_index,
+            fee_amount,
+            signature,
+        })
+    }
+
+    fn encode<W: Write + ?Sized>(&self, mut writer: &mut W) -> Result<usize, io::Error> {
+        let mut bytes_written = self.address_index.encode(writer)?
+            + writer.write_u64(self.amount)?
+            + writer.write_u8(self.fee_account_index)?
+            + self.fee_address_index.encode(writer)?
+            + writer.write_u64(self.fee_amount)?;
+        bytes_written += match &self.signature {
+            None => writer.write_u8(0)?,
+            Some(signature) => writer.write_u8(1)? + signature.encode(writer)?,
+        };
+        Ok(bytes_written)
+    }
+}
+
+impl Codable for WithdrawalSignature {
+    fn decode<R: Read + ?Sized>(reader: &mut R) -> Result<Self, io::Error> {
+        let nonce = reader.read_u64()?;
+        let expiry = reader.read_u64()?;
+        let witness_size = reader.read_u16()?;
+        let mut witness = Vec::with_capacity(usize::from(witness_size));
+        reader.take(witness_size as u64).read_to_end(&mut witness)?;
+
+        Ok(Self {
+            nonce,
+            expiry,
+            witness,
+        })
+    }
+
+    fn encode<W: Write + ?Sized>(&self, mut writer: &mut W) -> Result<usize, io::Error> {
+        let mut bytes_written = writer.write_u64(self.nonce)? +
+            writer.write_u64(self.expiry)? +
+            writer.write_usize_as_u16(self.witness.len())?;
+        writer.write_all(self.witness.as_slice())?;
+        bytes_written += self.witness.len();
+        Ok(bytes_written)
+    }
+}
+
 impl Codable for TokenDeposits {
     fn decode<R: Read + ?Sized>(reader: &mut R) -> Result<Self, io::Error> {
         let account_index = reader.read_u8()?;
@@ -386,7 +589,7 @@ impl Codable for TokenDeposits {
         let deposits_count = reader.read_u16_as_usize()?;
         let mut deposits = Vec::with_capacity(deposits_count);
         for _ in 0..deposits_count {
-            deposits.push(Adjustment::decode(reader)?);
+            deposits.push(Deposit::decode(reader)?);
         }
 
         Ok(Self {
@@ -495,6 +698,54 @@ impl Codable for InitProgramStateParams {
     }
 }
 
+impl Codable for UpdateFeeAccountParams {
+    fn decode<R: io::Read + ?Sized>(reader: &mut R) -> Result<Self, io::Error> {
+        Ok(Self {
+            fee_account: reader.read_string()?,
+        })
+    }
+
+    fn encode<W: Write + ?Sized>(&self, mut writer: &mut W) -> Result<usize, io::Error> {
+        writer.write_string(&self.fee_account)
+    }
+}
+
+impl Codable for UpdateProgramChangeAddressParams {
+    fn decode<R: io::Read + ?Sized>(reader: &mut R) -> Result<Self, io::Error> {
+        Ok(Self {
+            program_change_address: reader.read_string()?,
+        })
+    }
+
+    fn encode<W: Write + ?Sized>(&self, mut writer: &mut W) -> Result<usize, io::Error> {
+        writer.write_string(&self.program_change_address)
+    }
+}
+
+impl Codable for PauseParams {
+    fn decode<R: io::Read + ?Sized>(reader: &mut R) -> Result<Self, io::Error> {
+        Ok(Self {
+            pause_flags: reader.read_u8()?,
+        })
+    }
+
+    fn encode<W: Write + ?Sized>(&self, mut writer: &mut W) -> Result<usize, io::Error> {
+        writer.write_u8(self.pause_flags)
+    }
+}
+
+impl Codable for MigrateAccountParams {
+    fn decode<R: io::Read + ?Sized>(reader: &mut R) -> Result<Self, io::Error> {
+        Ok(Self {
+            account_index: reader.read_u8()?,
+        })
+    }
+
+    fn encode<W: Write + ?Sized>(&self, mut writer: &mut W) -> Result<usize, io::Error> {
+        writer.write_u8(self.account_index)
+    }
+}
+
 impl Codable for InitTokenStateParams {
     fn decode<R: io::Read + ?Sized>(reader: &mut R) -> Result<Self, io::Error> {
         Ok(Self {
@@ -519,351 +770,1317 @@ impl Codable for SetTokenRuneIdParams {
     }
 }
 
-impl Codable for InitWalletBalancesParams {
+impl Codable for CloseWalletBalanceParams {
     fn decode<R: io::Read + ?Sized>(reader: &mut R) -> Result<Self, io::Error> {
-        let token_state_setups_count = reader.read_u16_as_usize()?;
-
-        let mut token_state_setups: Vec<TokenStateSetup> = Vec::with_capacity(token_state_setups_count);
-        for _ in 0..token_state_setups_count {
-            token_state_setups.push(TokenStateSetup::decode(reader)?);
+        let account_index = reader.read_u8()?;
+        let address_indexes_count = reader.read_u16_as_usize()?;
+        let mut address_indexes = Vec::with_capacity(address_indexes_count);
+        for _ in 0..address_indexes_count {
+            address_indexes.push(AddressIndex::decode(reader)?);
         }
 
         Ok(Self {
-            token_state_setups
+            account_index,
+            address_indexes,
         })
     }
 
     fn encode<W: Write + ?Sized>(&self, mut writer: &mut W) -> Result<usize, io::Error> {
-        let mut bytes_written = writer.write_usize_as_u16(self.token_state_setups.len())?;
-        for token_state_setups in &self.token_state_setups {
-            bytes_written += token_state_setups.encode(writer)?;
+        let mut bytes_written = writer.write_u8(self.account_index)? +
+            writer.write_usize_as_u16(self.address_indexes.len())?;
+        for address_index in &self.address_indexes {
+            bytes_written += address_index.encode(writer)?;
         }
         Ok(bytes_written)
     }
 }
 
-impl Codable for DepositBatchParams {
-    fn decode<R: Read + ?Sized>(reader: &mut R) -> Result<Self, io::Error> {
-        let token_deposits_count = reader.read_u16_as_usize()?;
-
-        let mut token_deposits = Vec::with_capacity(token_deposits_count);
-        for _ in 0..token_deposits_count {
-            token_deposits.push(TokenDeposits::decode(reader)?);
-        }
-
+impl Codable for CompactBalancesParams {
+    fn decode<R: io::Read + ?Sized>(reader: &mut R) -> Result<Self, io::Error> {
         Ok(Self {
-            token_deposits
+            account_index: reader.read_u8()?,
         })
     }
 
     fn encode<W: Write + ?Sized>(&self, mut writer: &mut W) -> Result<usize, io::Error> {
-        let mut bytes_written = writer.write_usize_as_u16(self.token_deposits.len())?;
-        for token_deposits in &self.token_deposits {
-            bytes_written += token_deposits.encode(writer)?;
-        }
-        Ok(bytes_written)
+        writer.write_u8(self.account_index)
     }
 }
 
-impl Codable for WithdrawBatchParams {
-    fn decode<R: Read + ?Sized>(reader: &mut R) -> Result<Self, io::Error> {
-        let tx_hex_size = reader.read_u16()?;
-        let mut tx_hex = Vec::with_capacity(usize::from(tx_hex_size));
-        reader.take(tx_hex_size as u64).read_to_end(&mut tx_hex)?;
-
-        let change_amount = reader.read_u64()?;
-
-        let token_withdrawals_count = reader.read_u16_as_usize()?;
-        let mut token_withdrawals = Vec::with_capacity(token_withdrawals_count);
-        for _ in 0..token_withdrawals_count {
-            token_withdrawals.push(TokenWithdrawals::decode(reader)?);
-        }
-
-        let input_utxo_type_count = reader.read_u16_as_usize()?;
-        let mut input_utxo_types = Vec::with_capacity(input_utxo_type_count);
-        for _ in 0..input_utxo_type_count {
-            input_utxo_types.push(InputUtxoType::decode(reader)?);
-        }
+impl Codable for SetWithdrawalFeePolicyParams {
+    fn decode<R: io::Read + ?Sized>(reader: &mut R) -> Result<Self, io::Error> {
+        let account_index = reader.read_u8()?;
+        let fee_policy = match reader.read_u8()? {
+            0 => None,
+            1 => Some(WithdrawalFeePolicyParams {
+                min_fee: reader.read_u64()?,
+                flat_fee: reader.read_u64()?,
+                fee_account_index: match reader.read_u8()? {
+                    0 => None,
+                    1 => Some(reader.read_u8()?),
+                    _ => return Err(io::Error::new(io::ErrorKind::Other, "Invalid fee account flag"))
+                },
+            }),
+            _ => return Err(io::Error::new(io::ErrorKind::Other, "Invalid fee policy flag"))
+        };
 
         Ok(Self {
-            tx_hex,
-            change_amount,
-            input_utxo_types,
-            token_withdrawals,
+            account_index,
+            fee_policy,
         })
     }
 
     fn encode<W: Write + ?Sized>(&self, mut writer: &mut W) -> Result<usize, io::Error> {
-        let mut bytes_written = writer.write_usize_as_u16(self.tx_hex.len())?;
-        writer.write_all(self.tx_hex.as_slice())?;
-        bytes_written += self.tx_hex.len();
-        bytes_written += writer.write_u64(self.change_amount)?;
-        bytes_written += writer.write_usize_as_u16(self.token_withdrawals.len())?;
-        for token_withdrawals in &self.token_withdrawals {
-            bytes_written += token_withdrawals.encode(writer)?;
-        }
-        bytes_written += writer.write_usize_as_u16(self.input_utxo_types.len())?;
-        for input_utxo_type in &self.input_utxo_types {
-            bytes_written += input_utxo_type.encode(writer)?;
-        }
+        let mut bytes_written = writer.write_u8(self.account_index)?;
+        bytes_written += match &self.fee_policy {
+            None => writer.write_u8(0)?,
+            Some(fee_policy) => {
+                writer.write_u8(1)? +
+                    writer.write_u64(fee_policy.min_fee)? +
+                    writer.write_u64(fee_policy.flat_fee)? +
+                    match fee_policy.fee_account_index {
+                        None => writer.write_u8(0)?,
+                        Some(fee_account_index) => writer.write_u8(1)? + writer.write_u8(fee_account_index)?,
+                    }
+            }
+        };
         Ok(bytes_written)
     }
 }
 
-impl Codable for UpdateWithdrawStateUtxoParams {
+impl Codable for SetWithdrawalLimitParams {
     fn decode<R: io::Read + ?Sized>(reader: &mut R) -> Result<Self, io::Error> {
+        let limit = match reader.read_u8()? {
+            0 => None,
+            1 => Some(WithdrawalLimitParams {
+                max_amount: reader.read_u64()?,
+                window_blocks: reader.read_u64()?,
+            }),
+            _ => return Err(io::Error::new(io::ErrorKind::Other, "Invalid withdrawal limit flag"))
+        };
+
         Ok(Self {
-            tx_id: reader.read_string()?,
-            vout: reader.read_u32()?
+            limit,
         })
     }
 
     fn encode<W: Write + ?Sized>(&self, mut writer: &mut W) -> Result<usize, io::Error> {
-        let mut bytes_written = writer.write_string(&self.tx_id)?;
-        bytes_written += writer.write_u32(self.vout)?;
-        Ok(bytes_written)
+        Ok(match &self.limit {
+            None => writer.write_u8(0)?,
+            Some(limit) => {
+                writer.write_u8(1)? +
+                    writer.write_u64(limit.max_amount)? +
+                    writer.write_u64(limit.window_blocks)?
+            }
+        })
     }
 }
 
+impl Codable for SetWithdrawalFeeRateParams {
+    fn decode<R: io::Read + ?Sized>(reader: &mut R) -> Result<Self, io::Error> {
+        let fee_rate = match reader.read_u8()? {
+            0 => None,
+            1 => Some(WithdrawalFeeRateParams {
+                min_fee_rate: reader.read_u64()?,
+                max_fee_rate: reader.read_u64()?,
+            }),
+            _ => return Err(io::Error::new(io::ErrorKind::Other, "Invalid fee rate flag"))
+        };
 
-impl Codable for SettlementBatchParams {
-    fn decode<R: Read + ?Sized>(reader: &mut R) -> Result<Self, io::Error> {
-        let settlements_count = reader.read_u16_as_usize()?;
+        Ok(Self {
+            fee_rate,
+        })
+    }
 
-        let mut settlements = Vec::with_capacity(settlements_count);
-        for _ in 0..settlements_count {
-            settlements.push(SettlementAdjustments::decode(reader)?);
+    fn encode<W: Write + ?Sized>(&self, mut writer: &mut W) -> Result<usize, io::Error> {
+        Ok(match &self.fee_rate {
+            None => writer.write_u8(0)?,
+            Some(fee_rate) => {
+                writer.write_u8(1)? +
+                    writer.write_u64(fee_rate.min_fee_rate)? +
+                    writer.write_u64(fee_rate.max_fee_rate)?
+            }
+        })
+    }
+}
+
+impl Codable for RegisterUtxosParams {
+    fn decode<R: io::Read + ?Sized>(reader: &mut R) -> Result<Self, io::Error> {
+        let utxos_count = reader.read_u16_as_usize()?;
+        let mut utxos = Vec::with_capacity(utxos_count);
+        for _ in 0..utxos_count {
+            utxos.push(FundingOutpoint::decode(reader)?);
         }
 
         Ok(Self {
-            settlements
+            utxos,
         })
     }
 
     fn encode<W: Write + ?Sized>(&self, mut writer: &mut W) -> Result<usize, io::Error> {
-        let mut bytes_written = writer.write_usize_as_u16(self.settlements.len())?;
-        for settlement in &self.settlements {
-            bytes_written += settlement.encode(writer)?;
+        let mut bytes_written = writer.write_usize_as_u16(self.utxos.len())?;
+        for utxo in &self.utxos {
+            bytes_written += utxo.encode(writer)?;
         }
         Ok(bytes_written)
     }
 }
 
-impl Codable for RollbackWithdrawBatchParams {
+impl Codable for ConfirmWithdrawBatchParams {
     fn decode<R: Read + ?Sized>(reader: &mut R) -> Result<Self, io::Error> {
-        let token_withdrawals_count = reader.read_u16_as_usize()?;
-        let mut token_withdrawals = Vec::with_capacity(token_withdrawals_count);
-        for _ in 0..token_withdrawals_count {
-            token_withdrawals.push(TokenWithdrawals::decode(reader)?);
-        }
         Ok(Self {
-            token_withdrawals
+            tx_id: reader.read_hash()?,
         })
     }
 
-    fn encode<W: Write + ?Sized>(&self, mut writer: &mut W) -> Result<usize, io::Error> {
-        let mut bytes_written = writer.write_usize_as_u16(self.token_withdrawals.len())?;
-        for token_withdrawals in &self.token_withdrawals {
-            bytes_written += token_withdrawals.encode(writer)?;
-        }
-        Ok(bytes_written)
+    fn encode<W: Write + ?Sized>(&self, writer: &mut W) -> Result<usize, io::Error> {
+        writer.write_hash(&self.tx_id)
     }
 }
 
-impl Codable for Balance {
-    fn decode<R: Read + ?Sized>(reader: &mut R) -> Result<Self, io::Error> {
+impl Codable for BumpWithdrawBatchParams {
+    fn decode<R: io::Read + ?Sized>(reader: &mut R) -> Result<Self, io::Error> {
         Ok(Self {
-            address: reader.read_string_with_padding(MAX_ADDRESS_SIZE)?,
-            balance: reader.read_u64()?,
+            withdraw_batch: WithdrawBatchParams::decode(reader)?,
+            change_amount: reader.read_u64()?,
         })
     }
 
     fn encode<W: Write + ?Sized>(&self, mut writer: &mut W) -> Result<usize, io::Error> {
         Ok(
-            writer.write_string_with_padding(&self.address, MAX_ADDRESS_SIZE)? +
-                writer.write_u64(self.balance)?
+            self.withdraw_batch.encode(writer)? +
+                writer.write_u64(self.change_amount)?
         )
     }
 }
 
-impl Codable for Event {
-    fn decode<R: Read + ?Sized>(reader: &mut R) -> Result<Self, io::Error> {
-        let mut event_data = Vec::with_capacity(EVENT_SIZE);
-        reader.take(EVENT_SIZE as u64).read_to_end(&mut event_data)?;
+impl Codable for InitForcedWithdrawalsParams {
+    fn decode<R: io::Read + ?Sized>(reader: &mut R) -> Result<Self, io::Error> {
+        Ok(Self {
+            delay_blocks: reader.read_u64()?,
+        })
+    }
 
-        let mut event_data_reader = Cursor::new(event_data);
-        let event_type = event_data_reader.read_u8()?;
-        match event_type {
-            0 => Ok(Self::FailedSettlement {
-                account_index: event_data_reader.read_u8()?,
-                address_index: event_data_reader.read_u32()?,
-                requested_amount: event_data_reader.read_u64()?,
-                balance: event_data_reader.read_u64()?,
-                error_code: event_data_reader.read_u32()?
-            }),
-            1 => Ok(Self::FailedWithdrawal {
-                account_index: event_data_reader.read_u8()?,
-                address_index: event_data_reader.read_u32()?,
-                fee_account_index: event_data_reader.read_u8()?,
-                fee_address_index: event_data_reader.read_u32()?,
-                requested_amount: event_data_reader.read_u64()?,
-                fee_amount: event_data_reader.read_u64()?,
-                balance: event_data_reader.read_u64()?,
-                balance_in_fee_token: event_data_reader.read_u64()?,
-                error_code: event_data_reader.read_u32()?
-            }),
-            _ => Err(io::Error::new(io::ErrorKind::Other, "Invalid event type"))
+    fn encode<W: Write + ?Sized>(&self, mut writer: &mut W) -> Result<usize, io::Error> {
+        writer.write_u64(self.delay_blocks)
+    }
+}
+
+impl Codable for RequestForcedWithdrawalParams {
+    fn decode<R: io::Read + ?Sized>(reader: &mut R) -> Result<Self, io::Error> {
+        let account_index = reader.read_u8()?;
+        let address_index = AddressIndex::decode(reader)?;
+        let amount = reader.read_u64()?;
+        let nonce = reader.read_u64()?;
+        let signature_size = reader.read_u16()?;
+        let mut signature = Vec::with_capacity(usize::from(signature_size));
+        reader.take(signature_size as u64).read_to_end(&mut signature)?;
+
+        Ok(Self {
+            account_index,
+            address_index,
+            amount,
+            nonce,
+            signature,
+        })
+    }
+
+    fn encode<W: Write + ?Sized>(&self, mut writer: &mut W) -> Result<usize, io::Error> {
+        let mut bytes_written = writer.write_u8(self.account_index)? +
+            self.address_index.encode(writer)? +
+            writer.write_u64(self.amount)? +
+            writer.write_u64(self.nonce)? +
+            writer.write_usize_as_u16(self.signature.len())?;
+        writer.write_all(self.signature.as_slice())?;
+        bytes_written += self.signature.len();
+        Ok(bytes_written)
+    }
+}
+
+impl Codable for ForcedWithdrawParams {
+    fn decode<R: io::Read + ?Sized>(reader: &mut R) -> Result<Self, io::Error> {
+        let account_index = reader.read_u8()?;
+        let address_index = AddressIndex::decode(reader)?;
+        let tx_hex_size = reader.read_u16()?;
+        let mut tx_hex = Vec::with_capacity(usize::from(tx_hex_size));
+        reader.take(tx_hex_size as u64).read_to_end(&mut tx_hex)?;
+        let network_fee = reader.read_u64()?;
+        let signature_size = reader.read_u16()?;
+        let mut signature = Vec::with_capacity(usize::from(signature_size));
+        reader.take(signature_size as u64).read_to_end(&mut signature)?;
+
+        Ok(Self {
+            account_index,
+            address_index,
+            tx_hex,
+            network_fee,
+            signature,
+        })
+    }
+
+    fn encode<W: Write + ?Sized>(&self, mut writer: &mut W) -> Result<usize, io::Error> {
+        let mut bytes_written = writer.write_u8(self.account_index)? +
+            self.address_index.encode(writer)? +
+            writer.write_usize_as_u16(self.tx_hex.len())?;
+        writer.write_all(self.tx_hex.as_slice())?;
+        bytes_written += self.tx_hex.len();
+        bytes_written += writer.write_u64(self.network_fee)? +
+            writer.write_usize_as_u16(self.signature.len())?;
+        writer.write_all(self.signature.as_slice())?;
+        bytes_written += self.signature.len();
+        Ok(bytes_written)
+    }
+}
+
+impl Codable for InitWalletBalancesParams {
+    fn decode<R: io::Read + ?Sized>(reader: &mut R) -> Result<Self, io::Error> {
+        let token_state_setups_count = reader.read_u16_as_usize()?;
+
+        let mut token_state_setups: Vec<TokenStateSetup> = Vec::with_capacity(token_state_setups_count);
+        for _ in 0..token_state_setups_count {
+            token_state_setups.push(TokenStateSetup::decode(reader)?);
+        }
+
+        Ok(Self {
+            token_state_setups
+        })
+    }
+
+    fn encode<W: Write + ?Sized>(&self, mut writer: &mut W) -> Result<usize, io::Error> {
+        let mut bytes_written = writer.write_usize_as_u16(self.token_state_setups.len())?;
+        for token_state_setups in &self.token_state_setups {
+            bytes_written += token_state_setups.encode(writer)?;
+        }
+        Ok(bytes_written)
+    }
+}
+
+impl Codable for DepositBatchParams {
+    fn decode<R: Read + ?Sized>(reader: &mut R) -> Result<Self, io::Error> {
+        let token_deposits_count = reader.read_u16_as_usize()?;
+
+        let mut token_deposits = Vec::with_capacity(token_deposits_count);
+        for _ in 0..token_deposits_count {
+            token_deposits.push(TokenDeposits::decode(reader)?);
+        }
+
+        Ok(Self {
+            token_deposits
+        })
+    }
+
+    fn encode<W: Write + ?Sized>(&self, mut writer: &mut W) -> Result<usize, io::Error> {
+        let mut bytes_written = writer.write_usize_as_u16(self.token_deposits.len())?;
+        for token_deposits in &self.token_deposits {
+            bytes_written += token_deposits.encode(writer)?;
+        }
+        Ok(bytes_written)
+    }
+}
+
+impl Codable for WithdrawBatchParams {
+    fn decode<R: Read + ?Sized>(reader: &mut R) -> Result<Self, io::Error> {
+        let tx_hex_size = reader.read_u16()?;
+        let mut tx_hex = Vec::with_capacity(usize::from(tx_hex_size));
+        reader.take(tx_hex_size as u64).read_to_end(&mut tx_hex)?;
+
+        let change_amount = reader.read_u64()?;
+
+        let token_withdrawals_count = reader.read_u16_as_usize()?;
+        let mut token_withdrawals = Vec::with_capacity(token_withdrawals_count);
+        for _ in 0..token_withdrawals_count {
+            token_withdrawals.push(TokenWithdrawals::decode(reader)?);
+        }
+
+        let input_utxo_type_count = reader.read_u16_as_usize()?;
+        let mut input_utxo_types = Vec::with_capacity(input_utxo_type_count);
+        for _ in 0..input_utxo_type_count {
+            input_utxo_types.push(InputUtxoType::decode(reader)?);
+        }
+
+        Ok(Self {
+            tx_hex,
+            change_amount,
+            input_utxo_types,
+            token_withdrawals,
+        })
+    }
+
+    fn encode<W: Write + ?Sized>(&self, mut writer: &mut W) -> Result<usize, io::Error> {
+        let mut bytes_written = writer.write_usize_as_u16(self.tx_hex.len())?;
+        writer.write_all(self.tx_hex.as_slice())?;
+        bytes_written += self.tx_hex.len();
+        bytes_written += writer.write_u64(self.change_amount)?;
+        bytes_written += writer.write_usize_as_u16(self.token_withdrawals.len())?;
+        for token_withdrawals in &self.token_withdrawals {
+            bytes_written += token_withdrawals.encode(writer)?;
+        }
+        bytes_written += writer.write_usize_as_u16(self.input_utxo_types.len())?;
+        for input_utxo_type in &self.input_utxo_types {
+            bytes_written += input_utxo_type.encode(writer)?;
+        }
+        Ok(bytes_written)
+    }
+}
+
+impl Codable for UpdateWithdrawStateUtxoParams {
+    fn decode<R: io::Read + ?Sized>(reader: &mut R) -> Result<Self, io::Error> {
+        Ok(Self {
+            tx_id: reader.read_string()?,
+            vout: reader.read_u32()?
+        })
+    }
+
+    fn encode<W: Write + ?Sized>(&self, mut writer: &mut W) -> Result<usize, io::Error> {
+        let mut bytes_written = writer.write_string(&self.tx_id)?;
+        bytes_written += writer.write_u32(self.vout)?;
+        Ok(bytes_written)
+    }
+}
+
+
+impl Codable for SettlementBatchParams {
+    fn decode<R: Read + ?Sized>(reader: &mut R) -> Result<Self, io::Error> {
+        let settlements_count = reader.read_u16_as_usize()?;
+
+        let mut settlements = Vec::with_capacity(settlements_count);
+        for _ in 0..settlements_count {
+            settlements.push(SettlementAdjustments::decode(reader)?);
+        }
+
+        Ok(Self {
+            settlements
+        })
+    }
+
+    fn encode<W: Write + ?Sized>(&self, mut writer: &mut W) -> Result<usize, io::Error> {
+        let mut bytes_written = writer.write_usize_as_u16(self.settlements.len())?;
+        for settlement in &self.settlements {
+            bytes_written += settlement.encode(writer)?;
+        }
+        Ok(bytes_written)
+    }
+}
+
+impl Codable for RollbackWithdrawBatchParams {
+    fn decode<R: Read + ?Sized>(reader: &mut R) -> Result<Self, io::Error> {
+        let token_withdrawals_count = reader.read_u16_as_usize()?;
+        let mut token_withdrawals = Vec::with_capacity(token_withdrawals_count);
+        for _ in 0..token_withdrawals_count {
+            token_withdrawals.push(TokenWithdrawals::decode(reader)?);
+        }
+        Ok(Self {
+            token_withdrawals
+        })
+    }
+
+    fn encode<W: Write + ?Sized>(&self, mut writer: &mut W) -> Result<usize, io::Error> {
+        let mut bytes_written = writer.write_usize_as_u16(self.token_withdrawals.len())?;
+        for token_withdrawals in &self.token_withdrawals {
+            bytes_written += token_withdrawals.encode(writer)?;
+        }
+        Ok(bytes_written)
+    }
+}
+
+// balances keep the compact wallet address, free balances have an empty address
+impl Codable for Balance {
+    fn decode<R: Read + ?Sized>(reader: &mut R) -> Result<Self, io::Error> {
+        let mut address = WalletAddress::EMPTY;
+        reader.read_exact(&mut address.0)?;
+        Ok(Self {
+            address: address.to_address_string()
+                .map_err(|_| io::Error::new(io::ErrorKind::Other, "Invalid wallet address"))?,
+            balance: reader.read_u64()?,
+        })
+    }
+
+    fn encode<W: Write + ?Sized>(&self, mut writer: &mut W) -> Result<usize, io::Error> {
+        let address = if self.address.is_empty() {
+            WalletAddress::EMPTY
+        } else {
+            WalletAddress::from_address(&self.address)
+                .map_err(|_| io::Error::new(io::ErrorKind::Other, "Invalid wallet address"))?
+        };
+        writer.write_all(&address.0)?;
+        Ok(WALLET_ADDRESS_SIZE + writer.write_u64(self.balance)?)
+    }
+}
+
+impl Balance {
+    // token states before version 4 and balance shards before version 1 kept the address string
+    fn decode_versioned<R: Read + ?Sized>(reader: &mut R, is_legacy: bool) -> Result<Self, io::Error> {
+        if !is_legacy {
+            return Self::decode(reader);
+        }
+        Ok(Self {
+            address: reader.read_string_with_padding(MAX_ADDRESS_SIZE)?,
+            balance: reader.read_u64()?,
+        })
+    }
+
+    fn encode_versioned<W: Write + ?Sized>(&self, writer: &mut W, is_legacy: bool) -> Result<usize, io::Error> {
+        if !is_legacy {
+            return self.encode(writer);
+        }
+        Ok(
+            writer.write_string_with_padding(&self.address, MAX_ADDRESS_SIZE)? +
+                writer.write_u64(self.balance)?
+        )
+    }
+}
+
+impl Codable for Event {
+    fn decode<R: Read + ?Sized>(reader: &mut R) -> Result<Self, io::Error> {
+        let event_type = reader.read_u8()?;
+        match event_type {
+            0 => Ok(Self::FailedSettlement {
+                account_index: reader.read_u8()?,
+                address_index: reader.read_u32()?,
+                requested_amount: reader.read_u64()?,
+                balance: reader.read_u64()?,
+                error_code: reader.read_u32()?
+            }),
+            1 => Ok(Self::FailedWithdrawal {
+                account_index: reader.read_u8()?,
+                address_index: reader.read_u32()?,
+                fee_account_index: reader.read_u8()?,
+                fee_address_index: reader.read_u32()?,
+                requested_amount: reader.read_u64()?,
+                fee_amount: reader.read_u64()?,
+                balance: reader.read_u64()?,
+                balance_in_fee_token: reader.read_u64()?,
+                error_code: reader.read_u32()?
+            }),
+            2 => Ok(Self::DuplicateDeposit {
+                account_index: reader.read_u8()?,
+                address_index: reader.read_u32()?,
+                amount: reader.read_u64()?,
+                tx_id: reader.read_hash()?,
+                vout: reader.read_u32()?
+            }),
+            3 => Ok(Self::DepositCredited {
+                sequence: reader.read_u64()?,
+                account_index: reader.read_u8()?,
+                address_index: reader.read_u32()?,
+                amount: reader.read_u64()?
+            }),
+            4 => Ok(Self::SettlementApplied {
+                sequence: reader.read_u64()?,
+                account_index: reader.read_u8()?,
+                settlement_hash: reader.read_hash()?,
+                increment_total: reader.read_u64()?,
+                decrement_total: reader.read_u64()?
+            }),
+            5 => Ok(Self::WithdrawalPrepared {
+                sequence: reader.read_u64()?,
+                account_index: reader.read_u8()?,
+                address_index: reader.read_u32()?,
+                amount: reader.read_u64()?,
+                fee_amount: reader.read_u64()?
+            }),
+            6 => Ok(Self::WithdrawalSubmitted {
+                sequence: reader.read_u64()?,
+                tx_id: reader.read_hash()?,
+                num_withdrawals: reader.read_u32()?
+            }),
+            7 => Ok(Self::FeeCollected {
+                sequence: reader.read_u64()?,
+                account_index: reader.read_u8()?,
+                amount: reader.read_u64()?
+            }),
+            8 => Ok(Self::SettlementExcluded {
+                account_index: reader.read_u8()?,
+                settlement_index: reader.read_u16()?
+            }),
+            9 => Ok(Self::WithdrawalExcluded {
+                account_index: reader.read_u8()?,
+                withdrawal_index: reader.read_u16()?
+            }),
+            10 => Ok(Self::WalletBalanceClosed {
+                sequence: reader.read_u64()?,
+                account_index: reader.read_u8()?,
+                address_index: reader.read_u32()?
+            }),
+            11 => Ok(Self::BalanceIndexRemapped {
+                sequence: reader.read_u64()?,
+                account_index: reader.read_u8()?,
+                from_address_index: reader.read_u32()?,
+                to_address_index: reader.read_u32()?
+            }),
+            12 => Ok(Self::NettingFailed {
+                account_index: reader.read_u8()?,
+                increment_total: reader.read_u64()?,
+                decrement_total: reader.read_u64()?
+            }),
+            13 => Ok(Self::ForcedWithdrawalRequested {
+                sequence: reader.read_u64()?,
+                account_index: reader.read_u8()?,
+                address_index: reader.read_u32()?,
+                amount: reader.read_u64()?,
+                due_height: reader.read_u64()?
+            }),
+            14 => Ok(Self::ForcedWithdrawalExecuted {
+                sequence: reader.read_u64()?,
+                account_index: reader.read_u8()?,
+                address_index: reader.read_u32()?,
+                amount: reader.read_u64()?,
+                tx_id: reader.read_hash()?
+            }),
+            15 => Ok(Self::WithdrawalConfirmed {
+                sequence: reader.read_u64()?,
+                tx_id: reader.read_hash()?
+            }),
+            16 => Ok(Self::WithdrawalBumped {
+                sequence: reader.read_u64()?,
+                replaced_tx_id: reader.read_hash()?,
+                tx_id: reader.read_hash()?
+            }),
+            17 => Ok(Self::DepositLedgerLow {
+                remaining_deposits: reader.read_u32()?
+            }),
+            _ => Err(io::Error::new(io::ErrorKind::Other, "Invalid event type"))
+        }
+    }
+
+    fn encode<W: Write + ?Sized>(&self, mut writer: &mut W) -> Result<usize, io::Error> {
+        Ok(match self {
+            Self::FailedSettlement { account_index, address_index, requested_amount, balance, error_code } => {
+                writer.write_u8(0)? +
+                    writer.write_u8(*account_index)? +
+                    writer.write_u32(*address_index)? +
+                    writer.write_u64(*requested_amount)? +
+                    writer.write_u64(*balance)? +
+                    writer.write_u32(*error_code)?
+            }
+            Self::FailedWithdrawal { account_index, address_index, fee_account_index, fee_address_index, requested_amount, fee_amount, balance, balance_in_fee_token, error_code } => {
+                writer.write_u8(1)? +
+                    writer.write_u8(*account_index)? +
+                    writer.write_u32(*address_index)? +
+                    writer.write_u8(*fee_account_index)? +
+                    writer.write_u32(*fee_address_index)? +
+                    writer.write_u64(*requested_amount)? +
+                    writer.write_u64(*fee_amount)? +
+                    writer.write_u64(*balance)? +
+                    writer.write_u64(*balance_in_fee_token)? +
+                    writer.write_u32(*error_code)?
+            }
+            Self::DuplicateDeposit { account_index, address_index, amount, tx_id, vout } => {
+                writer.write_u8(2)? +
+                    writer.write_u8(*account_index)? +
+                    writer.write_u32(*address_index)? +
+                    writer.write_u64(*amount)? +
+                    writer.write_hash(tx_id)? +
+                    writer.write_u32(*vout)?
+            }
+            Self::DepositCredited { sequence, account_index, address_index, amount } => {
+                writer.write_u8(3)? +
+                    writer.write_u64(*sequence)? +
+                    writer.write_u8(*account_index)? +
+                    writer.write_u32(*address_index)? +
+                    writer.write_u64(*amount)?
+            }
+            Self::SettlementApplied { sequence, account_index, settlement_hash, increment_total, decrement_total } => {
+                writer.write_u8(4)? +
+                    writer.write_u64(*sequence)? +
+                    writer.write_u8(*account_index)? +
+                    writer.write_hash(settlement_hash)? +
+                    writer.write_u64(*increment_total)? +
+                    writer.write_u64(*decrement_total)?
+            }
+            Self::WithdrawalPrepared { sequence, account_index, address_index, amount, fee_amount } => {
+                writer.write_u8(5)? +
+                    writer.write_u64(*sequence)? +
+                    writer.write_u8(*account_index)? +
+                    writer.write_u32(*address_index)? +
+                    writer.write_u64(*amount)? +
+                    writer.write_u64(*fee_amount)?
+            }
+            Self::WithdrawalSubmitted { sequence, tx_id, num_withdrawals } => {
+                writer.write_u8(6)? +
+                    writer.write_u64(*sequence)? +
+                    writer.write_hash(tx_id)? +
+                    writer.write_u32(*num_withdrawals)?
+            }
+            Self::FeeCollected { sequence, account_index, amount } => {
+                writer.write_u8(7)? +
+                    writer.write_u64(*sequence)? +
+                    writer.write_u8(*account_index)? +
+                    writer.write_u64(*amount)?
+            }
+            Self::SettlementExcluded { account_index, settlement_index } => {
+                writer.write_u8(8)? +
+                    writer.write_u8(*account_index)? +
+                    writer.write_u16(*settlement_index)?
+            }
+            Self::WithdrawalExcluded { account_index, withdrawal_index } => {
+                writer.write_u8(9)? +
+                    writer.write_u8(*account_index)? +
+                    writer.write_u16(*withdrawal_index)?
+            }
+            Self::WalletBalanceClosed { sequence, account_index, address_index } => {
+                writer.write_u8(10)? +
+                    writer.write_u64(*sequence)? +
+                    writer.write_u8(*account_index)? +
+                    writer.write_u32(*address_index)?
+            }
+            Self::BalanceIndexRemapped { sequence, account_index, from_address_index, to_address_index } => {
+                writer.write_u8(11)? +
+                    writer.write_u64(*sequence)? +
+                    writer.write_u8(*account_index)? +
+                    writer.write_u32(*from_address_index)? +
+                    writer.write_u32(*to_address_index)?
+            }
+            Self::NettingFailed { account_index, increment_total, decrement_total } => {
+                writer.write_u8(12)? +
+                    writer.write_u8(*account_index)? +
+                    writer.write_u64(*increment_total)? +
+                    writer.write_u64(*decrement_total)?
+            }
+            Self::ForcedWithdrawalRequested { sequence, account_index, address_index, amount, due_height } => {
+                writer.write_u8(13)? +
+                    writer.write_u64(*sequence)? +
+                    writer.write_u8(*account_index)? +
+                    writer.write_u32(*address_index)? +
+                    writer.write_u64(*amount)? +
+                    writer.write_u64(*due_height)?
+            }
+            Self::ForcedWithdrawalExecuted { sequence, account_index, address_index, amount, tx_id } => {
+                writer.write_u8(14)? +
+                    writer.write_u64(*sequence)? +
+                    writer.write_u8(*account_index)? +
+                    writer.write_u32(*address_index)? +
+                    writer.write_u64(*amount)? +
+                    writer.write_hash(tx_id)?
+            }
+            Self::WithdrawalConfirmed { sequence, tx_id } => {
+                writer.write_u8(15)? +
+                    writer.write_u64(*sequence)? +
+                    writer.write_hash(tx_id)?
+            }
+            Self::WithdrawalBumped { sequence, replaced_tx_id, tx_id } => {
+                writer.write_u8(16)? +
+                    writer.write_u64(*sequence)? +
+                    writer.write_hash(replaced_tx_id)? +
+                    writer.write_hash(tx_id)?
+            }
+            Self::DepositLedgerLow { remaining_deposits } => {
+                writer.write_u8(17)? +
+                    writer.write_u32(*remaining_deposits)?
+            }
+        })
+    }
+}
+
+impl Codable for TokenState {
+    fn decode<R: Read + ?Sized>(reader: &mut R) -> Result<Self, Error> {
+        let account_type = AccountType::decode(reader)?;
+        let version = reader.read_u32()?;
+        let program_state_account = reader.read_pubkey()?;
+        let token_id = reader.read_string_with_padding(MAX_TOKEN_ID_SIZE)?;
+        let wallet_index_account = if version >= 1 { reader.read_pubkey()? } else { Pubkey::from([0u8; 32]) };
+        let (num_free_balances, first_free_balance) = if version >= 2 {
+            (reader.read_u32()?, reader.read_u32()?)
+        } else {
+            (0, 0)
+        };
+        let (num_balance_shards, first_balance_shard_account, last_balance_shard_account) = if version >= 3 {
+            (reader.read_u8()?, reader.read_pubkey()?, reader.read_pubkey()?)
+        } else {
+            (0, Pubkey::from([0u8; 32]), Pubkey::from([0u8; 32]))
+        };
+        let withdrawal_fee_policy = if version >= 5 {
+            let is_set = reader.read_u8()? != 0;
+            let min_fee = reader.read_u64()?;
+            let flat_fee = reader.read_u64()?;
+            let fee_token_account = reader.read_pubkey()?;
+            is_set.then(|| WithdrawalFeePolicy {
+                min_fee,
+                flat_fee,
+                fee_token_account: if fee_token_account == Pubkey::from([0u8; 32]) { None } else { Some(fee_token_account) },
+            })
+        } else {
+            None
+        };
+        let withdrawal_limit_account = if version >= 6 { reader.read_pubkey()? } else { Pubkey::from([0u8; 32]) };
+
+        let balances_count = reader.read_u32_as_usize()?;
+        let mut balances = Vec::with_capacity(balances_count);
+        for _ in 0..balances_count {
+            balances.push(Balance::decode_versioned(reader, version < 4)?);
+        }
+
+        Ok(Self {
+            account_type,
+            version,
+            program_state_account,
+            token_id,
+            wallet_index_account,
+            num_free_balances,
+            first_free_balance,
+            num_balance_shards,
+            first_balance_shard_account,
+            last_balance_shard_account,
+            withdrawal_fee_policy,
+            withdrawal_limit_account,
+            balances,
+        })
+    }
+
+    fn encode<W: Write + ?Sized>(&self, mut writer: &mut W) -> Result<usize, Error> {
+        let mut bytes_written = self.account_type.encode(writer)?;
+        bytes_written += writer.write_u32(self.version)?;
+        bytes_written += writer.write_pubkey(&self.program_state_account)?;
+        bytes_written += writer.write_string_with_padding(&self.token_id, MAX_TOKEN_ID_SIZE)?;
+        if self.version >= 1 {
+            bytes_written += writer.write_pubkey(&self.wallet_index_account)?;
+        }
+        if self.version >= 2 {
+            bytes_written += writer.write_u32(self.num_free_balances)? +
+                writer.write_u32(self.first_free_balance)?;
+        }
+        if self.version >= 3 {
+            bytes_written += writer.write_u8(self.num_balance_shards)? +
+                writer.write_pubkey(&self.first_balance_shard_account)? +
+                writer.write_pubkey(&self.last_balance_shard_account)?;
+        }
+        if self.version >= 5 {
+            bytes_written += match &self.withdrawal_fee_policy {
+                None => writer.write_u8(0)? + writer.write_padding(FEE_POLICY_SIZE - FEE_POLICY_FLAG_SIZE)?,
+                Some(fee_policy) => writer.write_u8(1)? +
+                    writer.write_u64(fee_policy.min_fee)? +
+                    writer.write_u64(fee_policy.flat_fee)? +
+                    writer.write_pubkey(&fee_policy.fee_token_account.unwrap_or(Pubkey::from([0u8; 32])))?,
+            };
+        }
+        if self.version >= 6 {
+            bytes_written += writer.write_pubkey(&self.withdrawal_limit_account)?;
+        }
+        bytes_written += writer.write_usize_as_u32(self.balances.len())?;
+        for balance in &self.balances {
+            bytes_written += balance.encode_versioned(writer, self.version < 4)?;
+        }
+        Ok(bytes_written)
+    }
+}
+
+impl Codable for BalanceShardState {
+    fn decode<R: Read + ?Sized>(reader: &mut R) -> Result<Self, Error> {
+        let account_type = AccountType::decode(reader)?;
+        let version = reader.read_u32()?;
+        let token_state_account = reader.read_pubkey()?;
+        let shard = reader.read_u8()?;
+        let next_balance_shard_account = reader.read_pubkey()?;
+        let balances_count = reader.read_u32_as_usize()?;
+        let mut balances = Vec::with_capacity(balances_count);
+        for _ in 0..balances_count {
+            balances.push(Balance::decode_versioned(reader, version < 1)?);
+        }
+
+        Ok(Self {
+            account_type,
+            version,
+            token_state_account,
+            shard,
+            next_balance_shard_account,
+            balances,
+        })
+    }
+
+    fn encode<W: Write + ?Sized>(&self, mut writer: &mut W) -> Result<usize, Error> {
+        let mut bytes_written = self.account_type.encode(writer)? +
+            writer.write_u32(self.version)? +
+            writer.write_pubkey(&self.token_state_account)? +
+            writer.write_u8(self.shard)? +
+            writer.write_pubkey(&self.next_balance_shard_account)? +
+            writer.write_usize_as_u32(self.balances.len())?;
+        for balance in &self.balances {
+            bytes_written += balance.encode_versioned(writer, self.version < 1)?;
+        }
+        Ok(bytes_written)
+    }
+}
+
+impl Codable for ProgramState {
+    fn decode<R: Read + ?Sized>(reader: &mut R) -> Result<Self, Error> {
+        let account_type = AccountType::decode(reader)?;
+        let version = reader.read_u32()?;
+        let withdraw_account = reader.read_pubkey()?;
+        let fee_account_address = reader.read_string_with_padding(MAX_ADDRESS_SIZE)?;
+        let program_change_address = reader.read_string_with_padding(MAX_ADDRESS_SIZE)?;
+        let network_type = NetworkType::decode(reader)?;
+        let settlement_batch_hash = reader.read_hash()?;
+        let last_settlement_batch_hash = reader.read_hash()?;
+        let pause_flags = if version >= 1 { reader.read_u8()? } else { 0 };
+        let event_sequence = if version >= 2 { reader.read_u64()? } else { 0 };
+        let events_count = reader.read_u16()?;
+        let (first_event_log_account, event_log_account) = if version >= 3 {
+            (reader.read_pubkey()?, reader.read_pubkey()?)
+        } else {
+            // events used to be kept inline, they are dropped when migrating to the event log
+            let mut legacy_events = vec![0u8; events_count as usize * LEGACY_EVENT_SIZE];
+            reader.read_exact(&mut legacy_events)?;
+            (Pubkey::from([0u8; 32]), Pubkey::from([0u8; 32]))
+        };
+
+        Ok(Self {
+            account_type,
+            version,
+            withdraw_account,
+            fee_account_address,
+            program_change_address,
+            network_type,
+            settlement_batch_hash,
+            last_settlement_batch_hash,
+            pause_flags,
+            event_sequence,
+            events_count,
+            first_event_log_account,
+            event_log_account,
+        })
+    }
+
+    fn encode<W: Write + ?Sized>(&self, mut writer: &mut W) -> Result<usize, Error> {
+        let mut bytes_written = self.account_type.encode(writer)? +
+            writer.write_u32(self.version)? +
+            writer.write_pubkey(&self.withdraw_account)? +
+            writer.write_string_with_padding(&self.fee_account_address, MAX_ADDRESS_SIZE)? +
+            writer.write_string_with_padding(&self.program_change_address, MAX_ADDRESS_SIZE)? +
+            self.network_type.encode(writer)? +
+            writer.write_hash(&self.settlement_batch_hash)? +
+            writer.write_hash(&self.last_settlement_batch_hash)?;
+        if self.version >= 1 {
+            bytes_written += writer.write_u8(self.pause_flags)?;
+        }
+        if self.version >= 2 {
+            bytes_written += writer.write_u64(self.event_sequence)?;
+        }
+        bytes_written += writer.write_u16(self.events_count)?;
+        if self.version >= 3 {
+            bytes_written += writer.write_pubkey(&self.first_event_log_account)? +
+                writer.write_pubkey(&self.event_log_account)?;
+        } else {
+            bytes_written += writer.write_padding(self.events_count as usize * LEGACY_EVENT_SIZE)?;
+        }
+        Ok(bytes_written)
+    }
+}
+
+impl Codable for WithdrawState {
+    fn decode<R: Read + ?Sized>(reader: &mut R) -> Result<Self, Error> {
+        let account_type = AccountType::decode(reader)?;
+        let version = reader.read_u32()?;
+        let program_state_account = reader.read_pubkey()?;
+        let batch_hash = reader.read_hash()?;
+        let status = WithdrawStatus::decode(reader)?;
+        let tx_id = reader.read_hash()?;
+        let submitted_height = reader.read_u64()?;
+        let num_replaced = reader.read_u32_as_usize()?;
+        let mut replaced_tx_ids = Vec::with_capacity(num_replaced);
+        for _ in 0..num_replaced {
+            replaced_tx_ids.push(reader.read_hash()?);
+        }
+        Ok(Self {
+            account_type,
+            version,
+            program_state_account,
+            batch_hash,
+            status,
+            tx_id,
+            submitted_height,
+            replaced_tx_ids,
+        })
+    }
+
+    fn encode<W: Write + ?Sized>(&self, mut writer: &mut W) -> Result<usize, Error> {
+        let mut bytes_written = self.account_type.encode(writer)? +
+            writer.write_u32(self.version)? +
+            writer.write_pubkey(&self.program_state_account)? +
+            writer.write_hash(&self.batch_hash)? +
+            self.status.encode(writer)? +
+            writer.write_hash(&self.tx_id)? +
+            writer.write_u64(self.submitted_height)? +
+            writer.write_usize_as_u32(self.replaced_tx_ids.len())?;
+        for tx_id in &self.replaced_tx_ids {
+            bytes_written += writer.write_hash(tx_id)?;
+        }
+        Ok(bytes_written)
+    }
+}
+
+impl Codable for WithdrawStatus {
+    fn decode<R: Read + ?Sized>(reader: &mut R) -> Result<Self, Error> {
+        Ok(match reader.read_u8()? {
+            0 => Self::Idle,
+            1 => Self::Prepared,
+            2 => Self::Submitted,
+            3 => Self::Confirmed,
+            4 => Self::Replaced,
+            _ => return Err(io::Error::new(io::ErrorKind::Other, "Invalid withdraw status"))
+        })
+    }
+
+    fn encode<W: Write + ?Sized>(&self, mut writer: &mut W) -> Result<usize, Error> {
+        Ok(writer.write_u8(match self {
+            Self::Idle => 0,
+            Self::Prepared => 1,
+            Self::Submitted => 2,
+            Self::Confirmed => 3,
+            Self::Replaced => 4,
+        })?)
+    }
+}
+
+impl Codable for RuneReceiverState {
+    fn decode<R: Read + ?Sized>(reader: &mut R) -> Result<Self, Error> {
+        Ok(Self {
+            account_type: AccountType::decode(reader)?,
+            version: reader.read_u32()?,
+            program_state_account: reader.read_pubkey()?,
+        })
+    }
+
+    fn encode<W: Write + ?Sized>(&self, mut writer: &mut W) -> Result<usize, Error> {
+        Ok(
+            self.account_type.encode(writer)? +
+                writer.write_u32(self.version)? +
+                writer.write_pubkey(&self.program_state_account)?
+        )
+    }
+}
+
+impl Codable for DepositLedgerState {
+    fn decode<R: Read + ?Sized>(reader: &mut R) -> Result<Self, Error> {
+        let account_type = AccountType::decode(reader)?;
+        let version = reader.read_u32()?;
+        let program_state_account = reader.read_pubkey()?;
+        let next_ledger_account = reader.read_pubkey()?;
+        let deposit_count = reader.read_u32_as_usize()?;
+        let mut deposits = Vec::with_capacity(deposit_count);
+        for _ in 0..deposit_count {
+            deposits.push(FundingOutpoint::decode(reader)?);
+        }
+
+        Ok(Self {
+            account_type,
+            version,
+            program_state_account,
+            next_ledger_account,
+            deposits,
+        })
+    }
+
+    fn encode<W: Write + ?Sized>(&self, mut writer: &mut W) -> Result<usize, Error> {
+        let mut bytes_written = self.account_type.encode(writer)? +
+            writer.write_u32(self.version)? +
+            writer.write_pubkey(&self.program_state_account)? +
+            writer.write_pubkey(&self.next_ledger_account)? +
+            writer.write_usize_as_u32(self.deposits.len())?;
+        for deposit in &self.deposits {
+            bytes_written += deposit.encode(writer)?;
+        }
+        Ok(bytes_written)
+    }
+}
+
+impl Codable for EventLogState {
+    fn decode<R: Read + ?Sized>(reader: &mut R) -> Result<Self, Error> {
+        let account_type = AccountType::decode(reader)?;
+        let version = reader.read_u32()?;
+        let program_state_account = reader.read_pubkey()?;
+        let next_event_log_account = reader.read_pubkey()?;
+        let event_count = reader.read_u32_as_usize()?;
+        let _data_size = reader.read_u32_as_usize()?;
+        let mut events = Vec::with_capacity(event_count);
+        for _ in 0..event_count {
+            let event_length = reader.read_u16_as_usize()?;
+            let mut event_data = vec![0u8; event_length];
+            reader.read_exact(&mut event_data)?;
+            events.push(Event::decode_from_slice(&event_data)?);
+        }
+
+        Ok(Self {
+            account_type,
+            version,
+            program_state_account,
+            next_event_log_account,
+            events,
+        })
+    }
+
+    fn encode<W: Write + ?Sized>(&self, mut writer: &mut W) -> Result<usize, Error> {
+        let encoded_events = self.events.iter()
+            .map(|event| event.encode_to_vec())
+            .collect::<Result<Vec<Vec<u8>>, Error>>()?;
+        let data_size: usize = encoded_events.iter().map(|event_data| EVENT_LENGTH_SIZE + event_data.len()).sum();
+        let mut bytes_written = self.account_type.encode(writer)? +
+            writer.write_u32(self.version)? +
+            writer.write_pubkey(&self.program_state_account)? +
+            writer.write_pubkey(&self.next_event_log_account)? +
+            writer.write_usize_as_u32(self.events.len())? +
+            writer.write_usize_as_u32(data_size)?;
+        for event_data in &encoded_events {
+            bytes_written += writer.write_usize_as_u16(event_data.len())?;
+            writer.write_all(event_data)?;
+            bytes_written += event_data.len();
+        }
+        Ok(bytes_written)
+    }
+}
+
+impl Codable for WalletIndexEntry {
+    fn decode<R: Read + ?Sized>(reader: &mut R) -> Result<Self, Error> {
+        Ok(Self {
+            address_hash: reader.read_hash()?,
+            balance_index: reader.read_u32()?,
+        })
+    }
+
+    fn encode<W: Write + ?Sized>(&self, mut writer: &mut W) -> Result<usize, Error> {
+        Ok(
+            writer.write_hash(&self.address_hash)? +
+                writer.write_u32(self.balance_index)?
+        )
+    }
+}
+
+impl Codable for WalletIndexState {
+    fn decode<R: Read + ?Sized>(reader: &mut R) -> Result<Self, Error> {
+        let account_type = AccountType::decode(reader)?;
+        let version = reader.read_u32()?;
+        let token_state_account = reader.read_pubkey()?;
+        let num_indexed_balances = reader.read_u32()?;
+        let entry_count = reader.read_u32_as_usize()?;
+        let mut entries = Vec::with_capacity(entry_count);
+        for _ in 0..entry_count {
+            entries.push(WalletIndexEntry::decode(reader)?);
+        }
+
+        Ok(Self {
+            account_type,
+            version,
+            token_state_account,
+            num_indexed_balances,
+            entries,
+        })
+    }
+
+    fn encode<W: Write + ?Sized>(&self, mut writer: &mut W) -> Result<usize, Error> {
+        let mut bytes_written = self.account_type.encode(writer)? +
+            writer.write_u32(self.version)? +
+            writer.write_pubkey(&self.token_state_account)? +
+            writer.write_u32(self.num_indexed_balances)? +
+            writer.write_usize_as_u32(self.entries.len())?;
+        for entry in &self.entries {
+            bytes_written += entry.encode(writer)?;
+        }
+        Ok(bytes_written)
+    }
+}
+
+impl Codable for WithdrawalLimitEntry {
+    fn decode<R: Read + ?Sized>(reader: &mut R) -> Result<Self, Error> {
+        Ok(Self {
+            address_hash: reader.read_hash()?,
+            amount: reader.read_u64()?,
+        })
+    }
+
+    fn encode<W: Write + ?Sized>(&self, mut writer: &mut W) -> Result<usize, Error> {
+        Ok(
+            writer.write_hash(&self.address_hash)? +
+                writer.write_u64(self.amount)?
+        )
+    }
+}
+
+impl Codable for WithdrawalLimitState {
+    fn decode<R: Read + ?Sized>(reader: &mut R) -> Result<Self, Error> {
+        let account_type = AccountType::decode(reader)?;
+        let version = reader.read_u32()?;
+        let token_state_account = reader.read_pubkey()?;
+        let max_amount = reader.read_u64()?;
+        let window_blocks = reader.read_u64()?;
+        let window_start = reader.read_u64()?;
+        let entry_count = reader.read_u32_as_usize()?;
+        let mut entries = Vec::with_capacity(entry_count);
+        for _ in 0..entry_count {
+            entries.push(WithdrawalLimitEntry::decode(reader)?);
+        }
+
+        Ok(Self {
+            account_type,
+            version,
+            token_state_account,
+            max_amount,
+            window_blocks,
+            window_start,
+            entries,
+        })
+    }
+
+    fn encode<W: Write + ?Sized>(&self, mut writer: &mut W) -> Result<usize, Error> {
+        let mut bytes_written = self.account_type.encode(writer)? +
+            writer.write_u32(self.version)? +
+            writer.write_pubkey(&self.token_state_account)? +
+            writer.write_u64(self.max_amount)? +
+            writer.write_u64(self.window_blocks)? +
+            writer.write_u64(self.window_start)? +
+            writer.write_usize_as_u32(self.entries.len())?;
+        for entry in &self.entries {
+            bytes_written += entry.encode(writer)?;
         }
+        Ok(bytes_written)
     }
+}
 
-    fn encode<W: Write + ?Sized>(&self, mut writer: &mut W) -> Result<usize, io::Error> {
-        let bytes_written = match self {
-            Self::FailedSettlement { account_index, address_index, requested_amount, balance, error_code } => {
-                writer.write_u8(0)? +
-                    writer.write_u8(*account_index)? +
-                    writer.write_u32(*address_index)? +
-                    writer.write_u64(*requested_amount)? +
-                    writer.write_u64(*balance)? +
-                    writer.write_u32(*error_code)?
-            }
-            Self::FailedWithdrawal { account_index, address_index, fee_account_index, fee_address_index, requested_amount, fee_amount, balance, balance_in_fee_token, error_code } => {
-                writer.write_u8(1)? +
-                    writer.write_u8(*account_index)? +
-                    writer.write_u32(*address_index)? +
-                    writer.write_u8(*fee_account_index)? +
-                    writer.write_u32(*fee_address_index)? +
-                    writer.write_u64(*requested_amount)? +
-                    writer.write_u64(*fee_amount)? +
-                    writer.write_u64(*balance)? +
-                    writer.write_u64(*balance_in_fee_token)? +
-                    writer.write_u32(*error_code)?
-            }
-        };
+impl Codable for ForcedWithdrawalEntry {
+    fn decode<R: Read + ?Sized>(reader: &mut R) -> Result<Self, Error> {
+        Ok(Self {
+            key: reader.read_hash()?,
+            amount: reader.read_u64()?,
+            nonce: reader.read_u64()?,
+            due_height: reader.read_u64()?,
+            pending: reader.read_u8()? != 0,
+        })
+    }
 
-        if bytes_written > EVENT_SIZE {
-            Err(io::Error::new(io::ErrorKind::Other, "Event is too large"))
-        } else {
-            writer.write_padding(EVENT_SIZE - bytes_written)
-        }
+    fn encode<W: Write + ?Sized>(&self, mut writer: &mut W) -> Result<usize, Error> {
+        Ok(
+            writer.write_hash(&self.key)? +
+                writer.write_u64(self.amount)? +
+                writer.write_u64(self.nonce)? +
+                writer.write_u64(self.due_height)? +
+                writer.write_u8(self.pending as u8)?
+        )
     }
 }
 
-impl Codable for TokenState {
+impl Codable for ForcedWithdrawalState {
     fn decode<R: Read + ?Sized>(reader: &mut R) -> Result<Self, Error> {
         let account_type = AccountType::decode(reader)?;
         let version = reader.read_u32()?;
         let program_state_account = reader.read_pubkey()?;
-        let token_id = reader.read_string_with_padding(MAX_TOKEN_ID_SIZE)?;
-
-        let balances_count = reader.read_u32_as_usize()?;
-        let mut balances = Vec::with_capacity(balances_count);
-        for _ in 0..balances_count {
-            balances.push(Balance::decode(reader)?);
+        let delay_blocks = reader.read_u64()?;
+        let entry_count = reader.read_u32_as_usize()?;
+        let mut entries = Vec::with_capacity(entry_count);
+        for _ in 0..entry_count {
+            entries.push(ForcedWithdrawalEntry::decode(reader)?);
         }
 
         Ok(Self {
             account_type,
             version,
             program_state_account,
-            token_id,
-            balances,
+            delay_blocks,
+            entries,
         })
     }
 
     fn encode<W: Write + ?Sized>(&self, mut writer: &mut W) -> Result<usize, Error> {
-        let mut bytes_written = self.account_type.encode(writer)?;
-        bytes_written += writer.write_u32(self.version)?;
-        bytes_written += writer.write_pubkey(&self.program_state_account)?;
-        bytes_written += writer.write_string_with_padding(&self.token_id, MAX_TOKEN_ID_SIZE)?;
-        bytes_written += writer.write_usize_as_u32(self.balances.len())?;
-        for balance in &self.balances {
-            bytes_written += balance.encode(writer)?;
+        let mut bytes_written = self.account_type.encode(writer)? +
+            writer.write_u32(self.version)? +
+            writer.write_pubkey(&self.program_state_account)? +
+            writer.write_u64(self.delay_blocks)? +
+            writer.write_usize_as_u32(self.entries.len())?;
+        for entry in &self.entries {
+            bytes_written += entry.encode(writer)?;
         }
         Ok(bytes_written)
     }
 }
 
-impl Codable for ProgramState {
+impl Codable for WithdrawalNonceEntry {
+    fn decode<R: Read + ?Sized>(reader: &mut R) -> Result<Self, Error> {
+        Ok(Self {
+            address_hash: reader.read_hash()?,
+            nonce: reader.read_u64()?,
+        })
+    }
+
+    fn encode<W: Write + ?Sized>(&self, mut writer: &mut W) -> Result<usize, Error> {
+        Ok(writer.write_hash(&self.address_hash)? + writer.write_u64(self.nonce)?)
+    }
+}
+
+impl Codable for WithdrawalNonceState {
     fn decode<R: Read + ?Sized>(reader: &mut R) -> Result<Self, Error> {
         let account_type = AccountType::decode(reader)?;
         let version = reader.read_u32()?;
-        let withdraw_account = reader.read_pubkey()?;
-        let fee_account_address = reader.read_string_with_padding(MAX_ADDRESS_SIZE)?;
-        let program_change_address = reader.read_string_with_padding(MAX_ADDRESS_SIZE)?;
-        let network_type = NetworkType::decode(reader)?;
-        let settlement_batch_hash = reader.read_hash()?;
-        let last_settlement_batch_hash = reader.read_hash()?;
-        let event_count = reader.read_u16_as_usize()?;
-        let mut events = Vec::with_capacity(event_count);
-        for _ in 0..event_count {
-            events.push(Event::decode(reader)?);
+        let program_state_account = reader.read_pubkey()?;
+        let entry_count = reader.read_u32_as_usize()?;
+        let mut entries = Vec::with_capacity(entry_count);
+        for _ in 0..entry_count {
+            entries.push(WithdrawalNonceEntry::decode(reader)?);
         }
 
         Ok(Self {
             account_type,
             version,
-            withdraw_account,
-            fee_account_address,
-            program_change_address,
-            network_type,
-            settlement_batch_hash,
-            last_settlement_batch_hash,
-            events,
+            program_state_account,
+            entries,
         })
     }
 
     fn encode<W: Write + ?Sized>(&self, mut writer: &mut W) -> Result<usize, Error> {
         let mut bytes_written = self.account_type.encode(writer)? +
             writer.write_u32(self.version)? +
-            writer.write_pubkey(&self.withdraw_account)? +
-            writer.write_string_with_padding(&self.fee_account_address, MAX_ADDRESS_SIZE)? +
-            writer.write_string_with_padding(&self.program_change_address, MAX_ADDRESS_SIZE)? +
-            self.network_type.encode(writer)? +
-            writer.write_hash(&self.settlement_batch_hash)? +
-            writer.write_hash(&self.last_settlement_batch_hash)? +
-            writer.write_usize_as_u16(self.events.len())?;
-        for event in &self.events {
-            bytes_written += event.encode(writer)?;
+            writer.write_pubkey(&self.program_state_account)? +
+            writer.write_usize_as_u32(self.entries.len())?;
+        for entry in &self.entries {
+            bytes_written += entry.encode(writer)?;
         }
         Ok(bytes_written)
     }
 }
 
-impl Codable for WithdrawState {
+impl Codable for UtxoEntry {
     fn decode<R: Read + ?Sized>(reader: &mut R) -> Result<Self, Error> {
+        let outpoint = FundingOutpoint::decode(reader)?;
+        let locked = match reader.read_u8()? {
+            0 => false,
+            1 => true,
+            _ => return Err(io::Error::new(io::ErrorKind::Other, "Invalid utxo locked flag"))
+        };
         Ok(Self {
-            account_type: AccountType::decode(reader)?,
-            version: reader.read_u32()?,
-            program_state_account: reader.read_pubkey()?,
-            batch_hash: reader.read_hash()?,
+            outpoint,
+            locked,
         })
     }
 
     fn encode<W: Write + ?Sized>(&self, mut writer: &mut W) -> Result<usize, Error> {
-        Ok(
-            self.account_type.encode(writer)? +
-                writer.write_u32(self.version)? +
-                writer.write_pubkey(&self.program_state_account)? +
-                writer.write_hash(&self.batch_hash)?
-        )
+        Ok(self.outpoint.encode(writer)? + writer.write_u8(self.locked as u8)?)
     }
 }
 
-impl Codable for RuneReceiverState {
+impl Codable for UtxoRegistryState {
     fn decode<R: Read + ?Sized>(reader: &mut R) -> Result<Self, Error> {
+        let account_type = AccountType::decode(reader)?;
+        let version = reader.read_u32()?;
+        let program_state_account = reader.read_pubkey()?;
+        let entry_count = reader.read_u32_as_usize()?;
+        let mut entries = Vec::with_capacity(entry_count);
+        for _ in 0..entry_count {
+            entries.push(UtxoEntry::decode(reader)?);
+        }
+
         Ok(Self {
-            account_type: AccountType::decode(reader)?,
-            version: reader.read_u32()?,
-            program_state_account: reader.read_pubkey()?,
+            account_type,
+            version,
+            program_state_account,
+            entries,
         })
     }
 
     fn encode<W: Write + ?Sized>(&self, mut writer: &mut W) -> Result<usize, Error> {
-        Ok(
-            self.account_type.encode(writer)? +
-                writer.write_u32(self.version)? +
-                writer.write_pubkey(&self.program_state_account)?
-        )
+        let mut bytes_written = self.account_type.encode(writer)? +
+            writer.write_u32(self.version)? +
+            writer.write_pubkey(&self.program_state_account)? +
+            writer.write_usize_as_u32(self.entries.len())?;
+        for entry in &self.entries {
+            bytes_written += entry.encode(writer)?;
+        }
+        Ok(bytes_written)
     }
 }
 
@@ -874,6 +2091,14 @@ impl Codable for AccountType {
             2 => Self::Token,
             3 => Self::Withdraw,
             4 => Self::RuneReceiver,
+            5 => Self::DepositLedger,
+            6 => Self::EventLog,
+            7 => Self::WalletIndex,
+            8 => Self::BalanceShard,
+            9 => Self::WithdrawalLimit,
+            10 => Self::ForcedWithdrawals,
+            11 => Self::WithdrawalNonces,
+            12 => Self::UtxoRegistry,
             _ => Self::Unknown,
         })
     }
@@ -884,6 +2109,14 @@ impl Codable for AccountType {
             Self::Token => 2,
             Self::Withdraw => 3,
             Self::RuneReceiver => 4,
+            Self::DepositLedger => 5,
+            Self::EventLog => 6,
+            Self::WalletIndex => 7,
+            Self::BalanceShard => 8,
+            Self::WithdrawalLimit => 9,
+            Self::ForcedWithdrawals => 10,
+            Self::WithdrawalNonces => 11,
+            Self::UtxoRegistry => 12,
             Self::Unknown => 0
         })?)
     }
@@ -892,6 +2125,8 @@ impl Codable for AccountType {
 
 #[cfg(test)]
 mod tests {
+    use arch_program::pubkey::Pubkey;
+    use crate::address::WalletAddress;
     use crate::state::*;
     use crate::instructions::*;
     use crate::serialization::Codable;
@@ -935,38 +2170,45 @@ mod tests {
                 TokenDeposits {
                     account_index: 0,
                     deposits: vec![
-                        Adjustment {
+                        Deposit {
                             address_index: AddressIndex {
                                 index: 123,
-                                last4: [1, 2, 3, 4],
+                                commitment: AddressCommitment::Last4([1, 2, 3, 4]),
                             },
                             amount: 456,
+                            funding_outpoint: Some(FundingOutpoint {
+                                tx_id: [7; 32],
+                                vout: 2,
+                            }),
                         },
-                        Adjustment {
+                        Deposit {
                             address_index: AddressIndex {
                                 index: 321,
-                                last4: [5, 6, 7, 8],
+                                commitment: AddressCommitment::Last4([5, 6, 7, 8]),
                             },
                             amount: 654,
+                            funding_outpoint: None,
                         },
                     ],
                 },
                 TokenDeposits {
                     account_index: 1,
                     deposits: vec![
-                        Adjustment {
+                        Deposit {
                             address_index: AddressIndex {
                                 index: 111,
-                                last4: [1, 2, 3, 4],
+                                commitment: AddressCommitment::Last4([1, 2, 3, 4]),
                             },
                             amount: 222,
+                            funding_outpoint: None,
                         },
-                        Adjustment {
+                        Deposit {
                             address_index: AddressIndex {
                                 index: 333,
-                                last4: [4, 3, 2, 1],
+                                commitment: AddressCommitment::Last4([4, 3, 2, 1]),
                             },
                             amount: 444,
+                            funding_outpoint: None,
                         },
                     ],
                 },
@@ -985,28 +2227,34 @@ mod tests {
                         Withdrawal {
                             address_index: AddressIndex {
                                 index: 123,
-                                last4: [1, 2, 3, 4],
+                                commitment: AddressCommitment::Last4([1, 2, 3, 4]),
                             },
                             amount: 456,
                             fee_account_index: 0,
                             fee_address_index: AddressIndex {
                                 index: 124,
-                                last4: [1, 2, 3, 5],
+                                commitment: AddressCommitment::Last4([1, 2, 3, 5]),
                             },
                             fee_amount: 789,
+                            signature: None,
                         },
                         Withdrawal {
                             address_index: AddressIndex {
                                 index: 321,
-                                last4: [4, 3, 2, 1],
+                                commitment: AddressCommitment::Last4([4, 3, 2, 1]),
                             },
                             amount: 654,
                             fee_account_index: 0,
                             fee_address_index: AddressIndex {
                                 index: 421,
-                                last4: [5, 3, 2, 1],
+                                commitment: AddressCommitment::Last4([5, 3, 2, 1]),
                             },
                             fee_amount: 987,
+                            signature: Some(WithdrawalSignature {
+                                nonce: 5,
+                                expiry: 1000,
+                                witness: vec![1, 2, 3],
+                            }),
                         },
                     ],
                 },
@@ -1016,28 +2264,30 @@ mod tests {
                         Withdrawal {
                             address_index: AddressIndex {
                                 index: 111,
-                                last4: [1, 2, 3, 4],
+                                commitment: AddressCommitment::Last4([1, 2, 3, 4]),
                             },
                             amount: 222,
                             fee_account_index: 1,
                             fee_address_index: AddressIndex {
                                 index: 222,
-                                last4: [1, 2, 3, 6],
+                                commitment: AddressCommitment::Last4([1, 2, 3, 6]),
                             },
                             fee_amount: 333,
+                            signature: None,
                         },
                         Withdrawal {
                             address_index: AddressIndex {
                                 index: 444,
-                                last4: [4, 3, 2, 1],
+                                commitment: AddressCommitment::Last4([4, 3, 2, 1]),
                             },
                             amount: 555,
                             fee_account_index: 1,
                             fee_address_index: AddressIndex {
                                 index: 555,
-                                last4: [1, 2, 3, 7],
+                                commitment: AddressCommitment::Last4([1, 2, 3, 7]),
                             },
                             fee_amount: 666,
+                            signature: None,
                         },
                     ],
                 },
@@ -1053,14 +2303,14 @@ mod tests {
                         Adjustment {
                             address_index: AddressIndex {
                                 index: 111,
-                                last4: [1, 2, 3, 4],
+                                commitment: AddressCommitment::Last4([1, 2, 3, 4]),
                             },
                             amount: 222,
                         },
                         Adjustment {
                             address_index: AddressIndex {
                                 index: 333,
-                                last4: [4, 3, 2, 1],
+                                commitment: AddressCommitment::Last4([4, 3, 2, 1]),
                             },
                             amount: 444,
                         },
@@ -1069,14 +2319,14 @@ mod tests {
                         Adjustment {
                             address_index: AddressIndex {
                                 index: 555,
-                                last4: [1, 2, 3, 4],
+                                commitment: AddressCommitment::Last4([1, 2, 3, 4]),
                             },
                             amount: 666,
                         },
                         Adjustment {
                             address_index: AddressIndex {
                                 index: 777,
-                                last4: [4, 3, 2, 1],
+                                commitment: AddressCommitment::Last4([4, 3, 2, 1]),
                             },
                             amount: 888,
                         },
@@ -1089,14 +2339,14 @@ mod tests {
                         Adjustment {
                             address_index: AddressIndex {
                                 index: 1111,
-                                last4: [1, 2, 3, 4],
+                                commitment: AddressCommitment::Last4([1, 2, 3, 4]),
                             },
                             amount: 2222,
                         },
                         Adjustment {
                             address_index: AddressIndex {
                                 index: 3333,
-                                last4: [4, 3, 2, 1],
+                                commitment: AddressCommitment::Last4([4, 3, 2, 1]),
                             },
                             amount: 4444,
                         },
@@ -1105,14 +2355,14 @@ mod tests {
                         Adjustment {
                             address_index: AddressIndex {
                                 index: 5555,
-                                last4: [1, 2, 3, 4],
+                                commitment: AddressCommitment::Last4([1, 2, 3, 4]),
                             },
                             amount: 6666,
                         },
                         Adjustment {
                             address_index: AddressIndex {
                                 index: 7777,
-                                last4: [4, 3, 2, 1],
+                                commitment: AddressCommitment::Last4([4, 3, 2, 1]),
                             },
                             amount: 8888,
                         },
@@ -1131,14 +2381,14 @@ mod tests {
                         Adjustment {
                             address_index: AddressIndex {
                                 index: 111,
-                                last4: [1, 2, 3, 4],
+                                commitment: AddressCommitment::Last4([1, 2, 3, 4]),
                             },
                             amount: 222,
                         },
                         Adjustment {
                             address_index: AddressIndex {
                                 index: 333,
-                                last4: [4, 3, 2, 1],
+                                commitment: AddressCommitment::Last4([4, 3, 2, 1]),
                             },
                             amount: 444,
                         },
@@ -1147,14 +2397,14 @@ mod tests {
                         Adjustment {
                             address_index: AddressIndex {
                                 index: 555,
-                                last4: [1, 2, 3, 4],
+                                commitment: AddressCommitment::Last4([1, 2, 3, 4]),
                             },
                             amount: 666,
                         },
                         Adjustment {
                             address_index: AddressIndex {
                                 index: 777,
-                                last4: [4, 3, 2, 1],
+                                commitment: AddressCommitment::Last4([4, 3, 2, 1]),
                             },
                             amount: 888,
                         },
@@ -1167,14 +2417,14 @@ mod tests {
                         Adjustment {
                             address_index: AddressIndex {
                                 index: 1111,
-                                last4: [1, 2, 3, 4],
+                                commitment: AddressCommitment::Last4([1, 2, 3, 4]),
                             },
                             amount: 2222,
                         },
                         Adjustment {
                             address_index: AddressIndex {
                                 index: 3333,
-                                last4: [4, 3, 2, 1],
+                                commitment: AddressCommitment::Last4([4, 3, 2, 1]),
                             },
                             amount: 4444,
                         },
@@ -1183,14 +2433,14 @@ mod tests {
                         Adjustment {
                             address_index: AddressIndex {
                                 index: 5555,
-                                last4: [1, 2, 3, 4],
+                                commitment: AddressCommitment::Last4([1, 2, 3, 4]),
                             },
                             amount: 6666,
                         },
                         Adjustment {
                             address_index: AddressIndex {
                                 index: 7777,
-                                last4: [4, 3, 2, 1],
+                                commitment: AddressCommitment::Last4([4, 3, 2, 1]),
                             },
                             amount: 8888,
                         },
@@ -1209,28 +2459,30 @@ mod tests {
                         Withdrawal {
                             address_index: AddressIndex {
                                 index: 123,
-                                last4: [1, 2, 3, 4],
+                                commitment: AddressCommitment::Last4([1, 2, 3, 4]),
                             },
                             amount: 456,
                             fee_account_index: 0,
                             fee_address_index: AddressIndex {
                                 index: 123,
-                                last4: [1, 2, 3, 4],
+                                commitment: AddressCommitment::Last4([1, 2, 3, 4]),
                             },
                             fee_amount: 789,
+                            signature: None,
                         },
                         Withdrawal {
                             address_index: AddressIndex {
                                 index: 321,
-                                last4: [4, 3, 2, 1],
+                                commitment: AddressCommitment::Last4([4, 3, 2, 1]),
                             },
                             amount: 654,
                             fee_account_index: 0,
                             fee_address_index: AddressIndex {
                                 index: 321,
-                                last4: [4, 3, 2, 1],
+                                commitment: AddressCommitment::Last4([4, 3, 2, 1]),
                             },
                             fee_amount: 987,
+                            signature: None,
                         },
                     ],
                 },
@@ -1240,34 +2492,604 @@ mod tests {
                         Withdrawal {
                             address_index: AddressIndex {
                                 index: 111,
-                                last4: [1, 2, 3, 4],
+                                commitment: AddressCommitment::Last4([1, 2, 3, 4]),
                             },
                             amount: 222,
                             fee_account_index: 1,
                             fee_address_index: AddressIndex {
                                 index: 111,
-                                last4: [1, 2, 3, 4],
+                                commitment: AddressCommitment::Last4([1, 2, 3, 4]),
                             },
                             fee_amount: 333,
+                            signature: None,
                         },
                         Withdrawal {
                             address_index: AddressIndex {
                                 index: 444,
-                                last4: [4, 3, 2, 1],
+                                commitment: AddressCommitment::Last4([4, 3, 2, 1]),
                             },
                             amount: 555,
                             fee_account_index: 1,
                             fee_address_index: AddressIndex {
                                 index: 111,
-                                last4: [1, 2, 3, 4],
+                                commitment: AddressCommitment::Last4([1, 2, 3, 4]),
                             },
                             fee_amount: 666,
+                            signature: None,
                         },
                     ],
                 },
             ]
         });
         assert_eq!(instruction, ProgramInstruction::decode_from_slice(&instruction.encode_to_vec().unwrap()).unwrap());
+
+        let instruction = ProgramInstruction::InitDepositLedger();
+        assert_eq!(instruction, ProgramInstruction::decode_from_slice(&instruction.encode_to_vec().unwrap()).unwrap());
+
+        let instruction = ProgramInstruction::UpdateFeeAccount(UpdateFeeAccountParams {
+            fee_account: "132F25rTsvBdp9JzLLBHP5mvGY66i1xdiM".to_string(),
+        });
+        assert_eq!(instruction, ProgramInstruction::decode_from_slice(&instruction.encode_to_vec().unwrap()).unwrap());
+
+        let instruction = ProgramInstruction::UpdateProgramChangeAddress(UpdateProgramChangeAddressParams {
+            program_change_address: "33iFwdLuRpW1uK1RTRqsoi8rR4NpDzk66k".to_string(),
+        });
+        assert_eq!(instruction, ProgramInstruction::decode_from_slice(&instruction.encode_to_vec().unwrap()).unwrap());
+
+        let instruction = ProgramInstruction::UpdateWithdrawAccount();
+        assert_eq!(instruction, ProgramInstruction::decode_from_slice(&instruction.encode_to_vec().unwrap()).unwrap());
+
+        let instruction = ProgramInstruction::Pause(PauseParams {
+            pause_flags: PAUSE_DEPOSITS | PAUSE_SETTLEMENTS,
+        });
+        assert_eq!(instruction, ProgramInstruction::decode_from_slice(&instruction.encode_to_vec().unwrap()).unwrap());
+
+        let instruction = ProgramInstruction::Unpause(PauseParams {
+            pause_flags: PAUSE_ALL,
+        });
+        assert_eq!(instruction, ProgramInstruction::decode_from_slice(&instruction.encode_to_vec().unwrap()).unwrap());
+
+        let instruction = ProgramInstruction::MigrateAccount(MigrateAccountParams {
+            account_index: 1,
+        });
+        assert_eq!(instruction, ProgramInstruction::decode_from_slice(&instruction.encode_to_vec().unwrap()).unwrap());
+
+        let instruction = ProgramInstruction::InitEventLog();
+        assert_eq!(instruction, ProgramInstruction::decode_from_slice(&instruction.encode_to_vec().unwrap()).unwrap());
+
+        let instruction = ProgramInstruction::PreparePartialBatchSettlement(SettlementBatchParams {
+            settlements: vec![SettlementAdjustments {
+                account_index: 1,
+                increments: vec![],
+                decrements: vec![],
+                fee_amount: 2,
+            }],
+        });
+        assert_eq!(instruction, ProgramInstruction::decode_from_slice(&instruction.encode_to_vec().unwrap()).unwrap());
+
+        let instruction = ProgramInstruction::PreparePartialBatchWithdraw(WithdrawBatchParams {
+            tx_hex: vec![1, 2, 3],
+            change_amount: 123,
+            input_utxo_types: vec![InputUtxoType::Rune],
+            token_withdrawals: vec![TokenWithdrawals {
+                account_index: 1,
+                withdrawals: vec![Withdrawal {
+                    address_index: AddressIndex {
+                        index: 2,
+                        commitment: AddressCommitment::Last4([1, 2, 3, 4]),
+                    },
+                    amount: 3,
+                    fee_account_index: 4,
+                    fee_address_index: AddressIndex {
+                        index: 5,
+                        commitment: AddressCommitment::Last4([4, 3, 2, 1]),
+                    },
+                    fee_amount: 6,
+                    signature: None,
+                }],
+            }],
+        });
+        assert_eq!(instruction, ProgramInstruction::decode_from_slice(&instruction.encode_to_vec().unwrap()).unwrap());
+
+        let instruction = ProgramInstruction::CloseWalletBalance(CloseWalletBalanceParams {
+            account_index: 1,
+            address_indexes: vec![AddressIndex {
+                index: 2,
+                commitment: AddressCommitment::Last4([1, 2, 3, 4]),
+            }],
+        });
+        assert_eq!(instruction, ProgramInstruction::decode_from_slice(&instruction.encode_to_vec().unwrap()).unwrap());
+
+        let instruction = ProgramInstruction::CompactBalances(CompactBalancesParams { account_index: 1 });
+        assert_eq!(instruction, ProgramInstruction::decode_from_slice(&instruction.encode_to_vec().unwrap()).unwrap());
+
+        for fee_policy in [
+            None,
+            Some(WithdrawalFeePolicyParams { min_fee: 100, flat_fee: 0, fee_account_index: None }),
+            Some(WithdrawalFeePolicyParams { min_fee: 0, flat_fee: 500, fee_account_index: Some(2) }),
+        ] {
+            let instruction = ProgramInstruction::SetWithdrawalFeePolicy(SetWithdrawalFeePolicyParams { account_index: 1, fee_policy });
+            assert_eq!(instruction, ProgramInstruction::decode_from_slice(&instruction.encode_to_vec().unwrap()).unwrap());
+        }
+
+        for limit in [None, Some(WithdrawalLimitParams { max_amount: 100_000, window_blocks: 144 })] {
+            let instruction = ProgramInstruction::SetWithdrawalLimit(SetWithdrawalLimitParams { limit });
+            assert_eq!(instruction, ProgramInstruction::decode_from_slice(&instruction.encode_to_vec().unwrap()).unwrap());
+        }
+
+        let instruction = ProgramInstruction::InitForcedWithdrawals(InitForcedWithdrawalsParams { delay_blocks: 144 });
+        assert_eq!(instruction, ProgramInstruction::decode_from_slice(&instruction.encode_to_vec().unwrap()).unwrap());
+
+        let instruction = ProgramInstruction::RequestForcedWithdrawal(RequestForcedWithdrawalParams {
+            account_index: 1,
+            address_index: AddressIndex {
+                index: 2,
+                commitment: AddressCommitment::ScriptHash([7; ADDRESS_SCRIPT_HASH_SIZE]),
+            },
+            amount: 3,
+            nonce: 4,
+            signature: vec![1, 64, 5, 5, 5],
+        });
+        assert_eq!(instruction, ProgramInstruction::decode_from_slice(&instruction.encode_to_vec().unwrap()).unwrap());
+
+        let instruction = ProgramInstruction::ForcedWithdraw(ForcedWithdrawParams {
+            account_index: 1,
+            address_index: AddressIndex {
+                index: 2,
+                commitment: AddressCommitment::Last4([1, 2, 3, 4]),
+            },
+            tx_hex: vec![2, 0, 0, 0],
+            network_fee: 500,
+            signature: vec![1, 64, 6, 6, 6],
+        });
+        assert_eq!(instruction, ProgramInstruction::decode_from_slice(&instruction.encode_to_vec().unwrap()).unwrap());
+
+        let instruction = ProgramInstruction::InitWithdrawalNonces();
+        assert_eq!(instruction, ProgramInstruction::decode_from_slice(&instruction.encode_to_vec().unwrap()).unwrap());
+
+        for fee_rate in [None, Some(WithdrawalFeeRateParams { min_fee_rate: 2, max_fee_rate: 50 })] {
+            let instruction = ProgramInstruction::SetWithdrawalFeeRate(SetWithdrawalFeeRateParams { fee_rate });
+            assert_eq!(instruction, ProgramInstruction::decode_from_slice(&instruction.encode_to_vec().unwrap()).unwrap());
+        }
+
+        let instruction = ProgramInstruction::InitUtxoRegistry();
+        assert_eq!(instruction, ProgramInstruction::decode_from_slice(&instruction.encode_to_vec().unwrap()).unwrap());
+
+        let instruction = ProgramInstruction::ConfirmWithdrawBatch(ConfirmWithdrawBatchParams { tx_id: [3; 32] });
+        assert_eq!(instruction, ProgramInstruction::decode_from_slice(&instruction.encode_to_vec().unwrap()).unwrap());
+
+        let instruction = ProgramInstruction::RegisterUtxos(RegisterUtxosParams {
+            utxos: vec![
+                FundingOutpoint { tx_id: [1; 32], vout: 0 },
+                FundingOutpoint { tx_id: [2; 32], vout: 3 },
+            ],
+        });
+        assert_eq!(instruction, ProgramInstruction::decode_from_slice(&instruction.encode_to_vec().unwrap()).unwrap());
+
+        let instruction = ProgramInstruction::BumpWithdrawBatch(BumpWithdrawBatchParams {
+            withdraw_batch: WithdrawBatchParams {
+                tx_hex: vec![1, 2, 3],
+                change_amount: 5000,
+                token_withdrawals: vec![],
+                input_utxo_types: vec![InputUtxoType::Bitcoin, InputUtxoType::Rune],
+            },
+            change_amount: 4000,
+        });
+        assert_eq!(instruction, ProgramInstruction::decode_from_slice(&instruction.encode_to_vec().unwrap()).unwrap());
+    }
+
+    #[test]
+    fn test_events_serialization() {
+        let events = vec![
+            Event::DuplicateDeposit { account_index: 1, address_index: 2, amount: 3, tx_id: [4; 32], vout: 5 },
+            Event::DepositCredited { sequence: 1, account_index: 2, address_index: 3, amount: 4 },
+            Event::SettlementApplied { sequence: 2, account_index: 3, settlement_hash: [4; 32], increment_total: 5, decrement_total: 6 },
+            Event::WithdrawalPrepared { sequence: 3, account_index: 4, address_index: 5, amount: 6, fee_amount: 7 },
+            Event::WithdrawalSubmitted { sequence: 4, tx_id: [5; 32], num_withdrawals: 6 },
+            Event::FeeCollected { sequence: 5, account_index: 6, amount: 7 },
+            Event::SettlementExcluded { account_index: 1, settlement_index: 2 },
+            Event::WithdrawalExcluded { account_index: 3, withdrawal_index: 4 },
+            Event::WalletBalanceClosed { sequence: 6, account_index: 1, address_index: 2 },
+            Event::BalanceIndexRemapped { sequence: 7, account_index: 1, from_address_index: 5, to_address_index: 2 },
+            Event::NettingFailed { account_index: 2, increment_total: u64::MAX, decrement_total: 3 },
+            Event::ForcedWithdrawalRequested { sequence: 8, account_index: 1, address_index: 2, amount: 3, due_height: 4 },
+            Event::ForcedWithdrawalExecuted { sequence: 9, account_index: 1, address_index: 2, amount: 3, tx_id: [4; 32] },
+            Event::WithdrawalConfirmed { sequence: 10, tx_id: [5; 32] },
+            Event::WithdrawalBumped { sequence: 11, replaced_tx_id: [6; 32], tx_id: [7; 32] },
+            Event::DepositLedgerLow { remaining_deposits: 12 },
+        ];
+        for event in &events {
+            assert_eq!(*event, Event::decode_from_slice(&event.encode_to_vec().unwrap()).unwrap());
+        }
+
+        let event_log = EventLogState {
+            account_type: AccountType::EventLog,
+            version: EVENT_LOG_STATE_VERSION,
+            program_state_account: Pubkey::new_unique(),
+            next_event_log_account: Pubkey::new_unique(),
+            events: events.clone(),
+        };
+        let decoded = EventLogState::decode_from_slice(&event_log.encode_to_vec().unwrap()).unwrap();
+        assert_eq!(event_log.next_event_log_account, decoded.next_event_log_account);
+        assert_eq!(events, decoded.events);
     }
-}
 
+    #[test]
+    fn test_event_log_paging() {
+        let event_log_account = Pubkey::new_unique();
+        let next_event_log_account = Pubkey::new_unique();
+        let events: Vec<Event> = (1..=5)
+            .map(|sequence| Event::FeeCollected { sequence, account_index: 1, amount: 10 })
+            .collect();
+        let mut event_log = EventLogState {
+            account_type: AccountType::EventLog,
+            version: EVENT_LOG_STATE_VERSION,
+            program_state_account: Pubkey::new_unique(),
+            next_event_log_account: Pubkey::from([0u8; 32]),
+            events: events.clone(),
+        };
+
+        let cursor = EventCursor { event_log_account, index: 0 };
+        let (page, cursor) = event_log.read_events(&cursor, 3);
+        assert_eq!(events[0..3].to_vec(), page);
+        assert_eq!(EventCursor { event_log_account, index: 3 }, cursor);
+        let (page, cursor) = event_log.read_events(&cursor, 3);
+        assert_eq!(events[3..5].to_vec(), page);
+        // the last log keeps the cursor at its end so new events are picked up
+        assert_eq!(EventCursor { event_log_account, index: 5 }, cursor);
+
+        event_log.next_event_log_account = next_event_log_account;
+        let (page, cursor) = event_log.read_events(&cursor, 3);
+        assert!(page.is_empty());
+        assert_eq!(EventCursor { event_log_account: next_event_log_account, index: 0 }, cursor);
+    }
+
+    #[test]
+    fn test_program_state_versions_serialization() {
+        let program_state = ProgramState {
+            account_type: AccountType::Program,
+            version: PROGRAM_STATE_VERSION,
+            withdraw_account: Pubkey::new_unique(),
+            fee_account_address: "fee".to_string(),
+            program_change_address: "change".to_string(),
+            network_type: NetworkType::Regtest,
+            settlement_batch_hash: [1; 32],
+            last_settlement_batch_hash: [2; 32],
+            pause_flags: PAUSE_WITHDRAWALS,
+            event_sequence: 7,
+            events_count: 0,
+            first_event_log_account: Pubkey::new_unique(),
+            event_log_account: Pubkey::new_unique(),
+        };
+        let encoded = program_state.encode_to_vec().unwrap();
+        assert_eq!(RUNE_RECEIVER_OFFSET, encoded.len());
+        assert_eq!(program_state, ProgramState::decode_from_slice(&encoded).unwrap());
+
+        // version 2 kept the events inline instead of pointing to an event log
+        let mut encoded_v2 = encoded.clone();
+        encoded_v2.drain(FIRST_EVENT_LOG_OFFSET..RUNE_RECEIVER_OFFSET);
+        encoded_v2[VERSION_OFFSET..VERSION_OFFSET + VERSION_SIZE].copy_from_slice(&2u32.to_le_bytes());
+        let program_state_v2 = ProgramState {
+            version: 2,
+            first_event_log_account: Pubkey::from([0u8; 32]),
+            event_log_account: Pubkey::from([0u8; 32]),
+            ..program_state.clone()
+        };
+        assert_eq!(program_state_v2, ProgramState::decode_from_slice(&encoded_v2).unwrap());
+        assert_eq!(encoded_v2, program_state_v2.encode_to_vec().unwrap());
+
+        let mut encoded_v2_with_events = encoded_v2.clone();
+        encoded_v2_with_events[EVENTS_SIZE_OFFSET..EVENTS_SIZE_OFFSET + 2].copy_from_slice(&2u16.to_le_bytes());
+        encoded_v2_with_events.extend_from_slice(&[0u8; 2 * LEGACY_EVENT_SIZE]);
+        assert_eq!(2, ProgramState::decode_from_slice(&encoded_v2_with_events).unwrap().events_count);
+
+        // version 1 has no event sequence
+        let mut encoded_v1 = encoded_v2.clone();
+        encoded_v1.drain(EVENT_SEQUENCE_OFFSET..EVENT_SEQUENCE_OFFSET + EVENT_SEQUENCE_SIZE);
+        encoded_v1[VERSION_OFFSET..VERSION_OFFSET + VERSION_SIZE].copy_from_slice(&1u32.to_le_bytes());
+        let program_state_v1 = ProgramState {
+            version: 1,
+            event_sequence: 0,
+            ..program_state_v2
+        };
+        assert_eq!(program_state_v1, ProgramState::decode_from_slice(&encoded_v1).unwrap());
+        assert_eq!(encoded_v1, program_state_v1.encode_to_vec().unwrap());
+
+        // version 0 has no pause flags either
+        let mut encoded_v0 = encoded_v1.clone();
+        encoded_v0.remove(PAUSE_FLAGS_OFFSET);
+        encoded_v0[VERSION_OFFSET..VERSION_OFFSET + VERSION_SIZE].copy_from_slice(&0u32.to_le_bytes());
+        let program_state_v0 = ProgramState {
+            version: 0,
+            pause_flags: 0,
+            ..program_state_v1
+        };
+        assert_eq!(program_state_v0, ProgramState::decode_from_slice(&encoded_v0).unwrap());
+        assert_eq!(encoded_v0, program_state_v0.encode_to_vec().unwrap());
+    }
+
+    #[test]
+    fn test_token_state_versions_serialization() {
+        let token_state = TokenState {
+            account_type: AccountType::Token,
+            version: TOKEN_STATE_VERSION,
+            program_state_account: Pubkey::new_unique(),
+            token_id: "btc".to_string(),
+            wallet_index_account: Pubkey::new_unique(),
+            num_free_balances: 1,
+            first_free_balance: 1,
+            num_balance_shards: 2,
+            first_balance_shard_account: Pubkey::new_unique(),
+            last_balance_shard_account: Pubkey::new_unique(),
+            withdrawal_fee_policy: Some(WithdrawalFeePolicy { min_fee: 100, flat_fee: 200, fee_token_account: Some(Pubkey::new_unique()) }),
+            withdrawal_limit_account: Pubkey::new_unique(),
+            balances: vec![
+                Balance { address: "bc1qhz5a7xfh5dj00u32x0j5we6jfpa8vgpqhvaqug".to_string(), balance: 10 },
+                Balance { address: "".to_string(), balance: 0 },
+            ],
+        };
+        let encoded = token_state.encode_to_vec().unwrap();
+        assert_eq!(BALANCES_OFFSET + 2 * BALANCE_SIZE, encoded.len());
+        assert_eq!(token_state, TokenState::decode_from_slice(&encoded).unwrap());
+        for withdrawal_fee_policy in [
+            None,
+            Some(WithdrawalFeePolicy { min_fee: 0, flat_fee: 0, fee_token_account: None }),
+        ] {
+            let token_state = TokenState { withdrawal_fee_policy, ..token_state.clone() };
+            let encoded = token_state.encode_to_vec().unwrap();
+            assert_eq!(BALANCES_OFFSET + 2 * BALANCE_SIZE, encoded.len());
+            assert_eq!(token_state, TokenState::decode_from_slice(&encoded).unwrap());
+        }
+
+        // version 5 had no withdrawal limit
+        let token_state_v5 = TokenState {
+            version: 5,
+            withdrawal_limit_account: Pubkey::from([0u8; 32]),
+            ..token_state
+        };
+        let mut encoded_v5 = encoded.clone();
+        encoded_v5.drain(WITHDRAWAL_LIMIT_OFFSET..BALANCE_COUNT_OFFSET);
+        encoded_v5[VERSION_OFFSET..VERSION_OFFSET + VERSION_SIZE].copy_from_slice(&5u32.to_le_bytes());
+        assert_eq!(token_state_v5, TokenState::decode_from_slice(&encoded_v5).unwrap());
+        assert_eq!(encoded_v5, token_state_v5.encode_to_vec().unwrap());
+
+        // version 4 had no withdrawal fee policy either
+        let token_state_v4 = TokenState {
+            version: 4,
+            withdrawal_fee_policy: None,
+            ..token_state_v5
+        };
+        let mut encoded_v4 = encoded_v5.clone();
+        encoded_v4.drain(FEE_POLICY_OFFSET..WITHDRAWAL_LIMIT_OFFSET);
+        encoded_v4[VERSION_OFFSET..VERSION_OFFSET + VERSION_SIZE].copy_from_slice(&4u32.to_le_bytes());
+        assert_eq!(token_state_v4, TokenState::decode_from_slice(&encoded_v4).unwrap());
+        assert_eq!(encoded_v4, token_state_v4.encode_to_vec().unwrap());
+
+        // version 3 kept the address strings in the balances
+        let token_state_v3 = TokenState {
+            version: 3,
+            ..token_state_v4
+        };
+        let encoded_v3 = token_state_v3.encode_to_vec().unwrap();
+        assert_eq!(LEGACY_BALANCE_COUNT_OFFSET + BALANCE_COUNT_SIZE + 2 * LEGACY_BALANCE_SIZE, encoded_v3.len());
+        assert_eq!(encoded_v4[VERSION_OFFSET + VERSION_SIZE..LEGACY_BALANCE_COUNT_OFFSET], encoded_v3[VERSION_OFFSET + VERSION_SIZE..LEGACY_BALANCE_COUNT_OFFSET]);
+        assert_eq!(token_state_v3, TokenState::decode_from_slice(&encoded_v3).unwrap());
+
+        // version 2 had no balance shards
+        let mut encoded_v2 = encoded_v3.clone();
+        encoded_v2.drain(BALANCE_SHARD_COUNT_OFFSET..LEGACY_BALANCE_COUNT_OFFSET);
+        encoded_v2[VERSION_OFFSET..VERSION_OFFSET + VERSION_SIZE].copy_from_slice(&2u32.to_le_bytes());
+        let token_state_v2 = TokenState {
+            version: 2,
+            num_balance_shards: 0,
+            first_balance_shard_account: Pubkey::from([0u8; 32]),
+            last_balance_shard_account: Pubkey::from([0u8; 32]),
+            ..token_state_v3
+        };
+        assert_eq!(token_state_v2, TokenState::decode_from_slice(&encoded_v2).unwrap());
+        assert_eq!(encoded_v2, token_state_v2.encode_to_vec().unwrap());
+
+        // version 1 had no free list either
+        let mut encoded_v1 = encoded_v2.clone();
+        encoded_v1.drain(FREE_BALANCE_COUNT_OFFSET..BALANCE_SHARD_COUNT_OFFSET);
+        encoded_v1[VERSION_OFFSET..VERSION_OFFSET + VERSION_SIZE].copy_from_slice(&1u32.to_le_bytes());
+        let token_state_v1 = TokenState {
+            version: 1,
+            num_free_balances: 0,
+            first_free_balance: 0,
+            ..token_state_v2
+        };
+        assert_eq!(token_state_v1, TokenState::decode_from_slice(&encoded_v1).unwrap());
+        assert_eq!(encoded_v1, token_state_v1.encode_to_vec().unwrap());
+
+        // version 0 had no wallet index either
+        let mut encoded_v0 = encoded_v1.clone();
+        encoded_v0.drain(WALLET_INDEX_OFFSET..FREE_BALANCE_COUNT_OFFSET);
+        encoded_v0[VERSION_OFFSET..VERSION_OFFSET + VERSION_SIZE].copy_from_slice(&0u32.to_le_bytes());
+        let token_state_v0 = TokenState {
+            version: 0,
+            wallet_index_account: Pubkey::from([0u8; 32]),
+            ..token_state_v1
+        };
+        assert_eq!(token_state_v0, TokenState::decode_from_slice(&encoded_v0).unwrap());
+        assert_eq!(encoded_v0, token_state_v0.encode_to_vec().unwrap());
+    }
+
+    #[test]
+    fn test_balance_shard_serialization() {
+        let balance_shard = BalanceShardState {
+            account_type: AccountType::BalanceShard,
+            version: BALANCE_SHARD_STATE_VERSION,
+            token_state_account: Pubkey::new_unique(),
+            shard: 2,
+            next_balance_shard_account: Pubkey::new_unique(),
+            balances: vec![Balance { address: "tb1q4sgwdxx8c3l08chkw2w3rewn5armr9urhe0pfk".to_string(), balance: 10 }],
+        };
+        let encoded = balance_shard.encode_to_vec().unwrap();
+        assert_eq!(SHARD_BALANCES_OFFSET + BALANCE_SIZE, encoded.len());
+        assert_eq!(balance_shard, BalanceShardState::decode_from_slice(&encoded).unwrap());
+
+        // version 0 kept the address strings in the balances
+        let balance_shard_v0 = BalanceShardState { version: 0, ..balance_shard };
+        let encoded_v0 = balance_shard_v0.encode_to_vec().unwrap();
+        assert_eq!(SHARD_BALANCES_OFFSET + LEGACY_BALANCE_SIZE, encoded_v0.len());
+        assert_eq!(balance_shard_v0, BalanceShardState::decode_from_slice(&encoded_v0).unwrap());
+
+        // addresses that cannot be stored in the compact form are rejected
+        let invalid = BalanceShardState {
+            balances: vec![Balance { address: "wallet".to_string(), balance: 10 }],
+            ..balance_shard_v0.clone()
+        };
+        assert!(BalanceShardState { version: BALANCE_SHARD_STATE_VERSION, ..invalid }.encode_to_vec().is_err());
+
+        let address_index = AddressIndex::new(2, 300, AddressCommitment::Last4([1, 2, 3, 4]));
+        assert_eq!((2 << 24) | 300, address_index.index);
+        assert_eq!(2, address_index.shard());
+        assert_eq!(300, address_index.balance_index());
+        let address_index = AddressIndex { index: 300, commitment: AddressCommitment::Last4([1, 2, 3, 4]) };
+        assert_eq!(0, address_index.shard());
+        assert_eq!(300, address_index.balance_index());
+
+        let instruction = ProgramInstruction::InitBalanceShard();
+        assert_eq!(instruction, ProgramInstruction::decode_from_slice(&instruction.encode_to_vec().unwrap()).unwrap());
+    }
+
+    #[test]
+    fn test_address_index_serialization() {
+        let address = "bc1qhz5a7xfh5dj00u32x0j5we6jfpa8vgpqhvaqug";
+        let address_index = AddressIndex::new(2, 300, address_commitment(address));
+        let encoded = address_index.encode_to_vec().unwrap();
+        assert_eq!(4 + ADDRESS_SCRIPT_HASH_SIZE, encoded.len());
+        assert_eq!(((2 << 24) | 300 | ADDRESS_SCRIPT_HASH_FLAG).to_le_bytes(), encoded[..4]);
+        let decoded = AddressIndex::decode_from_slice(&encoded).unwrap();
+        assert_eq!(address_index, decoded);
+        assert_eq!(2, decoded.shard());
+        assert_eq!(300, decoded.balance_index());
+        assert_ne!(address_commitment(address), address_commitment("33iFwdLuRpW1uK1RTRqsoi8rR4NpDzk66k"));
+
+        // address indexes of batches encoded before commitments were introduced still decode
+        let mut legacy_encoded = 300u32.to_le_bytes().to_vec();
+        legacy_encoded.extend_from_slice(&wallet_last4(address));
+        let decoded = AddressIndex::decode_from_slice(&legacy_encoded).unwrap();
+        assert_eq!(AddressIndex { index: 300, commitment: AddressCommitment::Last4(wallet_last4(address)) }, decoded);
+        assert_eq!(legacy_encoded, decoded.encode_to_vec().unwrap());
+    }
+
+    #[test]
+    fn test_wallet_index_serialization() {
+        let wallet1 = "132F25rTsvBdp9JzLLBHP5mvGY66i1xdiM";
+        let wallet2 = "33iFwdLuRpW1uK1RTRqsoi8rR4NpDzk66k";
+        let wallet3 = "bc1p5cyxnuxmeuwuvkwfem96lqzszd02n6xdcjrs20cac6yqjjwudpxqkedrcr";
+        let fee = "bc1qhz5a7xfh5dj00u32x0j5we6jfpa8vgpqhvaqug";
+        let address_hash = |address: &str| WalletAddress::from_address(address).unwrap().hash();
+        let mut entries = vec![
+            WalletIndexEntry { address_hash: address_hash(wallet1), balance_index: 1 },
+            WalletIndexEntry { address_hash: address_hash(wallet2), balance_index: 2 },
+            WalletIndexEntry { address_hash: address_hash(fee), balance_index: 0 },
+        ];
+        entries.sort_by(|a, b| a.address_hash.cmp(&b.address_hash));
+        let wallet_index = WalletIndexState {
+            account_type: AccountType::WalletIndex,
+            version: WALLET_INDEX_STATE_VERSION,
+            token_state_account: Pubkey::new_unique(),
+            num_indexed_balances: 3,
+            entries,
+        };
+        let encoded = wallet_index.encode_to_vec().unwrap();
+        assert_eq!(WALLET_INDEX_ENTRIES_OFFSET + 3 * WALLET_INDEX_ENTRY_SIZE, encoded.len());
+        let decoded = WalletIndexState::decode_from_slice(&encoded).unwrap();
+        assert_eq!(wallet_index.entries, decoded.entries);
+        assert_eq!(wallet_index.token_state_account, decoded.token_state_account);
+        assert_eq!(Some(2), decoded.find_balance_index(wallet2));
+        assert_eq!(Some(0), decoded.find_balance_index(fee));
+        assert_eq!(None, decoded.find_balance_index(wallet3));
+        assert_eq!(None, decoded.find_balance_index("wallet"));
+
+        let instruction = ProgramInstruction::InitWalletIndex();
+        assert_eq!(instruction, ProgramInstruction::decode_from_slice(&instruction.encode_to_vec().unwrap()).unwrap());
+        let instruction = ProgramInstruction::IndexWalletBalances();
+        assert_eq!(instruction, ProgramInstruction::decode_from_slice(&instruction.encode_to_vec().unwrap()).unwrap());
+    }
+
+    #[test]
+    fn test_withdrawal_limit_serialization() {
+        let withdrawal_limit = WithdrawalLimitState {
+            account_type: AccountType::WithdrawalLimit,
+            version: WITHDRAWAL_LIMIT_STATE_VERSION,
+            token_state_account: Pubkey::new_unique(),
+            max_amount: 100_000,
+            window_blocks: 144,
+            window_start: 864_000,
+            entries: vec![
+                WithdrawalLimitEntry { address_hash: [1; 32], amount: 500 },
+                WithdrawalLimitEntry { address_hash: [2; 32], amount: 100_000 },
+            ],
+        };
+        let encoded = withdrawal_limit.encode_to_vec().unwrap();
+        assert_eq!(WITHDRAWAL_LIMIT_ENTRIES_OFFSET + 2 * WITHDRAWAL_LIMIT_ENTRY_SIZE, encoded.len());
+        let decoded = WithdrawalLimitState::decode_from_slice(&encoded).unwrap();
+        assert_eq!(AccountType::WithdrawalLimit, decoded.account_type);
+        assert_eq!(withdrawal_limit.token_state_account, decoded.token_state_account);
+        assert_eq!((100_000, 144, 864_000), (decoded.max_amount, decoded.window_blocks, decoded.window_start));
+        assert_eq!(withdrawal_limit.entries, decoded.entries);
+    }
+
+    #[test]
+    fn test_forced_withdrawal_serialization() {
+        let forced_withdrawals = ForcedWithdrawalState {
+            account_type: AccountType::ForcedWithdrawals,
+            version: FORCED_WITHDRAWAL_STATE_VERSION,
+            program_state_account: Pubkey::new_unique(),
+            delay_blocks: 144,
+            entries: vec![
+                ForcedWithdrawalEntry { key: [1; 32], amount: 500, nonce: 1, due_height: 1144, pending: true },
+                ForcedWithdrawalEntry { key: [2; 32], amount: 100_000, nonce: 7, due_height: 900, pending: false },
+            ],
+        };
+        let encoded = forced_withdrawals.encode_to_vec().unwrap();
+        assert_eq!(FORCED_WITHDRAWAL_ENTRIES_OFFSET + 2 * FORCED_WITHDRAWAL_ENTRY_SIZE, encoded.len());
+        let decoded = ForcedWithdrawalState::decode_from_slice(&encoded).unwrap();
+        assert_eq!(AccountType::ForcedWithdrawals, decoded.account_type);
+        assert_eq!(forced_withdrawals.program_state_account, decoded.program_state_account);
+        assert_eq!(144, decoded.delay_blocks);
+        assert_eq!(forced_withdrawals.entries, decoded.entries);
+    }
+
+    #[test]
+    fn test_withdrawal_nonce_serialization() {
+        let withdrawal_nonces = WithdrawalNonceState {
+            account_type: AccountType::WithdrawalNonces,
+            version: WITHDRAWAL_NONCE_STATE_VERSION,
+            program_state_account: Pubkey::new_unique(),
+            entries: vec![
+                WithdrawalNonceEntry { address_hash: [1; 32], nonce: 3 },
+                WithdrawalNonceEntry { address_hash: [2; 32], nonce: u64::MAX },
+            ],
+        };
+        let encoded = withdrawal_nonces.encode_to_vec().unwrap();
+        assert_eq!(WITHDRAWAL_NONCE_ENTRIES_OFFSET + 2 * WITHDRAWAL_NONCE_ENTRY_SIZE, encoded.len());
+        let decoded = WithdrawalNonceState::decode_from_slice(&encoded).unwrap();
+        assert_eq!(AccountType::WithdrawalNonces, decoded.account_type);
+        assert_eq!(withdrawal_nonces.program_state_account, decoded.program_state_account);
+        assert_eq!(withdrawal_nonces.entries, decoded.entries);
+    }
+
+    #[test]
+    fn test_utxo_registry_serialization() {
+        let utxo_registry = UtxoRegistryState {
+            account_type: AccountType::UtxoRegistry,
+            version: UTXO_REGISTRY_STATE_VERSION,
+            program_state_account: Pubkey::new_unique(),
+            entries: vec![
+                UtxoEntry { outpoint: FundingOutpoint { tx_id: [1; 32], vout: 2 }, locked: false },
+                UtxoEntry { outpoint: FundingOutpoint { tx_id: [1; 32], vout: 3 }, locked: true },
+            ],
+        };
+        let encoded = utxo_registry.encode_to_vec().unwrap();
+        assert_eq!(UTXO_ENTRIES_OFFSET + 2 * UTXO_ENTRY_SIZE, encoded.len());
+        let decoded = UtxoRegistryState::decode_from_slice(&encoded).unwrap();
+        assert_eq!(AccountType::UtxoRegistry, decoded.account_type);
+        assert_eq!(utxo_registry.program_state_account, decoded.program_state_account);
+        assert_eq!(utxo_registry.entries, decoded.entries);
+    }
+}
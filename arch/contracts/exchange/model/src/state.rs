@@ -7,25 +7,68 @@ use arch_program::{
     program_error::ProgramError,
 };
 use bitcoin::Address;
+use bitcoin::hashes::{sha256, Hash as _, HashEngine};
+use crate::address::{WalletAddress, WALLET_ADDRESS_SIZE};
 use crate::error::*;
+use crate::instructions::{AddressCommitment, FundingOutpoint};
 use crate::serialization::Codable;
 use ordinals::RuneId;
 
 pub const ACCOUNT_TYPE_SIZE: usize = 1;
 pub const VERSION_SIZE: usize = 4;
 pub const PUBKEY_SIZE: usize = 32;
+pub const VERSION_OFFSET: usize = ACCOUNT_TYPE_SIZE;
 pub const PROGRAM_PUBKEY_OFFSET: usize = VERSION_SIZE + ACCOUNT_TYPE_SIZE;
 pub const MAX_TOKEN_ID_SIZE: usize = 32;
 pub const TOKEN_ID_OFFSET: usize = PROGRAM_PUBKEY_OFFSET + PUBKEY_SIZE;
+pub const WALLET_INDEX_OFFSET: usize = TOKEN_ID_OFFSET + MAX_TOKEN_ID_SIZE;
+// free balances have an empty address and are chained through their amount, which holds the
+// index of the next free balance
+pub const FREE_BALANCE_COUNT_SIZE: usize = 4;
+pub const FREE_BALANCE_COUNT_OFFSET: usize = WALLET_INDEX_OFFSET + PUBKEY_SIZE;
+pub const FIRST_FREE_BALANCE_SIZE: usize = 4;
+pub const FIRST_FREE_BALANCE_OFFSET: usize = FREE_BALANCE_COUNT_OFFSET + FREE_BALANCE_COUNT_SIZE;
+// balances beyond what a single account can hold go to balance shards, new wallets are added
+// to the last one
+pub const BALANCE_SHARD_COUNT_SIZE: usize = 1;
+pub const BALANCE_SHARD_COUNT_OFFSET: usize = FIRST_FREE_BALANCE_OFFSET + FIRST_FREE_BALANCE_SIZE;
+pub const FIRST_BALANCE_SHARD_OFFSET: usize = BALANCE_SHARD_COUNT_OFFSET + BALANCE_SHARD_COUNT_SIZE;
+pub const LAST_BALANCE_SHARD_OFFSET: usize = FIRST_BALANCE_SHARD_OFFSET + PUBKEY_SIZE;
+// a flag telling whether a withdrawal fee policy is set, followed by the minimum fee, the flat fee
+// and the token the fee is charged in, all zero for the token itself
+pub const FEE_POLICY_FLAG_SIZE: usize = 1;
+pub const FEE_POLICY_OFFSET: usize = LAST_BALANCE_SHARD_OFFSET + PUBKEY_SIZE;
+pub const MIN_FEE_OFFSET: usize = FEE_POLICY_OFFSET + FEE_POLICY_FLAG_SIZE;
+pub const FLAT_FEE_OFFSET: usize = MIN_FEE_OFFSET + BALANCE_AMOUNT_SIZE;
+pub const FEE_TOKEN_OFFSET: usize = FLAT_FEE_OFFSET + BALANCE_AMOUNT_SIZE;
+pub const FEE_POLICY_SIZE: usize = FEE_POLICY_FLAG_SIZE + 2 * BALANCE_AMOUNT_SIZE + PUBKEY_SIZE;
+// the key of the withdrawal limit of the token, see WithdrawalLimitState
+pub const WITHDRAWAL_LIMIT_OFFSET: usize = FEE_POLICY_OFFSET + FEE_POLICY_SIZE;
 pub const BALANCE_COUNT_SIZE: usize = 4;
-pub const BALANCE_COUNT_OFFSET: usize = TOKEN_ID_OFFSET + MAX_TOKEN_ID_SIZE;
+pub const BALANCE_COUNT_OFFSET: usize = WITHDRAWAL_LIMIT_OFFSET + PUBKEY_SIZE;
+// up to token state version 4 the balances followed the balance shards
+pub const LEGACY_BALANCE_COUNT_OFFSET: usize = FEE_POLICY_OFFSET;
 pub const BALANCES_OFFSET: usize = BALANCE_COUNT_OFFSET + BALANCE_COUNT_SIZE;
 
+pub const SHARD_NUMBER_SIZE: usize = 1;
+pub const SHARD_NUMBER_OFFSET: usize = PROGRAM_PUBKEY_OFFSET + PUBKEY_SIZE;
+pub const NEXT_BALANCE_SHARD_OFFSET: usize = SHARD_NUMBER_OFFSET + SHARD_NUMBER_SIZE;
+pub const SHARD_BALANCE_COUNT_OFFSET: usize = NEXT_BALANCE_SHARD_OFFSET + PUBKEY_SIZE;
+pub const SHARD_BALANCES_OFFSET: usize = SHARD_BALANCE_COUNT_OFFSET + BALANCE_COUNT_SIZE;
+// the top byte of an address index selects the balance shard, shard 0 being the token state
+pub const SHARD_INDEX_SHIFT: u32 = 24;
+// the top bit of an encoded address index tells a script hash commitment from a legacy last4
+pub const ADDRESS_SCRIPT_HASH_FLAG: u32 = 1 << 31;
+pub const MAX_BALANCE_SHARDS: usize = (ADDRESS_SCRIPT_HASH_FLAG >> SHARD_INDEX_SHIFT) as usize - 1;
+pub const ADDRESS_SCRIPT_HASH_SIZE: usize = 8;
+
 
 pub const MAX_ADDRESS_SIZE: usize = 92;
 pub const BALANCE_AMOUNT_SIZE: usize = 8;
-pub const BALANCE_AMOUNT_OFFSET: usize = MAX_ADDRESS_SIZE;
-pub const BALANCE_SIZE: usize = MAX_ADDRESS_SIZE + BALANCE_AMOUNT_SIZE;
+pub const BALANCE_AMOUNT_OFFSET: usize = WALLET_ADDRESS_SIZE;
+pub const BALANCE_SIZE: usize = WALLET_ADDRESS_SIZE + BALANCE_AMOUNT_SIZE;
+// up to token state version 3 and balance shard version 0 balances kept the address string
+pub const LEGACY_BALANCE_SIZE: usize = MAX_ADDRESS_SIZE + BALANCE_AMOUNT_SIZE;
 
 pub const NETWORK_TYPE_SIZE: usize = 1;
 pub const HASH_SIZE: usize = 32;
@@ -37,20 +80,71 @@ pub const NETWORK_TYPE_OFFSET: usize = PROGRAM_CHANGE_ADDRESS_OFFSET + MAX_ADDRE
 
 pub const SETTLEMENT_HASH_OFFSET: usize = NETWORK_TYPE_OFFSET + NETWORK_TYPE_SIZE;
 pub const LAST_SETTLEMENT_HASH_OFFSET: usize = SETTLEMENT_HASH_OFFSET + HASH_SIZE;
-pub const EVENTS_SIZE_OFFSET: usize = LAST_SETTLEMENT_HASH_OFFSET + HASH_SIZE;
-pub const EVENTS_OFFSET: usize = EVENTS_SIZE_OFFSET + 2;
-pub const EVENT_SIZE: usize = 64;
-pub const MAX_EVENTS: usize = 100;
-pub const RUNE_RECEIVER_OFFSET: usize = EVENTS_OFFSET + EVENT_SIZE * MAX_EVENTS;
+pub const PAUSE_FLAGS_SIZE: usize = 1;
+pub const PAUSE_FLAGS_OFFSET: usize = LAST_SETTLEMENT_HASH_OFFSET + HASH_SIZE;
+pub const EVENT_SEQUENCE_SIZE: usize = 8;
+pub const EVENT_SEQUENCE_OFFSET: usize = PAUSE_FLAGS_OFFSET + PAUSE_FLAGS_SIZE;
+// number of events emitted by the last instruction, the events themselves go to the event log
+pub const EVENTS_SIZE_OFFSET: usize = EVENT_SEQUENCE_OFFSET + EVENT_SEQUENCE_SIZE;
+pub const FIRST_EVENT_LOG_OFFSET: usize = EVENTS_SIZE_OFFSET + 2;
+pub const EVENT_LOG_OFFSET: usize = FIRST_EVENT_LOG_OFFSET + PUBKEY_SIZE;
+pub const RUNE_RECEIVER_OFFSET: usize = EVENT_LOG_OFFSET + PUBKEY_SIZE;
+pub const DEPOSIT_LEDGER_OFFSET: usize = RUNE_RECEIVER_OFFSET + PUBKEY_SIZE;
+pub const FORCED_WITHDRAWALS_OFFSET: usize = DEPOSIT_LEDGER_OFFSET + PUBKEY_SIZE;
+pub const WITHDRAWAL_NONCES_OFFSET: usize = FORCED_WITHDRAWALS_OFFSET + PUBKEY_SIZE;
+// bounds of the fee rate of withdrawal transactions in sat/vB, unchecked while the max is 0
+pub const FEE_RATE_SIZE: usize = 8;
+pub const MIN_FEE_RATE_OFFSET: usize = WITHDRAWAL_NONCES_OFFSET + PUBKEY_SIZE;
+pub const MAX_FEE_RATE_OFFSET: usize = MIN_FEE_RATE_OFFSET + FEE_RATE_SIZE;
+pub const UTXO_REGISTRY_OFFSET: usize = MAX_FEE_RATE_OFFSET + FEE_RATE_SIZE;
+
+// up to version 2 the program state held up to 100 fixed size events inline
+pub const LEGACY_EVENT_SIZE: usize = 64;
+pub const LEGACY_MAX_EVENTS: usize = 100;
 
 pub const FEE_ADDRESS_INDEX: u32 = 0;
 
+// current layout versions, see the migration module for how older layouts are upgraded
+pub const PROGRAM_STATE_VERSION: u32 = 3;
+pub const TOKEN_STATE_VERSION: u32 = 6;
+pub const WITHDRAW_STATE_VERSION: u32 = 1;
+pub const RUNE_RECEIVER_STATE_VERSION: u32 = 0;
+pub const DEPOSIT_LEDGER_STATE_VERSION: u32 = 0;
+pub const EVENT_LOG_STATE_VERSION: u32 = 0;
+pub const WALLET_INDEX_STATE_VERSION: u32 = 1;
+pub const BALANCE_SHARD_STATE_VERSION: u32 = 1;
+pub const WITHDRAWAL_LIMIT_STATE_VERSION: u32 = 0;
+pub const FORCED_WITHDRAWAL_STATE_VERSION: u32 = 0;
+pub const WITHDRAWAL_NONCE_STATE_VERSION: u32 = 0;
+pub const UTXO_REGISTRY_STATE_VERSION: u32 = 0;
+
+pub const PAUSE_DEPOSITS: u8 = 1;
+pub const PAUSE_WITHDRAWALS: u8 = 2;
+pub const PAUSE_SETTLEMENTS: u8 = 4;
+pub const PAUSE_ALL: u8 = PAUSE_DEPOSITS | PAUSE_WITHDRAWALS | PAUSE_SETTLEMENTS;
+
 pub const EMPTY_HASH: [u8; 32] = [0u8; 32];
 
 pub const DUST_THRESHOLD: u64 = 546;
 
+// program utxos are spent through the taproot key path, whose witness is a single 64 byte
+// signature, plus the segwit marker and flag of the transaction
+pub const TAPROOT_KEY_SPEND_WITNESS_WEIGHT: u64 = 66;
+// a p2wpkh witness is a signature of up to 72 bytes and a 33 byte compressed public key
+pub const P2WPKH_WITNESS_WEIGHT: u64 = 108;
+pub const SEGWIT_MARKER_WEIGHT: u64 = 2;
+
+// sat/vbyte a replacement has to pay on top of the fee of the transaction it replaces, the default
+// incrementalrelayfee of bitcoin core nodes (BIP125 rule 4)
+pub const INCREMENTAL_RELAY_FEE_RATE: u64 = 1;
+
+// blocks that have to be mined after a withdrawal transaction was sent before it counts as
+// confirmed, a reorg is not expected to go deeper
+pub const WITHDRAW_CONFIRMATION_DEPTH: u64 = 6;
+
 pub type Hash = [u8; 32];
 pub type WalletLast4 = [u8; 4];
+pub type AddressScriptHash = [u8; ADDRESS_SCRIPT_HASH_SIZE];
 
 #[derive(Clone, PartialEq, Debug)]
 pub enum NetworkType {
@@ -90,7 +184,103 @@ pub enum Event {
         balance: u64,
         balance_in_fee_token: u64,
         error_code: u32,
-    }
+    },
+    DuplicateDeposit {
+        account_index: u8,
+        address_index: u32,
+        amount: u64,
+        tx_id: Hash,
+        vout: u32,
+    },
+    // an entry left out of a partially prepared settlement batch, `settlement_index` is its
+    // position in the batch
+    SettlementExcluded {
+        account_index: u8,
+        settlement_index: u16,
+    },
+    // a withdrawal left out of a partially prepared withdrawal batch, `withdrawal_index` is its
+    // position in the withdrawals of its token
+    WithdrawalExcluded {
+        account_index: u8,
+        withdrawal_index: u16,
+    },
+    // success events carry a sequence number that increases by one for every success event emitted
+    DepositCredited {
+        sequence: u64,
+        account_index: u8,
+        address_index: u32,
+        amount: u64,
+    },
+    SettlementApplied {
+        sequence: u64,
+        account_index: u8,
+        settlement_hash: Hash,
+        increment_total: u64,
+        decrement_total: u64,
+    },
+    WithdrawalPrepared {
+        sequence: u64,
+        account_index: u8,
+        address_index: u32,
+        amount: u64,
+        fee_amount: u64,
+    },
+    WithdrawalSubmitted {
+        sequence: u64,
+        tx_id: Hash,
+        num_withdrawals: u32,
+    },
+    FeeCollected {
+        sequence: u64,
+        account_index: u8,
+        amount: u64,
+    },
+    WalletBalanceClosed {
+        sequence: u64,
+        account_index: u8,
+        address_index: u32,
+    },
+    // the balance at `from_address_index` moved to `to_address_index` when compacting
+    BalanceIndexRemapped {
+        sequence: u64,
+        account_index: u8,
+        from_address_index: u32,
+        to_address_index: u32,
+    },
+    // the increments plus the fee of a token in a partially prepared settlement batch did not add up
+    // to its decrements, outside partial mode the batch fails with ERROR_NETTING instead
+    NettingFailed {
+        account_index: u8,
+        increment_total: u64,
+        decrement_total: u64,
+    },
+    ForcedWithdrawalRequested {
+        sequence: u64,
+        account_index: u8,
+        address_index: u32,
+        amount: u64,
+        due_height: u64,
+    },
+    ForcedWithdrawalExecuted {
+        sequence: u64,
+        account_index: u8,
+        address_index: u32,
+        amount: u64,
+        tx_id: Hash,
+    },
+    WithdrawalConfirmed {
+        sequence: u64,
+        tx_id: Hash,
+    },
+    WithdrawalBumped {
+        sequence: u64,
+        replaced_tx_id: Hash,
+        tx_id: Hash,
+    },
+    // the last deposit ledger is running out of space, see LEDGER_LOW_WATERMARK
+    DepositLedgerLow {
+        remaining_deposits: u32,
+    },
 }
 
 #[derive(Clone, PartialEq, Debug)]
@@ -99,6 +289,14 @@ pub enum AccountType {
     Token,
     Withdraw,
     RuneReceiver,
+    DepositLedger,
+    EventLog,
+    WalletIndex,
+    BalanceShard,
+    WithdrawalLimit,
+    ForcedWithdrawals,
+    WithdrawalNonces,
+    UtxoRegistry,
     Unknown
 }
 
@@ -108,10 +306,60 @@ pub struct TokenState {
     pub version: u32,
     pub program_state_account: Pubkey,
     pub token_id: String,
+    pub wallet_index_account: Pubkey,
+    pub num_free_balances: u32,
+    pub first_free_balance: u32,
+    pub num_balance_shards: u8,
+    pub first_balance_shard_account: Pubkey,
+    pub last_balance_shard_account: Pubkey,
+    pub withdrawal_fee_policy: Option<WithdrawalFeePolicy>,
+    pub withdrawal_limit_account: Pubkey,
     pub balances: Vec<Balance>,
 }
 
-#[derive(Clone, Debug)]
+/// What every withdrawal of a token is charged. The fee has to be at least `min_fee`, and exactly
+/// `flat_fee` unless that is 0. It is paid in the token at `fee_token_account`, or in the token
+/// itself when that is not set.
+#[derive(Clone, Debug, PartialEq)]
+pub struct WithdrawalFeePolicy {
+    pub min_fee: u64,
+    pub flat_fee: u64,
+    pub fee_token_account: Option<Pubkey>,
+}
+
+impl WithdrawalFeePolicy {
+    pub fn allows(&self, fee_amount: u64, fee_token_account: &Pubkey, token_account: &Pubkey) -> bool {
+        fee_amount >= self.min_fee
+            && (self.flat_fee == 0 || fee_amount == self.flat_fee)
+            && fee_token_account == self.fee_token_account.as_ref().unwrap_or(token_account)
+    }
+}
+
+/// Bounds of the fee rate of withdrawal transactions, in sat/vB.
+#[derive(Clone, Debug, PartialEq)]
+pub struct WithdrawalFeeRate {
+    pub min_fee_rate: u64,
+    pub max_fee_rate: u64,
+}
+
+impl WithdrawalFeeRate {
+    pub fn allows(&self, fee: u64, vsize: u64) -> bool {
+        fee >= self.min_fee_rate.saturating_mul(vsize) && fee <= self.max_fee_rate.saturating_mul(vsize)
+    }
+}
+
+/// Holds the balances of shard `shard` of a token, see `AddressIndex::shard`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct BalanceShardState {
+    pub account_type: AccountType,
+    pub version: u32,
+    pub token_state_account: Pubkey,
+    pub shard: u8,
+    pub next_balance_shard_account: Pubkey,
+    pub balances: Vec<Balance>,
+}
+
+#[derive(Clone, Debug, PartialEq)]
 pub struct ProgramState {
     pub account_type: AccountType,
     pub version: u32,
@@ -121,15 +369,37 @@ pub struct ProgramState {
     pub network_type: NetworkType,
     pub settlement_batch_hash: Hash,
     pub last_settlement_batch_hash: Hash,
-    pub events: Vec<Event>,
+    pub pause_flags: u8,
+    pub event_sequence: u64,
+    pub events_count: u16,
+    pub first_event_log_account: Pubkey,
+    pub event_log_account: Pubkey,
 }
 
+/// Tracks the last withdrawal batch, `batch_hash` is the hash of its params and `tx_id` the
+/// bitcoin transaction it was submitted with. `replaced_tx_ids` are the transactions fee bumps
+/// replaced, any of which may still confirm instead of `tx_id`.
 #[derive(Clone, Debug)]
 pub struct WithdrawState {
     pub account_type: AccountType,
     pub version: u32,
     pub program_state_account: Pubkey,
     pub batch_hash: Hash,
+    pub status: WithdrawStatus,
+    pub tx_id: Hash,
+    pub submitted_height: u64,
+    pub replaced_tx_ids: Vec<Hash>,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum WithdrawStatus {
+    // no batch or the last one was rolled back
+    Idle,
+    Prepared,
+    Submitted,
+    Confirmed,
+    // the submitted transaction was replaced by a fee bump and none has confirmed yet
+    Replaced,
 }
 
 #[derive(Clone, Debug)]
@@ -139,15 +409,137 @@ pub struct RuneReceiverState {
     pub program_state_account: Pubkey,
 }
 
+#[derive(Clone, Debug)]
+pub struct DepositLedgerState {
+    pub account_type: AccountType,
+    pub version: u32,
+    pub program_state_account: Pubkey,
+    pub next_ledger_account: Pubkey,
+    pub deposits: Vec<FundingOutpoint>,
+}
+
+#[derive(Clone, Debug)]
+pub struct EventLogState {
+    pub account_type: AccountType,
+    pub version: u32,
+    pub program_state_account: Pubkey,
+    pub next_event_log_account: Pubkey,
+    pub events: Vec<Event>,
+}
+
+/// Maps the hash of a wallet address to its balance index in a token state. Entries are kept
+/// sorted by address hash, `num_indexed_balances` is the number of token balances covered so far.
+#[derive(Clone, Debug)]
+pub struct WalletIndexState {
+    pub account_type: AccountType,
+    pub version: u32,
+    pub token_state_account: Pubkey,
+    pub num_indexed_balances: u32,
+    pub entries: Vec<WalletIndexEntry>,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct WalletIndexEntry {
+    pub address_hash: Hash,
+    pub balance_index: u32,
+}
+
+/// Caps what a single wallet can withdraw from a token within a window of `window_blocks` bitcoin
+/// blocks. Windows start at multiples of `window_blocks`, the entries hold what each wallet has
+/// withdrawn in the window starting at `window_start`, sorted by address hash.
+#[derive(Clone, Debug)]
+pub struct WithdrawalLimitState {
+    pub account_type: AccountType,
+    pub version: u32,
+    pub token_state_account: Pubkey,
+    pub max_amount: u64,
+    pub window_blocks: u64,
+    pub window_start: u64,
+    pub entries: Vec<WithdrawalLimitEntry>,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct WithdrawalLimitEntry {
+    pub address_hash: Hash,
+    pub amount: u64,
+}
+
+/// Withdrawals wallets requested themselves because the operator did not process them. A request
+/// the operator does not serve within `delay_blocks` bitcoin blocks can be executed by the wallet
+/// owner and freezes settlement for that wallet. The entries are sorted by key, see `entry_key`.
+#[derive(Clone, Debug)]
+pub struct ForcedWithdrawalState {
+    pub account_type: AccountType,
+    pub version: u32,
+    pub program_state_account: Pubkey,
+    pub delay_blocks: u64,
+    pub entries: Vec<ForcedWithdrawalEntry>,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct ForcedWithdrawalEntry {
+    pub key: Hash,
+    pub amount: u64,
+    // the last nonce used by the wallet, a new request needs a higher one
+    pub nonce: u64,
+    pub due_height: u64,
+    pub pending: bool,
+}
+
+/// The last nonce each wallet used to sign a withdrawal, a signed withdrawal needs a higher one.
+/// Nonces are kept per wallet across tokens, the entries are sorted by address hash.
+///
+/// The nonces are not part of the `Balance` record on purpose. A balance slot is cleared by
+/// `CloseWalletBalance` and moved or reused by `CompactBalances`, so a nonce stored there would
+/// go back to zero and signed withdrawals that have not expired yet could be replayed. Growing
+/// `BALANCE_SIZE` would also mean rewriting every token state and balance shard in place.
+#[derive(Clone, Debug)]
+pub struct WithdrawalNonceState {
+    pub account_type: AccountType,
+    pub version: u32,
+    pub program_state_account: Pubkey,
+    pub entries: Vec<WithdrawalNonceEntry>,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct WithdrawalNonceEntry {
+    pub address_hash: Hash,
+    pub nonce: u64,
+}
+
+/// The bitcoin and rune utxos of the program that withdrawals can spend, sorted by (txid, vout).
+/// The inputs of a prepared withdrawal batch stay locked until it is submitted or rolled back.
+#[derive(Clone, Debug)]
+pub struct UtxoRegistryState {
+    pub account_type: AccountType,
+    pub version: u32,
+    pub program_state_account: Pubkey,
+    pub entries: Vec<UtxoEntry>,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct UtxoEntry {
+    pub outpoint: FundingOutpoint,
+    pub locked: bool,
+}
+
+/// Position of a reader in the event log chain.
+#[derive(Clone, Debug, PartialEq)]
+pub struct EventCursor {
+    pub event_log_account: Pubkey,
+    pub index: usize,
+}
+
 impl TokenState {
     pub fn initialize(account: &AccountInfo, token_id: &str, fee_account_address: &str, pubkey: &Pubkey) -> Result<(), ProgramError> {
         Self::grow_balance_accounts_if_needed(account, 1)?;
         set_type(account, AccountType::Token)?;
+        set_version(account, TOKEN_STATE_VERSION)?;
         Self::set_program_account(account, pubkey)?;
         Self::set_token_id(account, &token_id)?;
         if !Self::is_rune_id(token_id) {
             Self::set_num_balances(account, 1)?;
-            Balance::set_wallet_address(account, 0, fee_account_address)
+            Balance::set_wallet_address(account, 0, &WalletAddress::from_address(fee_account_address)?)
         } else {
             Ok(())
         }
@@ -157,8 +549,19 @@ impl TokenState {
         set_string(account, TOKEN_ID_OFFSET, token_id, MAX_TOKEN_ID_SIZE)
     }
 
+    pub fn get_wallet_index_key(account: &AccountInfo) -> Result<Option<Pubkey>, ProgramError> {
+        get_optional_pubkey(account, WALLET_INDEX_OFFSET)
+    }
+
+    pub fn set_wallet_index(account: &AccountInfo, pubkey: &Pubkey) -> Result<(), ProgramError> {
+        let mut data = account.data.try_borrow_mut().map_err(|_| ProgramError::InvalidAccountData)?;
+        Ok(data[WALLET_INDEX_OFFSET..WALLET_INDEX_OFFSET + PUBKEY_SIZE].copy_from_slice(
+            pubkey.0.as_slice()
+        ))
+    }
+
     pub fn get_num_balances(account: &AccountInfo) -> Result<usize, ProgramError> {
-        let offset = BALANCE_COUNT_OFFSET;
+        let offset = balance_count_offset(account)?;
         Ok(u32::from_le_bytes(
             account.data.borrow()[offset..offset + 4]
                 .try_into()
@@ -167,11 +570,135 @@ impl TokenState {
     }
 
     pub fn set_num_balances(account: &AccountInfo, num_balances: usize) -> Result<(), ProgramError> {
-        let offset = BALANCE_COUNT_OFFSET;
+        let offset = balance_count_offset(account)?;
         let mut data = account.data.try_borrow_mut().map_err(|_| ProgramError::InvalidAccountData)?;
         Ok(data[offset..offset + 4].copy_from_slice((num_balances as u32).to_le_bytes().as_slice()))
     }
 
+    pub fn get_num_free_balances(account: &AccountInfo) -> Result<usize, ProgramError> {
+        Ok(u32::from_le_bytes(
+            account.data.borrow()[FREE_BALANCE_COUNT_OFFSET..FREE_BALANCE_COUNT_OFFSET + FREE_BALANCE_COUNT_SIZE]
+                .try_into()
+                .map_err(|_| ProgramError::InvalidAccountData)?
+        ) as usize)
+    }
+
+    fn get_first_free_balance(account: &AccountInfo) -> Result<usize, ProgramError> {
+        Ok(u32::from_le_bytes(
+            account.data.borrow()[FIRST_FREE_BALANCE_OFFSET..FIRST_FREE_BALANCE_OFFSET + FIRST_FREE_BALANCE_SIZE]
+                .try_into()
+                .map_err(|_| ProgramError::InvalidAccountData)?
+        ) as usize)
+    }
+
+    fn set_free_balances(account: &AccountInfo, num_free_balances: usize, first_free_balance: usize) -> Result<(), ProgramError> {
+        let mut data = account.data.try_borrow_mut().map_err(|_| ProgramError::InvalidAccountData)?;
+        data[FREE_BALANCE_COUNT_OFFSET..FREE_BALANCE_COUNT_OFFSET + FREE_BALANCE_COUNT_SIZE].copy_from_slice(
+            (num_free_balances as u32).to_le_bytes().as_slice()
+        );
+        Ok(data[FIRST_FREE_BALANCE_OFFSET..FIRST_FREE_BALANCE_OFFSET + FIRST_FREE_BALANCE_SIZE].copy_from_slice(
+            (first_free_balance as u32).to_le_bytes().as_slice()
+        ))
+    }
+
+    /// Clears the balance at `index` and puts it on the free list.
+    pub fn free_balance(account: &AccountInfo, index: usize) -> Result<(), ProgramError> {
+        let num_free_balances = Self::get_num_free_balances(account)?;
+        let next_free_balance = if num_free_balances > 0 { Self::get_first_free_balance(account)? } else { 0 };
+        Balance::set_wallet_address(account, index, &WalletAddress::EMPTY)?;
+        Balance::set_wallet_balance(account, index, next_free_balance as u64)?;
+        Self::set_free_balances(account, num_free_balances + 1, index)
+    }
+
+    /// Takes the most recently freed balance off the free list.
+    pub fn take_free_balance(account: &AccountInfo) -> Result<Option<usize>, ProgramError> {
+        let num_free_balances = Self::get_num_free_balances(account)?;
+        if num_free_balances == 0 {
+            return Ok(None);
+        }
+        let index = Self::get_first_free_balance(account)?;
+        let next_free_balance = Balance::get_wallet_balance(account, index)? as usize;
+        Balance::set_wallet_balance(account, index, 0)?;
+        Self::set_free_balances(account, num_free_balances - 1, if num_free_balances > 1 { next_free_balance } else { 0 })?;
+        Ok(Some(index))
+    }
+
+    /// Returns the indexes on the free list and empties it.
+    pub fn take_free_balances(account: &AccountInfo) -> Result<Vec<usize>, ProgramError> {
+        let mut free_balances = vec![];
+        while let Some(index) = Self::take_free_balance(account)? {
+            free_balances.push(index);
+        }
+        Ok(free_balances)
+    }
+
+    pub fn is_free_balance(account: &AccountInfo, index: usize) -> Result<bool, ProgramError> {
+        Ok(account.data.borrow()[balances_offset(account)? + index * BALANCE_SIZE] == 0)
+    }
+
+    pub fn get_num_balance_shards(account: &AccountInfo) -> Result<usize, ProgramError> {
+        Ok(account.data.borrow()[BALANCE_SHARD_COUNT_OFFSET] as usize)
+    }
+
+    pub fn get_first_balance_shard_key(account: &AccountInfo) -> Result<Option<Pubkey>, ProgramError> {
+        get_optional_pubkey(account, FIRST_BALANCE_SHARD_OFFSET)
+    }
+
+    pub fn get_last_balance_shard_key(account: &AccountInfo) -> Result<Option<Pubkey>, ProgramError> {
+        get_optional_pubkey(account, LAST_BALANCE_SHARD_OFFSET)
+    }
+
+    /// Records `pubkey` as the new last balance shard and returns its shard number.
+    pub fn add_balance_shard(account: &AccountInfo, pubkey: &Pubkey) -> Result<u8, ProgramError> {
+        let num_balance_shards = Self::get_num_balance_shards(account)?;
+        if num_balance_shards >= MAX_BALANCE_SHARDS {
+            return Err(ProgramError::Custom(ERROR_VALUE_TOO_LARGE));
+        }
+        let mut data = account.data.try_borrow_mut().map_err(|_| ProgramError::InvalidAccountData)?;
+        if num_balance_shards == 0 {
+            data[FIRST_BALANCE_SHARD_OFFSET..FIRST_BALANCE_SHARD_OFFSET + PUBKEY_SIZE].copy_from_slice(pubkey.0.as_slice());
+        }
+        data[LAST_BALANCE_SHARD_OFFSET..LAST_BALANCE_SHARD_OFFSET + PUBKEY_SIZE].copy_from_slice(pubkey.0.as_slice());
+        data[BALANCE_SHARD_COUNT_OFFSET] = num_balance_shards as u8 + 1;
+        Ok(num_balance_shards as u8 + 1)
+    }
+
+    pub fn get_withdrawal_fee_policy(account: &AccountInfo) -> Result<Option<WithdrawalFeePolicy>, ProgramError> {
+        if account.data.borrow()[FEE_POLICY_OFFSET] == 0 {
+            return Ok(None);
+        }
+        Ok(Some(WithdrawalFeePolicy {
+            min_fee: get_u64(account, MIN_FEE_OFFSET)?,
+            flat_fee: get_u64(account, FLAT_FEE_OFFSET)?,
+            fee_token_account: get_optional_pubkey(account, FEE_TOKEN_OFFSET)?,
+        }))
+    }
+
+    pub fn set_withdrawal_fee_policy(account: &AccountInfo, fee_policy: &Option<WithdrawalFeePolicy>) -> Result<(), ProgramError> {
+        let mut data = account.data.try_borrow_mut().map_err(|_| ProgramError::InvalidAccountData)?;
+        data[FEE_POLICY_OFFSET..FEE_POLICY_OFFSET + FEE_POLICY_SIZE].fill(0);
+        if let Some(fee_policy) = fee_policy {
+            data[FEE_POLICY_OFFSET] = 1;
+            data[MIN_FEE_OFFSET..MIN_FEE_OFFSET + BALANCE_AMOUNT_SIZE].copy_from_slice(fee_policy.min_fee.to_le_bytes().as_slice());
+            data[FLAT_FEE_OFFSET..FLAT_FEE_OFFSET + BALANCE_AMOUNT_SIZE].copy_from_slice(fee_policy.flat_fee.to_le_bytes().as_slice());
+            if let Some(fee_token_account) = fee_policy.fee_token_account {
+                data[FEE_TOKEN_OFFSET..FEE_TOKEN_OFFSET + PUBKEY_SIZE].copy_from_slice(fee_token_account.0.as_slice());
+            }
+        }
+        Ok(())
+    }
+
+    pub fn get_withdrawal_limit_key(account: &AccountInfo) -> Result<Option<Pubkey>, ProgramError> {
+        get_optional_pubkey(account, WITHDRAWAL_LIMIT_OFFSET)
+    }
+
+    pub fn set_withdrawal_limit(account: &AccountInfo, pubkey: &Option<Pubkey>) -> Result<(), ProgramError> {
+        let mut data = account.data.try_borrow_mut().map_err(|_| ProgramError::InvalidAccountData)?;
+        Ok(data[WITHDRAWAL_LIMIT_OFFSET..WITHDRAWAL_LIMIT_OFFSET + PUBKEY_SIZE].copy_from_slice(
+            pubkey.unwrap_or(Pubkey::from([0u8; 32])).0.as_slice()
+        ))
+    }
+
     pub fn get_token_id(account: &AccountInfo) -> Result<String, ProgramError> {
         let mut tmp = [0u8; MAX_TOKEN_ID_SIZE];
         tmp[..MAX_TOKEN_ID_SIZE].copy_from_slice(&account.data.borrow()[TOKEN_ID_OFFSET..TOKEN_ID_OFFSET + MAX_TOKEN_ID_SIZE]);
@@ -213,12 +740,12 @@ impl TokenState {
     pub fn grow_balance_accounts_if_needed(account: &AccountInfo, additional_balances: usize) -> Result<(), ProgramError> {
         let original_data_len = unsafe { account.original_data_len() };
 
-        let num_balances = if original_data_len > 0 {
-            TokenState::get_num_balances(account)?
+        let (balances_offset, num_balances) = if original_data_len > 0 {
+            (balances_offset(account)?, TokenState::get_num_balances(account)?)
         } else {
-            0
+            (BALANCES_OFFSET, 0)
         };
-        if BALANCES_OFFSET + (num_balances + additional_balances) * BALANCE_SIZE > original_data_len {
+        if balances_offset + (num_balances + additional_balances) * BALANCE_SIZE > original_data_len {
             account.realloc(original_data_len + entrypoint::MAX_PERMITTED_DATA_INCREASE, true)?
         }
         Ok(())
@@ -234,7 +761,7 @@ impl TokenState {
 
 impl Balance {
     pub fn get_wallet_balance(account: &AccountInfo, index: usize) -> Result<u64, ProgramError> {
-        let offset = BALANCES_OFFSET + index * BALANCE_SIZE + BALANCE_AMOUNT_OFFSET;
+        let offset = balances_offset(account)? + index * BALANCE_SIZE + BALANCE_AMOUNT_OFFSET;
         Ok(u64::from_le_bytes(
             account.data.borrow()[offset..offset + 8]
                 .try_into()
@@ -243,7 +770,7 @@ impl Balance {
     }
 
     pub fn set_wallet_balance(account: &AccountInfo, index: usize, balance: u64) -> Result<(), ProgramError> {
-        let offset = BALANCES_OFFSET + index * BALANCE_SIZE + BALANCE_AMOUNT_OFFSET;
+        let offset = balances_offset(account)? + index * BALANCE_SIZE + BALANCE_AMOUNT_OFFSET;
         let mut data = account.data.try_borrow_mut().map_err(|_| ProgramError::InvalidAccountData)?;
         Ok(data[offset..offset + 8].copy_from_slice(
             balance.to_le_bytes().as_slice()
@@ -252,7 +779,7 @@ impl Balance {
 
     pub fn increment_wallet_balance(account: &AccountInfo, index: usize, balance_adjustment: u64) -> Result<(), ProgramError> {
         let current_balance = Self::get_wallet_balance(account, index)?;
-        Self::set_wallet_balance(account, index, current_balance + balance_adjustment)
+        Self::set_wallet_balance(account, index, checked_add_amount(current_balance, balance_adjustment)?)
     }
 
     pub fn decrement_wallet_balance(account: &AccountInfo, index: usize, balance_adjustment: u64) -> Result<(), ProgramError> {
@@ -265,16 +792,37 @@ impl Balance {
         Self::set_wallet_balance(account, index, current_balance)
     }
 
-    pub fn get_wallet_address(account: &AccountInfo, index: usize) -> Result<String, ProgramError> {
-        get_address(account, BALANCES_OFFSET + index * BALANCE_SIZE)
+    pub fn get_wallet_address(account: &AccountInfo, index: usize) -> Result<WalletAddress, ProgramError> {
+        let offset = balances_offset(account)? + index * BALANCE_SIZE;
+        Ok(WalletAddress(
+            account.data.borrow()[offset..offset + WALLET_ADDRESS_SIZE]
+                .try_into()
+                .map_err(|_| ProgramError::InvalidAccountData)?
+        ))
     }
 
-    pub fn set_wallet_address(account: &AccountInfo, index: usize, address: &str) -> Result<(), ProgramError> {
-        set_string(account, BALANCES_OFFSET + index * BALANCE_SIZE, address, MAX_ADDRESS_SIZE)
+    pub fn set_wallet_address(account: &AccountInfo, index: usize, address: &WalletAddress) -> Result<(), ProgramError> {
+        let offset = balances_offset(account)? + index * BALANCE_SIZE;
+        let mut data = account.data.try_borrow_mut().map_err(|_| ProgramError::InvalidAccountData)?;
+        Ok(data[offset..offset + WALLET_ADDRESS_SIZE].copy_from_slice(address.0.as_slice()))
     }
 
-    pub fn get_wallet_address_last4(account: &AccountInfo, index: usize) -> Result<WalletLast4, ProgramError> {
-        get_wallet_last4(account, BALANCES_OFFSET + index * BALANCE_SIZE)
+    /// Checks the wallet address at `index` against `commitment`, a free balance matches nothing.
+    pub fn verify_wallet_address(account: &AccountInfo, index: usize, commitment: &AddressCommitment) -> Result<(), ProgramError> {
+        let address = Self::get_wallet_address(account, index)?;
+        match commitment {
+            AddressCommitment::ScriptHash(script_hash) => {
+                if address.is_empty() || address.script_hash()? != *script_hash {
+                    return Err(ProgramError::Custom(ERROR_ADDRESS_COMMITMENT_MISMATCH));
+                }
+            }
+            AddressCommitment::Last4(last4) => {
+                if address.is_empty() || address.last4() != *last4 {
+                    return Err(ProgramError::Custom(ERROR_WALLET_LAST4_MISMATCH));
+                }
+            }
+        }
+        Ok(())
     }
 }
 
@@ -285,14 +833,29 @@ impl ProgramState {
             .try_into().map_err(|_| ProgramError::InvalidAccountData)?))
     }
 
+    pub fn set_withdraw_account_key(account: &AccountInfo, pubkey: &Pubkey) -> Result<(), ProgramError> {
+        let mut data = account.data.try_borrow_mut().map_err(|_| ProgramError::InvalidAccountData)?;
+        Ok(data[WITHDRAW_ACCOUNT_PUBKEY_OFFSET..WITHDRAW_ACCOUNT_PUBKEY_OFFSET + PUBKEY_SIZE].copy_from_slice(
+            pubkey.0.as_slice()
+        ))
+    }
+
     pub fn get_fee_account_address(account: &AccountInfo) -> Result<String, ProgramError> {
         get_address(account, FEE_ACCOUNT_OFFSET)
     }
 
+    pub fn set_fee_account_address(account: &AccountInfo, address: &str) -> Result<(), ProgramError> {
+        set_string(account, FEE_ACCOUNT_OFFSET, address, MAX_ADDRESS_SIZE)
+    }
+
     pub fn get_program_change_address(account: &AccountInfo) -> Result<String, ProgramError> {
         get_address(account, PROGRAM_CHANGE_ADDRESS_OFFSET)
     }
 
+    pub fn set_program_change_address(account: &AccountInfo, address: &str) -> Result<(), ProgramError> {
+        set_string(account, PROGRAM_CHANGE_ADDRESS_OFFSET, address, MAX_ADDRESS_SIZE)
+    }
+
     pub fn get_network_type(account: &AccountInfo) -> NetworkType {
         NetworkType::decode_from_slice(&account.data.borrow()[NETWORK_TYPE_OFFSET..]).unwrap()
     }
@@ -319,6 +882,40 @@ impl ProgramState {
         ))
     }
 
+    pub fn get_pause_flags(account: &AccountInfo) -> Result<u8, ProgramError> {
+        Ok(account.data.borrow()[PAUSE_FLAGS_OFFSET])
+    }
+
+    pub fn set_pause_flags(account: &AccountInfo, pause_flags: u8) -> Result<(), ProgramError> {
+        let mut data = account.data.try_borrow_mut().map_err(|_| ProgramError::InvalidAccountData)?;
+        data[PAUSE_FLAGS_OFFSET] = pause_flags;
+        Ok(())
+    }
+
+    pub fn verify_not_paused(account: &AccountInfo, pause_flag: u8) -> Result<(), ProgramError> {
+        if Self::get_pause_flags(account)? & pause_flag != 0 {
+            return Err(ProgramError::Custom(ERROR_PAUSED));
+        }
+        Ok(())
+    }
+
+    pub fn get_event_sequence(account: &AccountInfo) -> Result<u64, ProgramError> {
+        Ok(u64::from_le_bytes(
+            account.data.borrow()[EVENT_SEQUENCE_OFFSET..EVENT_SEQUENCE_OFFSET + EVENT_SEQUENCE_SIZE]
+                .try_into()
+                .map_err(|_| ProgramError::InvalidAccountData)?
+        ))
+    }
+
+    pub fn next_event_sequence(account: &AccountInfo) -> Result<u64, ProgramError> {
+        let sequence = Self::get_event_sequence(account)?
+            .checked_add(1)
+            .ok_or(ProgramError::Custom(ERROR_ARITHMETIC_OVERFLOW))?;
+        let mut data = account.data.try_borrow_mut().map_err(|_| ProgramError::InvalidAccountData)?;
+        data[EVENT_SEQUENCE_OFFSET..EVENT_SEQUENCE_OFFSET + EVENT_SEQUENCE_SIZE].copy_from_slice(sequence.to_le_bytes().as_slice());
+        Ok(sequence)
+    }
+
     pub fn clear_events(account: &AccountInfo) -> Result<(), ProgramError> {
         let mut data = account.data.try_borrow_mut().map_err(|_| ProgramError::InvalidAccountData)?;
         Ok(data[EVENTS_SIZE_OFFSET..EVENTS_SIZE_OFFSET + 2].copy_from_slice(0u16.to_le_bytes().as_slice()))
@@ -332,105 +929,1048 @@ impl ProgramState {
         ) as usize)
     }
 
-    pub fn emit_event(account: &AccountInfo, event: &Event) -> Result<(), ProgramError> {
-        let current_count = Self::get_events_count(account)?;
-        if current_count == MAX_EVENTS {
-            return Err(ProgramError::Custom(ERROR_VALUE_TOO_LARGE));
+    /// Appends `event` to the current event log. Once a log cannot take any more data in this
+    /// instruction the next log of the chain becomes the current one, so it has to be passed too.
+    /// Until `InitEventLog` links the first log, e.g. right after migrating a version 0 state,
+    /// events are dropped so that the instructions emitting them keep working.
+    pub fn emit_event(accounts: &[AccountInfo], event: &Event) -> Result<(), ProgramError> {
+        let Some(mut event_log_key) = Self::get_event_log_key(&accounts[0])? else {
+            return Ok(());
+        };
+        let event_data = event.encode_to_vec().map_err(|_| ProgramError::Custom(ERROR_VALUE_TOO_LARGE))?;
+        loop {
+            let event_log = get_event_log_account(accounts, &event_log_key)?;
+            if EventLogState::append_event(event_log, &event_data)? {
+                break;
+            }
+            event_log_key = EventLogState::get_next_event_log_key(event_log)?
+                .ok_or(ProgramError::Custom(ERROR_EVENT_LOG_FULL))?;
+            Self::set_event_log(&accounts[0], &event_log_key)?;
         }
-        let offset = EVENTS_OFFSET + (current_count * EVENT_SIZE);
-        let mut data = account.data.try_borrow_mut().map_err(|_| ProgramError::InvalidAccountData)?;
-        data[offset..offset + EVENT_SIZE].copy_from_slice(
-            event.encode_to_vec().unwrap().as_slice()
-        );
-        Ok(data[EVENTS_SIZE_OFFSET..EVENTS_SIZE_OFFSET + 2].copy_from_slice(((current_count + 1) as u16).to_le_bytes().as_slice()))
+        let events_count = Self::get_events_count(&accounts[0])?;
+        let mut data = accounts[0].data.try_borrow_mut().map_err(|_| ProgramError::InvalidAccountData)?;
+        Ok(data[EVENTS_SIZE_OFFSET..EVENTS_SIZE_OFFSET + 2].copy_from_slice((events_count as u16).saturating_add(1).to_le_bytes().as_slice()))
     }
 
-    pub fn get_failed_withdrawal_amount(account: &AccountInfo) -> Result<u64, ProgramError> {
-        let current_count = Self::get_events_count(account)? as usize;
-        let mut amount: u64 = 0;
-        let data = account.data.borrow();
-        for i in 0..current_count {
-            let offset = EVENTS_OFFSET + i * EVENT_SIZE;
-            let event = Event::decode_from_slice(
-                data[offset..offset + EVENT_SIZE].try_into().map_err(|_| ProgramError::InvalidAccountData)?
-            ).map_err(|_| ProgramError::InvalidAccountData)?;
-            match event {
-                Event::FailedWithdrawal { requested_amount, fee_amount, .. } => {
-                    amount += requested_amount - fee_amount
-                }
-                Event::FailedSettlement { .. } => {}
-            }
-        }
-        Ok(amount)
+    pub fn get_first_event_log_key(account: &AccountInfo) -> Result<Option<Pubkey>, ProgramError> {
+        get_optional_pubkey(account, FIRST_EVENT_LOG_OFFSET)
     }
 
-    fn set_rune_receiver(account: &AccountInfo, pubkey: &Pubkey) -> Result<(), ProgramError> {
+    pub fn get_event_log_key(account: &AccountInfo) -> Result<Option<Pubkey>, ProgramError> {
+        get_optional_pubkey(account, EVENT_LOG_OFFSET)
+    }
+
+    pub fn set_first_event_log(account: &AccountInfo, pubkey: &Pubkey) -> Result<(), ProgramError> {
         let mut data = account.data.try_borrow_mut().map_err(|_| ProgramError::InvalidAccountData)?;
-        Ok(data[RUNE_RECEIVER_OFFSET..RUNE_RECEIVER_OFFSET + PUBKEY_SIZE].copy_from_slice(
+        Ok(data[FIRST_EVENT_LOG_OFFSET..FIRST_EVENT_LOG_OFFSET + PUBKEY_SIZE].copy_from_slice(
             pubkey.0.as_slice()
         ))
     }
-}
 
-pub const WITHDRAW_HASH_OFFSET: usize = PROGRAM_PUBKEY_OFFSET + PUBKEY_SIZE;
-pub const WITHDRAW_ACCOUNT_SIZE: usize = WITHDRAW_HASH_OFFSET + HASH_SIZE;
-impl WithdrawState {
+    pub fn set_event_log(account: &AccountInfo, pubkey: &Pubkey) -> Result<(), ProgramError> {
+        let mut data = account.data.try_borrow_mut().map_err(|_| ProgramError::InvalidAccountData)?;
+        Ok(data[EVENT_LOG_OFFSET..EVENT_LOG_OFFSET + PUBKEY_SIZE].copy_from_slice(
+            pubkey.0.as_slice()
+        ))
+    }
 
-    pub fn initialize(accounts: &[AccountInfo]) -> Result<(), ProgramError> {
-        if accounts[1].data_is_empty() {
-            accounts[1].realloc(WITHDRAW_ACCOUNT_SIZE, true)?;
-            set_type(&accounts[1], AccountType::Withdraw)?;
-            Self::set_program_account(&accounts[1], accounts[0].key)
-        } else {
-            Ok(())
-        }
+    pub fn get_rune_receiver_key(account: &AccountInfo) -> Result<Option<Pubkey>, ProgramError> {
+        get_optional_pubkey(account, RUNE_RECEIVER_OFFSET)
     }
 
-    pub fn get_program_state_account_key(account: &AccountInfo) -> Result<Pubkey, ProgramError> {
-        Ok(Pubkey::from_slice(account.data.borrow()[PROGRAM_PUBKEY_OFFSET..PROGRAM_PUBKEY_OFFSET + PUBKEY_SIZE]
-            .try_into().map_err(|_| ProgramError::InvalidAccountData)?))
+    pub fn get_deposit_ledger_key(account: &AccountInfo) -> Result<Option<Pubkey>, ProgramError> {
+        get_optional_pubkey(account, DEPOSIT_LEDGER_OFFSET)
     }
 
-    fn set_program_account(account: &AccountInfo, pubkey: &Pubkey) -> Result<(), ProgramError> {
+    pub fn set_deposit_ledger(account: &AccountInfo, pubkey: &Pubkey) -> Result<(), ProgramError> {
+        if account.data_len() < DEPOSIT_LEDGER_OFFSET + PUBKEY_SIZE {
+            account.realloc(DEPOSIT_LEDGER_OFFSET + PUBKEY_SIZE, true)?;
+        }
+        let mut data = account.data.try_borrow_mut().map_err(|_| ProgramError::InvalidAccountData)?;
+        Ok(data[DEPOSIT_LEDGER_OFFSET..DEPOSIT_LEDGER_OFFSET + PUBKEY_SIZE].copy_from_slice(
+            pubkey.0.as_slice()
+        ))
+    }
+
+    pub fn get_forced_withdrawals_key(account: &AccountInfo) -> Result<Option<Pubkey>, ProgramError> {
+        get_optional_pubkey(account, FORCED_WITHDRAWALS_OFFSET)
+    }
+
+    pub fn set_forced_withdrawals(account: &AccountInfo, pubkey: &Pubkey) -> Result<(), ProgramError> {
+        if account.data_len() < FORCED_WITHDRAWALS_OFFSET + PUBKEY_SIZE {
+            account.realloc(FORCED_WITHDRAWALS_OFFSET + PUBKEY_SIZE, true)?;
+        }
+        let mut data = account.data.try_borrow_mut().map_err(|_| ProgramError::InvalidAccountData)?;
+        Ok(data[FORCED_WITHDRAWALS_OFFSET..FORCED_WITHDRAWALS_OFFSET + PUBKEY_SIZE].copy_from_slice(
+            pubkey.0.as_slice()
+        ))
+    }
+
+    pub fn get_withdrawal_fee_rate(account: &AccountInfo) -> Result<Option<WithdrawalFeeRate>, ProgramError> {
+        if account.data_len() < MAX_FEE_RATE_OFFSET + FEE_RATE_SIZE {
+            return Ok(None);
+        }
+        let max_fee_rate = get_u64(account, MAX_FEE_RATE_OFFSET)?;
+        if max_fee_rate == 0 {
+            return Ok(None);
+        }
+        Ok(Some(WithdrawalFeeRate {
+            min_fee_rate: get_u64(account, MIN_FEE_RATE_OFFSET)?,
+            max_fee_rate,
+        }))
+    }
+
+    pub fn set_withdrawal_fee_rate(account: &AccountInfo, fee_rate: &Option<WithdrawalFeeRate>) -> Result<(), ProgramError> {
+        if account.data_len() < MAX_FEE_RATE_OFFSET + FEE_RATE_SIZE {
+            account.realloc(MAX_FEE_RATE_OFFSET + FEE_RATE_SIZE, true)?;
+        }
+        let (min_fee_rate, max_fee_rate) = fee_rate.as_ref()
+            .map_or((0, 0), |fee_rate| (fee_rate.min_fee_rate, fee_rate.max_fee_rate));
+        set_u64(account, MIN_FEE_RATE_OFFSET, min_fee_rate)?;
+        set_u64(account, MAX_FEE_RATE_OFFSET, max_fee_rate)
+    }
+
+    pub fn get_withdrawal_nonces_key(account: &AccountInfo) -> Result<Option<Pubkey>, ProgramError> {
+        get_optional_pubkey(account, WITHDRAWAL_NONCES_OFFSET)
+    }
+
+    pub fn set_withdrawal_nonces(account: &AccountInfo, pubkey: &Pubkey) -> Result<(), ProgramError> {
+        if account.data_len() < WITHDRAWAL_NONCES_OFFSET + PUBKEY_SIZE {
+            account.realloc(WITHDRAWAL_NONCES_OFFSET + PUBKEY_SIZE, true)?;
+        }
+        let mut data = account.data.try_borrow_mut().map_err(|_| ProgramError::InvalidAccountData)?;
+        Ok(data[WITHDRAWAL_NONCES_OFFSET..WITHDRAWAL_NONCES_OFFSET + PUBKEY_SIZE].copy_from_slice(
+            pubkey.0.as_slice()
+        ))
+    }
+
+    pub fn get_utxo_registry_key(account: &AccountInfo) -> Result<Option<Pubkey>, ProgramError> {
+        get_optional_pubkey(account, UTXO_REGISTRY_OFFSET)
+    }
+
+    pub fn set_utxo_registry(account: &AccountInfo, pubkey: &Pubkey) -> Result<(), ProgramError> {
+        if account.data_len() < UTXO_REGISTRY_OFFSET + PUBKEY_SIZE {
+            account.realloc(UTXO_REGISTRY_OFFSET + PUBKEY_SIZE, true)?;
+        }
+        let mut data = account.data.try_borrow_mut().map_err(|_| ProgramError::InvalidAccountData)?;
+        Ok(data[UTXO_REGISTRY_OFFSET..UTXO_REGISTRY_OFFSET + PUBKEY_SIZE].copy_from_slice(
+            pubkey.0.as_slice()
+        ))
+    }
+
+    fn set_rune_receiver(account: &AccountInfo, pubkey: &Pubkey) -> Result<(), ProgramError> {
+        let mut data = account.data.try_borrow_mut().map_err(|_| ProgramError::InvalidAccountData)?;
+        Ok(data[RUNE_RECEIVER_OFFSET..RUNE_RECEIVER_OFFSET + PUBKEY_SIZE].copy_from_slice(
+            pubkey.0.as_slice()
+        ))
+    }
+}
+
+pub const WITHDRAW_HASH_OFFSET: usize = PROGRAM_PUBKEY_OFFSET + PUBKEY_SIZE;
+pub const WITHDRAW_STATUS_SIZE: usize = 1;
+pub const WITHDRAW_STATUS_OFFSET: usize = WITHDRAW_HASH_OFFSET + HASH_SIZE;
+pub const WITHDRAW_TX_ID_OFFSET: usize = WITHDRAW_STATUS_OFFSET + WITHDRAW_STATUS_SIZE;
+pub const WITHDRAW_SUBMITTED_HEIGHT_SIZE: usize = 8;
+pub const WITHDRAW_SUBMITTED_HEIGHT_OFFSET: usize = WITHDRAW_TX_ID_OFFSET + HASH_SIZE;
+pub const WITHDRAW_REPLACED_COUNT_SIZE: usize = 4;
+pub const WITHDRAW_REPLACED_COUNT_OFFSET: usize = WITHDRAW_SUBMITTED_HEIGHT_OFFSET + WITHDRAW_SUBMITTED_HEIGHT_SIZE;
+pub const WITHDRAW_REPLACED_TX_IDS_OFFSET: usize = WITHDRAW_REPLACED_COUNT_OFFSET + WITHDRAW_REPLACED_COUNT_SIZE;
+pub const WITHDRAW_ACCOUNT_SIZE: usize = WITHDRAW_REPLACED_TX_IDS_OFFSET;
+impl WithdrawState {
+
+    pub fn initialize(accounts: &[AccountInfo]) -> Result<(), ProgramError> {
+        if accounts[1].data_is_empty() {
+            accounts[1].realloc(WITHDRAW_ACCOUNT_SIZE, true)?;
+            set_type(&accounts[1], AccountType::Withdraw)?;
+            set_version(&accounts[1], WITHDRAW_STATE_VERSION)?;
+            Self::set_program_account(&accounts[1], accounts[0].key)
+        } else {
+            Ok(())
+        }
+    }
+
+    pub fn get_program_state_account_key(account: &AccountInfo) -> Result<Pubkey, ProgramError> {
+        Ok(Pubkey::from_slice(account.data.borrow()[PROGRAM_PUBKEY_OFFSET..PROGRAM_PUBKEY_OFFSET + PUBKEY_SIZE]
+            .try_into().map_err(|_| ProgramError::InvalidAccountData)?))
+    }
+
+    fn set_program_account(account: &AccountInfo, pubkey: &Pubkey) -> Result<(), ProgramError> {
+        let mut data = account.data.try_borrow_mut().map_err(|_| ProgramError::InvalidAccountData)?;
+        Ok(data[PROGRAM_PUBKEY_OFFSET..PROGRAM_PUBKEY_OFFSET + PUBKEY_SIZE].copy_from_slice(
+            pubkey.0.as_slice()
+        ))
+    }
+
+    pub fn get_hash(account: &AccountInfo) -> Result<Hash, ProgramError> {
+        hash_from_slice(account, WITHDRAW_HASH_OFFSET)
+    }
+
+    pub fn clear_hash(account: &AccountInfo) -> Result<(), ProgramError> {
+        Self::set_hash(account, [0u8; HASH_SIZE])
+    }
+
+    pub fn set_hash(account: &AccountInfo, hash: Hash) -> Result<(), ProgramError> {
+        let mut data = account.data.try_borrow_mut().map_err(|_| ProgramError::InvalidAccountData)?;
+        Ok(data[WITHDRAW_HASH_OFFSET..WITHDRAW_HASH_OFFSET + HASH_SIZE].copy_from_slice(
+            hash.as_slice()
+        ))
+    }
+
+    pub fn get_status(account: &AccountInfo) -> Result<WithdrawStatus, ProgramError> {
+        WithdrawStatus::decode_from_slice(&account.data.borrow()[WITHDRAW_STATUS_OFFSET..WITHDRAW_STATUS_OFFSET + WITHDRAW_STATUS_SIZE])
+            .map_err(|_| ProgramError::InvalidAccountData)
+    }
+
+    pub fn set_status(account: &AccountInfo, status: WithdrawStatus) -> Result<(), ProgramError> {
+        let mut data = account.data.try_borrow_mut().map_err(|_| ProgramError::InvalidAccountData)?;
+        Ok(data[WITHDRAW_STATUS_OFFSET..WITHDRAW_STATUS_OFFSET + WITHDRAW_STATUS_SIZE].copy_from_slice(
+            status.encode_to_vec().unwrap().as_slice()
+        ))
+    }
+
+    /// A prepared batch can still be submitted or rolled back.
+    pub fn is_prepared(account: &AccountInfo) -> Result<bool, ProgramError> {
+        Ok(Self::get_status(account)? == WithdrawStatus::Prepared)
+    }
+
+    /// A submitted batch, bumped or not, waits for its transaction to confirm.
+    pub fn is_submitted(account: &AccountInfo) -> Result<bool, ProgramError> {
+        Ok(matches!(Self::get_status(account)?, WithdrawStatus::Submitted | WithdrawStatus::Replaced))
+    }
+
+    /// A batch is in progress until it is rolled back or its transaction confirms, the next one
+    /// can only be prepared after that.
+    pub fn is_in_progress(account: &AccountInfo) -> Result<bool, ProgramError> {
+        Ok(Self::is_prepared(account)? || Self::is_submitted(account)?)
+    }
+
+    pub fn get_tx_id(account: &AccountInfo) -> Result<Hash, ProgramError> {
+        hash_from_slice(account, WITHDRAW_TX_ID_OFFSET)
+    }
+
+    pub fn set_tx_id(account: &AccountInfo, tx_id: Hash) -> Result<(), ProgramError> {
+        let mut data = account.data.try_borrow_mut().map_err(|_| ProgramError::InvalidAccountData)?;
+        Ok(data[WITHDRAW_TX_ID_OFFSET..WITHDRAW_TX_ID_OFFSET + HASH_SIZE].copy_from_slice(
+            tx_id.as_slice()
+        ))
+    }
+
+    /// The bitcoin block height when the current transaction was sent.
+    pub fn get_submitted_height(account: &AccountInfo) -> Result<u64, ProgramError> {
+        get_u64(account, WITHDRAW_SUBMITTED_HEIGHT_OFFSET)
+    }
+
+    pub fn set_submitted_height(account: &AccountInfo, block_height: u64) -> Result<(), ProgramError> {
+        set_u64(account, WITHDRAW_SUBMITTED_HEIGHT_OFFSET, block_height)
+    }
+
+    pub fn get_replaced_tx_ids(account: &AccountInfo) -> Result<Vec<Hash>, ProgramError> {
+        let num_replaced = Self::get_num_replaced(account)?;
+        (0..num_replaced)
+            .map(|index| hash_from_slice(account, WITHDRAW_REPLACED_TX_IDS_OFFSET + index * HASH_SIZE))
+            .collect()
+    }
+
+    /// Records `tx_id` as replaced by a fee bump, it stays a candidate to confirm.
+    pub fn add_replaced_tx_id(account: &AccountInfo, tx_id: Hash) -> Result<(), ProgramError> {
+        let num_replaced = Self::get_num_replaced(account)?;
+        let offset = WITHDRAW_REPLACED_TX_IDS_OFFSET + num_replaced * HASH_SIZE;
+        account.realloc(offset + HASH_SIZE, true)?;
+        {
+            let mut data = account.data.try_borrow_mut().map_err(|_| ProgramError::InvalidAccountData)?;
+            data[offset..offset + HASH_SIZE].copy_from_slice(tx_id.as_slice());
+        }
+        Self::set_num_replaced(account, num_replaced + 1)
+    }
+
+    pub fn clear_replaced_tx_ids(account: &AccountInfo) -> Result<(), ProgramError> {
+        Self::set_num_replaced(account, 0)?;
+        account.realloc(WITHDRAW_ACCOUNT_SIZE, true)
+    }
+
+    fn get_num_replaced(account: &AccountInfo) -> Result<usize, ProgramError> {
+        Ok(u32::from_le_bytes(
+            account.data.borrow()[WITHDRAW_REPLACED_COUNT_OFFSET..WITHDRAW_REPLACED_COUNT_OFFSET + WITHDRAW_REPLACED_COUNT_SIZE]
+                .try_into()
+                .map_err(|_| ProgramError::InvalidAccountData)?
+        ) as usize)
+    }
+
+    fn set_num_replaced(account: &AccountInfo, num_replaced: usize) -> Result<(), ProgramError> {
+        let mut data = account.data.try_borrow_mut().map_err(|_| ProgramError::InvalidAccountData)?;
+        Ok(data[WITHDRAW_REPLACED_COUNT_OFFSET..WITHDRAW_REPLACED_COUNT_OFFSET + WITHDRAW_REPLACED_COUNT_SIZE].copy_from_slice(
+            (num_replaced as u32).to_le_bytes().as_slice()
+        ))
+    }
+}
+
+pub const RUNE_RECEIVER_ACCOUNT_SIZE: usize = PROGRAM_PUBKEY_OFFSET + PUBKEY_SIZE;
+impl RuneReceiverState {
+
+    pub fn initialize(accounts: &[AccountInfo], account_index: usize) -> Result<(), ProgramError> {
+        if accounts[1].data_is_empty() {
+            accounts[1].realloc(RUNE_RECEIVER_ACCOUNT_SIZE, true)?;
+            set_type(&accounts[account_index], AccountType::RuneReceiver)?;
+            set_version(&accounts[account_index], RUNE_RECEIVER_STATE_VERSION)?;
+            Self::set_program_account(&accounts[account_index], accounts[0].key)?;
+            if ProgramState::get_rune_receiver_key(&accounts[0])?.is_none() {
+                if accounts[0].data_len() < RUNE_RECEIVER_OFFSET + PUBKEY_SIZE {
+                    accounts[0].realloc(RUNE_RECEIVER_OFFSET + PUBKEY_SIZE, true)?;
+                }
+                ProgramState::set_rune_receiver(&accounts[0], accounts[1].key)
+            } else {
+                Ok(())
+            }
+        } else {
+            Ok(())
+        }
+    }
+
+    pub fn get_program_state_account_key(account: &AccountInfo) -> Result<Pubkey, ProgramError> {
+        Ok(Pubkey::from_slice(account.data.borrow()[PROGRAM_PUBKEY_OFFSET..PROGRAM_PUBKEY_OFFSET + PUBKEY_SIZE]
+            .try_into().map_err(|_| ProgramError::InvalidAccountData)?))
+    }
+
+    fn set_program_account(account: &AccountInfo, pubkey: &Pubkey) -> Result<(), ProgramError> {
+        let mut data = account.data.try_borrow_mut().map_err(|_| ProgramError::InvalidAccountData)?;
+        Ok(data[PROGRAM_PUBKEY_OFFSET..PROGRAM_PUBKEY_OFFSET + PUBKEY_SIZE].copy_from_slice(
+            pubkey.0.as_slice()
+        ))
+    }
+}
+
+pub const NEXT_DEPOSIT_LEDGER_OFFSET: usize = PROGRAM_PUBKEY_OFFSET + PUBKEY_SIZE;
+pub const DEPOSIT_COUNT_SIZE: usize = 4;
+pub const DEPOSIT_COUNT_OFFSET: usize = NEXT_DEPOSIT_LEDGER_OFFSET + PUBKEY_SIZE;
+pub const DEPOSITS_OFFSET: usize = DEPOSIT_COUNT_OFFSET + DEPOSIT_COUNT_SIZE;
+pub const DEPOSIT_OUTPOINT_SIZE: usize = HASH_SIZE + 4;
+// keeps the memmove on a sorted insert cheap, a new ledger account is chained once full
+pub const MAX_LEDGER_DEPOSITS: usize = 16384;
+// once the last ledger has this few free slots left every deposit batch emits a
+// DepositLedgerLow event, the operator then chains a new ledger with InitDepositLedger
+// before deposits start failing with ERROR_DEPOSIT_LEDGER_FULL
+pub const LEDGER_LOW_WATERMARK: usize = 1024;
+
+impl DepositLedgerState {
+
+    pub fn initialize(account: &AccountInfo, program_state_account: &Pubkey) -> Result<(), ProgramError> {
+        account.realloc(DEPOSITS_OFFSET, true)?;
+        set_type(account, AccountType::DepositLedger)?;
+        set_version(account, DEPOSIT_LEDGER_STATE_VERSION)?;
+        Self::set_program_account(account, program_state_account)
+    }
+
+    pub fn get_program_state_account_key(account: &AccountInfo) -> Result<Pubkey, ProgramError> {
+        Ok(Pubkey::from_slice(account.data.borrow()[PROGRAM_PUBKEY_OFFSET..PROGRAM_PUBKEY_OFFSET + PUBKEY_SIZE]
+            .try_into().map_err(|_| ProgramError::InvalidAccountData)?))
+    }
+
+    fn set_program_account(account: &AccountInfo, pubkey: &Pubkey) -> Result<(), ProgramError> {
+        let mut data = account.data.try_borrow_mut().map_err(|_| ProgramError::InvalidAccountData)?;
+        Ok(data[PROGRAM_PUBKEY_OFFSET..PROGRAM_PUBKEY_OFFSET + PUBKEY_SIZE].copy_from_slice(
+            pubkey.0.as_slice()
+        ))
+    }
+
+    pub fn get_next_ledger_key(account: &AccountInfo) -> Result<Option<Pubkey>, ProgramError> {
+        get_optional_pubkey(account, NEXT_DEPOSIT_LEDGER_OFFSET)
+    }
+
+    pub fn set_next_ledger_key(account: &AccountInfo, pubkey: &Pubkey) -> Result<(), ProgramError> {
+        let mut data = account.data.try_borrow_mut().map_err(|_| ProgramError::InvalidAccountData)?;
+        Ok(data[NEXT_DEPOSIT_LEDGER_OFFSET..NEXT_DEPOSIT_LEDGER_OFFSET + PUBKEY_SIZE].copy_from_slice(
+            pubkey.0.as_slice()
+        ))
+    }
+
+    pub fn get_num_deposits(account: &AccountInfo) -> Result<usize, ProgramError> {
+        Ok(u32::from_le_bytes(
+            account.data.borrow()[DEPOSIT_COUNT_OFFSET..DEPOSIT_COUNT_OFFSET + DEPOSIT_COUNT_SIZE]
+                .try_into()
+                .map_err(|_| ProgramError::InvalidAccountData)?
+        ) as usize)
+    }
+
+    fn set_num_deposits(account: &AccountInfo, num_deposits: usize) -> Result<(), ProgramError> {
+        let mut data = account.data.try_borrow_mut().map_err(|_| ProgramError::InvalidAccountData)?;
+        Ok(data[DEPOSIT_COUNT_OFFSET..DEPOSIT_COUNT_OFFSET + DEPOSIT_COUNT_SIZE].copy_from_slice(
+            (num_deposits as u32).to_le_bytes().as_slice()
+        ))
+    }
+
+    pub fn is_full(account: &AccountInfo) -> Result<bool, ProgramError> {
+        Ok(Self::get_num_deposits(account)? >= MAX_LEDGER_DEPOSITS)
+    }
+
+    pub fn get_remaining_deposits(account: &AccountInfo) -> Result<usize, ProgramError> {
+        Ok(MAX_LEDGER_DEPOSITS.saturating_sub(Self::get_num_deposits(account)?))
+    }
+
+    /// Deposits are kept sorted by (txid, vout). Returns the position of the outpoint if it
+    /// has been recorded, otherwise the position it has to be inserted at.
+    pub fn find_deposit(account: &AccountInfo, outpoint: &FundingOutpoint) -> Result<Result<usize, usize>, ProgramError> {
+        let num_deposits = Self::get_num_deposits(account)?;
+        let data = account.data.borrow();
+        let (mut low, mut high) = (0, num_deposits);
+        while low < high {
+            let mid = low + (high - low) / 2;
+            let offset = DEPOSITS_OFFSET + mid * DEPOSIT_OUTPOINT_SIZE;
+            let vout = u32::from_le_bytes(
+                data[offset + HASH_SIZE..offset + DEPOSIT_OUTPOINT_SIZE]
+                    .try_into()
+                    .map_err(|_| ProgramError::InvalidAccountData)?
+            );
+            match (&data[offset..offset + HASH_SIZE], vout).cmp(&(outpoint.tx_id.as_slice(), outpoint.vout)) {
+                std::cmp::Ordering::Less => low = mid + 1,
+                std::cmp::Ordering::Greater => high = mid,
+                std::cmp::Ordering::Equal => return Ok(Ok(mid)),
+            }
+        }
+        Ok(Err(low))
+    }
+
+    pub fn insert_deposit(account: &AccountInfo, position: usize, outpoint: &FundingOutpoint) -> Result<(), ProgramError> {
+        let num_deposits = Self::get_num_deposits(account)?;
+        if num_deposits >= MAX_LEDGER_DEPOSITS {
+            return Err(ProgramError::Custom(ERROR_DEPOSIT_LEDGER_FULL));
+        }
+        let required_len = DEPOSITS_OFFSET + (num_deposits + 1) * DEPOSIT_OUTPOINT_SIZE;
+        if required_len > account.data_len() {
+            let original_data_len = unsafe { account.original_data_len() };
+            account.realloc(original_data_len + entrypoint::MAX_PERMITTED_DATA_INCREASE, true)?;
+            if required_len > account.data_len() {
+                return Err(ProgramError::InvalidRealloc);
+            }
+        }
+        {
+            let mut data = account.data.try_borrow_mut().map_err(|_| ProgramError::InvalidAccountData)?;
+            let offset = DEPOSITS_OFFSET + position * DEPOSIT_OUTPOINT_SIZE;
+            data.copy_within(
+                offset..DEPOSITS_OFFSET + num_deposits * DEPOSIT_OUTPOINT_SIZE,
+                offset + DEPOSIT_OUTPOINT_SIZE,
+            );
+            data[offset..offset + HASH_SIZE].copy_from_slice(outpoint.tx_id.as_slice());
+            data[offset + HASH_SIZE..offset + DEPOSIT_OUTPOINT_SIZE].copy_from_slice(outpoint.vout.to_le_bytes().as_slice());
+        }
+        Self::set_num_deposits(account, num_deposits + 1)
+    }
+}
+
+pub const NEXT_EVENT_LOG_OFFSET: usize = PROGRAM_PUBKEY_OFFSET + PUBKEY_SIZE;
+pub const EVENT_LOG_COUNT_SIZE: usize = 4;
+pub const EVENT_LOG_COUNT_OFFSET: usize = NEXT_EVENT_LOG_OFFSET + PUBKEY_SIZE;
+pub const EVENT_LOG_DATA_SIZE_SIZE: usize = 4;
+pub const EVENT_LOG_DATA_SIZE_OFFSET: usize = EVENT_LOG_COUNT_OFFSET + EVENT_LOG_COUNT_SIZE;
+pub const EVENT_LOG_ENTRIES_OFFSET: usize = EVENT_LOG_DATA_SIZE_OFFSET + EVENT_LOG_DATA_SIZE_SIZE;
+// every entry is the encoded event prefixed with its length
+pub const EVENT_LENGTH_SIZE: usize = 2;
+
+impl EventLogState {
+
+    pub fn initialize(account: &AccountInfo, program_state_account: &Pubkey) -> Result<(), ProgramError> {
+        account.realloc(EVENT_LOG_ENTRIES_OFFSET, true)?;
+        set_type(account, AccountType::EventLog)?;
+        set_version(account, EVENT_LOG_STATE_VERSION)?;
+        Self::set_program_account(account, program_state_account)
+    }
+
+    pub fn get_program_state_account_key(account: &AccountInfo) -> Result<Pubkey, ProgramError> {
+        Ok(Pubkey::from_slice(account.data.borrow()[PROGRAM_PUBKEY_OFFSET..PROGRAM_PUBKEY_OFFSET + PUBKEY_SIZE]
+            .try_into().map_err(|_| ProgramError::InvalidAccountData)?))
+    }
+
+    fn set_program_account(account: &AccountInfo, pubkey: &Pubkey) -> Result<(), ProgramError> {
+        let mut data = account.data.try_borrow_mut().map_err(|_| ProgramError::InvalidAccountData)?;
+        Ok(data[PROGRAM_PUBKEY_OFFSET..PROGRAM_PUBKEY_OFFSET + PUBKEY_SIZE].copy_from_slice(
+            pubkey.0.as_slice()
+        ))
+    }
+
+    pub fn get_next_event_log_key(account: &AccountInfo) -> Result<Option<Pubkey>, ProgramError> {
+        get_optional_pubkey(account, NEXT_EVENT_LOG_OFFSET)
+    }
+
+    pub fn set_next_event_log_key(account: &AccountInfo, pubkey: &Pubkey) -> Result<(), ProgramError> {
+        let mut data = account.data.try_borrow_mut().map_err(|_| ProgramError::InvalidAccountData)?;
+        Ok(data[NEXT_EVENT_LOG_OFFSET..NEXT_EVENT_LOG_OFFSET + PUBKEY_SIZE].copy_from_slice(
+            pubkey.0.as_slice()
+        ))
+    }
+
+    pub fn get_num_events(account: &AccountInfo) -> Result<usize, ProgramError> {
+        Ok(u32::from_le_bytes(
+            account.data.borrow()[EVENT_LOG_COUNT_OFFSET..EVENT_LOG_COUNT_OFFSET + EVENT_LOG_COUNT_SIZE]
+                .try_into()
+                .map_err(|_| ProgramError::InvalidAccountData)?
+        ) as usize)
+    }
+
+    pub fn get_data_size(account: &AccountInfo) -> Result<usize, ProgramError> {
+        Ok(u32::from_le_bytes(
+            account.data.borrow()[EVENT_LOG_DATA_SIZE_OFFSET..EVENT_LOG_DATA_SIZE_OFFSET + EVENT_LOG_DATA_SIZE_SIZE]
+                .try_into()
+                .map_err(|_| ProgramError::InvalidAccountData)?
+        ) as usize)
+    }
+
+    /// Returns false if the event does not fit, an account can only grow by
+    /// `MAX_PERMITTED_DATA_INCREASE` per instruction.
+    pub fn append_event(account: &AccountInfo, event_data: &[u8]) -> Result<bool, ProgramError> {
+        if event_data.len() > u16::MAX as usize {
+            return Err(ProgramError::Custom(ERROR_VALUE_TOO_LARGE));
+        }
+        let num_events = Self::get_num_events(account)?;
+        let data_size = Self::get_data_size(account)?;
+        let offset = EVENT_LOG_ENTRIES_OFFSET + data_size;
+        let required_len = offset + EVENT_LENGTH_SIZE + event_data.len();
+        if required_len > account.data_len() {
+            let original_data_len = unsafe { account.original_data_len() };
+            let max_len = (original_data_len + entrypoint::MAX_PERMITTED_DATA_INCREASE)
+                .min(entrypoint::MAX_PERMITTED_DATA_LENGTH);
+            if required_len > max_len {
+                return Ok(false);
+            }
+            account.realloc(max_len, true)?;
+        }
+        let mut data = account.data.try_borrow_mut().map_err(|_| ProgramError::InvalidAccountData)?;
+        data[offset..offset + EVENT_LENGTH_SIZE].copy_from_slice((event_data.len() as u16).to_le_bytes().as_slice());
+        data[offset + EVENT_LENGTH_SIZE..required_len].copy_from_slice(event_data);
+        data[EVENT_LOG_COUNT_OFFSET..EVENT_LOG_COUNT_OFFSET + EVENT_LOG_COUNT_SIZE].copy_from_slice(
+            ((num_events + 1) as u32).to_le_bytes().as_slice()
+        );
+        data[EVENT_LOG_DATA_SIZE_OFFSET..EVENT_LOG_DATA_SIZE_OFFSET + EVENT_LOG_DATA_SIZE_SIZE].copy_from_slice(
+            ((required_len - EVENT_LOG_ENTRIES_OFFSET) as u32).to_le_bytes().as_slice()
+        );
+        Ok(true)
+    }
+
+    /// Returns up to `limit` events starting at `cursor`, which has to point into this log, and
+    /// the cursor to continue from. Once this log is exhausted the cursor moves on to the next
+    /// log in the chain, if there is one.
+    pub fn read_events(&self, cursor: &EventCursor, limit: usize) -> (Vec<Event>, EventCursor) {
+        let events: Vec<Event> = self.events.iter().skip(cursor.index).take(limit).cloned().collect();
+        let index = cursor.index + events.len();
+        let next_cursor = if index >= self.events.len() && self.next_event_log_account != Pubkey::from([0u8; 32]) {
+            EventCursor {
+                event_log_account: self.next_event_log_account,
+                index: 0,
+            }
+        } else {
+            EventCursor {
+                event_log_account: cursor.event_log_account,
+                index,
+            }
+        };
+        (events, next_cursor)
+    }
+}
+
+impl BalanceShardState {
+
+    pub fn initialize(account: &AccountInfo, token_state_account: &Pubkey, shard: u8) -> Result<(), ProgramError> {
+        account.realloc(SHARD_BALANCES_OFFSET, true)?;
+        set_type(account, AccountType::BalanceShard)?;
+        set_version(account, BALANCE_SHARD_STATE_VERSION)?;
+        let mut data = account.data.try_borrow_mut().map_err(|_| ProgramError::InvalidAccountData)?;
+        data[PROGRAM_PUBKEY_OFFSET..PROGRAM_PUBKEY_OFFSET + PUBKEY_SIZE].copy_from_slice(
+            token_state_account.0.as_slice()
+        );
+        data[SHARD_NUMBER_OFFSET] = shard;
+        Ok(())
+    }
+
+    pub fn get_token_state_account_key(account: &AccountInfo) -> Result<Pubkey, ProgramError> {
+        Ok(Pubkey::from_slice(account.data.borrow()[PROGRAM_PUBKEY_OFFSET..PROGRAM_PUBKEY_OFFSET + PUBKEY_SIZE]
+            .try_into().map_err(|_| ProgramError::InvalidAccountData)?))
+    }
+
+    pub fn get_shard(account: &AccountInfo) -> Result<u8, ProgramError> {
+        Ok(account.data.borrow()[SHARD_NUMBER_OFFSET])
+    }
+
+    pub fn get_next_balance_shard_key(account: &AccountInfo) -> Result<Option<Pubkey>, ProgramError> {
+        get_optional_pubkey(account, NEXT_BALANCE_SHARD_OFFSET)
+    }
+
+    pub fn set_next_balance_shard_key(account: &AccountInfo, pubkey: &Pubkey) -> Result<(), ProgramError> {
+        let mut data = account.data.try_borrow_mut().map_err(|_| ProgramError::InvalidAccountData)?;
+        Ok(data[NEXT_BALANCE_SHARD_OFFSET..NEXT_BALANCE_SHARD_OFFSET + PUBKEY_SIZE].copy_from_slice(
+            pubkey.0.as_slice()
+        ))
+    }
+}
+
+// balance shards keep their balances like the token state does, behind a shorter header
+fn balance_count_offset(account: &AccountInfo) -> Result<usize, ProgramError> {
+    if get_type(account)? == AccountType::BalanceShard {
+        Ok(SHARD_BALANCE_COUNT_OFFSET)
+    } else {
+        Ok(BALANCE_COUNT_OFFSET)
+    }
+}
+
+pub(crate) fn balances_offset(account: &AccountInfo) -> Result<usize, ProgramError> {
+    Ok(balance_count_offset(account)? + BALANCE_COUNT_SIZE)
+}
+
+pub const NUM_INDEXED_BALANCES_SIZE: usize = 4;
+pub const NUM_INDEXED_BALANCES_OFFSET: usize = PROGRAM_PUBKEY_OFFSET + PUBKEY_SIZE;
+pub const WALLET_INDEX_COUNT_SIZE: usize = 4;
+pub const WALLET_INDEX_COUNT_OFFSET: usize = NUM_INDEXED_BALANCES_OFFSET + NUM_INDEXED_BALANCES_SIZE;
+pub const WALLET_INDEX_ENTRIES_OFFSET: usize = WALLET_INDEX_COUNT_OFFSET + WALLET_INDEX_COUNT_SIZE;
+pub const BALANCE_INDEX_SIZE: usize = 4;
+pub const WALLET_INDEX_ENTRY_SIZE: usize = HASH_SIZE + BALANCE_INDEX_SIZE;
+
+impl WalletIndexState {
+
+    pub fn initialize(account: &AccountInfo, token_state_account: &Pubkey) -> Result<(), ProgramError> {
+        account.realloc(WALLET_INDEX_ENTRIES_OFFSET, true)?;
+        set_type(account, AccountType::WalletIndex)?;
+        set_version(account, WALLET_INDEX_STATE_VERSION)?;
+        let mut data = account.data.try_borrow_mut().map_err(|_| ProgramError::InvalidAccountData)?;
+        Ok(data[PROGRAM_PUBKEY_OFFSET..PROGRAM_PUBKEY_OFFSET + PUBKEY_SIZE].copy_from_slice(
+            token_state_account.0.as_slice()
+        ))
+    }
+
+    // the index sits where the other accounts keep the program state key
+    pub fn get_token_state_account_key(account: &AccountInfo) -> Result<Pubkey, ProgramError> {
+        Ok(Pubkey::from_slice(account.data.borrow()[PROGRAM_PUBKEY_OFFSET..PROGRAM_PUBKEY_OFFSET + PUBKEY_SIZE]
+            .try_into().map_err(|_| ProgramError::InvalidAccountData)?))
+    }
+
+    pub fn get_num_indexed_balances(account: &AccountInfo) -> Result<usize, ProgramError> {
+        Ok(u32::from_le_bytes(
+            account.data.borrow()[NUM_INDEXED_BALANCES_OFFSET..NUM_INDEXED_BALANCES_OFFSET + NUM_INDEXED_BALANCES_SIZE]
+                .try_into()
+                .map_err(|_| ProgramError::InvalidAccountData)?
+        ) as usize)
+    }
+
+    pub fn set_num_indexed_balances(account: &AccountInfo, num_indexed_balances: usize) -> Result<(), ProgramError> {
+        let mut data = account.data.try_borrow_mut().map_err(|_| ProgramError::InvalidAccountData)?;
+        Ok(data[NUM_INDEXED_BALANCES_OFFSET..NUM_INDEXED_BALANCES_OFFSET + NUM_INDEXED_BALANCES_SIZE].copy_from_slice(
+            (num_indexed_balances as u32).to_le_bytes().as_slice()
+        ))
+    }
+
+    pub fn get_num_entries(account: &AccountInfo) -> Result<usize, ProgramError> {
+        Ok(u32::from_le_bytes(
+            account.data.borrow()[WALLET_INDEX_COUNT_OFFSET..WALLET_INDEX_COUNT_OFFSET + WALLET_INDEX_COUNT_SIZE]
+                .try_into()
+                .map_err(|_| ProgramError::InvalidAccountData)?
+        ) as usize)
+    }
+
+    fn set_num_entries(account: &AccountInfo, num_entries: usize) -> Result<(), ProgramError> {
+        let mut data = account.data.try_borrow_mut().map_err(|_| ProgramError::InvalidAccountData)?;
+        Ok(data[WALLET_INDEX_COUNT_OFFSET..WALLET_INDEX_COUNT_OFFSET + WALLET_INDEX_COUNT_SIZE].copy_from_slice(
+            (num_entries as u32).to_le_bytes().as_slice()
+        ))
+    }
+
+    /// Returns the position of the entry for `address_hash` if there is one, otherwise the
+    /// position it has to be inserted at.
+    pub fn find(account: &AccountInfo, address_hash: &Hash) -> Result<Result<usize, usize>, ProgramError> {
+        let num_entries = Self::get_num_entries(account)?;
+        let data = account.data.borrow();
+        let (mut low, mut high) = (0, num_entries);
+        while low < high {
+            let mid = low + (high - low) / 2;
+            let offset = WALLET_INDEX_ENTRIES_OFFSET + mid * WALLET_INDEX_ENTRY_SIZE;
+            match data[offset..offset + HASH_SIZE].cmp(address_hash.as_slice()) {
+                std::cmp::Ordering::Less => low = mid + 1,
+                std::cmp::Ordering::Greater => high = mid,
+                std::cmp::Ordering::Equal => return Ok(Ok(mid)),
+            }
+        }
+        Ok(Err(low))
+    }
+
+    pub fn get_balance_index(account: &AccountInfo, position: usize) -> Result<usize, ProgramError> {
+        let offset = WALLET_INDEX_ENTRIES_OFFSET + position * WALLET_INDEX_ENTRY_SIZE + HASH_SIZE;
+        Ok(u32::from_le_bytes(
+            account.data.borrow()[offset..offset + BALANCE_INDEX_SIZE]
+                .try_into()
+                .map_err(|_| ProgramError::InvalidAccountData)?
+        ) as usize)
+    }
+
+    /// Inserts an entry at `position`, as returned by `find`. Returns false if the account
+    /// cannot grow any further in this instruction.
+    pub fn insert(account: &AccountInfo, position: usize, address_hash: &Hash, balance_index: usize) -> Result<bool, ProgramError> {
+        let num_entries = Self::get_num_entries(account)?;
+        let required_len = WALLET_INDEX_ENTRIES_OFFSET + (num_entries + 1) * WALLET_INDEX_ENTRY_SIZE;
+        if required_len > account.data_len() {
+            let original_data_len = unsafe { account.original_data_len() };
+            let max_len = (original_data_len + entrypoint::MAX_PERMITTED_DATA_INCREASE)
+                .min(entrypoint::MAX_PERMITTED_DATA_LENGTH);
+            if required_len > max_len {
+                return Ok(false);
+            }
+            account.realloc(max_len, true)?;
+        }
+        {
+            let mut data = account.data.try_borrow_mut().map_err(|_| ProgramError::InvalidAccountData)?;
+            let offset = WALLET_INDEX_ENTRIES_OFFSET + position * WALLET_INDEX_ENTRY_SIZE;
+            data.copy_within(
+                offset..WALLET_INDEX_ENTRIES_OFFSET + num_entries * WALLET_INDEX_ENTRY_SIZE,
+                offset + WALLET_INDEX_ENTRY_SIZE,
+            );
+            data[offset..offset + HASH_SIZE].copy_from_slice(address_hash.as_slice());
+            data[offset + HASH_SIZE..offset + WALLET_INDEX_ENTRY_SIZE].copy_from_slice(
+                (balance_index as u32).to_le_bytes().as_slice()
+            );
+        }
+        Self::set_num_entries(account, num_entries + 1)?;
+        Ok(true)
+    }
+
+    pub fn set_balance_index(account: &AccountInfo, position: usize, balance_index: usize) -> Result<(), ProgramError> {
+        let offset = WALLET_INDEX_ENTRIES_OFFSET + position * WALLET_INDEX_ENTRY_SIZE + HASH_SIZE;
+        let mut data = account.data.try_borrow_mut().map_err(|_| ProgramError::InvalidAccountData)?;
+        Ok(data[offset..offset + BALANCE_INDEX_SIZE].copy_from_slice(
+            (balance_index as u32).to_le_bytes().as_slice()
+        ))
+    }
+
+    pub fn remove(account: &AccountInfo, position: usize) -> Result<(), ProgramError> {
+        let num_entries = Self::get_num_entries(account)?;
+        {
+            let mut data = account.data.try_borrow_mut().map_err(|_| ProgramError::InvalidAccountData)?;
+            let offset = WALLET_INDEX_ENTRIES_OFFSET + position * WALLET_INDEX_ENTRY_SIZE;
+            data.copy_within(
+                offset + WALLET_INDEX_ENTRY_SIZE..WALLET_INDEX_ENTRIES_OFFSET + num_entries * WALLET_INDEX_ENTRY_SIZE,
+                offset,
+            );
+        }
+        Self::set_num_entries(account, num_entries - 1)
+    }
+
+    /// Looks up the balance index of `address` in a decoded index.
+    pub fn find_balance_index(&self, address: &str) -> Option<u32> {
+        let address_hash = WalletAddress::from_address(address).ok()?.hash();
+        self.entries
+            .binary_search_by(|entry| entry.address_hash.cmp(&address_hash))
+            .ok()
+            .map(|position| self.entries[position].balance_index)
+    }
+}
+
+pub const WITHDRAWAL_LIMIT_MAX_AMOUNT_OFFSET: usize = PROGRAM_PUBKEY_OFFSET + PUBKEY_SIZE;
+pub const WITHDRAWAL_LIMIT_WINDOW_BLOCKS_OFFSET: usize = WITHDRAWAL_LIMIT_MAX_AMOUNT_OFFSET + BALANCE_AMOUNT_SIZE;
+pub const BLOCK_HEIGHT_SIZE: usize = 8;
+pub const WITHDRAWAL_LIMIT_WINDOW_START_OFFSET: usize = WITHDRAWAL_LIMIT_WINDOW_BLOCKS_OFFSET + BLOCK_HEIGHT_SIZE;
+pub const WITHDRAWAL_LIMIT_COUNT_SIZE: usize = 4;
+pub const WITHDRAWAL_LIMIT_COUNT_OFFSET: usize = WITHDRAWAL_LIMIT_WINDOW_START_OFFSET + BLOCK_HEIGHT_SIZE;
+pub const WITHDRAWAL_LIMIT_ENTRIES_OFFSET: usize = WITHDRAWAL_LIMIT_COUNT_OFFSET + WITHDRAWAL_LIMIT_COUNT_SIZE;
+pub const WITHDRAWAL_LIMIT_ENTRY_SIZE: usize = HASH_SIZE + BALANCE_AMOUNT_SIZE;
+
+impl WithdrawalLimitState {
+
+    pub fn initialize(account: &AccountInfo, token_state_account: &Pubkey, max_amount: u64, window_blocks: u64) -> Result<(), ProgramError> {
+        account.realloc(WITHDRAWAL_LIMIT_ENTRIES_OFFSET, true)?;
+        set_type(account, AccountType::WithdrawalLimit)?;
+        set_version(account, WITHDRAWAL_LIMIT_STATE_VERSION)?;
+        {
+            let mut data = account.data.try_borrow_mut().map_err(|_| ProgramError::InvalidAccountData)?;
+            data[PROGRAM_PUBKEY_OFFSET..PROGRAM_PUBKEY_OFFSET + PUBKEY_SIZE].copy_from_slice(
+                token_state_account.0.as_slice()
+            );
+        }
+        Self::set_limit(account, max_amount, window_blocks)
+    }
+
+    // like the wallet index, the limit keeps the token state key where other accounts keep the
+    // program state key
+    pub fn get_token_state_account_key(account: &AccountInfo) -> Result<Pubkey, ProgramError> {
+        Ok(Pubkey::from_slice(account.data.borrow()[PROGRAM_PUBKEY_OFFSET..PROGRAM_PUBKEY_OFFSET + PUBKEY_SIZE]
+            .try_into().map_err(|_| ProgramError::InvalidAccountData)?))
+    }
+
+    pub fn get_max_amount(account: &AccountInfo) -> Result<u64, ProgramError> {
+        get_u64(account, WITHDRAWAL_LIMIT_MAX_AMOUNT_OFFSET)
+    }
+
+    pub fn get_window_blocks(account: &AccountInfo) -> Result<u64, ProgramError> {
+        get_u64(account, WITHDRAWAL_LIMIT_WINDOW_BLOCKS_OFFSET)
+    }
+
+    // what was withdrawn so far only carries over when the windows stay the same
+    pub fn set_limit(account: &AccountInfo, max_amount: u64, window_blocks: u64) -> Result<(), ProgramError> {
+        if window_blocks == 0 {
+            return Err(ProgramError::Custom(ERROR_INVALID_WITHDRAWAL_LIMIT));
+        }
+        if Self::get_window_blocks(account)? != window_blocks {
+            Self::reset(account, 0)?;
+        }
+        set_u64(account, WITHDRAWAL_LIMIT_MAX_AMOUNT_OFFSET, max_amount)?;
+        set_u64(account, WITHDRAWAL_LIMIT_WINDOW_BLOCKS_OFFSET, window_blocks)
+    }
+
+    pub fn get_num_entries(account: &AccountInfo) -> Result<usize, ProgramError> {
+        Ok(u32::from_le_bytes(
+            account.data.borrow()[WITHDRAWAL_LIMIT_COUNT_OFFSET..WITHDRAWAL_LIMIT_COUNT_OFFSET + WITHDRAWAL_LIMIT_COUNT_SIZE]
+                .try_into()
+                .map_err(|_| ProgramError::InvalidAccountData)?
+        ) as usize)
+    }
+
+    fn set_num_entries(account: &AccountInfo, num_entries: usize) -> Result<(), ProgramError> {
         let mut data = account.data.try_borrow_mut().map_err(|_| ProgramError::InvalidAccountData)?;
-        Ok(data[PROGRAM_PUBKEY_OFFSET..PROGRAM_PUBKEY_OFFSET + PUBKEY_SIZE].copy_from_slice(
-            pubkey.0.as_slice()
+        Ok(data[WITHDRAWAL_LIMIT_COUNT_OFFSET..WITHDRAWAL_LIMIT_COUNT_OFFSET + WITHDRAWAL_LIMIT_COUNT_SIZE].copy_from_slice(
+            (num_entries as u32).to_le_bytes().as_slice()
         ))
     }
 
-    pub fn get_hash(account: &AccountInfo) -> Result<Hash, ProgramError> {
-        hash_from_slice(account, WITHDRAW_HASH_OFFSET)
+    fn window_start(account: &AccountInfo, block_height: u64) -> Result<u64, ProgramError> {
+        let window_blocks = Self::get_window_blocks(account)?;
+        if window_blocks == 0 {
+            return Err(ProgramError::InvalidAccountData);
+        }
+        Ok(block_height - block_height % window_blocks)
     }
 
-    pub fn clear_hash(account: &AccountInfo) -> Result<(), ProgramError> {
-        Self::set_hash(account, [0u8; HASH_SIZE])
+    // drops the entries of the previous window, the account keeps its size so the next window
+    // does not have to grow it again
+    fn reset(account: &AccountInfo, window_start: u64) -> Result<(), ProgramError> {
+        set_u64(account, WITHDRAWAL_LIMIT_WINDOW_START_OFFSET, window_start)?;
+        Self::set_num_entries(account, 0)
     }
 
-    pub fn set_hash(account: &AccountInfo, hash: Hash) -> Result<(), ProgramError> {
+    fn find(account: &AccountInfo, address_hash: &Hash) -> Result<Result<usize, usize>, ProgramError> {
+        let num_entries = Self::get_num_entries(account)?;
+        let data = account.data.borrow();
+        let (mut low, mut high) = (0, num_entries);
+        while low < high {
+            let mid = low + (high - low) / 2;
+            let offset = WITHDRAWAL_LIMIT_ENTRIES_OFFSET + mid * WITHDRAWAL_LIMIT_ENTRY_SIZE;
+            match data[offset..offset + HASH_SIZE].cmp(address_hash.as_slice()) {
+                std::cmp::Ordering::Less => low = mid + 1,
+                std::cmp::Ordering::Greater => high = mid,
+                std::cmp::Ordering::Equal => return Ok(Ok(mid)),
+            }
+        }
+        Ok(Err(low))
+    }
+
+    /// What the wallet with `address_hash` has withdrawn in the window containing `block_height`.
+    pub fn get_withdrawn(account: &AccountInfo, address_hash: &Hash, block_height: u64) -> Result<u64, ProgramError> {
+        if get_u64(account, WITHDRAWAL_LIMIT_WINDOW_START_OFFSET)? != Self::window_start(account, block_height)? {
+            return Ok(0);
+        }
+        match Self::find(account, address_hash)? {
+            Ok(position) => get_u64(account, WITHDRAWAL_LIMIT_ENTRIES_OFFSET + position * WITHDRAWAL_LIMIT_ENTRY_SIZE + HASH_SIZE),
+            Err(_) => Ok(0),
+        }
+    }
+
+    /// Adds `amount` to what the wallet with `address_hash` has withdrawn in the window containing
+    /// `block_height`, failing if that goes over the limit.
+    pub fn record_withdrawal(account: &AccountInfo, address_hash: &Hash, amount: u64, block_height: u64) -> Result<(), ProgramError> {
+        let window_start = Self::window_start(account, block_height)?;
+        if get_u64(account, WITHDRAWAL_LIMIT_WINDOW_START_OFFSET)? != window_start {
+            Self::reset(account, window_start)?;
+        }
+        let position = Self::find(account, address_hash)?;
+        let withdrawn = match position {
+            Ok(position) => get_u64(account, WITHDRAWAL_LIMIT_ENTRIES_OFFSET + position * WITHDRAWAL_LIMIT_ENTRY_SIZE + HASH_SIZE)?,
+            Err(_) => 0,
+        };
+        let withdrawn = checked_add_amount(withdrawn, amount)?;
+        if withdrawn > Self::get_max_amount(account)? {
+            return Err(ProgramError::Custom(ERROR_WITHDRAWAL_LIMIT_EXCEEDED));
+        }
+        let position = match position {
+            Ok(position) => position,
+            Err(position) => {
+                Self::insert(account, position, address_hash)?;
+                position
+            }
+        };
+        set_u64(account, WITHDRAWAL_LIMIT_ENTRIES_OFFSET + position * WITHDRAWAL_LIMIT_ENTRY_SIZE + HASH_SIZE, withdrawn)
+    }
+
+    fn insert(account: &AccountInfo, position: usize, address_hash: &Hash) -> Result<(), ProgramError> {
+        let num_entries = Self::get_num_entries(account)?;
+        let required_len = WITHDRAWAL_LIMIT_ENTRIES_OFFSET + (num_entries + 1) * WITHDRAWAL_LIMIT_ENTRY_SIZE;
+        if required_len > account.data_len() {
+            let original_data_len = unsafe { account.original_data_len() };
+            let max_len = (original_data_len + entrypoint::MAX_PERMITTED_DATA_INCREASE)
+                .min(entrypoint::MAX_PERMITTED_DATA_LENGTH);
+            if required_len > max_len {
+                return Err(ProgramError::Custom(ERROR_WITHDRAWAL_LIMIT_FULL));
+            }
+            account.realloc(max_len, true)?;
+        }
+        {
+            let mut data = account.data.try_borrow_mut().map_err(|_| ProgramError::InvalidAccountData)?;
+            let offset = WITHDRAWAL_LIMIT_ENTRIES_OFFSET + position * WITHDRAWAL_LIMIT_ENTRY_SIZE;
+            data.copy_within(
+                offset..WITHDRAWAL_LIMIT_ENTRIES_OFFSET + num_entries * WITHDRAWAL_LIMIT_ENTRY_SIZE,
+                offset + WITHDRAWAL_LIMIT_ENTRY_SIZE,
+            );
+            data[offset..offset + HASH_SIZE].copy_from_slice(address_hash.as_slice());
+        }
+        Self::set_num_entries(account, num_entries + 1)
+    }
+}
+
+pub const FORCED_WITHDRAWAL_DELAY_OFFSET: usize = PROGRAM_PUBKEY_OFFSET + PUBKEY_SIZE;
+pub const FORCED_WITHDRAWAL_COUNT_SIZE: usize = 4;
+pub const FORCED_WITHDRAWAL_COUNT_OFFSET: usize = FORCED_WITHDRAWAL_DELAY_OFFSET + BLOCK_HEIGHT_SIZE;
+pub const FORCED_WITHDRAWAL_ENTRIES_OFFSET: usize = FORCED_WITHDRAWAL_COUNT_OFFSET + FORCED_WITHDRAWAL_COUNT_SIZE;
+pub const FORCED_WITHDRAWAL_NONCE_SIZE: usize = 8;
+pub const FORCED_WITHDRAWAL_ENTRY_SIZE: usize = HASH_SIZE + BALANCE_AMOUNT_SIZE + FORCED_WITHDRAWAL_NONCE_SIZE + BLOCK_HEIGHT_SIZE + 1;
+
+impl ForcedWithdrawalState {
+
+    pub fn initialize(account: &AccountInfo, program_state_account: &Pubkey, delay_blocks: u64) -> Result<(), ProgramError> {
+        account.realloc(FORCED_WITHDRAWAL_ENTRIES_OFFSET, true)?;
+        set_type(account, AccountType::ForcedWithdrawals)?;
+        set_version(account, FORCED_WITHDRAWAL_STATE_VERSION)?;
+        {
+            let mut data = account.data.try_borrow_mut().map_err(|_| ProgramError::InvalidAccountData)?;
+            data[PROGRAM_PUBKEY_OFFSET..PROGRAM_PUBKEY_OFFSET + PUBKEY_SIZE].copy_from_slice(
+                program_state_account.0.as_slice()
+            );
+        }
+        set_u64(account, FORCED_WITHDRAWAL_DELAY_OFFSET, delay_blocks)
+    }
+
+    pub fn get_program_state_account_key(account: &AccountInfo) -> Result<Pubkey, ProgramError> {
+        Ok(Pubkey::from_slice(account.data.borrow()[PROGRAM_PUBKEY_OFFSET..PROGRAM_PUBKEY_OFFSET + PUBKEY_SIZE]
+            .try_into().map_err(|_| ProgramError::InvalidAccountData)?))
+    }
+
+    pub fn get_delay_blocks(account: &AccountInfo) -> Result<u64, ProgramError> {
+        get_u64(account, FORCED_WITHDRAWAL_DELAY_OFFSET)
+    }
+
+    pub fn get_num_entries(account: &AccountInfo) -> Result<usize, ProgramError> {
+        Ok(u32::from_le_bytes(
+            account.data.borrow()[FORCED_WITHDRAWAL_COUNT_OFFSET..FORCED_WITHDRAWAL_COUNT_OFFSET + FORCED_WITHDRAWAL_COUNT_SIZE]
+                .try_into()
+                .map_err(|_| ProgramError::InvalidAccountData)?
+        ) as usize)
+    }
+
+    fn set_num_entries(account: &AccountInfo, num_entries: usize) -> Result<(), ProgramError> {
         let mut data = account.data.try_borrow_mut().map_err(|_| ProgramError::InvalidAccountData)?;
-        Ok(data[WITHDRAW_HASH_OFFSET..WITHDRAW_HASH_OFFSET + HASH_SIZE].copy_from_slice(
-            hash.as_slice()
+        Ok(data[FORCED_WITHDRAWAL_COUNT_OFFSET..FORCED_WITHDRAWAL_COUNT_OFFSET + FORCED_WITHDRAWAL_COUNT_SIZE].copy_from_slice(
+            (num_entries as u32).to_le_bytes().as_slice()
         ))
     }
-}
 
-pub const RUNE_RECEIVER_ACCOUNT_SIZE: usize = PROGRAM_PUBKEY_OFFSET + PUBKEY_SIZE;
-impl RuneReceiverState {
+    /// Requests are kept per wallet and token, the key is the sha256 of the token state key
+    /// followed by the wallet address.
+    pub fn entry_key(token_state_account: &Pubkey, wallet_address: &WalletAddress) -> Hash {
+        let mut engine = sha256::Hash::engine();
+        engine.input(token_state_account.0.as_slice());
+        engine.input(wallet_address.0.as_slice());
+        sha256::Hash::from_engine(engine).to_byte_array()
+    }
 
-    pub fn initialize(accounts: &[AccountInfo], account_index: usize) -> Result<(), ProgramError> {
-        if accounts[1].data_is_empty() {
-            accounts[1].realloc(RUNE_RECEIVER_ACCOUNT_SIZE, true)?;
-            set_type(&accounts[account_index], AccountType::RuneReceiver)?;
-            Self::set_program_account(&accounts[account_index], accounts[0].key)?;
-            if accounts[0].data_len() == RUNE_RECEIVER_OFFSET {
-                accounts[0].realloc(RUNE_RECEIVER_OFFSET + PUBKEY_SIZE, true)?;
-                ProgramState::set_rune_receiver(&accounts[0], accounts[1].key)
-            } else {
-                Ok(())
+    fn find(account: &AccountInfo, key: &Hash) -> Result<Result<usize, usize>, ProgramError> {
+        let num_entries = Self::get_num_entries(account)?;
+        let data = account.data.borrow();
+        let (mut low, mut high) = (0, num_entries);
+        while low < high {
+            let mid = low + (high - low) / 2;
+            let offset = FORCED_WITHDRAWAL_ENTRIES_OFFSET + mid * FORCED_WITHDRAWAL_ENTRY_SIZE;
+            match data[offset..offset + HASH_SIZE].cmp(key.as_slice()) {
+                std::cmp::Ordering::Less => low = mid + 1,
+                std::cmp::Ordering::Greater => high = mid,
+                std::cmp::Ordering::Equal => return Ok(Ok(mid)),
             }
-        } else {
-            Ok(())
         }
+        Ok(Err(low))
+    }
+
+    pub fn get_entry(account: &AccountInfo, key: &Hash) -> Result<Option<ForcedWithdrawalEntry>, ProgramError> {
+        let Ok(position) = Self::find(account, key)? else {
+            return Ok(None);
+        };
+        let offset = FORCED_WITHDRAWAL_ENTRIES_OFFSET + position * FORCED_WITHDRAWAL_ENTRY_SIZE;
+        let amount_offset = offset + HASH_SIZE;
+        let nonce_offset = amount_offset + BALANCE_AMOUNT_SIZE;
+        let due_height_offset = nonce_offset + FORCED_WITHDRAWAL_NONCE_SIZE;
+        let pending_offset = due_height_offset + BLOCK_HEIGHT_SIZE;
+        Ok(Some(ForcedWithdrawalEntry {
+            key: *key,
+            amount: get_u64(account, amount_offset)?,
+            nonce: get_u64(account, nonce_offset)?,
+            due_height: get_u64(account, due_height_offset)?,
+            pending: account.data.borrow()[pending_offset] != 0,
+        }))
+    }
+
+    /// Stores `entry`, replacing the one with the same key if there is one.
+    pub fn set_entry(account: &AccountInfo, entry: &ForcedWithdrawalEntry) -> Result<(), ProgramError> {
+        let position = match Self::find(account, &entry.key)? {
+            Ok(position) => position,
+            Err(position) => {
+                Self::insert(account, position)?;
+                position
+            }
+        };
+        let mut data = account.data.try_borrow_mut().map_err(|_| ProgramError::InvalidAccountData)?;
+        let offset = FORCED_WITHDRAWAL_ENTRIES_OFFSET + position * FORCED_WITHDRAWAL_ENTRY_SIZE;
+        let mut entry_data = Vec::with_capacity(FORCED_WITHDRAWAL_ENTRY_SIZE);
+        entry_data.extend_from_slice(entry.key.as_slice());
+        entry_data.extend_from_slice(entry.amount.to_le_bytes().as_slice());
+        entry_data.extend_from_slice(entry.nonce.to_le_bytes().as_slice());
+        entry_data.extend_from_slice(entry.due_height.to_le_bytes().as_slice());
+        entry_data.push(entry.pending as u8);
+        Ok(data[offset..offset + FORCED_WITHDRAWAL_ENTRY_SIZE].copy_from_slice(entry_data.as_slice()))
+    }
+
+    /// Whether the wallet with entry `key` has a request the operator did not serve in time.
+    pub fn is_overdue(account: &AccountInfo, key: &Hash, block_height: u64) -> Result<bool, ProgramError> {
+        Ok(Self::get_entry(account, key)?
+            .is_some_and(|entry| entry.pending && block_height >= entry.due_height))
+    }
+
+    fn insert(account: &AccountInfo, position: usize) -> Result<(), ProgramError> {
+        let num_entries = Self::get_num_entries(account)?;
+        let required_len = FORCED_WITHDRAWAL_ENTRIES_OFFSET + (num_entries + 1) * FORCED_WITHDRAWAL_ENTRY_SIZE;
+        if required_len > account.data_len() {
+            let original_data_len = unsafe { account.original_data_len() };
+            let max_len = (original_data_len + entrypoint::MAX_PERMITTED_DATA_INCREASE)
+                .min(entrypoint::MAX_PERMITTED_DATA_LENGTH);
+            if required_len > max_len {
+                return Err(ProgramError::Custom(ERROR_FORCED_WITHDRAWALS_FULL));
+            }
+            account.realloc(max_len, true)?;
+        }
+        {
+            let mut data = account.data.try_borrow_mut().map_err(|_| ProgramError::InvalidAccountData)?;
+            let offset = FORCED_WITHDRAWAL_ENTRIES_OFFSET + position * FORCED_WITHDRAWAL_ENTRY_SIZE;
+            data.copy_within(
+                offset..FORCED_WITHDRAWAL_ENTRIES_OFFSET + num_entries * FORCED_WITHDRAWAL_ENTRY_SIZE,
+                offset + FORCED_WITHDRAWAL_ENTRY_SIZE,
+            );
+        }
+        Self::set_num_entries(account, num_entries + 1)
+    }
+}
+
+pub const WITHDRAWAL_NONCE_COUNT_SIZE: usize = 4;
+pub const WITHDRAWAL_NONCE_COUNT_OFFSET: usize = PROGRAM_PUBKEY_OFFSET + PUBKEY_SIZE;
+pub const WITHDRAWAL_NONCE_ENTRIES_OFFSET: usize = WITHDRAWAL_NONCE_COUNT_OFFSET + WITHDRAWAL_NONCE_COUNT_SIZE;
+pub const WITHDRAWAL_NONCE_SIZE: usize = 8;
+pub const WITHDRAWAL_NONCE_ENTRY_SIZE: usize = HASH_SIZE + WITHDRAWAL_NONCE_SIZE;
+
+impl WithdrawalNonceState {
+
+    pub fn initialize(account: &AccountInfo, program_state_account: &Pubkey) -> Result<(), ProgramError> {
+        account.realloc(WITHDRAWAL_NONCE_ENTRIES_OFFSET, true)?;
+        set_type(account, AccountType::WithdrawalNonces)?;
+        set_version(account, WITHDRAWAL_NONCE_STATE_VERSION)?;
+        let mut data = account.data.try_borrow_mut().map_err(|_| ProgramError::InvalidAccountData)?;
+        Ok(data[PROGRAM_PUBKEY_OFFSET..PROGRAM_PUBKEY_OFFSET + PUBKEY_SIZE].copy_from_slice(
+            program_state_account.0.as_slice()
+        ))
     }
 
     pub fn get_program_state_account_key(account: &AccountInfo) -> Result<Pubkey, ProgramError> {
@@ -438,12 +1978,281 @@ impl RuneReceiverState {
             .try_into().map_err(|_| ProgramError::InvalidAccountData)?))
     }
 
-    fn set_program_account(account: &AccountInfo, pubkey: &Pubkey) -> Result<(), ProgramError> {
+    pub fn get_num_entries(account: &AccountInfo) -> Result<usize, ProgramError> {
+        Ok(u32::from_le_bytes(
+            account.data.borrow()[WITHDRAWAL_NONCE_COUNT_OFFSET..WITHDRAWAL_NONCE_COUNT_OFFSET + WITHDRAWAL_NONCE_COUNT_SIZE]
+                .try_into()
+                .map_err(|_| ProgramError::InvalidAccountData)?
+        ) as usize)
+    }
+
+    fn set_num_entries(account: &AccountInfo, num_entries: usize) -> Result<(), ProgramError> {
+        let mut data = account.data.try_borrow_mut().map_err(|_| ProgramError::InvalidAccountData)?;
+        Ok(data[WITHDRAWAL_NONCE_COUNT_OFFSET..WITHDRAWAL_NONCE_COUNT_OFFSET + WITHDRAWAL_NONCE_COUNT_SIZE].copy_from_slice(
+            (num_entries as u32).to_le_bytes().as_slice()
+        ))
+    }
+
+    fn find(account: &AccountInfo, address_hash: &Hash) -> Result<Result<usize, usize>, ProgramError> {
+        let num_entries = Self::get_num_entries(account)?;
+        let data = account.data.borrow();
+        let (mut low, mut high) = (0, num_entries);
+        while low < high {
+            let mid = low + (high - low) / 2;
+            let offset = WITHDRAWAL_NONCE_ENTRIES_OFFSET + mid * WITHDRAWAL_NONCE_ENTRY_SIZE;
+            match data[offset..offset + HASH_SIZE].cmp(address_hash.as_slice()) {
+                std::cmp::Ordering::Less => low = mid + 1,
+                std::cmp::Ordering::Greater => high = mid,
+                std::cmp::Ordering::Equal => return Ok(Ok(mid)),
+            }
+        }
+        Ok(Err(low))
+    }
+
+    /// The last nonce the wallet with `address_hash` signed a withdrawal with, if it ever did.
+    pub fn get_last_nonce(account: &AccountInfo, address_hash: &Hash) -> Result<Option<u64>, ProgramError> {
+        match Self::find(account, address_hash)? {
+            Ok(position) => Ok(Some(get_u64(account, WITHDRAWAL_NONCE_ENTRIES_OFFSET + position * WITHDRAWAL_NONCE_ENTRY_SIZE + HASH_SIZE)?)),
+            Err(_) => Ok(None),
+        }
+    }
+
+    /// Records `nonce` as used by the wallet with `address_hash`, it has to be higher than the last
+    /// one.
+    pub fn use_nonce(account: &AccountInfo, address_hash: &Hash, nonce: u64) -> Result<(), ProgramError> {
+        let position = match Self::find(account, address_hash)? {
+            Ok(position) => {
+                let last_nonce = get_u64(account, WITHDRAWAL_NONCE_ENTRIES_OFFSET + position * WITHDRAWAL_NONCE_ENTRY_SIZE + HASH_SIZE)?;
+                if nonce <= last_nonce {
+                    return Err(ProgramError::Custom(ERROR_NONCE_USED));
+                }
+                position
+            }
+            Err(position) => {
+                Self::insert(account, position, address_hash)?;
+                position
+            }
+        };
+        set_u64(account, WITHDRAWAL_NONCE_ENTRIES_OFFSET + position * WITHDRAWAL_NONCE_ENTRY_SIZE + HASH_SIZE, nonce)
+    }
+
+    fn insert(account: &AccountInfo, position: usize, address_hash: &Hash) -> Result<(), ProgramError> {
+        let num_entries = Self::get_num_entries(account)?;
+        let required_len = WITHDRAWAL_NONCE_ENTRIES_OFFSET + (num_entries + 1) * WITHDRAWAL_NONCE_ENTRY_SIZE;
+        if required_len > account.data_len() {
+            let original_data_len = unsafe { account.original_data_len() };
+            let max_len = (original_data_len + entrypoint::MAX_PERMITTED_DATA_INCREASE)
+                .min(entrypoint::MAX_PERMITTED_DATA_LENGTH);
+            if required_len > max_len {
+                return Err(ProgramError::Custom(ERROR_WITHDRAWAL_NONCES_FULL));
+            }
+            account.realloc(max_len, true)?;
+        }
+        {
+            let mut data = account.data.try_borrow_mut().map_err(|_| ProgramError::InvalidAccountData)?;
+            let offset = WITHDRAWAL_NONCE_ENTRIES_OFFSET + position * WITHDRAWAL_NONCE_ENTRY_SIZE;
+            data.copy_within(
+                offset..WITHDRAWAL_NONCE_ENTRIES_OFFSET + num_entries * WITHDRAWAL_NONCE_ENTRY_SIZE,
+                offset + WITHDRAWAL_NONCE_ENTRY_SIZE,
+            );
+            data[offset..offset + HASH_SIZE].copy_from_slice(address_hash.as_slice());
+        }
+        Self::set_num_entries(account, num_entries + 1)
+    }
+}
+
+pub const UTXO_COUNT_SIZE: usize = 4;
+pub const UTXO_COUNT_OFFSET: usize = PROGRAM_PUBKEY_OFFSET + PUBKEY_SIZE;
+pub const UTXO_ENTRIES_OFFSET: usize = UTXO_COUNT_OFFSET + UTXO_COUNT_SIZE;
+pub const UTXO_OUTPOINT_SIZE: usize = HASH_SIZE + 4;
+pub const UTXO_ENTRY_SIZE: usize = UTXO_OUTPOINT_SIZE + 1;
+
+impl UtxoRegistryState {
+
+    pub fn initialize(account: &AccountInfo, program_state_account: &Pubkey) -> Result<(), ProgramError> {
+        account.realloc(UTXO_ENTRIES_OFFSET, true)?;
+        set_type(account, AccountType::UtxoRegistry)?;
+        set_version(account, UTXO_REGISTRY_STATE_VERSION)?;
         let mut data = account.data.try_borrow_mut().map_err(|_| ProgramError::InvalidAccountData)?;
         Ok(data[PROGRAM_PUBKEY_OFFSET..PROGRAM_PUBKEY_OFFSET + PUBKEY_SIZE].copy_from_slice(
-            pubkey.0.as_slice()
+            program_state_account.0.as_slice()
+        ))
+    }
+
+    pub fn get_program_state_account_key(account: &AccountInfo) -> Result<Pubkey, ProgramError> {
+        Ok(Pubkey::from_slice(account.data.borrow()[PROGRAM_PUBKEY_OFFSET..PROGRAM_PUBKEY_OFFSET + PUBKEY_SIZE]
+            .try_into().map_err(|_| ProgramError::InvalidAccountData)?))
+    }
+
+    pub fn get_num_entries(account: &AccountInfo) -> Result<usize, ProgramError> {
+        Ok(u32::from_le_bytes(
+            account.data.borrow()[UTXO_COUNT_OFFSET..UTXO_COUNT_OFFSET + UTXO_COUNT_SIZE]
+                .try_into()
+                .map_err(|_| ProgramError::InvalidAccountData)?
+        ) as usize)
+    }
+
+    fn set_num_entries(account: &AccountInfo, num_entries: usize) -> Result<(), ProgramError> {
+        let mut data = account.data.try_borrow_mut().map_err(|_| ProgramError::InvalidAccountData)?;
+        Ok(data[UTXO_COUNT_OFFSET..UTXO_COUNT_OFFSET + UTXO_COUNT_SIZE].copy_from_slice(
+            (num_entries as u32).to_le_bytes().as_slice()
         ))
     }
+
+    fn find(account: &AccountInfo, outpoint: &FundingOutpoint) -> Result<Result<usize, usize>, ProgramError> {
+        let num_entries = Self::get_num_entries(account)?;
+        let data = account.data.borrow();
+        let (mut low, mut high) = (0, num_entries);
+        while low < high {
+            let mid = low + (high - low) / 2;
+            let offset = UTXO_ENTRIES_OFFSET + mid * UTXO_ENTRY_SIZE;
+            let vout = u32::from_le_bytes(
+                data[offset + HASH_SIZE..offset + UTXO_OUTPOINT_SIZE]
+                    .try_into()
+                    .map_err(|_| ProgramError::InvalidAccountData)?
+            );
+            match (&data[offset..offset + HASH_SIZE], vout).cmp(&(outpoint.tx_id.as_slice(), outpoint.vout)) {
+                std::cmp::Ordering::Less => low = mid + 1,
+                std::cmp::Ordering::Greater => high = mid,
+                std::cmp::Ordering::Equal => return Ok(Ok(mid)),
+            }
+        }
+        Ok(Err(low))
+    }
+
+    fn set_locked(account: &AccountInfo, position: usize, locked: bool) -> Result<(), ProgramError> {
+        let mut data = account.data.try_borrow_mut().map_err(|_| ProgramError::InvalidAccountData)?;
+        data[UTXO_ENTRIES_OFFSET + position * UTXO_ENTRY_SIZE + UTXO_OUTPOINT_SIZE] = locked as u8;
+        Ok(())
+    }
+
+    /// Registers `outpoint` as spendable, registering it again leaves it as it is.
+    pub fn add_utxo(account: &AccountInfo, outpoint: &FundingOutpoint) -> Result<(), ProgramError> {
+        match Self::find(account, outpoint)? {
+            Ok(_) => Ok(()),
+            Err(position) => Self::insert(account, position, outpoint),
+        }
+    }
+
+    /// Registers `outpoint` reserved, e.g. the change of a withdrawal transaction that has yet to
+    /// confirm.
+    pub fn add_locked_utxo(account: &AccountInfo, outpoint: &FundingOutpoint) -> Result<(), ProgramError> {
+        let position = match Self::find(account, outpoint)? {
+            Ok(position) => position,
+            Err(position) => {
+                Self::insert(account, position, outpoint)?;
+                position
+            }
+        };
+        Self::set_locked(account, position, true)
+    }
+
+    /// Reserves `outpoint` for a withdrawal, it has to be registered and not reserved yet.
+    pub fn lock_utxo(account: &AccountInfo, outpoint: &FundingOutpoint) -> Result<(), ProgramError> {
+        let position = Self::find(account, outpoint)?
+            .map_err(|_| ProgramError::Custom(ERROR_UTXO_NOT_REGISTERED))?;
+        if account.data.borrow()[UTXO_ENTRIES_OFFSET + position * UTXO_ENTRY_SIZE + UTXO_OUTPOINT_SIZE] != 0 {
+            return Err(ProgramError::Custom(ERROR_UTXO_LOCKED));
+        }
+        Self::set_locked(account, position, true)
+    }
+
+    pub fn unlock_utxos(account: &AccountInfo) -> Result<(), ProgramError> {
+        for position in 0..Self::get_num_entries(account)? {
+            Self::set_locked(account, position, false)?;
+        }
+        Ok(())
+    }
+
+    /// Removes `outpoint` once a transaction spending it is signed.
+    pub fn spend_utxo(account: &AccountInfo, outpoint: &FundingOutpoint) -> Result<(), ProgramError> {
+        let position = Self::find(account, outpoint)?
+            .map_err(|_| ProgramError::Custom(ERROR_UTXO_NOT_REGISTERED))?;
+        Self::remove(account, position)
+    }
+
+    /// Releases the outputs of `tx_id` once it confirms.
+    pub fn unlock_tx(account: &AccountInfo, tx_id: &Hash) -> Result<(), ProgramError> {
+        for position in Self::find_tx(account, tx_id)? {
+            Self::set_locked(account, position, false)?;
+        }
+        Ok(())
+    }
+
+    /// Removes the outputs of `tx_id`, e.g. the change of a withdrawal transaction that lost to
+    /// another one spending the same inputs.
+    pub fn forget_tx(account: &AccountInfo, tx_id: &Hash) -> Result<(), ProgramError> {
+        let positions = Self::find_tx(account, tx_id)?;
+        for _ in 0..positions.len() {
+            Self::remove(account, positions.start)?;
+        }
+        Ok(())
+    }
+
+    // entries are sorted by outpoint, so the outputs of a transaction are next to each other
+    fn find_tx(account: &AccountInfo, tx_id: &Hash) -> Result<std::ops::Range<usize>, ProgramError> {
+        let start = match Self::find(account, &FundingOutpoint { tx_id: *tx_id, vout: 0 })? {
+            Ok(position) | Err(position) => position,
+        };
+        let num_entries = Self::get_num_entries(account)?;
+        let data = account.data.borrow();
+        let end = (start..num_entries)
+            .find(|position| {
+                let offset = UTXO_ENTRIES_OFFSET + position * UTXO_ENTRY_SIZE;
+                data[offset..offset + HASH_SIZE] != tx_id[..]
+            })
+            .unwrap_or(num_entries);
+        Ok(start..end)
+    }
+
+    fn remove(account: &AccountInfo, position: usize) -> Result<(), ProgramError> {
+        let num_entries = Self::get_num_entries(account)?;
+        {
+            let mut data = account.data.try_borrow_mut().map_err(|_| ProgramError::InvalidAccountData)?;
+            let offset = UTXO_ENTRIES_OFFSET + position * UTXO_ENTRY_SIZE;
+            data.copy_within(
+                offset + UTXO_ENTRY_SIZE..UTXO_ENTRIES_OFFSET + num_entries * UTXO_ENTRY_SIZE,
+                offset,
+            );
+        }
+        Self::set_num_entries(account, num_entries - 1)
+    }
+
+    fn insert(account: &AccountInfo, position: usize, outpoint: &FundingOutpoint) -> Result<(), ProgramError> {
+        let num_entries = Self::get_num_entries(account)?;
+        let required_len = UTXO_ENTRIES_OFFSET + (num_entries + 1) * UTXO_ENTRY_SIZE;
+        if required_len > account.data_len() {
+            let original_data_len = unsafe { account.original_data_len() };
+            let max_len = (original_data_len + entrypoint::MAX_PERMITTED_DATA_INCREASE)
+                .min(entrypoint::MAX_PERMITTED_DATA_LENGTH);
+            if required_len > max_len {
+                return Err(ProgramError::Custom(ERROR_UTXO_REGISTRY_FULL));
+            }
+            account.realloc(max_len, true)?;
+        }
+        {
+            let mut data = account.data.try_borrow_mut().map_err(|_| ProgramError::InvalidAccountData)?;
+            let offset = UTXO_ENTRIES_OFFSET + position * UTXO_ENTRY_SIZE;
+            data.copy_within(
+                offset..UTXO_ENTRIES_OFFSET + num_entries * UTXO_ENTRY_SIZE,
+                offset + UTXO_ENTRY_SIZE,
+            );
+            data[offset..offset + HASH_SIZE].copy_from_slice(outpoint.tx_id.as_slice());
+            data[offset + HASH_SIZE..offset + UTXO_OUTPOINT_SIZE].copy_from_slice(outpoint.vout.to_le_bytes().as_slice());
+            data[offset + UTXO_OUTPOINT_SIZE] = 0;
+        }
+        Self::set_num_entries(account, num_entries + 1)
+    }
+}
+
+fn get_event_log_account<'a, 'b>(accounts: &'a [AccountInfo<'b>], event_log_key: &Pubkey) -> Result<&'a AccountInfo<'b>, ProgramError> {
+    let event_log = accounts.iter()
+        .find(|account| account.key == event_log_key)
+        .ok_or(ProgramError::Custom(ERROR_EVENT_LOG_MISSING))?;
+    if event_log.is_signer || !event_log.is_writable {
+        return Err(ProgramError::Custom(ERROR_INVALID_ACCOUNT_FLAGS));
+    }
+    Ok(event_log)
 }
 
 pub fn set_type(account: &AccountInfo, account_type: AccountType) -> Result<(), ProgramError> {
@@ -455,18 +2264,44 @@ pub fn get_type(account: &AccountInfo) -> Result<AccountType, ProgramError> {
     Ok(AccountType::decode_from_slice(&account.data.borrow()[0..1]).map_err(|_| ProgramError::InvalidAccountData)?)
 }
 
-fn get_address(account: &AccountInfo, offset: usize) -> Result<String, ProgramError> {
-    let mut tmp = [0u8; MAX_ADDRESS_SIZE];
-    tmp[..MAX_ADDRESS_SIZE].copy_from_slice(&account.data.borrow()[offset..offset + MAX_ADDRESS_SIZE]);
-    let pos = tmp.iter().position(|&r| r == 0).unwrap_or(MAX_ADDRESS_SIZE);
-    String::from_utf8(tmp[..pos].to_vec()).map_err(|_| ProgramError::InvalidAccountData)
+pub fn get_version(account: &AccountInfo) -> Result<u32, ProgramError> {
+    Ok(u32::from_le_bytes(
+        account.data.borrow()[VERSION_OFFSET..VERSION_OFFSET + VERSION_SIZE]
+            .try_into()
+            .map_err(|_| ProgramError::InvalidAccountData)?
+    ))
+}
+
+pub fn set_version(account: &AccountInfo, version: u32) -> Result<(), ProgramError> {
+    let mut data = account.data.try_borrow_mut().map_err(|_| ProgramError::InvalidAccountData)?;
+    Ok(data[VERSION_OFFSET..VERSION_OFFSET + VERSION_SIZE].copy_from_slice(version.to_le_bytes().as_slice()))
+}
+
+impl AccountType {
+    pub fn current_version(&self) -> u32 {
+        match self {
+            AccountType::Program => PROGRAM_STATE_VERSION,
+            AccountType::Token => TOKEN_STATE_VERSION,
+            AccountType::Withdraw => WITHDRAW_STATE_VERSION,
+            AccountType::RuneReceiver => RUNE_RECEIVER_STATE_VERSION,
+            AccountType::DepositLedger => DEPOSIT_LEDGER_STATE_VERSION,
+            AccountType::EventLog => EVENT_LOG_STATE_VERSION,
+            AccountType::WalletIndex => WALLET_INDEX_STATE_VERSION,
+            AccountType::BalanceShard => BALANCE_SHARD_STATE_VERSION,
+            AccountType::WithdrawalLimit => WITHDRAWAL_LIMIT_STATE_VERSION,
+            AccountType::ForcedWithdrawals => FORCED_WITHDRAWAL_STATE_VERSION,
+            AccountType::WithdrawalNonces => WITHDRAWAL_NONCE_STATE_VERSION,
+            AccountType::UtxoRegistry => UTXO_REGISTRY_STATE_VERSION,
+            AccountType::Unknown => 0,
+        }
+    }
 }
 
-fn get_wallet_last4(account: &AccountInfo, offset: usize) -> Result<WalletLast4, ProgramError> {
+pub(crate) fn get_address(account: &AccountInfo, offset: usize) -> Result<String, ProgramError> {
     let mut tmp = [0u8; MAX_ADDRESS_SIZE];
     tmp[..MAX_ADDRESS_SIZE].copy_from_slice(&account.data.borrow()[offset..offset + MAX_ADDRESS_SIZE]);
     let pos = tmp.iter().position(|&r| r == 0).unwrap_or(MAX_ADDRESS_SIZE);
-    tmp[pos-4 .. pos].try_into().map_err(|_| ProgramError::InvalidAccountData)
+    String::from_utf8(tmp[..pos].to_vec()).map_err(|_| ProgramError::InvalidAccountData)
 }
 
 pub fn set_string(account: &AccountInfo, offset: usize, string: &str, max_size: usize) -> Result<(), ProgramError> {
@@ -479,6 +2314,33 @@ pub fn set_string(account: &AccountInfo, offset: usize, string: &str, max_size:
     Ok(data[offset..offset + bytes.len()].copy_from_slice(bytes))
 }
 
+// an all zero key means not set, accounts that predate the field may also be too short to hold it
+fn get_optional_pubkey(account: &AccountInfo, offset: usize) -> Result<Option<Pubkey>, ProgramError> {
+    if account.data_len() < offset + PUBKEY_SIZE {
+        return Ok(None);
+    }
+    let pubkey = Pubkey::from_slice(account.data.borrow()[offset..offset + PUBKEY_SIZE]
+        .try_into().map_err(|_| ProgramError::InvalidAccountData)?);
+    if pubkey == Pubkey::from([0u8; 32]) {
+        Ok(None)
+    } else {
+        Ok(Some(pubkey))
+    }
+}
+
+fn get_u64(account: &AccountInfo, offset: usize) -> Result<u64, ProgramError> {
+    Ok(u64::from_le_bytes(
+        account.data.borrow()[offset..offset + 8]
+            .try_into()
+            .map_err(|_| ProgramError::InvalidAccountData)?
+    ))
+}
+
+fn set_u64(account: &AccountInfo, offset: usize, value: u64) -> Result<(), ProgramError> {
+    let mut data = account.data.try_borrow_mut().map_err(|_| ProgramError::InvalidAccountData)?;
+    Ok(data[offset..offset + 8].copy_from_slice(value.to_le_bytes().as_slice()))
+}
+
 fn hash_from_slice(account: &AccountInfo, offset: usize) -> Result<Hash, ProgramError> {
     let mut tmp = EMPTY_HASH;
     tmp[..HASH_SIZE].copy_from_slice(account.data.borrow()[offset..offset + HASH_SIZE]
@@ -486,13 +2348,42 @@ fn hash_from_slice(account: &AccountInfo, offset: usize) -> Result<Hash, Program
     Ok(tmp)
 }
 
+/// The last four bytes of the witness program or hash of `address`, see `WalletAddress::last4`.
 pub fn wallet_last4(address: &str) -> WalletLast4 {
-    let mut tmp: WalletLast4 = [0u8; 4];
-    tmp[0..4].copy_from_slice(&address.as_bytes()[address.len() - 4..address.len()]);
-    tmp
+    WalletAddress::from_address(address).map(|address| address.last4()).unwrap_or_default()
+}
+
+/// The commitment to `address` to put into an address index, see `WalletAddress::script_hash`.
+pub fn address_commitment(address: &str) -> AddressCommitment {
+    AddressCommitment::ScriptHash(
+        WalletAddress::from_address(address)
+            .and_then(|address| address.script_hash())
+            .unwrap_or_default()
+    )
+}
+
+/// Adds two token amounts, failing with `ERROR_ARITHMETIC_OVERFLOW` instead of wrapping.
+pub fn checked_add_amount(amount: u64, addition: u64) -> Result<u64, ProgramError> {
+    amount.checked_add(addition).ok_or(ProgramError::Custom(ERROR_ARITHMETIC_OVERFLOW))
+}
+
+/// Subtracts one token amount from another, failing with `ERROR_ARITHMETIC_OVERFLOW` instead of wrapping.
+pub fn checked_sub_amount(amount: u64, subtraction: u64) -> Result<u64, ProgramError> {
+    amount.checked_sub(subtraction).ok_or(ProgramError::Custom(ERROR_ARITHMETIC_OVERFLOW))
 }
 
 pub fn validate_account(accounts: &[AccountInfo], index: u8, is_signer: bool, is_writable: bool, account_type: Option<AccountType>, related_account_index: Option<u8>) -> Result<(), ProgramError> {
+    validate_account_any_version(accounts, index, is_signer, is_writable, account_type.clone(), related_account_index)?;
+    if let Some(account_type) = account_type {
+        // accounts with an older layout have to be migrated before they can be used
+        if get_version(&accounts[index as usize])? != account_type.current_version() {
+            return Err(ProgramError::Custom(ERROR_INVALID_ACCOUNT_VERSION));
+        }
+    }
+    Ok(())
+}
+
+pub fn validate_account_any_version(accounts: &[AccountInfo], index: u8, is_signer: bool, is_writable: bool, account_type: Option<AccountType>, related_account_index: Option<u8>) -> Result<(), ProgramError> {
     if index as usize >= accounts.len() {
         return Err(ProgramError::Custom(ERROR_INVALID_ACCOUNT_INDEX));
     }
@@ -510,6 +2401,14 @@ pub fn validate_account(accounts: &[AccountInfo], index: u8, is_signer: bool, is
                 AccountType::Withdraw => WithdrawState::get_program_state_account_key(&account),
                 AccountType::Token => TokenState::get_program_state_account_key(&account),
                 AccountType::RuneReceiver => RuneReceiverState::get_program_state_account_key(&account),
+                AccountType::DepositLedger => DepositLedgerState::get_program_state_account_key(&account),
+                AccountType::EventLog => EventLogState::get_program_state_account_key(&account),
+                AccountType::WalletIndex => WalletIndexState::get_token_state_account_key(&account),
+                AccountType::BalanceShard => BalanceShardState::get_token_state_account_key(&account),
+                AccountType::WithdrawalLimit => WithdrawalLimitState::get_token_state_account_key(&account),
+                AccountType::ForcedWithdrawals => ForcedWithdrawalState::get_program_state_account_key(&account),
+                AccountType::WithdrawalNonces => WithdrawalNonceState::get_program_state_account_key(&account),
+                AccountType::UtxoRegistry => UtxoRegistryState::get_program_state_account_key(&account),
                 _ => Err(ProgramError::Custom(ERROR_INVALID_ACCOUNT_TYPE))
             }?;
             if related_key != *accounts[related_account_index as usize].key {
@@ -552,8 +2451,110 @@ fn map_network_type(network_type: &NetworkType) -> bitcoin::Network {
 #[cfg(test)]
 mod tests {
     use arch_program::program_error::ProgramError::Custom;
+    use arch_program::utxo::UtxoMeta;
+    use proptest::prelude::*;
+    use crate::instructions::{AddressIndex, Withdrawal};
     use crate::state::*;
 
+    // amounts close to the edges of the u64 range turn up far more often than a uniform draw would give
+    fn amount() -> impl Strategy<Value = u64> {
+        prop_oneof![
+            0..1000u64,
+            u64::MAX - 1000..=u64::MAX,
+            u64::MAX / 2 - 1000..u64::MAX / 2 + 1000,
+            any::<u64>(),
+        ]
+    }
+
+    fn with_balance(balance: u64, check: impl FnOnce(&AccountInfo)) {
+        let key = Pubkey::new_unique();
+        let utxo = UtxoMeta::from([0; 32], 0);
+        let mut data = vec![0u8; BALANCES_OFFSET + BALANCE_SIZE];
+        let account = AccountInfo::new(&key, &mut data, &key, &utxo, false, true, false);
+        set_type(&account, AccountType::Token).unwrap();
+        Balance::set_wallet_balance(&account, 0, balance).unwrap();
+        check(&account);
+    }
+
+    proptest! {
+        #[test]
+        fn test_checked_amounts(amount in amount(), adjustment in amount()) {
+            let fits = amount as u128 + adjustment as u128 <= u64::MAX as u128;
+            match checked_add_amount(amount, adjustment) {
+                Ok(sum) => {
+                    prop_assert!(fits);
+                    prop_assert_eq!(Ok(amount), checked_sub_amount(sum, adjustment));
+                }
+                Err(error) => {
+                    prop_assert!(!fits);
+                    prop_assert_eq!(Custom(ERROR_ARITHMETIC_OVERFLOW), error);
+                }
+            }
+            match checked_sub_amount(amount, adjustment) {
+                Ok(difference) => prop_assert_eq!(Ok(amount), checked_add_amount(difference, adjustment)),
+                Err(error) => {
+                    prop_assert!(adjustment > amount);
+                    prop_assert_eq!(Custom(ERROR_ARITHMETIC_OVERFLOW), error);
+                }
+            }
+        }
+
+        #[test]
+        fn test_balance_adjustments(balance in amount(), adjustment in amount()) {
+            with_balance(balance, |account| {
+                match Balance::increment_wallet_balance(account, 0, adjustment) {
+                    Ok(()) => assert_eq!(balance as u128 + adjustment as u128, Balance::get_wallet_balance(account, 0).unwrap() as u128),
+                    Err(error) => {
+                        assert_eq!(Custom(ERROR_ARITHMETIC_OVERFLOW), error);
+                        assert_eq!(balance, Balance::get_wallet_balance(account, 0).unwrap());
+                    }
+                }
+            });
+            with_balance(balance, |account| {
+                match Balance::decrement_wallet_balance(account, 0, adjustment) {
+                    Ok(()) => assert_eq!(balance - adjustment, Balance::get_wallet_balance(account, 0).unwrap()),
+                    Err(error) => {
+                        assert!(adjustment > balance);
+                        assert_eq!(Custom(ERROR_INSUFFICIENT_BALANCE), error);
+                        assert_eq!(balance, Balance::get_wallet_balance(account, 0).unwrap());
+                    }
+                }
+            });
+        }
+
+        #[test]
+        fn test_withdrawal_net_amount(amount in amount(), fee_amount in amount()) {
+            let address_index = AddressIndex { index: 1, commitment: AddressCommitment::ScriptHash([0; 8]) };
+            let withdrawal = Withdrawal {
+                address_index: address_index.clone(),
+                amount,
+                fee_account_index: 1,
+                fee_address_index: address_index,
+                fee_amount,
+                signature: None,
+            };
+            match withdrawal.net_amount() {
+                Ok(net_amount) => prop_assert_eq!(amount, net_amount + fee_amount),
+                Err(error) => {
+                    prop_assert!(fee_amount > amount);
+                    prop_assert_eq!(Custom(ERROR_ARITHMETIC_OVERFLOW), error);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_event_sequence_overflow() {
+        let key = Pubkey::new_unique();
+        let utxo = UtxoMeta::from([0; 32], 0);
+        let mut data = vec![0u8; EVENTS_SIZE_OFFSET];
+        data[EVENT_SEQUENCE_OFFSET..EVENT_SEQUENCE_OFFSET + EVENT_SEQUENCE_SIZE].copy_from_slice(&(u64::MAX - 1).to_le_bytes());
+        let account = AccountInfo::new(&key, &mut data, &key, &utxo, false, true, false);
+        assert_eq!(Ok(u64::MAX), ProgramState::next_event_sequence(&account));
+        assert_eq!(Err(Custom(ERROR_ARITHMETIC_OVERFLOW)), ProgramState::next_event_sequence(&account));
+        assert_eq!(Ok(u64::MAX), ProgramState::get_event_sequence(&account));
+    }
+
     #[test]
     fn test_validate_bitcoin_address() {
         // testnet address valid on testnet
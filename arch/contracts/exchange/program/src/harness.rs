@@ -0,0 +1,297 @@
+//! In-process execution harness for `process_instruction`.
+//!
+//! Accounts are kept in memory and serialized into the same input layout the runtime
+//! hands to the SBF entrypoint, so `realloc` and `set_utxo` behave as they do on chain.
+//! Syscalls are served by [`Syscalls`], whose handlers can be replaced per test.
+
+use std::cell::{RefCell, RefMut};
+use std::collections::HashMap;
+use std::mem::size_of;
+use std::rc::Rc;
+use std::str::FromStr;
+
+use arch_program::{
+    account::AccountMeta,
+    entrypoint::{deserialize, MAX_PERMITTED_DATA_INCREASE, NON_DUP_MARKER},
+    input_to_sign::InputToSign,
+    program_error::ProgramError,
+    program_stubs::{set_syscall_stubs, SyscallStubs},
+    pubkey::Pubkey,
+    transaction_to_sign::TransactionToSign,
+    utxo::UtxoMeta,
+};
+use bitcoin::{
+    absolute::LockTime, hashes::Hash, transaction::Version, Address, Amount, Network, ScriptBuf,
+    Transaction, TxIn, TxOut, Txid,
+};
+
+use model::instructions::ProgramInstruction;
+use model::serialization::Codable;
+
+use crate::process_instruction;
+
+pub const ACCOUNT_UTXO_VALUE: u64 = 1500;
+
+pub type GetBitcoinTx = Rc<dyn Fn(&Txid) -> Option<Transaction>>;
+pub type GetAccountScriptPubkey = Rc<dyn Fn(&Pubkey) -> [u8; 34]>;
+pub type SetTransactionToSign = Rc<dyn Fn(&Transaction, &[InputToSign]) -> u64>;
+pub type ValidateUtxoOwnership = Rc<dyn Fn(&UtxoMeta, &Pubkey) -> bool>;
+pub type GetBitcoinBlockHeight = Rc<dyn Fn() -> u64>;
+
+#[derive(Clone, Debug)]
+pub struct SignedTransaction {
+    pub transaction: Transaction,
+    pub inputs_to_sign: Vec<InputToSign>,
+}
+
+/// Syscall state shared between the harness and the program under test.
+///
+/// The `Option` handlers override the default behaviour, which serves transactions
+/// from `bitcoin_txs`, derives a taproot script pubkey from the account key, records
+/// signed transactions, accepts every UTXO and reports `block_height`.
+#[derive(Default)]
+pub struct Syscalls {
+    pub bitcoin_txs: HashMap<Txid, Transaction>,
+    pub block_height: u64,
+    pub signed_transactions: Vec<SignedTransaction>,
+    pub logs: Vec<String>,
+    pub get_bitcoin_tx: Option<GetBitcoinTx>,
+    pub get_account_script_pubkey: Option<GetAccountScriptPubkey>,
+    pub set_transaction_to_sign: Option<SetTransactionToSign>,
+    pub validate_utxo_ownership: Option<ValidateUtxoOwnership>,
+    pub get_bitcoin_block_height: Option<GetBitcoinBlockHeight>,
+}
+
+pub fn default_account_script_pubkey(pubkey: &Pubkey) -> [u8; 34] {
+    let mut script_pubkey = [0u8; 34];
+    script_pubkey[0] = 0x51;
+    script_pubkey[1] = 0x20;
+    script_pubkey[2..].copy_from_slice(&pubkey.0);
+    script_pubkey
+}
+
+pub fn txid_to_bytes(txid: &Txid) -> [u8; 32] {
+    let mut bytes = txid.to_byte_array();
+    bytes.reverse();
+    bytes
+}
+
+pub fn txid_from_bytes(bytes: &[u8]) -> Txid {
+    Txid::from_str(&hex::encode(bytes)).unwrap()
+}
+
+struct HarnessStubs(Rc<RefCell<Syscalls>>);
+
+impl SyscallStubs for HarnessStubs {
+    fn sol_log(&self, message: &str) {
+        self.0.borrow_mut().logs.push(message.to_string());
+    }
+
+    fn arch_set_transaction_to_sign(&self, transaction_to_sign: &[u8]) -> u64 {
+        let transaction_to_sign = TransactionToSign::from_slice(transaction_to_sign);
+        let transaction: Transaction = bitcoin::consensus::deserialize(transaction_to_sign.tx_bytes)
+            .expect("transaction to sign should deserialize");
+        let handler = self.0.borrow().set_transaction_to_sign.clone();
+        if let Some(handler) = handler {
+            return handler(&transaction, transaction_to_sign.inputs_to_sign);
+        }
+        let mut syscalls = self.0.borrow_mut();
+        syscalls.bitcoin_txs.insert(transaction.compute_txid(), transaction.clone());
+        syscalls.signed_transactions.push(SignedTransaction {
+            transaction,
+            inputs_to_sign: transaction_to_sign.inputs_to_sign.to_vec(),
+        });
+        0
+    }
+
+    fn arch_get_bitcoin_tx(&self, txid: &[u8; 32]) -> Option<Vec<u8>> {
+        let txid = txid_from_bytes(txid);
+        let handler = self.0.borrow().get_bitcoin_tx.clone();
+        let tx = match handler {
+            Some(handler) => handler(&txid),
+            None => self.0.borrow().bitcoin_txs.get(&txid).cloned(),
+        };
+        tx.map(|tx| bitcoin::consensus::serialize(&tx))
+    }
+
+    fn arch_validate_utxo_ownership(&self, utxo: &UtxoMeta, owner: &Pubkey) -> bool {
+        let handler = self.0.borrow().validate_utxo_ownership.clone();
+        match handler {
+            Some(handler) => handler(utxo, owner),
+            None => true,
+        }
+    }
+
+    fn arch_get_account_script_pubkey(&self, pubkey: &Pubkey) -> [u8; 34] {
+        let handler = self.0.borrow().get_account_script_pubkey.clone();
+        match handler {
+            Some(handler) => handler(pubkey),
+            None => default_account_script_pubkey(pubkey),
+        }
+    }
+
+    fn arch_get_bitcoin_block_height(&self) -> u64 {
+        let handler = self.0.borrow().get_bitcoin_block_height.clone();
+        match handler {
+            Some(handler) => handler(),
+            None => self.0.borrow().block_height,
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct TestAccount {
+    pub data: Vec<u8>,
+    pub utxo: UtxoMeta,
+    pub owner: Pubkey,
+}
+
+pub struct Harness {
+    pub program_id: Pubkey,
+    pub network: Network,
+    accounts: HashMap<Pubkey, TestAccount>,
+    syscalls: Rc<RefCell<Syscalls>>,
+}
+
+impl Harness {
+    pub fn new() -> Self {
+        Self {
+            program_id: Pubkey::new_unique(),
+            network: Network::Regtest,
+            accounts: HashMap::new(),
+            syscalls: Rc::new(RefCell::new(Syscalls::default())),
+        }
+    }
+
+    pub fn syscalls(&self) -> RefMut<Syscalls> {
+        self.syscalls.borrow_mut()
+    }
+
+    /// Creates an empty program-owned account anchored to a freshly funded UTXO.
+    pub fn create_account(&mut self) -> Pubkey {
+        let pubkey = Pubkey::new_unique();
+        let txid = self.add_bitcoin_tx(Transaction {
+            version: Version::TWO,
+            lock_time: LockTime::ZERO,
+            input: vec![TxIn::default()],
+            output: vec![TxOut {
+                value: Amount::from_sat(ACCOUNT_UTXO_VALUE),
+                script_pubkey: self.script_pubkey(&pubkey),
+            }],
+        });
+        self.accounts.insert(pubkey, TestAccount {
+            data: vec![],
+            utxo: UtxoMeta::from(txid_to_bytes(&txid), 0),
+            owner: self.program_id,
+        });
+        pubkey
+    }
+
+    pub fn account(&self, pubkey: &Pubkey) -> &TestAccount {
+        self.accounts.get(pubkey).expect("unknown account")
+    }
+
+    /// Gives direct access to an account, e.g. to lay out data in an older format.
+    pub fn account_mut(&mut self, pubkey: &Pubkey) -> &mut TestAccount {
+        self.accounts.get_mut(pubkey).expect("unknown account")
+    }
+
+    pub fn decode_account<T: Codable>(&self, pubkey: &Pubkey) -> T {
+        T::decode_from_slice(&self.account(pubkey).data).expect("account data should decode")
+    }
+
+    pub fn add_bitcoin_tx(&mut self, tx: Transaction) -> Txid {
+        let txid = tx.compute_txid();
+        self.syscalls().bitcoin_txs.insert(txid, tx);
+        txid
+    }
+
+    pub fn script_pubkey(&self, pubkey: &Pubkey) -> ScriptBuf {
+        let handler = self.syscalls.borrow().get_account_script_pubkey.clone();
+        ScriptBuf::from_bytes(match handler {
+            Some(handler) => handler(pubkey),
+            None => default_account_script_pubkey(pubkey),
+        }.to_vec())
+    }
+
+    pub fn address(&self, pubkey: &Pubkey) -> Address {
+        Address::from_script(&self.script_pubkey(pubkey), self.network).unwrap()
+    }
+
+    pub fn last_signed_transaction(&self) -> Option<SignedTransaction> {
+        self.syscalls.borrow().signed_transactions.last().cloned()
+    }
+
+    /// Runs `instruction` against the given accounts. Account changes, signed
+    /// transactions and the bitcoin transactions they made fetchable are only kept
+    /// if the instruction succeeds, as on chain.
+    pub fn process(&mut self, accounts: &[AccountMeta], instruction: &ProgramInstruction) -> Result<(), ProgramError> {
+        let instruction_data = instruction.encode_to_vec().unwrap();
+        let mut input = self.serialize_input(accounts, &instruction_data);
+        let signed_count = self.syscalls.borrow().signed_transactions.len();
+        let bitcoin_txs = self.syscalls.borrow().bitcoin_txs.clone();
+
+        let previous_stubs = set_syscall_stubs(Box::new(HarnessStubs(self.syscalls.clone())));
+        let (program_id, account_infos, instruction_data) = unsafe { deserialize(input.as_mut_ptr() as *mut u8) };
+        let mut result = process_instruction(program_id, &account_infos, instruction_data);
+        set_syscall_stubs(previous_stubs);
+
+        if result.is_ok() {
+            for (meta, account_info) in accounts.iter().zip(account_infos.iter()) {
+                if !meta.is_writable && **account_info.data.borrow() != self.accounts[&meta.pubkey].data[..] {
+                    result = Err(ProgramError::Immutable);
+                }
+            }
+        }
+        if result.is_ok() {
+            for (meta, account_info) in accounts.iter().zip(account_infos.iter()) {
+                let account = self.accounts.get_mut(&meta.pubkey).unwrap();
+                account.data = account_info.data.borrow().to_vec();
+                account.utxo = account_info.utxo.clone();
+            }
+        } else {
+            let mut syscalls = self.syscalls();
+            syscalls.signed_transactions.truncate(signed_count);
+            syscalls.bitcoin_txs = bitcoin_txs;
+        }
+        result
+    }
+
+    /// Lays out accounts, instruction data and program id the way `entrypoint::deserialize`
+    /// expects. The buffer is backed by `u64`s so that it is 8-byte aligned.
+    fn serialize_input(&self, accounts: &[AccountMeta], instruction_data: &[u8]) -> Vec<u64> {
+        let mut input: Vec<u8> = vec![];
+        input.extend_from_slice(&(accounts.len() as u64).to_le_bytes());
+        for (position, meta) in accounts.iter().enumerate() {
+            if let Some(original) = accounts[..position].iter().position(|m| m.pubkey == meta.pubkey) {
+                input.push(original as u8);
+                input.extend_from_slice(&[0u8; 7]);
+                continue;
+            }
+            let account = self.accounts.get(&meta.pubkey).expect("unknown account");
+            input.push(NON_DUP_MARKER);
+            input.extend_from_slice(&[0u8; 4]);
+            input.push(meta.is_signer as u8);
+            input.push(meta.is_writable as u8);
+            input.push(0);
+            input.extend_from_slice(&meta.pubkey.0);
+            input.extend_from_slice(&(account.data.len() as u64).to_le_bytes());
+            input.extend_from_slice(&(account.data.len() as u64).to_le_bytes());
+            input.extend_from_slice(&account.data);
+            input.resize(input.len() + MAX_PERMITTED_DATA_INCREASE, 0);
+            input.resize(input.len().next_multiple_of(size_of::<u64>()), 0);
+            input.extend_from_slice(&account.owner.0);
+            input.extend_from_slice(&account.utxo.serialize());
+            input.extend_from_slice(&[0u8; 4]);
+        }
+        input.extend_from_slice(&(instruction_data.len() as u64).to_le_bytes());
+        input.extend_from_slice(instruction_data);
+        input.extend_from_slice(&self.program_id.0);
+
+        let mut aligned = vec![0u64; input.len().div_ceil(size_of::<u64>())];
+        unsafe {
+            std::ptr::copy_nonoverlapping(input.as_ptr(), aligned.as_mut_ptr() as *mut u8, input.len());
+        }
+        aligned
+    }
+}
@@ -4,22 +4,29 @@ use arch_program::{
     program_error::ProgramError,
     pubkey::Pubkey,
     transaction_to_sign::TransactionToSign,
-    program::{get_account_script_pubkey, set_transaction_to_sign},
+    program::{get_account_script_pubkey, get_bitcoin_block_height, get_bitcoin_tx, set_transaction_to_sign, validate_utxo_ownership},
     input_to_sign::InputToSign,
     helper::get_state_transition_tx,
     msg,
 };
 use sha256::digest;
 use arch_program::utxo::UtxoMeta;
-use bitcoin::{Amount, ScriptBuf, Transaction, TxOut};
-use std::collections::{HashSet};
-use ordinals::{Edict, RuneId, Runestone};
+use bitcoin::{absolute::LockTime, transaction::Version, Address, Amount, Network, ScriptBuf, Sequence, Transaction, TxIn, TxOut, Witness};
+use bitcoin::hashes::Hash as _;
+use std::collections::{HashMap, HashSet};
+use ordinals::{Artifact, Edict, RuneId, Runestone};
 
 use model::state::*;
+use model::address::WalletAddress;
 use model::instructions::*;
 use model::error::*;
 use model::serialization::Codable;
 
+#[cfg(test)]
+mod harness;
+#[cfg(test)]
+mod tests;
+
 entrypoint!(process_instruction);
 pub fn process_instruction(
     program_id: &Pubkey,
@@ -33,17 +40,43 @@ pub fn process_instruction(
     match instruction {
         ProgramInstruction::InitProgramState(params) => init_program_state(accounts, &params),
         ProgramInstruction::InitTokenState(params) => init_token_state(accounts, &params),
-        ProgramInstruction::InitWalletBalances(params) => init_wallet_balances(accounts, &params),
-        ProgramInstruction::BatchDeposit(params) => deposit_batch(accounts, &params),
-        ProgramInstruction::PrepareBatchWithdraw(params) => prepare_withdraw_batch(accounts, &params, &params_raw_data),
-        ProgramInstruction::SubmitBatchSettlement(params) => submit_settlement_batch(accounts, &params, &params_raw_data),
-        ProgramInstruction::PrepareBatchSettlement(params) => prepare_settlement_batch(accounts, &params, &params_raw_data),
+        ProgramInstruction::InitWalletBalances(params) => init_wallet_balances(program_id, accounts, &params),
+        ProgramInstruction::BatchDeposit(params) => deposit_batch(program_id, accounts, &params),
+        ProgramInstruction::PrepareBatchWithdraw(params) => prepare_withdraw_batch(program_id, accounts, &params, &params_raw_data, false),
+        ProgramInstruction::SubmitBatchSettlement(params) => submit_settlement_batch(program_id, accounts, &params, &params_raw_data),
+        ProgramInstruction::PrepareBatchSettlement(params) => prepare_settlement_batch(program_id, accounts, &params, &params_raw_data, false),
         ProgramInstruction::RollbackBatchSettlement() => rollback_settlement_batch(accounts),
-        ProgramInstruction::RollbackBatchWithdraw(params) => rollback_withdraw_batch(accounts, &params),
+        ProgramInstruction::RollbackBatchWithdraw(params) => rollback_withdraw_batch(program_id, accounts, &params),
         ProgramInstruction::SubmitBatchWithdraw(params) => submit_withdraw_batch(program_id, accounts, &params, &params_raw_data),
         ProgramInstruction::UpdateWithdrawStateUtxo(params) => update_withdraw_state_utxo(accounts, &params),
         ProgramInstruction::InitRuneReceiverState() => init_rune_receiver_state(accounts),
-        ProgramInstruction::SetTokeRuneId(params) => set_token_rune_id(accounts, &params)
+        ProgramInstruction::SetTokeRuneId(params) => set_token_rune_id(accounts, &params),
+        ProgramInstruction::InitDepositLedger() => init_deposit_ledger(accounts),
+        ProgramInstruction::UpdateFeeAccount(params) => update_fee_account(accounts, &params),
+        ProgramInstruction::UpdateProgramChangeAddress(params) => update_program_change_address(accounts, &params),
+        ProgramInstruction::UpdateWithdrawAccount() => update_withdraw_account(accounts),
+        ProgramInstruction::Pause(params) => pause(accounts, &params),
+        ProgramInstruction::Unpause(params) => unpause(accounts, &params),
+        ProgramInstruction::MigrateAccount(params) => migrate_account(accounts, &params),
+        ProgramInstruction::InitEventLog() => init_event_log(accounts),
+        ProgramInstruction::PreparePartialBatchSettlement(params) => prepare_settlement_batch(program_id, accounts, &params, &params_raw_data, true),
+        ProgramInstruction::PreparePartialBatchWithdraw(params) => prepare_withdraw_batch(program_id, accounts, &params, &params_raw_data, true),
+        ProgramInstruction::InitWalletIndex() => init_wallet_index(accounts),
+        ProgramInstruction::IndexWalletBalances() => index_wallet_balances(accounts),
+        ProgramInstruction::CloseWalletBalance(params) => close_wallet_balance(program_id, accounts, &params),
+        ProgramInstruction::CompactBalances(params) => compact_balances(accounts, &params),
+        ProgramInstruction::InitBalanceShard() => init_balance_shard(accounts),
+        ProgramInstruction::SetWithdrawalFeePolicy(params) => set_withdrawal_fee_policy(accounts, &params),
+        ProgramInstruction::SetWithdrawalLimit(params) => set_withdrawal_limit(accounts, &params),
+        ProgramInstruction::InitForcedWithdrawals(params) => init_forced_withdrawals(accounts, &params),
+        ProgramInstruction::RequestForcedWithdrawal(params) => request_forced_withdrawal(program_id, accounts, &params),
+        ProgramInstruction::ForcedWithdraw(params) => forced_withdraw(program_id, accounts, &params),
+        ProgramInstruction::InitWithdrawalNonces() => init_withdrawal_nonces(accounts),
+        ProgramInstruction::SetWithdrawalFeeRate(params) => set_withdrawal_fee_rate(accounts, &params),
+        ProgramInstruction::InitUtxoRegistry() => init_utxo_registry(accounts),
+        ProgramInstruction::RegisterUtxos(params) => register_utxos(program_id, accounts, &params),
+        ProgramInstruction::ConfirmWithdrawBatch(params) => confirm_withdraw_batch(accounts, &params),
+        ProgramInstruction::BumpWithdrawBatch(params) => bump_withdraw_batch(program_id, accounts, &params),
     }
 }
 
@@ -58,15 +91,19 @@ pub fn init_program_state(accounts: &[AccountInfo],
     validate_bitcoin_address(&params.fee_account, &params.network_type, true)?;
     init_state_data(&accounts[0], ProgramState {
         account_type: AccountType::Program,
-        version: 0,
+        version: PROGRAM_STATE_VERSION,
         withdraw_account: *accounts[1].key,
         fee_account_address: params.fee_account.clone(),
         program_change_address: params.program_change_address.clone(),
         network_type: params.network_type.clone(),
         settlement_batch_hash: EMPTY_HASH,
         last_settlement_batch_hash: EMPTY_HASH,
-        events: vec![],
-    }.encode_to_vec().expect("Serialization error"), EVENT_SIZE * MAX_EVENTS)?;
+        pause_flags: 0,
+        event_sequence: 0,
+        events_count: 0,
+        first_event_log_account: Pubkey::from([0u8; 32]),
+        event_log_account: Pubkey::from([0u8; 32]),
+    }.encode_to_vec().expect("Serialization error"), 0)?;
     if accounts.len() == 3 {
         RuneReceiverState::initialize(accounts, 2)?;
     }
@@ -96,6 +133,303 @@ pub fn set_token_rune_id(accounts: &[AccountInfo],
     TokenState::set_token_id(&accounts[1], rune_id)
 }
 
+pub fn set_withdrawal_fee_policy(accounts: &[AccountInfo], params: &SetWithdrawalFeePolicyParams) -> Result<(), ProgramError> {
+    validate_account(accounts, 0, true, false, Some(AccountType::Program), None)?;
+    validate_account(accounts, params.account_index, false, true, Some(AccountType::Token), Some(0))?;
+    let account = &accounts[params.account_index as usize];
+    let fee_policy = match &params.fee_policy {
+        None => None,
+        Some(fee_policy) => {
+            if fee_policy.flat_fee != 0 && fee_policy.flat_fee < fee_policy.min_fee {
+                return Err(ProgramError::Custom(ERROR_INVALID_FEE_POLICY));
+            }
+            let fee_token_account = match fee_policy.fee_account_index {
+                Some(fee_account_index) if fee_account_index != params.account_index => {
+                    validate_account(accounts, fee_account_index, false, false, Some(AccountType::Token), Some(0))?;
+                    Some(*accounts[fee_account_index as usize].key)
+                }
+                _ => None,
+            };
+            // rune tokens have no fee balance, their fees have to be charged in another token
+            if fee_token_account.is_none() && TokenState::is_rune_account(account) {
+                return Err(ProgramError::Custom(ERROR_INVALID_FEE_POLICY));
+            }
+            Some(WithdrawalFeePolicy {
+                min_fee: fee_policy.min_fee,
+                flat_fee: fee_policy.flat_fee,
+                fee_token_account,
+            })
+        }
+    };
+    TokenState::set_withdrawal_fee_policy(account, &fee_policy)
+}
+
+// the first limit set on a token initializes the limit account, later ones update it in place
+pub fn set_withdrawal_limit(accounts: &[AccountInfo], params: &SetWithdrawalLimitParams) -> Result<(), ProgramError> {
+    validate_account(accounts, 0, true, false, Some(AccountType::Program), None)?;
+    validate_account(accounts, 1, false, true, Some(AccountType::Token), Some(0))?;
+    let Some(limit) = &params.limit else {
+        return TokenState::set_withdrawal_limit(&accounts[1], &None);
+    };
+    match TokenState::get_withdrawal_limit_key(&accounts[1])? {
+        Some(limit_key) => {
+            validate_account(accounts, 2, false, true, Some(AccountType::WithdrawalLimit), Some(1))?;
+            if *accounts[2].key != limit_key {
+                return Err(ProgramError::Custom(ERROR_STATE_ACCOUNT_MISMATCH));
+            }
+            WithdrawalLimitState::set_limit(&accounts[2], limit.max_amount, limit.window_blocks)
+        }
+        None => {
+            validate_account(accounts, 2, false, true, None, None)?;
+            WithdrawalLimitState::initialize(&accounts[2], accounts[1].key, limit.max_amount, limit.window_blocks)?;
+            TokenState::set_withdrawal_limit(&accounts[1], &Some(*accounts[2].key))
+        }
+    }
+}
+
+pub fn set_withdrawal_fee_rate(accounts: &[AccountInfo], params: &SetWithdrawalFeeRateParams) -> Result<(), ProgramError> {
+    validate_account(accounts, 0, true, true, Some(AccountType::Program), None)?;
+    let fee_rate = match &params.fee_rate {
+        None => None,
+        Some(fee_rate) => {
+            if fee_rate.max_fee_rate == 0 || fee_rate.min_fee_rate > fee_rate.max_fee_rate {
+                return Err(ProgramError::Custom(ERROR_INVALID_FEE_RATE));
+            }
+            Some(WithdrawalFeeRate {
+                min_fee_rate: fee_rate.min_fee_rate,
+                max_fee_rate: fee_rate.max_fee_rate,
+            })
+        }
+    };
+    ProgramState::set_withdrawal_fee_rate(&accounts[0], &fee_rate)
+}
+
+pub fn init_forced_withdrawals(accounts: &[AccountInfo], params: &InitForcedWithdrawalsParams) -> Result<(), ProgramError> {
+    validate_account(accounts, 0, true, true, Some(AccountType::Program), None)?;
+    validate_account(accounts, 1, false, true, None, None)?;
+    if ProgramState::get_forced_withdrawals_key(&accounts[0])?.is_some() {
+        return Err(ProgramError::Custom(ERROR_ALREADY_INITIALIZED));
+    }
+    ForcedWithdrawalState::initialize(&accounts[1], accounts[0].key, params.delay_blocks)?;
+    ProgramState::set_forced_withdrawals(&accounts[0], accounts[1].key)
+}
+
+pub fn init_withdrawal_nonces(accounts: &[AccountInfo]) -> Result<(), ProgramError> {
+    validate_account(accounts, 0, true, true, Some(AccountType::Program), None)?;
+    validate_account(accounts, 1, false, true, None, None)?;
+    if ProgramState::get_withdrawal_nonces_key(&accounts[0])?.is_some() {
+        return Err(ProgramError::Custom(ERROR_ALREADY_INITIALIZED));
+    }
+    WithdrawalNonceState::initialize(&accounts[1], accounts[0].key)?;
+    ProgramState::set_withdrawal_nonces(&accounts[0], accounts[1].key)
+}
+
+// The registry has to start out without a withdrawal batch in progress, whose inputs and change
+// it would miss.
+pub fn init_utxo_registry(accounts: &[AccountInfo]) -> Result<(), ProgramError> {
+    validate_account(accounts, 0, true, true, Some(AccountType::Program), Some(2))?;
+    validate_account(accounts, 1, false, true, None, None)?;
+    validate_account(accounts, 2, false, false, Some(AccountType::Withdraw), Some(0))?;
+    if ProgramState::get_utxo_registry_key(&accounts[0])?.is_some() {
+        return Err(ProgramError::Custom(ERROR_ALREADY_INITIALIZED));
+    }
+    if WithdrawState::is_in_progress(&accounts[2])? {
+        return Err(ProgramError::Custom(ERROR_WITHDRAWAL_IN_PROGRESS));
+    }
+    UtxoRegistryState::initialize(&accounts[1], accounts[0].key)?;
+    ProgramState::set_utxo_registry(&accounts[0], accounts[1].key)
+}
+
+pub fn register_utxos(program_id: &Pubkey, accounts: &[AccountInfo], params: &RegisterUtxosParams) -> Result<(), ProgramError> {
+    validate_account(accounts, 0, true, false, Some(AccountType::Program), None)?;
+    let utxo_registry = get_utxo_registry(accounts)?
+        .ok_or(ProgramError::Custom(ERROR_UTXO_REGISTRY_MISSING))?;
+    let program_scripts = get_program_scripts(program_id, &accounts[0])?;
+    for utxo in &params.utxos {
+        let tx_bytes = get_bitcoin_tx(utxo.tx_id)
+            .ok_or(ProgramError::Custom(ERROR_PREVOUT_NOT_FOUND))?;
+        let tx: Transaction = bitcoin::consensus::deserialize(&tx_bytes)
+            .map_err(|_| ProgramError::Custom(ERROR_INVALID_INPUT_TX))?;
+        let output = tx.output.get(utxo.vout as usize)
+            .ok_or(ProgramError::Custom(ERROR_PREVOUT_NOT_FOUND))?;
+        if !program_scripts.contains(&output.script_pubkey) {
+            return Err(ProgramError::Custom(ERROR_INVALID_UTXO_OWNER));
+        }
+        UtxoRegistryState::add_utxo(utxo_registry, utxo)?;
+    }
+    Ok(())
+}
+
+// Lets a wallet ask for a withdrawal without the operator. If the operator does not serve the
+// request within the delay of the registry, the wallet can withdraw itself with ForcedWithdraw
+// and settlements for it are refused. Runes can't be withdrawn this way.
+pub fn request_forced_withdrawal(program_id: &Pubkey, accounts: &[AccountInfo], params: &RequestForcedWithdrawalParams) -> Result<(), ProgramError> {
+    validate_account(accounts, 0, false, true, Some(AccountType::Program), None)?;
+    validate_account(accounts, params.account_index, false, false, Some(AccountType::Token), Some(0))?;
+    let (registry, block_height) = get_forced_withdrawals(accounts)?
+        .ok_or(ProgramError::Custom(ERROR_FORCED_WITHDRAWALS_MISSING))?;
+    let token_account = &accounts[params.account_index as usize];
+    if TokenState::is_rune_account(token_account) {
+        return Err(ProgramError::Custom(ERROR_WITHDRAWAL_NOT_ALLOWED));
+    }
+    let network_type = ProgramState::get_network_type(&accounts[0]);
+    let (balance_account, index) = get_validated_index_withdraw(program_id, accounts, params.account_index, &params.address_index, &network_type)?;
+    if params.amount == 0 || params.amount > Balance::get_wallet_balance(balance_account, index)? {
+        return Err(ProgramError::Custom(ERROR_INSUFFICIENT_BALANCE));
+    }
+    let wallet_address = Balance::get_wallet_address(balance_account, index)?;
+    let message = params.message(accounts[0].key, token_account.key, &wallet_address.to_address_string()?);
+    verify_wallet_signature(&wallet_address, &message, &params.signature)?;
+
+    let key = ForcedWithdrawalState::entry_key(token_account.key, &wallet_address);
+    if let Some(entry) = ForcedWithdrawalState::get_entry(registry, &key)? {
+        if entry.pending {
+            return Err(ProgramError::Custom(ERROR_FORCED_WITHDRAWAL_PENDING));
+        }
+        if params.nonce <= entry.nonce {
+            return Err(ProgramError::Custom(ERROR_NONCE_USED));
+        }
+    }
+    let due_height = checked_add_amount(block_height, ForcedWithdrawalState::get_delay_blocks(registry)?)?;
+    ForcedWithdrawalState::set_entry(registry, &ForcedWithdrawalEntry {
+        key,
+        amount: params.amount,
+        nonce: params.nonce,
+        due_height,
+        pending: true,
+    })?;
+
+    ProgramState::clear_events(&accounts[0])?;
+    ProgramState::emit_event(
+        accounts,
+        &Event::ForcedWithdrawalRequested {
+            sequence: ProgramState::next_event_sequence(&accounts[0])?,
+            account_index: params.account_index,
+            address_index: params.address_index.index,
+            amount: params.amount,
+            due_height,
+        },
+    )
+}
+
+// Pays out an overdue forced withdrawal request. The wallet passes program owned utxos to spend,
+// the program adds the payout and its change and signs all inputs. The network fee taken from the
+// payout has to be signed by the wallet, since anyone can send the instruction. Pausing does not
+// stop it, but a prepared settlement batch may count on the balance and a withdrawal batch in
+// progress on the program utxos, so the payout waits until they are done.
+pub fn forced_withdraw(program_id: &Pubkey, accounts: &[AccountInfo], params: &ForcedWithdrawParams) -> Result<(), ProgramError> {
+    validate_account(accounts, 0, false, true, Some(AccountType::Program), None)?;
+    if ProgramState::get_settlement_hash(&accounts[0])? != EMPTY_HASH {
+        return Err(ProgramError::Custom(ERROR_SETTLEMENT_IN_PROGRESS));
+    }
+    if WithdrawState::is_in_progress(get_withdraw_state(accounts)?)? {
+        return Err(ProgramError::Custom(ERROR_WITHDRAWAL_IN_PROGRESS));
+    }
+    validate_account(accounts, params.account_index, false, true, Some(AccountType::Token), Some(0))?;
+    let (registry, block_height) = get_forced_withdrawals(accounts)?
+        .ok_or(ProgramError::Custom(ERROR_FORCED_WITHDRAWALS_MISSING))?;
+    let token_account = &accounts[params.account_index as usize];
+    let network_type = ProgramState::get_network_type(&accounts[0]);
+    let (balance_account, index) = get_validated_index_withdraw(program_id, accounts, params.account_index, &params.address_index, &network_type)?;
+    let wallet_address = Balance::get_wallet_address(balance_account, index)?;
+    let key = ForcedWithdrawalState::entry_key(token_account.key, &wallet_address);
+    let mut entry = match ForcedWithdrawalState::get_entry(registry, &key)? {
+        Some(entry) if entry.pending => entry,
+        _ => return Err(ProgramError::Custom(ERROR_NO_FORCED_WITHDRAWAL)),
+    };
+    if block_height < entry.due_height {
+        return Err(ProgramError::Custom(ERROR_FORCED_WITHDRAWAL_NOT_DUE));
+    }
+    let message = params.message(accounts[0].key, token_account.key, &wallet_address.to_address_string()?, entry.amount, entry.nonce);
+    verify_wallet_signature(&wallet_address, &message, &params.signature)?;
+
+    let amount = entry.amount.min(Balance::get_wallet_balance(balance_account, index)?);
+    let payout = amount.checked_sub(params.network_fee)
+        .filter(|payout| *payout >= DUST_THRESHOLD)
+        .ok_or(ProgramError::Custom(ERROR_INVALID_FORCED_WITHDRAWAL_TX))?;
+
+    let mut tx: Transaction = bitcoin::consensus::deserialize(&params.tx_hex)
+        .map_err(|_| ProgramError::Custom(ERROR_INVALID_INPUT_TX))?;
+    if tx.output.len() > 0 {
+        return Err(ProgramError::Custom(ERROR_NO_OUTPUTS_ALLOWED));
+    }
+    if tx.input.is_empty() {
+        return Err(ProgramError::Custom(ERROR_INVALID_FORCED_WITHDRAWAL_TX));
+    }
+    let program_script = ScriptBuf::from_bytes(get_account_script_pubkey(program_id).to_vec());
+    let mut input_total: u64 = 0;
+    for input in &tx.input {
+        let mut prev_tx_id = input.previous_output.txid.to_byte_array();
+        prev_tx_id.reverse();
+        let prev_tx_bytes = get_bitcoin_tx(prev_tx_id)
+            .ok_or(ProgramError::Custom(ERROR_INVALID_FORCED_WITHDRAWAL_TX))?;
+        let prev_tx: Transaction = bitcoin::consensus::deserialize(&prev_tx_bytes)
+            .map_err(|_| ProgramError::Custom(ERROR_INVALID_INPUT_TX))?;
+        let prev_output = prev_tx.output.get(input.previous_output.vout as usize)
+            .filter(|output| output.script_pubkey == program_script)
+            .ok_or(ProgramError::Custom(ERROR_INVALID_FORCED_WITHDRAWAL_TX))?;
+        input_total = checked_add_amount(input_total, prev_output.value.to_sat())?;
+    }
+    // the network fee comes out of the withdrawn amount, everything else goes back to the program
+    let change = input_total.checked_sub(amount)
+        .filter(|change| *change == 0 || *change >= DUST_THRESHOLD)
+        .ok_or(ProgramError::Custom(ERROR_INVALID_FORCED_WITHDRAWAL_TX))?;
+    tx.output.push(
+        TxOut {
+            value: Amount::from_sat(payout),
+            script_pubkey: wallet_address.script_pubkey()?,
+        }
+    );
+    if change > 0 {
+        tx.output.push(
+            TxOut {
+                value: Amount::from_sat(change),
+                script_pubkey: program_script,
+            }
+        );
+    }
+
+    let inputs_to_sign: Vec<InputToSign> = (0..tx.input.len())
+        .map(|index| InputToSign {
+            index: index as u32,
+            signer: *program_id,
+        })
+        .collect();
+    let tx_to_sign = TransactionToSign {
+        tx_bytes: &bitcoin::consensus::serialize(&tx),
+        inputs_to_sign: &inputs_to_sign,
+    };
+    set_transaction_to_sign(&[], tx_to_sign)?;
+
+    Balance::decrement_wallet_balance(balance_account, index, amount)?;
+    entry.pending = false;
+    ForcedWithdrawalState::set_entry(registry, &entry)?;
+
+    let mut tx_id = tx.compute_txid().to_byte_array();
+    tx_id.reverse();
+    if let Some(utxo_registry) = get_utxo_registry(accounts)? {
+        // locking first refuses inputs reserved by a prepared withdrawal batch
+        for input in &tx.input {
+            UtxoRegistryState::lock_utxo(utxo_registry, &input_outpoint(input))?;
+        }
+        for input in &tx.input {
+            UtxoRegistryState::spend_utxo(utxo_registry, &input_outpoint(input))?;
+        }
+        register_program_outputs(utxo_registry, program_id, &accounts[0], tx_id, &tx.output, 0, false)?;
+    }
+    ProgramState::clear_events(&accounts[0])?;
+    ProgramState::emit_event(
+        accounts,
+        &Event::ForcedWithdrawalExecuted {
+            sequence: ProgramState::next_event_sequence(&accounts[0])?,
+            account_index: params.account_index,
+            address_index: params.address_index.index,
+            amount,
+            tx_id,
+        },
+    )
+}
+
 
 pub fn init_rune_receiver_state(accounts: &[AccountInfo]) -> Result<(), ProgramError> {
     validate_account(accounts, 0, true, true, Some(AccountType::Program), None)?;
@@ -103,37 +437,476 @@ pub fn init_rune_receiver_state(accounts: &[AccountInfo]) -> Result<(), ProgramE
     RuneReceiverState::initialize(accounts, 1)
 }
 
+pub fn init_deposit_ledger(accounts: &[AccountInfo]) -> Result<(), ProgramError> {
+    validate_account(accounts, 0, true, true, Some(AccountType::Program), None)?;
+    validate_account(accounts, 1, false, true, None, None)?;
+    match ProgramState::get_deposit_ledger_key(&accounts[0])? {
+        None => ProgramState::set_deposit_ledger(&accounts[0], accounts[1].key)?,
+        Some(_) => {
+            // new ledgers are chained to the current last one, which should happen once
+            // deposit batches start emitting DepositLedgerLow
+            validate_account(accounts, 2, false, true, Some(AccountType::DepositLedger), Some(0))?;
+            if DepositLedgerState::get_next_ledger_key(&accounts[2])?.is_some() {
+                return Err(ProgramError::Custom(ERROR_STATE_ACCOUNT_MISMATCH));
+            }
+            DepositLedgerState::set_next_ledger_key(&accounts[2], accounts[1].key)?;
+        }
+    }
+    DepositLedgerState::initialize(&accounts[1], accounts[0].key)
+}
+
+pub fn init_event_log(accounts: &[AccountInfo]) -> Result<(), ProgramError> {
+    validate_account(accounts, 0, true, true, Some(AccountType::Program), None)?;
+    validate_account(accounts, 1, false, true, None, None)?;
+    match ProgramState::get_event_log_key(&accounts[0])? {
+        None => {
+            ProgramState::set_first_event_log(&accounts[0], accounts[1].key)?;
+            ProgramState::set_event_log(&accounts[0], accounts[1].key)?;
+        }
+        Some(_) => {
+            // new logs are chained to the last one and only written to once the logs before are full
+            validate_account(accounts, 2, false, true, Some(AccountType::EventLog), Some(0))?;
+            if EventLogState::get_next_event_log_key(&accounts[2])?.is_some() {
+                return Err(ProgramError::Custom(ERROR_STATE_ACCOUNT_MISMATCH));
+            }
+            EventLogState::set_next_event_log_key(&accounts[2], accounts[1].key)?;
+        }
+    }
+    EventLogState::initialize(&accounts[1], accounts[0].key)
+}
+
+// balances registered before the index existed are indexed in as many instructions as needed,
+// see index_wallet_balances
+pub fn init_wallet_index(accounts: &[AccountInfo]) -> Result<(), ProgramError> {
+    validate_account(accounts, 0, true, false, Some(AccountType::Program), None)?;
+    validate_account(accounts, 1, false, true, Some(AccountType::Token), Some(0))?;
+    validate_account(accounts, 2, false, true, None, None)?;
+    if TokenState::get_wallet_index_key(&accounts[1])?.is_some() {
+        return Err(ProgramError::Custom(ERROR_ALREADY_INITIALIZED));
+    }
+    // only the token state itself is indexed, so the index has to exist before the first shard
+    if TokenState::get_num_balance_shards(&accounts[1])? != 0 {
+        return Err(ProgramError::Custom(ERROR_TOKEN_SHARDED));
+    }
+    WalletIndexState::initialize(&accounts[2], accounts[1].key)?;
+    TokenState::set_wallet_index(&accounts[1], accounts[2].key)?;
+    index_balances(&accounts[1], &accounts[2])
+}
+
+pub fn index_wallet_balances(accounts: &[AccountInfo]) -> Result<(), ProgramError> {
+    validate_account(accounts, 0, true, false, Some(AccountType::Program), None)?;
+    validate_account(accounts, 1, false, false, Some(AccountType::Token), Some(0))?;
+    validate_account(accounts, 2, false, true, Some(AccountType::WalletIndex), Some(1))?;
+    if TokenState::get_wallet_index_key(&accounts[1])? != Some(*accounts[2].key) {
+        return Err(ProgramError::Custom(ERROR_STATE_ACCOUNT_MISMATCH));
+    }
+    index_balances(&accounts[1], &accounts[2])
+}
+
+// once the token state cannot grow any further new wallets are added to the last balance shard
+pub fn init_balance_shard(accounts: &[AccountInfo]) -> Result<(), ProgramError> {
+    validate_account(accounts, 0, true, false, Some(AccountType::Program), None)?;
+    validate_account(accounts, 1, false, true, Some(AccountType::Token), Some(0))?;
+    validate_account(accounts, 2, false, true, None, None)?;
+    if let Some(last_balance_shard_key) = TokenState::get_last_balance_shard_key(&accounts[1])? {
+        validate_account(accounts, 3, false, true, Some(AccountType::BalanceShard), Some(1))?;
+        if *accounts[3].key != last_balance_shard_key {
+            return Err(ProgramError::Custom(ERROR_STATE_ACCOUNT_MISMATCH));
+        }
+        BalanceShardState::set_next_balance_shard_key(&accounts[3], accounts[2].key)?;
+    }
+    let shard = TokenState::add_balance_shard(&accounts[1], accounts[2].key)?;
+    BalanceShardState::initialize(&accounts[2], accounts[1].key, shard)
+}
+
+// all token accounts have to be passed so the fee balance of each token moves to the new address,
+// together with the wallet index of every indexed token
+pub fn update_fee_account(accounts: &[AccountInfo], params: &UpdateFeeAccountParams) -> Result<(), ProgramError> {
+    validate_account(accounts, 0, true, true, Some(AccountType::Program), None)?;
+    let network_type = ProgramState::get_network_type(&accounts[0]);
+    validate_bitcoin_address(&params.fee_account, &network_type, true)?;
+    if ProgramState::get_settlement_hash(&accounts[0])? != EMPTY_HASH {
+        return Err(ProgramError::Custom(ERROR_SETTLEMENT_IN_PROGRESS));
+    }
+    let previous_fee_account = WalletAddress::from_address(&ProgramState::get_fee_account_address(&accounts[0])?)?;
+    let fee_account = WalletAddress::from_address(&params.fee_account)?;
+    for index in 1..accounts.len() {
+        if !accounts[index].data_is_empty() && get_type(&accounts[index])? == AccountType::WalletIndex {
+            continue;
+        }
+        validate_account(accounts, index as u8, false, true, Some(AccountType::Token), Some(0))?;
+        let account = &accounts[index];
+        // rune tokens do not reserve the first balance for fees unless the fee account was added to them
+        if TokenState::get_num_balances(account)? > FEE_ADDRESS_INDEX as usize
+            && Balance::get_wallet_address(account, FEE_ADDRESS_INDEX as usize)? == previous_fee_account {
+            Balance::set_wallet_address(account, FEE_ADDRESS_INDEX as usize, &fee_account)?;
+            if let Some(index_account) = get_wallet_index_account(accounts, account)? {
+                if WalletIndexState::get_num_indexed_balances(index_account)? > FEE_ADDRESS_INDEX as usize {
+                    reindex_fee_address(index_account, &previous_fee_account, &fee_account)?;
+                }
+            }
+        }
+    }
+    ProgramState::set_fee_account_address(&accounts[0], &params.fee_account)
+}
 
-pub fn init_wallet_balances(accounts: &[AccountInfo], params: &InitWalletBalancesParams) -> Result<(), ProgramError> {
+pub fn update_program_change_address(accounts: &[AccountInfo], params: &UpdateProgramChangeAddressParams) -> Result<(), ProgramError> {
+    validate_account(accounts, 0, true, true, Some(AccountType::Program), None)?;
+    let network_type = ProgramState::get_network_type(&accounts[0]);
+    validate_bitcoin_address(&params.program_change_address, &network_type, true)?;
+    ProgramState::set_program_change_address(&accounts[0], &params.program_change_address)
+}
+
+pub fn update_withdraw_account(accounts: &[AccountInfo]) -> Result<(), ProgramError> {
+    validate_account(accounts, 0, true, true, Some(AccountType::Program), Some(2))?;
+    validate_account(accounts, 1, false, true, None, None)?;
+    validate_account(accounts, 2, false, false, Some(AccountType::Withdraw), Some(0))?;
+    if WithdrawState::is_in_progress(&accounts[2])? {
+        return Err(ProgramError::Custom(ERROR_WITHDRAWAL_IN_PROGRESS));
+    }
+    WithdrawState::initialize(accounts)?;
+    ProgramState::set_withdraw_account_key(&accounts[0], accounts[1].key)
+}
+
+pub fn pause(accounts: &[AccountInfo], params: &PauseParams) -> Result<(), ProgramError> {
+    validate_account(accounts, 0, true, true, Some(AccountType::Program), None)?;
+    let pause_flags = ProgramState::get_pause_flags(&accounts[0])?;
+    ProgramState::set_pause_flags(&accounts[0], pause_flags | (params.pause_flags & PAUSE_ALL))
+}
+
+pub fn unpause(accounts: &[AccountInfo], params: &PauseParams) -> Result<(), ProgramError> {
+    validate_account(accounts, 0, true, true, Some(AccountType::Program), None)?;
+    let pause_flags = ProgramState::get_pause_flags(&accounts[0])?;
+    ProgramState::set_pause_flags(&accounts[0], pause_flags & !params.pause_flags)
+}
+
+pub fn migrate_account(accounts: &[AccountInfo], params: &MigrateAccountParams) -> Result<(), ProgramError> {
+    validate_account_any_version(accounts, 0, true, true, Some(AccountType::Program), None)?;
+    if params.account_index != 0 {
+        if params.account_index as usize >= accounts.len() {
+            return Err(ProgramError::Custom(ERROR_INVALID_ACCOUNT_INDEX));
+        }
+        let account_type = get_type(&accounts[params.account_index as usize])?;
+        if account_type == AccountType::Program {
+            return Err(ProgramError::Custom(ERROR_INVALID_ACCOUNT_TYPE));
+        }
+        let related_account_index = match account_type {
+            // wallet indexes, balance shards and withdrawal limits belong to a token state, which has
+            // to be passed as well
+            AccountType::WalletIndex | AccountType::BalanceShard | AccountType::WithdrawalLimit => get_owning_token_index(accounts, params.account_index)?,
+            _ => 0,
+        };
+        validate_account_any_version(accounts, params.account_index, false, true, Some(account_type), Some(related_account_index))?;
+    }
+    let version = model::migration::migrate_account(&accounts[params.account_index as usize])?;
+    msg!("migrated account {} to version {}", params.account_index, version);
+    Ok(())
+}
+
+
+// wallet indexes, balance shards and withdrawal limits all keep the key of their token state where
+// other accounts keep the program state key
+fn get_owning_token_index(accounts: &[AccountInfo], account_index: u8) -> Result<u8, ProgramError> {
+    let token_key = WalletIndexState::get_token_state_account_key(&accounts[account_index as usize])?;
+    let token_index = accounts.iter()
+        .position(|account| *account.key == token_key)
+        .ok_or(ProgramError::Custom(ERROR_STATE_ACCOUNT_MISMATCH))?;
+    let token_account = &accounts[token_index];
+    if get_type(token_account)? != AccountType::Token
+        || TokenState::get_program_state_account_key(token_account)? != *accounts[0].key {
+        return Err(ProgramError::Custom(ERROR_STATE_ACCOUNT_MISMATCH));
+    }
+    Ok(token_index as u8)
+}
+
+pub fn init_wallet_balances(program_id: &Pubkey, accounts: &[AccountInfo], params: &InitWalletBalancesParams) -> Result<(), ProgramError> {
     validate_account(accounts, 0, true, false, Some(AccountType::Program), None)?;
     let network_type = ProgramState::get_network_type(&accounts[0]);
     for token_state_setup in &params.token_state_setups {
         validate_account(accounts, token_state_setup.account_index, false, true, Some(AccountType::Token), Some(0))?;
         let account = &accounts[token_state_setup.account_index as usize];
-        TokenState::grow_balance_accounts_if_needed(account, token_state_setup.wallet_addresses.len())?;
-        let mut num_balances = TokenState::get_num_balances(account)?;
+        let shard = TokenState::get_num_balance_shards(account)? as u8;
+        let balance_account = get_balance_account(program_id, accounts, token_state_setup.account_index, shard)?;
+        TokenState::grow_balance_accounts_if_needed(balance_account, token_state_setup.wallet_addresses.len())?;
+        let mut num_balances = TokenState::get_num_balances(balance_account)?;
+        let index_account = get_complete_wallet_index_account(accounts, account)?;
         for wallet_address in &token_state_setup.wallet_addresses {
             validate_bitcoin_address(wallet_address, &network_type, false)?;
-            Balance::set_wallet_address(account, num_balances, &wallet_address)?;
-            num_balances += 1;
+            let wallet_address = WalletAddress::from_address(wallet_address)?;
+            let address_hash = wallet_address.hash();
+            let index_position = match index_account {
+                // wallets that are already registered keep their balance
+                Some(index_account) => match WalletIndexState::find(index_account, &address_hash)? {
+                    Ok(_) => continue,
+                    Err(position) => Some((index_account, position)),
+                },
+                None => None,
+            };
+            // closed balances are reused before the token state or its last shard grows
+            let (target_account, target_shard, balance_index) = match TokenState::take_free_balance(account)? {
+                Some(balance_index) => (account, 0, balance_index),
+                None => {
+                    num_balances += 1;
+                    (balance_account, shard, num_balances - 1)
+                }
+            };
+            if let Some((index_account, position)) = index_position {
+                let packed_index = AddressIndex::pack(target_shard, balance_index) as usize;
+                if !WalletIndexState::insert(index_account, position, &address_hash, packed_index)? {
+                    return Err(ProgramError::Custom(ERROR_WALLET_INDEX_FULL));
+                }
+            }
+            Balance::set_wallet_address(target_account, balance_index, &wallet_address)?;
+        }
+        TokenState::set_num_balances(balance_account, num_balances)?;
+        if let Some(index_account) = index_account {
+            WalletIndexState::set_num_indexed_balances(index_account, TokenState::get_num_balances(account)?)?;
+        }
+    }
+    Ok(())
+}
+
+// Closed balances must be empty, they are put on the free list of the token and reused by
+// init_wallet_balances. No batch may be in progress since batches refer to balances by index.
+pub fn close_wallet_balance(program_id: &Pubkey, accounts: &[AccountInfo], params: &CloseWalletBalanceParams) -> Result<(), ProgramError> {
+    validate_account(accounts, 0, true, true, Some(AccountType::Program), Some(1))?;
+    validate_account(accounts, 1, false, false, Some(AccountType::Withdraw), Some(0))?;
+    verify_no_batch_in_progress(accounts)?;
+    ProgramState::clear_events(&accounts[0])?;
+    validate_account(accounts, params.account_index, false, true, Some(AccountType::Token), Some(0))?;
+    let account = &accounts[params.account_index as usize];
+    let index_account = get_complete_wallet_index_account(accounts, account)?;
+    for address_index in &params.address_indexes {
+        // only balances of the token state itself go on the free list
+        if address_index.shard() != 0 {
+            return Err(ProgramError::Custom(ERROR_INVALID_ADDRESS_INDEX));
+        }
+        let (_, index) = get_validated_index(program_id, accounts, params.account_index, address_index)?;
+        if index == FEE_ADDRESS_INDEX as usize {
+            return Err(ProgramError::Custom(ERROR_INVALID_ADDRESS_INDEX));
+        }
+        if Balance::get_wallet_balance(account, index)? != 0 {
+            return Err(ProgramError::Custom(ERROR_BALANCE_NOT_EMPTY));
+        }
+        if let Some(index_account) = index_account {
+            let address_hash = Balance::get_wallet_address(account, index)?.hash();
+            if let Ok(position) = WalletIndexState::find(index_account, &address_hash)? {
+                // a wallet registered twice before the index existed is only indexed at its first balance
+                if WalletIndexState::get_balance_index(index_account, position)? == index {
+                    WalletIndexState::remove(index_account, position)?;
+                }
+            }
+        }
+        TokenState::free_balance(account, index)?;
+        ProgramState::emit_event(
+            accounts,
+            &Event::WalletBalanceClosed {
+                sequence: ProgramState::next_event_sequence(&accounts[0])?,
+                account_index: params.account_index,
+                address_index: address_index.index,
+            },
+        )?;
+    }
+    Ok(())
+}
+
+// Moves the last balances into the free ones and shrinks the token state, every move is
+// reported with a BalanceIndexRemapped event.
+pub fn compact_balances(accounts: &[AccountInfo], params: &CompactBalancesParams) -> Result<(), ProgramError> {
+    validate_account(accounts, 0, true, true, Some(AccountType::Program), Some(1))?;
+    validate_account(accounts, 1, false, false, Some(AccountType::Withdraw), Some(0))?;
+    verify_no_batch_in_progress(accounts)?;
+    ProgramState::clear_events(&accounts[0])?;
+    validate_account(accounts, params.account_index, false, true, Some(AccountType::Token), Some(0))?;
+    let account = &accounts[params.account_index as usize];
+    let index_account = get_complete_wallet_index_account(accounts, account)?;
+    let mut free_balances = TokenState::take_free_balances(account)?;
+    free_balances.sort_unstable();
+    let mut num_balances = TokenState::get_num_balances(account)?;
+    // free balances in [next_free, end) are still to be filled or dropped
+    let (mut next_free, mut end) = (0, free_balances.len());
+    while next_free < end {
+        let last = num_balances - 1;
+        num_balances -= 1;
+        if free_balances[end - 1] == last {
+            end -= 1;
+            continue;
+        }
+        let to = free_balances[next_free];
+        next_free += 1;
+        let wallet_address = Balance::get_wallet_address(account, last)?;
+        Balance::set_wallet_address(account, to, &wallet_address)?;
+        Balance::set_wallet_balance(account, to, Balance::get_wallet_balance(account, last)?)?;
+        if let Some(index_account) = index_account {
+            if let Ok(position) = WalletIndexState::find(index_account, &wallet_address.hash())? {
+                if WalletIndexState::get_balance_index(index_account, position)? == last {
+                    WalletIndexState::set_balance_index(index_account, position, to)?;
+                }
+            }
         }
-        TokenState::set_num_balances(account, num_balances)?;
+        ProgramState::emit_event(
+            accounts,
+            &Event::BalanceIndexRemapped {
+                sequence: ProgramState::next_event_sequence(&accounts[0])?,
+                account_index: params.account_index,
+                from_address_index: last as u32,
+                to_address_index: to as u32,
+            },
+        )?;
+    }
+    TokenState::set_num_balances(account, num_balances)?;
+    if let Some(index_account) = index_account {
+        WalletIndexState::set_num_indexed_balances(index_account, num_balances)?;
+    }
+    msg!("compacted to {} balances", num_balances);
+    account.realloc(BALANCES_OFFSET + num_balances * BALANCE_SIZE, true)
+}
+
+fn verify_no_batch_in_progress(accounts: &[AccountInfo]) -> Result<(), ProgramError> {
+    if ProgramState::get_settlement_hash(&accounts[0])? != EMPTY_HASH {
+        return Err(ProgramError::Custom(ERROR_SETTLEMENT_IN_PROGRESS));
+    }
+    if WithdrawState::is_in_progress(&accounts[1])? {
+        return Err(ProgramError::Custom(ERROR_WITHDRAWAL_IN_PROGRESS));
     }
     Ok(())
 }
 
+// balances can only be added, closed or moved once the whole token state is indexed
+fn get_complete_wallet_index_account<'a, 'b>(accounts: &'a [AccountInfo<'b>], token_account: &AccountInfo) -> Result<Option<&'a AccountInfo<'b>>, ProgramError> {
+    let index_account = get_wallet_index_account(accounts, token_account)?;
+    if let Some(index_account) = index_account {
+        if WalletIndexState::get_num_indexed_balances(index_account)? != TokenState::get_num_balances(token_account)? {
+            return Err(ProgramError::Custom(ERROR_WALLET_INDEX_INCOMPLETE));
+        }
+    }
+    Ok(index_account)
+}
+
+// tokens without a wallet index keep appending every address that is registered
+fn get_wallet_index_account<'a, 'b>(accounts: &'a [AccountInfo<'b>], token_account: &AccountInfo) -> Result<Option<&'a AccountInfo<'b>>, ProgramError> {
+    let Some(index_key) = TokenState::get_wallet_index_key(token_account)? else {
+        return Ok(None);
+    };
+    let index_account = accounts.iter()
+        .find(|account| *account.key == index_key)
+        .ok_or(ProgramError::Custom(ERROR_WALLET_INDEX_MISSING))?;
+    if index_account.is_signer || !index_account.is_writable {
+        return Err(ProgramError::Custom(ERROR_INVALID_ACCOUNT_FLAGS));
+    }
+    if get_type(index_account)? != AccountType::WalletIndex {
+        return Err(ProgramError::Custom(ERROR_INVALID_ACCOUNT_TYPE));
+    }
+    if get_version(index_account)? != WALLET_INDEX_STATE_VERSION {
+        return Err(ProgramError::Custom(ERROR_INVALID_ACCOUNT_VERSION));
+    }
+    if WalletIndexState::get_token_state_account_key(index_account)? != *token_account.key {
+        return Err(ProgramError::Custom(ERROR_STATE_ACCOUNT_MISMATCH));
+    }
+    Ok(Some(index_account))
+}
 
-pub fn deposit_batch(accounts: &[AccountInfo],
+// indexes balances until all are covered or the index cannot grow any further in this
+// instruction, a wallet that was registered more than once is found at its first balance
+fn index_balances(token_account: &AccountInfo, index_account: &AccountInfo) -> Result<(), ProgramError> {
+    let num_balances = TokenState::get_num_balances(token_account)?;
+    let mut num_indexed = WalletIndexState::get_num_indexed_balances(index_account)?;
+    while num_indexed < num_balances {
+        if TokenState::is_free_balance(token_account, num_indexed)? {
+            num_indexed += 1;
+            continue;
+        }
+        let address_hash = Balance::get_wallet_address(token_account, num_indexed)?.hash();
+        if let Err(position) = WalletIndexState::find(index_account, &address_hash)? {
+            if !WalletIndexState::insert(index_account, position, &address_hash, num_indexed)? {
+                break;
+            }
+        }
+        num_indexed += 1;
+    }
+    msg!("indexed {} of {} balances", num_indexed, num_balances);
+    WalletIndexState::set_num_indexed_balances(index_account, num_indexed)
+}
+
+fn reindex_fee_address(index_account: &AccountInfo, previous_fee_account: &WalletAddress, fee_account: &WalletAddress) -> Result<(), ProgramError> {
+    if let Ok(position) = WalletIndexState::find(index_account, &previous_fee_account.hash())? {
+        if WalletIndexState::get_balance_index(index_account, position)? == FEE_ADDRESS_INDEX as usize {
+            WalletIndexState::remove(index_account, position)?;
+        }
+    }
+    let address_hash = fee_account.hash();
+    if let Err(position) = WalletIndexState::find(index_account, &address_hash)? {
+        if !WalletIndexState::insert(index_account, position, &address_hash, FEE_ADDRESS_INDEX as usize)? {
+            return Err(ProgramError::Custom(ERROR_WALLET_INDEX_FULL));
+        }
+    }
+    Ok(())
+}
+
+
+pub fn deposit_batch(program_id: &Pubkey,
+                     accounts: &[AccountInfo],
                      params: &DepositBatchParams) -> Result<(), ProgramError> {
-    validate_account(accounts, 0, true, false, Some(AccountType::Program), None)?;
+    validate_account(accounts, 0, true, true, Some(AccountType::Program), None)?;
+    ProgramState::verify_not_paused(&accounts[0], PAUSE_DEPOSITS)?;
+    ProgramState::clear_events(&accounts[0])?;
+    let ledger_accounts = get_deposit_ledger_accounts(accounts)?;
+    let utxo_registry = get_utxo_registry(accounts)?;
     for token_deposits in &params.token_deposits {
         validate_account(accounts, token_deposits.account_index, false, true, Some(AccountType::Token), Some(0))?;
-        handle_increments(&accounts[token_deposits.account_index as usize], &token_deposits.deposits)?;
+        let account = &accounts[token_deposits.account_index as usize];
+        for deposit in &token_deposits.deposits {
+            let (balance_account, index) = get_validated_index(program_id, accounts, token_deposits.account_index, &deposit.address_index)?;
+            if let Some(funding_outpoint) = &deposit.funding_outpoint {
+                if !ledger_accounts.is_empty() && is_deposit_recorded(&ledger_accounts, funding_outpoint)? {
+                    ProgramState::emit_event(
+                        accounts,
+                        &Event::DuplicateDeposit {
+                            account_index: token_deposits.account_index,
+                            address_index: deposit.address_index.index,
+                            amount: deposit.amount,
+                            tx_id: funding_outpoint.tx_id,
+                            vout: funding_outpoint.vout,
+                        },
+                    )?;
+                    continue;
+                }
+                verify_deposit_funding(program_id, &accounts[0], account, funding_outpoint, deposit.amount)?;
+                if let Some(ledger_account) = ledger_accounts.last() {
+                    record_deposit(ledger_account, funding_outpoint)?;
+                }
+                if let Some(utxo_registry) = utxo_registry {
+                    UtxoRegistryState::add_utxo(utxo_registry, funding_outpoint)?;
+                }
+            }
+            Balance::increment_wallet_balance(balance_account, index, deposit.amount)?;
+            ProgramState::emit_event(
+                accounts,
+                &Event::DepositCredited {
+                    sequence: ProgramState::next_event_sequence(&accounts[0])?,
+                    account_index: token_deposits.account_index,
+                    address_index: deposit.address_index.index,
+                    amount: deposit.amount,
+                },
+            )?;
+        }
+    }
+    if let Some(ledger_account) = ledger_accounts.last() {
+        let remaining_deposits = DepositLedgerState::get_remaining_deposits(ledger_account)?;
+        if remaining_deposits <= LEDGER_LOW_WATERMARK {
+            ProgramState::emit_event(
+                accounts,
+                &Event::DepositLedgerLow { remaining_deposits: remaining_deposits as u32 },
+            )?;
+        }
     }
     Ok(())
 }
 
-pub fn prepare_withdraw_batch(accounts: &[AccountInfo], params: &WithdrawBatchParams, params_raw_data: &[u8]) -> Result<(), ProgramError> {
+// In partial mode failing withdrawals are dropped and reported instead of failing the whole batch.
+// The value of their outputs goes to the change, and the hash of the remaining batch is stored,
+// so the remaining batch is what has to be submitted.
+pub fn prepare_withdraw_batch(program_id: &Pubkey, accounts: &[AccountInfo], params: &WithdrawBatchParams, params_raw_data: &[u8], partial: bool) -> Result<(), ProgramError> {
     validate_account(accounts, 0, true, true, Some(AccountType::Program), Some(1))?;
     validate_account(accounts, 1, false, true, Some(AccountType::Withdraw), Some(0))?;
     let has_rune_receiver = if get_type(&accounts[2])? == AccountType::RuneReceiver {
@@ -142,49 +915,109 @@ pub fn prepare_withdraw_batch(accounts: &[AccountInfo], params: &WithdrawBatchPa
     } else {
         false
     };
+    ProgramState::verify_not_paused(&accounts[0], PAUSE_WITHDRAWALS)?;
     if ProgramState::get_settlement_hash(&accounts[0])? != EMPTY_HASH {
         return Err(ProgramError::Custom(ERROR_SETTLEMENT_IN_PROGRESS));
     }
-    if WithdrawState::get_hash(&accounts[1])? != EMPTY_HASH {
+    if WithdrawState::is_in_progress(&accounts[1])? {
         return Err(ProgramError::Custom(ERROR_WITHDRAWAL_IN_PROGRESS));
     }
 
-    let mut tx: Transaction = bitcoin::consensus::deserialize(&params.tx_hex)
+    let tx_with_inputs: Transaction = bitcoin::consensus::deserialize(&params.tx_hex)
         .map_err(|_| ProgramError::Custom(ERROR_INVALID_INPUT_TX))?;
-    if tx.output.len() > 0 {
+    if tx_with_inputs.output.len() > 0 {
         return Err(ProgramError::Custom(ERROR_NO_OUTPUTS_ALLOWED));
     }
-    if tx.input.len() != params.input_utxo_types.len() {
+    if tx_with_inputs.input.len() != params.input_utxo_types.len() {
         return Err(ProgramError::Custom(ERROR_INVALID_UTXO_TYPES));
     }
+    // the batch is built the way it will be submitted so the fee rate is checked against the
+    // size of the transaction that gets signed
+    let mut tx = withdraw_state_transition_tx(accounts);
+    let state_transition_outputs = tx.output.len();
+    tx.input.extend(tx_with_inputs.input);
 
     ProgramState::clear_events(&accounts[0])?;
     let network_type = ProgramState::get_network_type(&accounts[0]);
 
+    let mut remaining_token_withdrawals = vec![];
+    let mut excluded_output_amount: u64 = 0;
+    // the highest nonce each wallet signed a withdrawal of this batch with so far
+    let mut batch_nonces: HashMap<Hash, u64> = HashMap::new();
+    // what each wallet withdraws of each token in the withdrawals of this batch that passed so far
+    let mut batch_withdrawn: HashMap<(Pubkey, Hash), u64> = HashMap::new();
     for token_withdrawals in &params.token_withdrawals {
         validate_account(accounts, token_withdrawals.account_index, false, true, Some(AccountType::Token), Some(0))?;
-        verify_withdrawals(
+        let failed_indexes = verify_withdrawals(
+            program_id,
             &accounts,
             token_withdrawals.account_index,
             &token_withdrawals.withdrawals,
             &network_type,
+            &mut batch_nonces,
+            &mut batch_withdrawn,
         )?;
+        if partial {
+            let account = &accounts[token_withdrawals.account_index as usize];
+            let mut withdrawals = vec![];
+            for (withdrawal_index, withdrawal) in token_withdrawals.withdrawals.iter().enumerate() {
+                if failed_indexes.contains(&withdrawal_index) {
+                    ProgramState::emit_event(
+                        accounts,
+                        &Event::WithdrawalExcluded {
+                            account_index: token_withdrawals.account_index,
+                            withdrawal_index: withdrawal_index as u16,
+                        },
+                    )?;
+                    // a fee larger than the amount was reported above, such a withdrawal never had an output
+                    let output_amount = match withdrawal_output_amount(account, withdrawal) {
+                        Ok(output_amount) => output_amount,
+                        Err(_) => 0,
+                    };
+                    excluded_output_amount = checked_add_amount(excluded_output_amount, output_amount)?;
+                } else {
+                    withdrawals.push(withdrawal.clone());
+                }
+            }
+            if !withdrawals.is_empty() {
+                remaining_token_withdrawals.push(TokenWithdrawals {
+                    account_index: token_withdrawals.account_index,
+                    withdrawals,
+                });
+            }
+        }
     }
 
-    if ProgramState::get_events_count(&accounts[0])? != 0 {
-        return Ok(());
-    }
+    let remaining_params: WithdrawBatchParams;
+    let params = if partial {
+        if remaining_token_withdrawals.is_empty() {
+            return Ok(());
+        }
+        remaining_params = WithdrawBatchParams {
+            tx_hex: params.tx_hex.clone(),
+            change_amount: checked_add_amount(params.change_amount, excluded_output_amount)?,
+            token_withdrawals: remaining_token_withdrawals,
+            input_utxo_types: params.input_utxo_types.clone(),
+        };
+        &remaining_params
+    } else {
+        if ProgramState::get_events_count(&accounts[0])? != 0 {
+            return Ok(());
+        }
+        params
+    };
 
     let mut edicts: Vec<Edict> = vec![];
 
     // Apply all the changes in the batch
+    let fee_account_address = WalletAddress::from_address(&ProgramState::get_fee_account_address(&accounts[0])?)?;
     for token_withdrawals in &params.token_withdrawals {
         handle_prepare_withdrawals(
+            program_id,
             accounts,
             token_withdrawals,
-            &ProgramState::get_fee_account_address(&accounts[0])?,
+            &fee_account_address,
             &mut tx.output,
-            &network_type,
             &mut edicts,
         )?;
     }
@@ -193,14 +1026,33 @@ pub fn prepare_withdraw_batch(accounts: &[AccountInfo], params: &WithdrawBatchPa
         return Err(ProgramError::Custom(ERROR_NO_RUNE_RECEIVER));
     }
 
-    if tx.output.len() == 0 {
+    if tx.output.len() == state_transition_outputs {
         return Err(ProgramError::Custom(ERROR_NO_TX_OUTPUTS));
     }
-    WithdrawState::set_hash(&accounts[1], hash(params_raw_data))
+    if params.change_amount > 0 && params.change_amount < DUST_THRESHOLD {
+        return Err(ProgramError::Custom(ERROR_INVALID_CHANGE));
+    }
+    add_change_outputs(program_id, accounts, &mut tx.output, edicts, params.change_amount)?;
+    verify_withdraw_fee(program_id, accounts, has_rune_receiver, &tx, state_transition_outputs, &params.input_utxo_types)?;
+    if let Some(utxo_registry) = get_utxo_registry(accounts)? {
+        for input in &tx.input[state_transition_outputs..] {
+            UtxoRegistryState::lock_utxo(utxo_registry, &input_outpoint(input))?;
+        }
+    }
+
+    let batch_hash = if partial {
+        let remaining_params_data = params.encode_to_vec().map_err(|_| ProgramError::InvalidInstructionData)?;
+        hash(&remaining_params_data)
+    } else {
+        hash(params_raw_data)
+    };
+    WithdrawState::set_hash(&accounts[1], batch_hash)?;
+    WithdrawState::set_tx_id(&accounts[1], EMPTY_HASH)?;
+    WithdrawState::set_status(&accounts[1], WithdrawStatus::Prepared)
 }
 
 pub fn submit_withdraw_batch(program_id: &Pubkey, accounts: &[AccountInfo], params: &WithdrawBatchParams, params_raw_data: &[u8]) -> Result<(), ProgramError> {
-    validate_account(accounts, 0, true, false, Some(AccountType::Program), Some(1))?;
+    validate_account(accounts, 0, true, true, Some(AccountType::Program), Some(1))?;
     validate_account(accounts, 1, true, true, Some(AccountType::Withdraw), Some(0))?;
     let has_rune_receiver = if get_type(&accounts[2])? == AccountType::RuneReceiver {
         validate_account(accounts, 2, true, false, Some(AccountType::RuneReceiver), Some(0))?;
@@ -208,29 +1060,173 @@ pub fn submit_withdraw_batch(program_id: &Pubkey, accounts: &[AccountInfo], para
     } else {
         false
     };
+    ProgramState::verify_not_paused(&accounts[0], PAUSE_WITHDRAWALS)?;
 
-    if WithdrawState::get_hash(&accounts[1])? != hash(params_raw_data) {
+    if !WithdrawState::is_prepared(&accounts[1])? || WithdrawState::get_hash(&accounts[1])? != hash(params_raw_data) {
         return Err(ProgramError::Custom(ERROR_WITHDRAWAL_BATCH_MISMATCH));
     }
-    let network_type = ProgramState::get_network_type(&accounts[0]);
+    ProgramState::clear_events(&accounts[0])?;
 
-    let mut tx = get_state_transition_tx(accounts);
+    let mut tx = withdraw_state_transition_tx(accounts);
+    let state_transition_outputs = tx.output.len();
 
     let tx_with_inputs: Transaction = bitcoin::consensus::deserialize(&params.tx_hex)
         .map_err(|_| ProgramError::Custom(ERROR_INVALID_INPUT_TX))?;
     for input in tx_with_inputs.input.iter() {
         tx.input.push(input.clone())
     }
+    // every input signals replaceability so a transaction stuck at too low a fee can be bumped
+    for input in tx.input.iter_mut() {
+        input.sequence = Sequence::ENABLE_RBF_NO_LOCKTIME;
+    }
 
-    let mut edicts: Vec<Edict> = vec![];
+    let forced_withdrawals = get_forced_withdrawals(accounts)?;
+    add_withdraw_batch_outputs(
+        program_id,
+        accounts,
+        params,
+        params.change_amount,
+        has_rune_receiver,
+        forced_withdrawals.map(|(registry, _)| registry),
+        &mut tx,
+    )?;
+
+    let tx_id = sign_withdraw_tx(program_id, accounts, &tx, &params.input_utxo_types)?;
+    if let Some(utxo_registry) = get_utxo_registry(accounts)? {
+        for input in &tx_with_inputs.input {
+            UtxoRegistryState::spend_utxo(utxo_registry, &input_outpoint(input))?;
+        }
+        // the outputs are reserved until the transaction confirms
+        register_program_outputs(utxo_registry, program_id, &accounts[0], tx_id, &tx.output, state_transition_outputs, true)?;
+    }
+
+    ProgramState::emit_event(
+        accounts,
+        &Event::WithdrawalSubmitted {
+            sequence: ProgramState::next_event_sequence(&accounts[0])?,
+            tx_id,
+            num_withdrawals: params.token_withdrawals.iter().map(|t| t.withdrawals.len() as u32).sum(),
+        },
+    )?;
+
+    // the batch hash is kept so the submitted batch can still be identified until it confirms
+    WithdrawState::set_tx_id(&accounts[1], tx_id)?;
+    WithdrawState::set_submitted_height(&accounts[1], get_bitcoin_block_height())?;
+    WithdrawState::set_status(&accounts[1], WithdrawStatus::Submitted)
+}
+
+// Replaces the transaction of a submitted batch that is stuck at too low a fee. The replacement
+// spends the same inputs, including the withdraw account utxo the submitted transaction spent, and
+// pays the same withdrawals, which the batch hash guarantees, so only its change can be lower.
+pub fn bump_withdraw_batch(program_id: &Pubkey, accounts: &[AccountInfo], params: &BumpWithdrawBatchParams) -> Result<(), ProgramError> {
+    validate_account(accounts, 0, true, true, Some(AccountType::Program), Some(1))?;
+    validate_account(accounts, 1, true, true, Some(AccountType::Withdraw), Some(0))?;
+    let has_rune_receiver = if get_type(&accounts[2])? == AccountType::RuneReceiver {
+        validate_account(accounts, 2, true, false, Some(AccountType::RuneReceiver), Some(0))?;
+        true
+    } else {
+        false
+    };
+    ProgramState::verify_not_paused(&accounts[0], PAUSE_WITHDRAWALS)?;
+
+    let batch_data = params.withdraw_batch.encode_to_vec().map_err(|_| ProgramError::InvalidInstructionData)?;
+    if !WithdrawState::is_submitted(&accounts[1])? || WithdrawState::get_hash(&accounts[1])? != hash(&batch_data) {
+        return Err(ProgramError::Custom(ERROR_WITHDRAWAL_BATCH_MISMATCH));
+    }
+    if params.change_amount > 0 && params.change_amount < DUST_THRESHOLD {
+        return Err(ProgramError::Custom(ERROR_INVALID_CHANGE));
+    }
+    ProgramState::clear_events(&accounts[0])?;
+
+    let replaced_tx_id = WithdrawState::get_tx_id(&accounts[1])?;
+    let replaced_tx_bytes = get_bitcoin_tx(replaced_tx_id)
+        .ok_or(ProgramError::Custom(ERROR_WITHDRAWAL_TX_NOT_FOUND))?;
+    let replaced_tx: Transaction = bitcoin::consensus::deserialize(&replaced_tx_bytes)
+        .map_err(|_| ProgramError::Custom(ERROR_INVALID_INPUT_TX))?;
+    let tx_with_inputs: Transaction = bitcoin::consensus::deserialize(&params.withdraw_batch.tx_hex)
+        .map_err(|_| ProgramError::Custom(ERROR_INVALID_INPUT_TX))?;
+    // the replaced transaction spends the withdraw account utxo, its only state transition, ahead
+    // of the inputs of the batch
+    let state_transition_outputs = 1;
+    if replaced_tx.input.len() != state_transition_outputs + tx_with_inputs.input.len()
+        || replaced_tx.output.len() < state_transition_outputs
+        || replaced_tx.input[state_transition_outputs..].iter().zip(&tx_with_inputs.input)
+            .any(|(replaced_input, input)| replaced_input.previous_output != input.previous_output)
+    {
+        return Err(ProgramError::Custom(ERROR_INVALID_INPUT_TX));
+    }
+
+    let mut tx = Transaction {
+        version: Version::TWO,
+        lock_time: LockTime::ZERO,
+        input: replaced_tx.input.iter().map(|input| TxIn {
+            previous_output: input.previous_output,
+            script_sig: ScriptBuf::new(),
+            sequence: Sequence::ENABLE_RBF_NO_LOCKTIME,
+            witness: Witness::new(),
+        }).collect(),
+        output: replaced_tx.output[..state_transition_outputs].to_vec(),
+    };
+    // forced withdrawals were already served when the batch was submitted
+    let vsize = add_withdraw_batch_outputs(
+        program_id,
+        accounts,
+        &params.withdraw_batch,
+        params.change_amount,
+        has_rune_receiver,
+        None,
+        &mut tx,
+    )?;
+    // with the same inputs, the fee increases by what the outputs are worth less, which nodes only
+    // relay when it pays the incremental relay fee for the size of the replacement
+    let output_amount = |outputs: &[TxOut]| outputs.iter()
+        .try_fold(0, |total, output| checked_add_amount(total, output.value.to_sat()));
+    let fee_increase = output_amount(&replaced_tx.output)?.saturating_sub(output_amount(&tx.output)?);
+    if fee_increase < vsize * INCREMENTAL_RELAY_FEE_RATE {
+        return Err(ProgramError::Custom(ERROR_FEE_NOT_INCREASED));
+    }
 
+    let tx_id = sign_withdraw_tx(program_id, accounts, &tx, &params.withdraw_batch.input_utxo_types)?;
+    // either transaction may still confirm, so the outputs of both stay reserved until one does
+    if let Some(utxo_registry) = get_utxo_registry(accounts)? {
+        register_program_outputs(utxo_registry, program_id, &accounts[0], tx_id, &tx.output, state_transition_outputs, true)?;
+    }
+
+    ProgramState::emit_event(
+        accounts,
+        &Event::WithdrawalBumped {
+            sequence: ProgramState::next_event_sequence(&accounts[0])?,
+            replaced_tx_id,
+            tx_id,
+        },
+    )?;
+    WithdrawState::add_replaced_tx_id(&accounts[1], replaced_tx_id)?;
+    WithdrawState::set_tx_id(&accounts[1], tx_id)?;
+    WithdrawState::set_submitted_height(&accounts[1], get_bitcoin_block_height())?;
+    WithdrawState::set_status(&accounts[1], WithdrawStatus::Replaced)
+}
+
+// adds the withdrawal and change outputs of a batch after the state transition outputs of `tx`,
+// checks the fee they leave and returns the virtual size of the signed transaction
+fn add_withdraw_batch_outputs(
+    program_id: &Pubkey,
+    accounts: &[AccountInfo],
+    params: &WithdrawBatchParams,
+    change_amount: u64,
+    has_rune_receiver: bool,
+    forced_withdrawals: Option<&AccountInfo>,
+    tx: &mut Transaction,
+) -> Result<u64, ProgramError> {
+    let state_transition_outputs = tx.output.len();
+    let mut edicts: Vec<Edict> = vec![];
     for token_withdrawals in &params.token_withdrawals {
         validate_account(accounts, token_withdrawals.account_index, false, false, Some(AccountType::Token), Some(0))?;
         handle_submit_withdrawals(
+            program_id,
             accounts,
             token_withdrawals,
+            forced_withdrawals,
             &mut tx.output,
-            &network_type,
             &mut edicts,
         )?;
     }
@@ -239,43 +1235,33 @@ pub fn submit_withdraw_batch(program_id: &Pubkey, accounts: &[AccountInfo], para
         return Err(ProgramError::Custom(ERROR_NO_RUNE_RECEIVER));
     }
 
-    if params.change_amount > 0 {
-        tx.output.push(
-            TxOut {
-                value: Amount::from_sat(params.change_amount),
-                script_pubkey: ScriptBuf::from_bytes(get_account_script_pubkey(program_id).to_vec()),
-            }
-        );
-    }
-
-    if !edicts.is_empty() {
-        let rune_ids: HashSet<RuneId> = HashSet::from_iter(edicts.iter().map(|e| e.id).collect::<Vec<RuneId>>().to_vec());
-        rune_ids.into_iter().for_each(|rune_id| {
-            add_edict_and_output(
-                rune_id,
-                &mut tx.output,
-                &mut edicts,
-                ScriptBuf::from_bytes(get_account_script_pubkey(accounts[2].key).to_vec()),
-                0,
-            ).expect("cannot add change edict");
-        });
-
-        let runestone = Runestone {
-            edicts,
-            etching: None,
-            mint: None,
-            pointer: None,
-        };
-
-        let runestone_bytes = runestone.encipher().to_bytes();
-        tx.output.push(
-            TxOut {
-                script_pubkey: ScriptBuf::from_bytes(runestone_bytes),
-                value: Amount::from_sat(0),
-            },
-        );
-    }
+    add_change_outputs(program_id, accounts, &mut tx.output, edicts, change_amount)?;
+    verify_withdraw_fee(
+        program_id,
+        accounts,
+        has_rune_receiver,
+        tx,
+        state_transition_outputs,
+        &params.input_utxo_types,
+    )
+}
+
+// the withdraw account is the only state transition of a withdrawal transaction, the other
+// writable accounts of a batch keep their utxo
+fn withdraw_state_transition_tx(accounts: &[AccountInfo]) -> Transaction {
+    get_state_transition_tx(&accounts[1..2])
+}
 
+// signs a withdrawal transaction whose first input is the withdraw account and returns its txid
+fn sign_withdraw_tx(
+    program_id: &Pubkey,
+    accounts: &[AccountInfo],
+    tx: &Transaction,
+    input_utxo_types: &[InputUtxoType],
+) -> Result<Hash, ProgramError> {
+    // the witness is not part of the txid, so it is final before the inputs are signed
+    let mut tx_id = tx.compute_txid().to_byte_array();
+    tx_id.reverse();
 
     let mut inputs_to_sign: Vec<InputToSign> = vec![];
     for (index, _) in tx.input.iter().enumerate() {
@@ -285,7 +1271,7 @@ pub fn submit_withdraw_batch(program_id: &Pubkey, accounts: &[AccountInfo], para
                 signer: if index == 0 {
                     *accounts[1].key
                 } else {
-                    if params.input_utxo_types[index - 1] == InputUtxoType::Bitcoin {
+                    if input_utxo_types[index - 1] == InputUtxoType::Bitcoin {
                         program_id.clone()
                     } else {
                         *accounts[2].key
@@ -297,49 +1283,109 @@ pub fn submit_withdraw_batch(program_id: &Pubkey, accounts: &[AccountInfo], para
 
 
     let tx_to_sign = TransactionToSign {
-        tx_bytes: &bitcoin::consensus::serialize(&tx),
+        tx_bytes: &bitcoin::consensus::serialize(tx),
         inputs_to_sign: &inputs_to_sign,
     };
 
     set_transaction_to_sign(vec![accounts[1].clone()].as_slice(), tx_to_sign)?;
-
-    WithdrawState::clear_hash(&accounts[1])
+    Ok(tx_id)
 }
 
-pub fn rollback_withdraw_batch(accounts: &[AccountInfo], params: &RollbackWithdrawBatchParams) -> Result<(), ProgramError> {
+pub fn rollback_withdraw_batch(program_id: &Pubkey, accounts: &[AccountInfo], params: &RollbackWithdrawBatchParams) -> Result<(), ProgramError> {
     validate_account(accounts, 0, true, false, Some(AccountType::Program), Some(1))?;
     validate_account(accounts, 1, false, true, Some(AccountType::Withdraw), Some(0))?;
+    // a submitted batch has already been signed, so its balances can no longer be restored
+    if !WithdrawState::is_prepared(&accounts[1])? {
+        return Err(ProgramError::Custom(ERROR_WITHDRAWAL_BATCH_MISMATCH));
+    }
+    let fee_account_address = WalletAddress::from_address(&ProgramState::get_fee_account_address(&accounts[0])?)?;
     for token_withdrawals in &params.token_withdrawals {
         validate_account(accounts, token_withdrawals.account_index, false, true, Some(AccountType::Token), Some(0))?;
         handle_rollback_withdrawals(
+            program_id,
             accounts,
             token_withdrawals,
-            &ProgramState::get_fee_account_address(&accounts[0])?,
+            &fee_account_address,
         )?;
     }
+    if let Some(utxo_registry) = get_utxo_registry(accounts)? {
+        UtxoRegistryState::unlock_utxos(utxo_registry)?;
+    }
     WithdrawState::clear_hash(&accounts[1])?;
-    Ok(())
+    WithdrawState::set_status(&accounts[1], WithdrawStatus::Idle)
 }
 
+// Moves the withdraw account by hand, a confirmed batch moves it through ConfirmWithdrawBatch.
 pub fn update_withdraw_state_utxo(accounts: &[AccountInfo], params: &UpdateWithdrawStateUtxoParams) -> Result<(), ProgramError> {
     validate_account(accounts, 0, true, false, Some(AccountType::Program), Some(1))?;
     validate_account(accounts, 1, true, true, Some(AccountType::Withdraw), Some(0))?;
 
-    accounts[1].set_utxo(
-        &UtxoMeta::from(
-            hex::decode(params.tx_id.clone())
-                .unwrap()
-                .try_into()
-                .unwrap(),
-            params.vout.clone(),
-        )
-    );
-
+    let tx_id: [u8; 32] = hex::decode(&params.tx_id)
+        .map_err(|_| ProgramError::InvalidInstructionData)?
+        .try_into()
+        .map_err(|_| ProgramError::InvalidInstructionData)?;
+    accounts[1].set_utxo(&UtxoMeta::from(tx_id, params.vout));
     Ok(())
 }
 
-pub fn submit_settlement_batch(accounts: &[AccountInfo], params: &SettlementBatchParams, raw_params_data: &[u8]) -> Result<(), ProgramError> {
+// Confirms the submitted batch once one of its transactions, the last one or one a fee bump
+// replaced, is known to the bitcoin node and WITHDRAW_CONFIRMATION_DEPTH blocks were mined since
+// the last one was sent. The runtime can't tell which block holds a transaction, so the depth is
+// counted from the height recorded on submission. The withdraw account moves to the state
+// transition output of that transaction, its change is released and the change of the others is
+// dropped.
+pub fn confirm_withdraw_batch(accounts: &[AccountInfo], params: &ConfirmWithdrawBatchParams) -> Result<(), ProgramError> {
+    validate_account(accounts, 0, true, true, Some(AccountType::Program), Some(1))?;
+    validate_account(accounts, 1, true, true, Some(AccountType::Withdraw), Some(0))?;
+    if !WithdrawState::is_submitted(&accounts[1])? {
+        return Err(ProgramError::Custom(ERROR_NO_SUBMITTED_WITHDRAWAL));
+    }
+    let mut candidates = WithdrawState::get_replaced_tx_ids(&accounts[1])?;
+    candidates.push(WithdrawState::get_tx_id(&accounts[1])?);
+    let tx_id = params.tx_id;
+    if !candidates.contains(&tx_id) {
+        return Err(ProgramError::Custom(ERROR_WITHDRAWAL_TX_NOT_FOUND));
+    }
+    let tx_bytes = get_bitcoin_tx(tx_id)
+        .ok_or(ProgramError::Custom(ERROR_WITHDRAWAL_TX_NOT_FOUND))?;
+    let tx: Transaction = bitcoin::consensus::deserialize(&tx_bytes)
+        .map_err(|_| ProgramError::Custom(ERROR_INVALID_INPUT_TX))?;
+    let mut found_tx_id = tx.compute_txid().to_byte_array();
+    found_tx_id.reverse();
+    if found_tx_id != tx_id {
+        return Err(ProgramError::Custom(ERROR_WITHDRAWAL_TX_NOT_FOUND));
+    }
+    let confirmed_height = checked_add_amount(WithdrawState::get_submitted_height(&accounts[1])?, WITHDRAW_CONFIRMATION_DEPTH)?;
+    if get_bitcoin_block_height() < confirmed_height {
+        return Err(ProgramError::Custom(ERROR_WITHDRAWAL_NOT_CONFIRMED));
+    }
+    ProgramState::clear_events(&accounts[0])?;
+
+    if let Some(utxo_registry) = get_utxo_registry(accounts)? {
+        for candidate in &candidates {
+            if *candidate == tx_id {
+                UtxoRegistryState::unlock_tx(utxo_registry, candidate)?;
+            } else {
+                UtxoRegistryState::forget_tx(utxo_registry, candidate)?;
+            }
+        }
+    }
+    accounts[1].set_utxo(&UtxoMeta::from(tx_id, 0));
+    WithdrawState::set_tx_id(&accounts[1], tx_id)?;
+    WithdrawState::clear_replaced_tx_ids(&accounts[1])?;
+    WithdrawState::set_status(&accounts[1], WithdrawStatus::Confirmed)?;
+    ProgramState::emit_event(
+        accounts,
+        &Event::WithdrawalConfirmed {
+            sequence: ProgramState::next_event_sequence(&accounts[0])?,
+            tx_id,
+        },
+    )
+}
+
+pub fn submit_settlement_batch(program_id: &Pubkey, accounts: &[AccountInfo], params: &SettlementBatchParams, raw_params_data: &[u8]) -> Result<(), ProgramError> {
     validate_account(accounts, 0, true, true, Some(AccountType::Program), None)?;
+    ProgramState::verify_not_paused(&accounts[0], PAUSE_SETTLEMENTS)?;
     let current_hash = ProgramState::get_settlement_hash(&accounts[0])?;
     let params_hash = hash(raw_params_data);
 
@@ -349,51 +1395,141 @@ pub fn submit_settlement_batch(accounts: &[AccountInfo], params: &SettlementBatc
     if current_hash != params_hash {
         return Err(ProgramError::Custom(ERROR_SETTLEMENT_BATCH_MISMATCH));
     }
+    ProgramState::clear_events(&accounts[0])?;
 
     for token_settlements in &params.settlements {
         validate_account(accounts, token_settlements.account_index, false, true, Some(AccountType::Token), Some(0))?;
         if token_settlements.fee_amount > 0 {
-            handle_increments(&accounts[token_settlements.account_index as usize], &vec![Adjustment {
+            handle_increments(program_id, accounts, token_settlements.account_index, &vec![Adjustment {
                 address_index: AddressIndex {
                     index: FEE_ADDRESS_INDEX,
-                    last4: Balance::get_wallet_address_last4(&accounts[token_settlements.account_index as usize], FEE_ADDRESS_INDEX as usize)?,
+                    commitment: AddressCommitment::ScriptHash(
+                        Balance::get_wallet_address(&accounts[token_settlements.account_index as usize], FEE_ADDRESS_INDEX as usize)?.script_hash()?
+                    ),
                 },
                 amount: token_settlements.fee_amount,
             }])?;
+            ProgramState::emit_event(
+                accounts,
+                &Event::FeeCollected {
+                    sequence: ProgramState::next_event_sequence(&accounts[0])?,
+                    account_index: token_settlements.account_index,
+                    amount: token_settlements.fee_amount,
+                },
+            )?;
         }
-        handle_increments(&accounts[token_settlements.account_index as usize], &token_settlements.increments)?;
-        handle_decrements(&accounts[token_settlements.account_index as usize], &token_settlements.decrements)?;
+        let increment_total = handle_increments(program_id, accounts, token_settlements.account_index, &token_settlements.increments)?;
+        let decrement_total = handle_decrements(program_id, accounts, token_settlements.account_index, &token_settlements.decrements)?;
+        ProgramState::emit_event(
+            accounts,
+            &Event::SettlementApplied {
+                sequence: ProgramState::next_event_sequence(&accounts[0])?,
+                account_index: token_settlements.account_index,
+                settlement_hash: params_hash,
+                increment_total,
+                decrement_total,
+            },
+        )?;
     }
 
     ProgramState::set_last_settlement_hash(&accounts[0], params_hash)?;
     ProgramState::clear_settlement_hash(&accounts[0])
 }
 
-pub fn prepare_settlement_batch(accounts: &[AccountInfo], params: &SettlementBatchParams, raw_params_data: &[u8]) -> Result<(), ProgramError> {
+// In partial mode entries with a failed decrement are left out and reported instead of failing the
+// whole batch. A trade moves two tokens between the same wallets, so every other entry touching a
+// wallet of an excluded entry is left out too. The hash of the remaining entries is stored, so they
+// are what has to be submitted.
+pub fn prepare_settlement_batch(program_id: &Pubkey, accounts: &[AccountInfo], params: &SettlementBatchParams, raw_params_data: &[u8], partial: bool) -> Result<(), ProgramError> {
     validate_account(accounts, 0, true, true, Some(AccountType::Program), None)?;
+    ProgramState::verify_not_paused(&accounts[0], PAUSE_SETTLEMENTS)?;
     if ProgramState::get_settlement_hash(&accounts[0])? != EMPTY_HASH {
         return Err(ProgramError::Custom(ERROR_SETTLEMENT_IN_PROGRESS));
     }
     ProgramState::clear_events(&accounts[0])?;
-    let mut running_netting_total: i64 = 0;
+    let mut settlement_wallets = vec![];
+    let mut excluded = vec![false; params.settlements.len()];
+    let forced_withdrawals = get_forced_withdrawals(accounts)?;
 
-    for token_settlements in &params.settlements {
+    for (settlement_index, token_settlements) in params.settlements.iter().enumerate() {
         validate_account(accounts, token_settlements.account_index, false, false, Some(AccountType::Token), Some(0))?;
-        let decrement_sum: u64 = verify_decrements(&accounts, token_settlements.account_index, &token_settlements.decrements)?;
-        let increment_sum: u64 = verify_increments(&accounts, token_settlements.account_index, &token_settlements.increments)? + token_settlements.fee_amount;
-        running_netting_total += increment_sum as i64 - decrement_sum as i64;
+        let events_count = ProgramState::get_events_count(&accounts[0])?;
+        let decrement_sum: u64 = verify_decrements(program_id, &accounts, token_settlements.account_index, &token_settlements.decrements, forced_withdrawals)?;
+        let increment_sum: u64 = checked_add_amount(
+            verify_increments(program_id, &accounts, token_settlements.account_index, &token_settlements.increments, forced_withdrawals)?,
+            token_settlements.fee_amount,
+        )?;
+        // every token has to net out on its own, a surplus in one can't cover a shortfall in another
+        if increment_sum != decrement_sum {
+            msg!("Netting failed for account {}: {} != {}", token_settlements.account_index, increment_sum, decrement_sum);
+            if !partial {
+                return Err(ProgramError::Custom(ERROR_NETTING));
+            }
+            ProgramState::emit_event(
+                accounts,
+                &Event::NettingFailed {
+                    account_index: token_settlements.account_index,
+                    increment_total: increment_sum,
+                    decrement_total: decrement_sum,
+                },
+            )?;
+        }
+        if partial {
+            excluded[settlement_index] = ProgramState::get_events_count(&accounts[0])? > events_count;
+            settlement_wallets.push(get_settlement_wallets(program_id, accounts, token_settlements)?);
+        }
     }
 
-    if running_netting_total != 0 {
-        msg!("Netting failed {}", running_netting_total);
-        return Err(ProgramError::Custom(ERROR_NETTING));
+    if !partial {
+        return if ProgramState::get_events_count(&accounts[0])? == 0 {
+            ProgramState::set_settlement_hash(&accounts[0], hash(raw_params_data))
+        } else {
+            Ok(())
+        };
     }
 
-    if ProgramState::get_events_count(&accounts[0])? == 0 {
-        ProgramState::set_settlement_hash(&accounts[0], hash(raw_params_data))
-    } else {
-        Ok(())
+    let mut excluded_wallets: Vec<WalletAddress> = vec![];
+    let mut newly_excluded: Vec<usize> = (0..excluded.len()).filter(|index| excluded[*index]).collect();
+    while let Some(settlement_index) = newly_excluded.pop() {
+        excluded_wallets.extend(&settlement_wallets[settlement_index]);
+        for (other_index, wallets) in settlement_wallets.iter().enumerate() {
+            if !excluded[other_index] && wallets.iter().any(|wallet| excluded_wallets.contains(wallet)) {
+                excluded[other_index] = true;
+                newly_excluded.push(other_index);
+            }
+        }
+    }
+
+    let mut remaining_settlements = vec![];
+    for (settlement_index, token_settlements) in params.settlements.iter().enumerate() {
+        if excluded[settlement_index] {
+            ProgramState::emit_event(
+                accounts,
+                &Event::SettlementExcluded {
+                    account_index: token_settlements.account_index,
+                    settlement_index: settlement_index as u16,
+                },
+            )?;
+        } else {
+            remaining_settlements.push(token_settlements.clone());
+        }
+    }
+    if remaining_settlements.is_empty() {
+        return Ok(());
+    }
+    let remaining_params = SettlementBatchParams {
+        settlements: remaining_settlements,
+    }.encode_to_vec().map_err(|_| ProgramError::InvalidInstructionData)?;
+    ProgramState::set_settlement_hash(&accounts[0], hash(&remaining_params))
+}
+
+fn get_settlement_wallets(program_id: &Pubkey, accounts: &[AccountInfo], token_settlements: &SettlementAdjustments) -> Result<Vec<WalletAddress>, ProgramError> {
+    let mut wallets = vec![];
+    for adjustment in token_settlements.increments.iter().chain(&token_settlements.decrements) {
+        let (balance_account, index) = get_validated_index(program_id, accounts, token_settlements.account_index, &adjustment.address_index)?;
+        wallets.push(Balance::get_wallet_address(balance_account, index)?);
     }
+    Ok(wallets)
 }
 
 pub fn rollback_settlement_batch(accounts: &[AccountInfo]) -> Result<(), ProgramError> {
@@ -407,72 +1543,229 @@ fn hash(data: &[u8]) -> Hash {
     tmp
 }
 
-fn handle_increments(account: &AccountInfo, adjustments: &Vec<Adjustment>) -> Result<(), ProgramError> {
-    handle_adjustments(account, adjustments, true)
+fn handle_increments(program_id: &Pubkey, accounts: &[AccountInfo], account_index: u8, adjustments: &Vec<Adjustment>) -> Result<u64, ProgramError> {
+    handle_adjustments(program_id, accounts, account_index, adjustments, true)
 }
 
-fn handle_decrements(account: &AccountInfo, adjustments: &Vec<Adjustment>) -> Result<(), ProgramError> {
-    handle_adjustments(account, adjustments, false)
+fn handle_decrements(program_id: &Pubkey, accounts: &[AccountInfo], account_index: u8, adjustments: &Vec<Adjustment>) -> Result<u64, ProgramError> {
+    handle_adjustments(program_id, accounts, account_index, adjustments, false)
 }
 
-fn handle_adjustments(account: &AccountInfo, adjustments: &Vec<Adjustment>, increment: bool) -> Result<(), ProgramError> {
+fn handle_adjustments(program_id: &Pubkey, accounts: &[AccountInfo], account_index: u8, adjustments: &Vec<Adjustment>, increment: bool) -> Result<u64, ProgramError> {
+    let mut total: u64 = 0;
     for adjustment in adjustments {
-        let index = get_validated_index(account, &adjustment.address_index)?;
+        total = checked_add_amount(total, adjustment.amount)?;
+        let (balance_account, index) = get_validated_index(program_id, accounts, account_index, &adjustment.address_index)?;
         if increment {
-            Balance::increment_wallet_balance(account, index, adjustment.amount)?;
+            Balance::increment_wallet_balance(balance_account, index, adjustment.amount)?;
         } else {
-            Balance::decrement_wallet_balance(account, index, adjustment.amount)?;
+            Balance::decrement_wallet_balance(balance_account, index, adjustment.amount)?;
         }
     }
-    Ok(())
+    Ok(total)
 }
 
-fn verify_decrements(accounts: &[AccountInfo], account_index: u8, adjustments: &Vec<Adjustment>) -> Result<u64, ProgramError> {
-    let account = &accounts[account_index as usize];
+fn verify_decrements(program_id: &Pubkey, accounts: &[AccountInfo], account_index: u8, adjustments: &Vec<Adjustment>, forced_withdrawals: Option<(&AccountInfo, u64)>) -> Result<u64, ProgramError> {
     let mut total: u64 = 0;
     for adjustment in adjustments {
-        let index = get_validated_index(account, &adjustment.address_index)?;
-        let current_balance = Balance::get_wallet_balance(account, index)?;
-        total += adjustment.amount;
-        if adjustment.amount > current_balance {
+        let (balance_account, index) = get_validated_index(program_id, accounts, account_index, &adjustment.address_index)?;
+        let current_balance = Balance::get_wallet_balance(balance_account, index)?;
+        total = checked_add_amount(total, adjustment.amount)?;
+        let error_code = if is_frozen(accounts, account_index, balance_account, index, forced_withdrawals)? {
+            Some(ERROR_WALLET_FROZEN)
+        } else if adjustment.amount > current_balance {
+            Some(ERROR_INSUFFICIENT_BALANCE)
+        } else {
+            None
+        };
+        if let Some(error_code) = error_code {
             ProgramState::emit_event(
-                &accounts[0],
+                accounts,
                 &Event::FailedSettlement {
                     account_index,
                     address_index: adjustment.address_index.index,
                     requested_amount: adjustment.amount,
                     balance: current_balance,
-                    error_code: ERROR_INSUFFICIENT_BALANCE,
+                    error_code,
                 })?;
         };
     }
     Ok(total)
 }
 
-fn verify_increments(accounts: &[AccountInfo], account_index: u8, adjustments: &Vec<Adjustment>) -> Result<u64, ProgramError> {
-    let account = &accounts[account_index as usize];
+fn verify_increments(program_id: &Pubkey, accounts: &[AccountInfo], account_index: u8, adjustments: &Vec<Adjustment>, forced_withdrawals: Option<(&AccountInfo, u64)>) -> Result<u64, ProgramError> {
     let mut total: u64 = 0;
     for adjustment in adjustments {
-        let _ = get_validated_index(account, &adjustment.address_index)?;
-        total += adjustment.amount;
+        let (balance_account, index) = get_validated_index(program_id, accounts, account_index, &adjustment.address_index)?;
+        total = checked_add_amount(total, adjustment.amount)?;
+        if is_frozen(accounts, account_index, balance_account, index, forced_withdrawals)? {
+            ProgramState::emit_event(
+                accounts,
+                &Event::FailedSettlement {
+                    account_index,
+                    address_index: adjustment.address_index.index,
+                    requested_amount: adjustment.amount,
+                    balance: Balance::get_wallet_balance(balance_account, index)?,
+                    error_code: ERROR_WALLET_FROZEN,
+                })?;
+        }
     }
     Ok(total)
 }
 
-fn verify_withdrawals(accounts: &[AccountInfo], account_index: u8, withdrawals: &Vec<Withdrawal>, network_type: &NetworkType) -> Result<(), ProgramError> {
-    let account = &accounts[account_index as usize];
-    for withdrawal in withdrawals {
-        let index_result = get_validated_index_withdraw(account, &withdrawal.address_index, network_type);
+// settlements for a wallet stop once the operator let its forced withdrawal request go overdue
+fn is_frozen(accounts: &[AccountInfo], account_index: u8, balance_account: &AccountInfo, index: usize, forced_withdrawals: Option<(&AccountInfo, u64)>) -> Result<bool, ProgramError> {
+    let Some((registry, block_height)) = forced_withdrawals else {
+        return Ok(false);
+    };
+    let key = ForcedWithdrawalState::entry_key(accounts[account_index as usize].key, &Balance::get_wallet_address(balance_account, index)?);
+    ForcedWithdrawalState::is_overdue(registry, &key, block_height)
+}
+
+// the whole ledger chain has to be passed, otherwise a duplicate could go unnoticed
+fn get_deposit_ledger_accounts<'a, 'b>(accounts: &'a [AccountInfo<'b>]) -> Result<Vec<&'a AccountInfo<'b>>, ProgramError> {
+    let mut ledger_accounts = vec![];
+    let mut next_ledger_key = ProgramState::get_deposit_ledger_key(&accounts[0])?;
+    while let Some(ledger_key) = next_ledger_key {
+        let ledger_account = accounts.iter()
+            .find(|account| *account.key == ledger_key)
+            .ok_or(ProgramError::Custom(ERROR_DEPOSIT_LEDGER_MISSING))?;
+        if ledger_account.is_signer || !ledger_account.is_writable {
+            return Err(ProgramError::Custom(ERROR_INVALID_ACCOUNT_FLAGS));
+        }
+        ledger_accounts.push(ledger_account);
+        next_ledger_key = DepositLedgerState::get_next_ledger_key(ledger_account)?;
+    }
+    Ok(ledger_accounts)
+}
+
+fn is_deposit_recorded(ledger_accounts: &[&AccountInfo], funding_outpoint: &FundingOutpoint) -> Result<bool, ProgramError> {
+    for ledger_account in ledger_accounts {
+        if DepositLedgerState::find_deposit(ledger_account, funding_outpoint)?.is_ok() {
+            return Ok(true);
+        }
+    }
+    Ok(false)
+}
+
+fn record_deposit(ledger_account: &AccountInfo, funding_outpoint: &FundingOutpoint) -> Result<(), ProgramError> {
+    match DepositLedgerState::find_deposit(ledger_account, funding_outpoint)? {
+        Ok(_) => Ok(()),
+        Err(position) => DepositLedgerState::insert_deposit(ledger_account, position, funding_outpoint),
+    }
+}
+
+fn verify_deposit_funding(
+    program_id: &Pubkey,
+    program_account: &AccountInfo,
+    account: &AccountInfo,
+    funding_outpoint: &FundingOutpoint,
+    amount: u64,
+) -> Result<(), ProgramError> {
+    let tx_bytes = get_bitcoin_tx(funding_outpoint.tx_id)
+        .ok_or(ProgramError::Custom(ERROR_DEPOSIT_TX_NOT_FOUND))?;
+    let tx: Transaction = bitcoin::consensus::deserialize(&tx_bytes)
+        .map_err(|_| ProgramError::Custom(ERROR_INVALID_INPUT_TX))?;
+    let output = tx.output.get(funding_outpoint.vout as usize)
+        .ok_or(ProgramError::Custom(ERROR_DEPOSIT_OUTPUT_MISMATCH))?;
+
+    let funded_amount = if TokenState::is_rune_account(account) {
+        // runes are deposited to the rune receiver and only count if explicitly assigned to this output
+        let rune_receiver = ProgramState::get_rune_receiver_key(program_account)?
+            .ok_or(ProgramError::Custom(ERROR_NO_RUNE_RECEIVER))?;
+        if output.script_pubkey.as_bytes() != get_account_script_pubkey(&rune_receiver).as_slice() {
+            return Err(ProgramError::Custom(ERROR_DEPOSIT_OUTPUT_MISMATCH));
+        }
+        let rune_id = TokenState::get_rune_id(account)?;
+        match Runestone::decipher(&tx) {
+            Some(Artifact::Runestone(runestone)) => runestone.edicts.iter()
+                .filter(|edict| edict.id == rune_id && edict.output == funding_outpoint.vout)
+                .map(|edict| edict.amount)
+                .sum::<u128>(),
+            _ => 0,
+        }
+    } else {
+        if output.script_pubkey.as_bytes() != get_account_script_pubkey(program_id).as_slice() {
+            return Err(ProgramError::Custom(ERROR_DEPOSIT_OUTPUT_MISMATCH));
+        }
+        output.value.to_sat() as u128
+    };
+
+    if funded_amount < amount as u128 {
+        msg!("Deposit of {} not covered by funding output {}", amount, funded_amount);
+        return Err(ProgramError::Custom(ERROR_DEPOSIT_AMOUNT_MISMATCH));
+    }
+    Ok(())
+}
+
+// returns the positions of the withdrawals that failed, a FailedWithdrawal event is emitted for each
+fn verify_withdrawals(
+    program_id: &Pubkey,
+    accounts: &[AccountInfo],
+    account_index: u8,
+    withdrawals: &Vec<Withdrawal>,
+    network_type: &NetworkType,
+    batch_nonces: &mut HashMap<Hash, u64>,
+    batch_withdrawn: &mut HashMap<(Pubkey, Hash), u64>,
+) -> Result<Vec<usize>, ProgramError> {
+    let mut failed_indexes = vec![];
+    let token_account = &accounts[account_index as usize];
+    let fee_policy = TokenState::get_withdrawal_fee_policy(token_account)?;
+    let withdrawal_limit = get_withdrawal_limit(accounts, token_account)?;
+    for (withdrawal_index, withdrawal) in withdrawals.iter().enumerate() {
+        let index_result = get_validated_index_withdraw(program_id, accounts, account_index, &withdrawal.address_index, network_type);
         validate_account(accounts, withdrawal.fee_account_index, false, true, Some(AccountType::Token), Some(0))?;
-        let fee_account = &accounts[withdrawal.fee_account_index as usize];
         match index_result {
-            Ok(index) => {
-                let current_balance = Balance::get_wallet_balance(account, index)?;
-                let fee_index = get_validated_index_withdraw(fee_account, &withdrawal.fee_address_index, network_type)?;
-                let balance_in_fee_token = Balance::get_wallet_balance(fee_account, fee_index)?;
-                if withdrawal.amount > current_balance || withdrawal.fee_amount > balance_in_fee_token {
+            Ok((balance_account, index)) => {
+                let current_balance = Balance::get_wallet_balance(balance_account, index)?;
+                let (fee_balance_account, fee_index) = get_validated_index_withdraw(program_id, accounts, withdrawal.fee_account_index, &withdrawal.fee_address_index, network_type)?;
+                let balance_in_fee_token = Balance::get_wallet_balance(fee_balance_account, fee_index)?;
+                let fee_token_account = accounts[withdrawal.fee_account_index as usize].key;
+                let error_code = match &fee_policy {
+                    Some(fee_policy) if !fee_policy.allows(withdrawal.fee_amount, fee_token_account, token_account.key) => Some(ERROR_WITHDRAWAL_FEE_POLICY),
+                    _ if withdrawal.amount > current_balance || withdrawal.fee_amount > balance_in_fee_token => Some(ERROR_INSUFFICIENT_BALANCE),
+                    _ if withdrawal_output_amount(token_account, withdrawal).is_err() => Some(ERROR_FEE_EXCEEDS_AMOUNT),
+                    _ => None,
+                };
+                let wallet_address = Balance::get_wallet_address(balance_account, index)?;
+                let address_hash = wallet_address.hash();
+                let withdrawn_key = (*token_account.key, address_hash);
+                let error_code = match (error_code, &withdrawal.signature) {
+                    (None, Some(signature)) => verify_withdrawal_signature(
+                        accounts,
+                        token_account,
+                        withdrawal,
+                        signature,
+                        &wallet_address,
+                        batch_nonces,
+                    )?,
+                    (error_code, _) => error_code,
+                };
+                let error_code = match (error_code, &withdrawal_limit) {
+                    (None, Some((limit_account, block_height))) => {
+                        let withdrawn = checked_add_amount(
+                            WithdrawalLimitState::get_withdrawn(limit_account, &address_hash, *block_height)?,
+                            batch_withdrawn.get(&withdrawn_key).copied().unwrap_or(0),
+                        )?;
+                        if checked_add_amount(withdrawn, withdrawal.amount)? > WithdrawalLimitState::get_max_amount(limit_account)? {
+                            Some(ERROR_WITHDRAWAL_LIMIT_EXCEEDED)
+                        } else {
+                            None
+                        }
+                    }
+                    (error_code, _) => error_code,
+                };
+                // only a withdrawal that passed every check counts towards the limit and uses up its nonce
+                if error_code.is_none() {
+                    let withdrawn = batch_withdrawn.get(&withdrawn_key).copied().unwrap_or(0);
+                    batch_withdrawn.insert(withdrawn_key, checked_add_amount(withdrawn, withdrawal.amount)?);
+                    if let Some(signature) = &withdrawal.signature {
+                        batch_nonces.insert(address_hash, signature.nonce);
+                    }
+                }
+                if let Some(error_code) = error_code {
                     ProgramState::emit_event(
-                        &accounts[0],
+                        accounts,
                         &Event::FailedWithdrawal {
                             account_index,
                             address_index: withdrawal.address_index.index,
@@ -482,14 +1775,15 @@ fn verify_withdrawals(accounts: &[AccountInfo], account_index: u8, withdrawals:
                             fee_amount: withdrawal.fee_amount,
                             balance: current_balance,
                             balance_in_fee_token,
-                            error_code: ERROR_INSUFFICIENT_BALANCE,
+                            error_code,
                         },
                     )?;
+                    failed_indexes.push(withdrawal_index);
                 };
             }
             Err(program_error) => {
                 ProgramState::emit_event(
-                    &accounts[0],
+                    accounts,
                     &Event::FailedWithdrawal {
                         account_index,
                         address_index: withdrawal.address_index.index,
@@ -502,59 +1796,305 @@ fn verify_withdrawals(accounts: &[AccountInfo], account_index: u8, withdrawals:
                         error_code: u64::from(program_error) as u32,
                     },
                 )?;
+                failed_indexes.push(withdrawal_index);
+            }
+        }
+    }
+    Ok(failed_indexes)
+}
+
+// checks the wallet's authorization of a signed withdrawal, returning the error to report when it
+// does not hold
+fn verify_withdrawal_signature(
+    accounts: &[AccountInfo],
+    token_account: &AccountInfo,
+    withdrawal: &Withdrawal,
+    signature: &WithdrawalSignature,
+    wallet_address: &WalletAddress,
+    batch_nonces: &HashMap<Hash, u64>,
+) -> Result<Option<u32>, ProgramError> {
+    let nonce_account = get_withdrawal_nonces(accounts)?;
+    if get_bitcoin_block_height() > signature.expiry {
+        return Ok(Some(ERROR_WITHDRAWAL_EXPIRED));
+    }
+    let address_hash = wallet_address.hash();
+    let last_nonce = match batch_nonces.get(&address_hash) {
+        Some(nonce) => Some(*nonce),
+        None => WithdrawalNonceState::get_last_nonce(nonce_account, &address_hash)?,
+    };
+    if last_nonce.is_some_and(|last_nonce| signature.nonce <= last_nonce) {
+        return Ok(Some(ERROR_NONCE_USED));
+    }
+    let message = withdrawal.message(
+        accounts[0].key,
+        token_account.key,
+        accounts[withdrawal.fee_account_index as usize].key,
+        &wallet_address.to_address_string()?,
+        signature.nonce,
+        signature.expiry,
+    );
+    if verify_wallet_signature(wallet_address, &message, &signature.witness).is_err() {
+        return Ok(Some(ERROR_INVALID_SIGNATURE));
+    }
+    Ok(None)
+}
+
+// the nonce account of the program, only needed for batches with signed withdrawals
+fn get_withdrawal_nonces<'a, 'b>(accounts: &'a [AccountInfo<'b>]) -> Result<&'a AccountInfo<'b>, ProgramError> {
+    let nonce_key = ProgramState::get_withdrawal_nonces_key(&accounts[0])?
+        .ok_or(ProgramError::Custom(ERROR_WITHDRAWAL_NONCES_MISSING))?;
+    let nonce_account = accounts.iter()
+        .find(|account| *account.key == nonce_key)
+        .ok_or(ProgramError::Custom(ERROR_WITHDRAWAL_NONCES_MISSING))?;
+    if nonce_account.is_signer || !nonce_account.is_writable {
+        return Err(ProgramError::Custom(ERROR_INVALID_ACCOUNT_FLAGS));
+    }
+    if get_type(nonce_account)? != AccountType::WithdrawalNonces {
+        return Err(ProgramError::Custom(ERROR_INVALID_ACCOUNT_TYPE));
+    }
+    if get_version(nonce_account)? != WITHDRAWAL_NONCE_STATE_VERSION {
+        return Err(ProgramError::Custom(ERROR_INVALID_ACCOUNT_VERSION));
+    }
+    if WithdrawalNonceState::get_program_state_account_key(nonce_account)? != *accounts[0].key {
+        return Err(ProgramError::Custom(ERROR_STATE_ACCOUNT_MISMATCH));
+    }
+    Ok(nonce_account)
+}
+
+// the utxo registry of the program, withdrawals are only checked against it once it has one
+fn get_withdraw_state<'a, 'b>(accounts: &'a [AccountInfo<'b>]) -> Result<&'a AccountInfo<'b>, ProgramError> {
+    let withdraw_key = ProgramState::get_withdraw_account_key(&accounts[0])?;
+    let withdraw_account = accounts.iter()
+        .find(|account| *account.key == withdraw_key)
+        .ok_or(ProgramError::Custom(ERROR_WITHDRAW_ACCOUNT_MISSING))?;
+    if get_type(withdraw_account)? != AccountType::Withdraw {
+        return Err(ProgramError::Custom(ERROR_INVALID_ACCOUNT_TYPE));
+    }
+    if get_version(withdraw_account)? != WITHDRAW_STATE_VERSION {
+        return Err(ProgramError::Custom(ERROR_INVALID_ACCOUNT_VERSION));
+    }
+    Ok(withdraw_account)
+}
+
+fn get_utxo_registry<'a, 'b>(accounts: &'a [AccountInfo<'b>]) -> Result<Option<&'a AccountInfo<'b>>, ProgramError> {
+    let Some(registry_key) = ProgramState::get_utxo_registry_key(&accounts[0])? else {
+        return Ok(None);
+    };
+    let utxo_registry = accounts.iter()
+        .find(|account| *account.key == registry_key)
+        .ok_or(ProgramError::Custom(ERROR_UTXO_REGISTRY_MISSING))?;
+    if utxo_registry.is_signer || !utxo_registry.is_writable {
+        return Err(ProgramError::Custom(ERROR_INVALID_ACCOUNT_FLAGS));
+    }
+    if get_type(utxo_registry)? != AccountType::UtxoRegistry {
+        return Err(ProgramError::Custom(ERROR_INVALID_ACCOUNT_TYPE));
+    }
+    if get_version(utxo_registry)? != UTXO_REGISTRY_STATE_VERSION {
+        return Err(ProgramError::Custom(ERROR_INVALID_ACCOUNT_VERSION));
+    }
+    if UtxoRegistryState::get_program_state_account_key(utxo_registry)? != *accounts[0].key {
+        return Err(ProgramError::Custom(ERROR_STATE_ACCOUNT_MISMATCH));
+    }
+    Ok(Some(utxo_registry))
+}
+
+// the program holds bitcoin at its own script pubkey and runes at the one of the rune receiver
+fn get_program_scripts(program_id: &Pubkey, program_account: &AccountInfo) -> Result<Vec<ScriptBuf>, ProgramError> {
+    let mut scripts = vec![ScriptBuf::from_bytes(get_account_script_pubkey(program_id).to_vec())];
+    if let Some(rune_receiver) = ProgramState::get_rune_receiver_key(program_account)? {
+        scripts.push(ScriptBuf::from_bytes(get_account_script_pubkey(&rune_receiver).to_vec()));
+    }
+    Ok(scripts)
+}
+
+// registers the outputs from `first_vout` on of transaction `tx_id` that go back to the program,
+// reserved while the transaction may still be replaced
+fn register_program_outputs(
+    utxo_registry: &AccountInfo,
+    program_id: &Pubkey,
+    program_account: &AccountInfo,
+    tx_id: Hash,
+    outputs: &[TxOut],
+    first_vout: usize,
+    locked: bool,
+) -> Result<(), ProgramError> {
+    let program_scripts = get_program_scripts(program_id, program_account)?;
+    for (vout, output) in outputs.iter().enumerate().skip(first_vout) {
+        if program_scripts.contains(&output.script_pubkey) {
+            let outpoint = FundingOutpoint { tx_id, vout: vout as u32 };
+            if locked {
+                UtxoRegistryState::add_locked_utxo(utxo_registry, &outpoint)?;
+            } else {
+                UtxoRegistryState::add_utxo(utxo_registry, &outpoint)?;
             }
         }
     }
     Ok(())
 }
 
+fn input_outpoint(input: &TxIn) -> FundingOutpoint {
+    let mut tx_id = input.previous_output.txid.to_byte_array();
+    tx_id.reverse();
+    FundingOutpoint { tx_id, vout: input.previous_output.vout }
+}
+
+// the limit account of a token together with the current bitcoin block height. Withdrawals that
+// are rolled back keep counting against the limit of their wallet.
+fn get_withdrawal_limit<'a, 'b>(accounts: &'a [AccountInfo<'b>], token_account: &AccountInfo) -> Result<Option<(&'a AccountInfo<'b>, u64)>, ProgramError> {
+    let Some(limit_key) = TokenState::get_withdrawal_limit_key(token_account)? else {
+        return Ok(None);
+    };
+    let limit_account = accounts.iter()
+        .find(|account| *account.key == limit_key)
+        .ok_or(ProgramError::Custom(ERROR_WITHDRAWAL_LIMIT_MISSING))?;
+    if limit_account.is_signer || !limit_account.is_writable {
+        return Err(ProgramError::Custom(ERROR_INVALID_ACCOUNT_FLAGS));
+    }
+    if get_type(limit_account)? != AccountType::WithdrawalLimit {
+        return Err(ProgramError::Custom(ERROR_INVALID_ACCOUNT_TYPE));
+    }
+    if get_version(limit_account)? != WITHDRAWAL_LIMIT_STATE_VERSION {
+        return Err(ProgramError::Custom(ERROR_INVALID_ACCOUNT_VERSION));
+    }
+    if WithdrawalLimitState::get_token_state_account_key(limit_account)? != *token_account.key {
+        return Err(ProgramError::Custom(ERROR_STATE_ACCOUNT_MISMATCH));
+    }
+    Ok(Some((limit_account, get_bitcoin_block_height())))
+}
+
+// the forced withdrawal registry of the program, if it has one, together with the current bitcoin
+// block height
+fn get_forced_withdrawals<'a, 'b>(accounts: &'a [AccountInfo<'b>]) -> Result<Option<(&'a AccountInfo<'b>, u64)>, ProgramError> {
+    let Some(registry_key) = ProgramState::get_forced_withdrawals_key(&accounts[0])? else {
+        return Ok(None);
+    };
+    let registry = accounts.iter()
+        .find(|account| *account.key == registry_key)
+        .ok_or(ProgramError::Custom(ERROR_FORCED_WITHDRAWALS_MISSING))?;
+    if registry.is_signer {
+        return Err(ProgramError::Custom(ERROR_INVALID_ACCOUNT_FLAGS));
+    }
+    if get_type(registry)? != AccountType::ForcedWithdrawals {
+        return Err(ProgramError::Custom(ERROR_INVALID_ACCOUNT_TYPE));
+    }
+    if get_version(registry)? != FORCED_WITHDRAWAL_STATE_VERSION {
+        return Err(ProgramError::Custom(ERROR_INVALID_ACCOUNT_VERSION));
+    }
+    if ForcedWithdrawalState::get_program_state_account_key(registry)? != *accounts[0].key {
+        return Err(ProgramError::Custom(ERROR_STATE_ACCOUNT_MISMATCH));
+    }
+    Ok(Some((registry, get_bitcoin_block_height())))
+}
+
+// a withdrawal submitted by the operator serves the pending forced withdrawal request of its
+// wallet, completely once it covers the requested amount or nothing is left to withdraw
+fn serve_forced_withdrawal(registry: &AccountInfo, token_account: &AccountInfo, wallet_address: &WalletAddress, amount: u64, balance: u64) -> Result<(), ProgramError> {
+    let key = ForcedWithdrawalState::entry_key(token_account.key, wallet_address);
+    match ForcedWithdrawalState::get_entry(registry, &key)? {
+        Some(mut entry) if entry.pending => {
+            entry.amount = entry.amount.saturating_sub(amount);
+            entry.pending = entry.amount > 0 && balance > 0;
+            ForcedWithdrawalState::set_entry(registry, &entry)
+        }
+        _ => Ok(()),
+    }
+}
+
+// BIP322 simple signature of `message` by the wallet, taproot and P2WPKH wallets can sign
+fn verify_wallet_signature(wallet_address: &WalletAddress, message: &str, signature: &[u8]) -> Result<(), ProgramError> {
+    let witness: Witness = bitcoin::consensus::deserialize(signature)
+        .map_err(|_| ProgramError::Custom(ERROR_INVALID_SIGNATURE))?;
+    // the network only changes how the address is displayed, not the script signed for
+    let address = Address::from_script(&wallet_address.script_pubkey()?, Network::Bitcoin)
+        .map_err(|_| ProgramError::Custom(ERROR_INVALID_SIGNATURE))?;
+    bip322::verify_simple(&address, message.as_bytes(), witness)
+        .map_err(|_| ProgramError::Custom(ERROR_INVALID_SIGNATURE))
+}
+
 fn handle_prepare_withdrawals(
+    program_id: &Pubkey,
     accounts: &[AccountInfo],
     token_withdrawals: &TokenWithdrawals,
-    fee_account_address: &str,
+    fee_account_address: &WalletAddress,
     tx_outs: &mut Vec<TxOut>,
-    network_type: &NetworkType,
     edicts: &mut Vec<Edict>,
 ) -> Result<(), ProgramError> {
     let account = &accounts[token_withdrawals.account_index as usize];
+    let withdrawal_limit = get_withdrawal_limit(accounts, account)?;
     for withdrawal in &token_withdrawals.withdrawals {
-        Balance::decrement_wallet_balance(account, withdrawal.address_index.index as usize, withdrawal.amount)?;
+        let (balance_account, index) = get_validated_index(program_id, accounts, token_withdrawals.account_index, &withdrawal.address_index)?;
+        Balance::decrement_wallet_balance(balance_account, index, withdrawal.amount)?;
+        // nonces stay used when the batch is rolled back
+        if let Some(signature) = &withdrawal.signature {
+            let address_hash = Balance::get_wallet_address(balance_account, index)?.hash();
+            WithdrawalNonceState::use_nonce(get_withdrawal_nonces(accounts)?, &address_hash, signature.nonce)?;
+        }
+        if let Some((limit_account, block_height)) = withdrawal_limit {
+            let address_hash = Balance::get_wallet_address(balance_account, index)?.hash();
+            WithdrawalLimitState::record_withdrawal(limit_account, &address_hash, withdrawal.amount, block_height)?;
+        }
         if withdrawal.fee_amount > 0 {
             let fee_account = &accounts[withdrawal.fee_account_index as usize];
-            if Balance::get_wallet_address(fee_account, FEE_ADDRESS_INDEX as usize)? != fee_account_address {
+            if Balance::get_wallet_address(fee_account, FEE_ADDRESS_INDEX as usize)? != *fee_account_address {
                 return Err(ProgramError::Custom(ERROR_ADDRESS_MISMATCH));
             }
             Balance::increment_wallet_balance(fee_account, FEE_ADDRESS_INDEX as usize, withdrawal.fee_amount)?;
             if fee_account.key != account.key {
-                Balance::decrement_wallet_balance(fee_account, withdrawal.fee_address_index.index as usize, withdrawal.fee_amount)?;
+                let (fee_balance_account, fee_index) = get_validated_index(program_id, accounts, withdrawal.fee_account_index, &withdrawal.fee_address_index)?;
+                Balance::decrement_wallet_balance(fee_balance_account, fee_index, withdrawal.fee_amount)?;
             }
         }
-        add_withdrawal_output(account, &withdrawal, tx_outs, network_type, edicts)?;
+        ProgramState::emit_event(
+            accounts,
+            &Event::WithdrawalPrepared {
+                sequence: ProgramState::next_event_sequence(&accounts[0])?,
+                account_index: token_withdrawals.account_index,
+                address_index: withdrawal.address_index.index,
+                amount: withdrawal.amount,
+                fee_amount: withdrawal.fee_amount,
+            },
+        )?;
+        if withdrawal.fee_amount > 0 {
+            ProgramState::emit_event(
+                accounts,
+                &Event::FeeCollected {
+                    sequence: ProgramState::next_event_sequence(&accounts[0])?,
+                    account_index: withdrawal.fee_account_index,
+                    amount: withdrawal.fee_amount,
+                },
+            )?;
+        }
+        add_withdrawal_output(account, &Balance::get_wallet_address(balance_account, index)?, &withdrawal, tx_outs, edicts)?;
     }
     Ok(())
 }
 
 fn handle_submit_withdrawals(
+    program_id: &Pubkey,
     accounts: &[AccountInfo],
     token_withdrawals: &TokenWithdrawals,
+    forced_withdrawals: Option<&AccountInfo>,
     tx_outs: &mut Vec<TxOut>,
-    network_type: &NetworkType,
     edicts: &mut Vec<Edict>,
 ) -> Result<(), ProgramError> {
     let account = &accounts[token_withdrawals.account_index as usize];
     for withdrawal in &token_withdrawals.withdrawals {
         validate_account(accounts, withdrawal.fee_account_index, false, false, Some(AccountType::Token), Some(0))?;
-        let _ = get_validated_index(account, &withdrawal.address_index)?;
-        add_withdrawal_output(account, &withdrawal, tx_outs, &network_type, edicts)?;
+        let (balance_account, index) = get_validated_index(program_id, accounts, token_withdrawals.account_index, &withdrawal.address_index)?;
+        let wallet_address = Balance::get_wallet_address(balance_account, index)?;
+        if let Some(registry) = forced_withdrawals {
+            serve_forced_withdrawal(registry, account, &wallet_address, withdrawal.amount, Balance::get_wallet_balance(balance_account, index)?)?;
+        }
+        add_withdrawal_output(account, &wallet_address, &withdrawal, tx_outs, edicts)?;
     }
     Ok(())
 }
 
 fn add_withdrawal_output(
     account: &AccountInfo,
+    wallet_address: &WalletAddress,
     withdrawal: &Withdrawal,
     tx_outs: &mut Vec<TxOut>,
-    network_type: &NetworkType,
     edicts: &mut Vec<Edict>,
 ) -> Result<(), ProgramError> {
     let is_rune = TokenState::is_rune_account(account);
@@ -563,26 +2103,141 @@ fn add_withdrawal_output(
             TokenState::get_rune_id(account)?,
             tx_outs,
             edicts,
-            get_bitcoin_address(
-                &Balance::get_wallet_address(account, withdrawal.address_index.index as usize)?,
-                network_type,
-            ).script_pubkey(),
+            wallet_address.script_pubkey()?,
             withdrawal.amount,
         )?;
     } else {
         tx_outs.push(
             TxOut {
-                value: Amount::from_sat(withdrawal.amount - withdrawal.fee_amount),
-                script_pubkey: get_bitcoin_address(
-                    &Balance::get_wallet_address(account, withdrawal.address_index.index as usize)?,
-                    network_type,
-                ).script_pubkey(),
+                value: Amount::from_sat(withdrawal.net_amount()?),
+                script_pubkey: wallet_address.script_pubkey()?,
+            }
+        );
+    }
+    Ok(())
+}
+
+// the amount of sats the output of `withdrawal` would take from the inputs
+fn withdrawal_output_amount(account: &AccountInfo, withdrawal: &Withdrawal) -> Result<u64, ProgramError> {
+    if TokenState::is_rune_account(account) {
+        Ok(DUST_THRESHOLD)
+    } else {
+        withdrawal.amount.checked_sub(withdrawal.fee_amount).ok_or(ProgramError::Custom(ERROR_FEE_EXCEEDS_AMOUNT))
+    }
+}
+
+// adds the change of a withdrawal batch and, when it withdraws runes, the rune change outputs and
+// the runestone
+fn add_change_outputs(
+    program_id: &Pubkey,
+    accounts: &[AccountInfo],
+    tx_outs: &mut Vec<TxOut>,
+    mut edicts: Vec<Edict>,
+    change_amount: u64,
+) -> Result<(), ProgramError> {
+    if change_amount > 0 {
+        tx_outs.push(
+            TxOut {
+                value: Amount::from_sat(change_amount),
+                script_pubkey: ScriptBuf::from_bytes(get_account_script_pubkey(program_id).to_vec()),
             }
         );
     }
+
+    if !edicts.is_empty() {
+        let rune_ids: HashSet<RuneId> = HashSet::from_iter(edicts.iter().map(|e| e.id).collect::<Vec<RuneId>>().to_vec());
+        rune_ids.into_iter().for_each(|rune_id| {
+            add_edict_and_output(
+                rune_id,
+                tx_outs,
+                &mut edicts,
+                ScriptBuf::from_bytes(get_account_script_pubkey(accounts[2].key).to_vec()),
+                0,
+            ).expect("cannot add change edict");
+        });
+
+        let runestone = Runestone {
+            edicts,
+            etching: None,
+            mint: None,
+            pointer: None,
+        };
+
+        let runestone_bytes = runestone.encipher().to_bytes();
+        tx_outs.push(
+            TxOut {
+                script_pubkey: ScriptBuf::from_bytes(runestone_bytes),
+                value: Amount::from_sat(0),
+            },
+        );
+    }
     Ok(())
 }
 
+// Checks that the inputs of a withdrawal batch are utxos of the program that pay for its outputs,
+// the change included, at a fee rate within the bounds of the program state. The first
+// `state_transitions` inputs and outputs of `tx` are the state transition, which carries its own
+// value, so they are left out of the fee but not of the size of the transaction, which is returned.
+fn verify_withdraw_fee(
+    program_id: &Pubkey,
+    accounts: &[AccountInfo],
+    has_rune_receiver: bool,
+    tx: &Transaction,
+    state_transitions: usize,
+    input_utxo_types: &[InputUtxoType],
+) -> Result<u64, ProgramError> {
+    // a state transition output pays to the script of the account whose utxo the input spends
+    let mut witness_weight: u64 = 0;
+    for output in &tx.output[..state_transitions] {
+        witness_weight += signed_witness_weight(&output.script_pubkey)?;
+    }
+    let mut input_amount: u64 = 0;
+    for (input, input_utxo_type) in tx.input[state_transitions..].iter().zip(input_utxo_types) {
+        let owner = match input_utxo_type {
+            InputUtxoType::Bitcoin => program_id,
+            InputUtxoType::Rune if has_rune_receiver => accounts[2].key,
+            InputUtxoType::Rune => return Err(ProgramError::Custom(ERROR_NO_RUNE_RECEIVER)),
+        };
+        let mut prev_tx_id = input.previous_output.txid.to_byte_array();
+        prev_tx_id.reverse();
+        if !validate_utxo_ownership(&UtxoMeta::from(prev_tx_id, input.previous_output.vout), owner) {
+            return Err(ProgramError::Custom(ERROR_INVALID_UTXO_OWNER));
+        }
+        let prev_tx_bytes = get_bitcoin_tx(prev_tx_id)
+            .ok_or(ProgramError::Custom(ERROR_PREVOUT_NOT_FOUND))?;
+        let prev_tx: Transaction = bitcoin::consensus::deserialize(&prev_tx_bytes)
+            .map_err(|_| ProgramError::Custom(ERROR_INVALID_INPUT_TX))?;
+        let prev_output = prev_tx.output.get(input.previous_output.vout as usize)
+            .ok_or(ProgramError::Custom(ERROR_PREVOUT_NOT_FOUND))?;
+        input_amount = checked_add_amount(input_amount, prev_output.value.to_sat())?;
+        witness_weight += signed_witness_weight(&prev_output.script_pubkey)?;
+    }
+    let output_amount = tx.output[state_transitions..].iter()
+        .try_fold(0, |total, output| checked_add_amount(total, output.value.to_sat()))?;
+    // the change can't take more than what the inputs leave after the outputs
+    let fee = input_amount.checked_sub(output_amount)
+        .ok_or(ProgramError::Custom(ERROR_INVALID_CHANGE))?;
+    // the inputs are unsigned, so the witnesses they will get are added to the weight
+    let vsize = (tx.weight().to_wu() + SEGWIT_MARKER_WEIGHT + witness_weight).div_ceil(4);
+    if let Some(fee_rate) = ProgramState::get_withdrawal_fee_rate(&accounts[0])? {
+        if !fee_rate.allows(fee, vsize) {
+            return Err(ProgramError::Custom(ERROR_FEE_RATE_OUT_OF_BOUNDS));
+        }
+    }
+    Ok(vsize)
+}
+
+// the weight of the witness that spends an output with `script_pubkey`
+fn signed_witness_weight(script_pubkey: &ScriptBuf) -> Result<u64, ProgramError> {
+    if script_pubkey.is_p2tr() {
+        Ok(TAPROOT_KEY_SPEND_WITNESS_WEIGHT)
+    } else if script_pubkey.is_p2wpkh() {
+        Ok(P2WPKH_WITNESS_WEIGHT)
+    } else {
+        Err(ProgramError::Custom(ERROR_INVALID_INPUT_TX))
+    }
+}
+
 fn add_edict_and_output(
     rune_id: RuneId,
     tx_outs: &mut Vec<TxOut>,
@@ -605,23 +2260,25 @@ fn add_edict_and_output(
 }
 
 fn handle_rollback_withdrawals(
+    program_id: &Pubkey,
     accounts: &[AccountInfo],
     token_withdrawals: &TokenWithdrawals,
-    fee_account_address: &str,
+    fee_account_address: &WalletAddress,
 ) -> Result<(), ProgramError> {
     let account = &accounts[token_withdrawals.account_index as usize];
     for withdrawal in &token_withdrawals.withdrawals {
-        let index = get_validated_index(account, &withdrawal.address_index)?;
-        Balance::increment_wallet_balance(account, index, withdrawal.amount)?;
+        let (balance_account, index) = get_validated_index(program_id, accounts, token_withdrawals.account_index, &withdrawal.address_index)?;
+        Balance::increment_wallet_balance(balance_account, index, withdrawal.amount)?;
         if withdrawal.fee_amount > 0 {
             validate_account(accounts, withdrawal.fee_account_index, false, true, Some(AccountType::Token), Some(0))?;
             let fee_account = &accounts[withdrawal.fee_account_index as usize];
-            if Balance::get_wallet_address(fee_account, FEE_ADDRESS_INDEX as usize)? != fee_account_address {
+            if Balance::get_wallet_address(fee_account, FEE_ADDRESS_INDEX as usize)? != *fee_account_address {
                 return Err(ProgramError::Custom(ERROR_ADDRESS_MISMATCH));
             }
             Balance::decrement_wallet_balance(fee_account, FEE_ADDRESS_INDEX as usize, withdrawal.fee_amount)?;
             if fee_account.key != account.key {
-                Balance::increment_wallet_balance(fee_account, withdrawal.fee_address_index.index as usize, withdrawal.fee_amount)?;
+                let (fee_balance_account, fee_index) = get_validated_index(program_id, accounts, withdrawal.fee_account_index, &withdrawal.fee_address_index)?;
+                Balance::increment_wallet_balance(fee_balance_account, fee_index, withdrawal.fee_amount)?;
             }
         }
     }
@@ -638,28 +2295,75 @@ fn init_state_data(account: &AccountInfo, new_data: Vec<u8>, additional_bytes: u
         return Err(ProgramError::InvalidRealloc);
     }
     account.realloc(new_data.len() + additional_bytes, true)?;
-    account.data.try_borrow_mut().unwrap()[0..EVENTS_OFFSET].copy_from_slice(new_data.as_slice());
+    account.data.try_borrow_mut().unwrap()[0..new_data.len()].copy_from_slice(new_data.as_slice());
     Ok(())
 }
 
-pub fn get_validated_index(account: &AccountInfo, address_index: &AddressIndex) -> Result<usize, ProgramError> {
-    let index = address_index.index as usize;
-    if index >= TokenState::get_num_balances(account)? {
+// returns the account holding the balance together with its index in that account
+pub fn get_validated_index<'a, 'b>(program_id: &Pubkey, accounts: &'a [AccountInfo<'b>], account_index: u8, address_index: &AddressIndex) -> Result<(&'a AccountInfo<'b>, usize), ProgramError> {
+    let balance_account = get_balance_account(program_id, accounts, account_index, address_index.shard())?;
+    let index = address_index.balance_index();
+    if index >= TokenState::get_num_balances(balance_account)? {
         return Err(ProgramError::Custom(ERROR_INVALID_ADDRESS_INDEX));
     }
-    if Balance::get_wallet_address_last4(account, index)? != address_index.last4 {
-        return Err(ProgramError::Custom(ERROR_WALLET_LAST4_MISMATCH));
-    }
-    Ok(index)
+    Balance::verify_wallet_address(balance_account, index, &address_index.commitment)?;
+    Ok((balance_account, index))
 }
 
-pub fn get_validated_index_withdraw(account: &AccountInfo, address_index: &AddressIndex, network_type: &NetworkType) -> Result<usize, ProgramError> {
-    let index = get_validated_index(account, address_index)?;
-    let wallet_address = Balance::get_wallet_address(account, index)?;
-    validate_bitcoin_address(&wallet_address, network_type, true)?;
-    if TokenState::can_withdraw(account) {
-        Ok(index)
+pub fn get_validated_index_withdraw<'a, 'b>(program_id: &Pubkey, accounts: &'a [AccountInfo<'b>], account_index: u8, address_index: &AddressIndex, network_type: &NetworkType) -> Result<(&'a AccountInfo<'b>, usize), ProgramError> {
+    let (balance_account, index) = get_validated_index(program_id, accounts, account_index, address_index)?;
+    if !Balance::get_wallet_address(balance_account, index)?.is_valid_for_network(network_type) {
+        return Err(ProgramError::Custom(ERROR_INVALID_ADDRESS_NETWORK));
+    }
+    if TokenState::can_withdraw(&accounts[account_index as usize]) {
+        Ok((balance_account, index))
     } else {
         Err(ProgramError::Custom(ERROR_WITHDRAWAL_NOT_ALLOWED))
     }
 }
+
+// shard 0 is the token state itself, the balance shards of a token can be passed in any position
+fn get_balance_account<'a, 'b>(program_id: &Pubkey, accounts: &'a [AccountInfo<'b>], account_index: u8, shard: u8) -> Result<&'a AccountInfo<'b>, ProgramError> {
+    let token_account = &accounts[account_index as usize];
+    if shard == 0 {
+        return Ok(token_account);
+    }
+    let balance_account = find_balance_shard(accounts, token_account, shard)?;
+    if balance_account.owner != program_id || !is_balance_shard(balance_account, token_account.key, shard) {
+        return Err(ProgramError::Custom(ERROR_STATE_ACCOUNT_MISMATCH));
+    }
+    if balance_account.is_signer || (token_account.is_writable && !balance_account.is_writable) {
+        return Err(ProgramError::Custom(ERROR_INVALID_ACCOUNT_FLAGS));
+    }
+    if get_version(balance_account)? != BALANCE_SHARD_STATE_VERSION {
+        return Err(ProgramError::Custom(ERROR_INVALID_ACCOUNT_VERSION));
+    }
+    Ok(balance_account)
+}
+
+// follows the shard chain of the token state, so every shard before the requested one has to be
+// passed as well unless it is the last shard
+fn find_balance_shard<'a, 'b>(accounts: &'a [AccountInfo<'b>], token_account: &AccountInfo, shard: u8) -> Result<&'a AccountInfo<'b>, ProgramError> {
+    let num_balance_shards = TokenState::get_num_balance_shards(token_account)?;
+    let find_account = |key: Option<Pubkey>| key
+        .and_then(|key| accounts.iter().find(|account| *account.key == key))
+        .ok_or(ProgramError::Custom(ERROR_BALANCE_SHARD_MISSING));
+    if shard as usize > num_balance_shards {
+        return Err(ProgramError::Custom(ERROR_BALANCE_SHARD_MISSING));
+    }
+    if shard as usize == num_balance_shards {
+        return find_account(TokenState::get_last_balance_shard_key(token_account)?);
+    }
+    let mut balance_account = find_account(TokenState::get_first_balance_shard_key(token_account)?)?;
+    for _ in 1..shard {
+        balance_account = find_account(BalanceShardState::get_next_balance_shard_key(balance_account)?)?;
+    }
+    Ok(balance_account)
+}
+
+fn is_balance_shard(account: &AccountInfo, token_key: &Pubkey, shard: u8) -> bool {
+    !account.data_is_empty()
+        && get_type(account).is_ok_and(|account_type| account_type == AccountType::BalanceShard)
+        && BalanceShardState::get_token_state_account_key(account).is_ok_and(|key| key == *token_key)
+        && BalanceShardState::get_shard(account).is_ok_and(|number| number == shard)
+}
@@ -0,0 +1,3181 @@
+use arch_program::account::AccountMeta;
+use arch_program::program_error::ProgramError;
+use arch_program::pubkey::Pubkey;
+use arch_program::utxo::UtxoMeta;
+use bitcoin::hashes::Hash;
+use bitcoin::{
+    absolute::LockTime, transaction::Version, Address, Amount, Network, OutPoint, ScriptBuf,
+    Sequence, Transaction, TxIn, TxOut, Txid, WPubkeyHash, Witness,
+};
+use ordinals::{Artifact, Edict, RuneId, Runestone};
+use std::rc::Rc;
+use std::str::FromStr;
+
+use model::address::WalletAddress;
+use model::error::*;
+use model::instructions::*;
+use model::state::*;
+use model::serialization::Codable;
+
+use crate::harness::*;
+
+fn meta(pubkey: Pubkey, is_signer: bool, is_writable: bool) -> AccountMeta {
+    AccountMeta {
+        pubkey,
+        is_signer,
+        is_writable,
+    }
+}
+
+fn wallet_address(seed: u8) -> String {
+    Address::from_script(
+        &ScriptBuf::new_p2wpkh(&WPubkeyHash::from_byte_array([seed; 20])),
+        Network::Regtest,
+    ).unwrap().to_string()
+}
+
+fn numbered_wallet_address(number: u32) -> String {
+    let mut hash = [0xff; 20];
+    hash[16..].copy_from_slice(&number.to_le_bytes());
+    Address::from_script(
+        &ScriptBuf::new_p2wpkh(&WPubkeyHash::from_byte_array(hash)),
+        Network::Regtest,
+    ).unwrap().to_string()
+}
+
+fn funding_outpoint(txid: Txid, vout: u32) -> Option<FundingOutpoint> {
+    Some(FundingOutpoint {
+        tx_id: txid_to_bytes(&txid),
+        vout,
+    })
+}
+
+struct TestExchange {
+    harness: Harness,
+    program_state: Pubkey,
+    withdraw: Pubkey,
+    rune_receiver: Pubkey,
+    deposit_ledgers: Vec<Pubkey>,
+    event_logs: Vec<Pubkey>,
+    wallet_indexes: Vec<Pubkey>,
+    balance_shards: Vec<Pubkey>,
+    withdrawal_limits: Vec<Pubkey>,
+    forced_withdrawals: Vec<Pubkey>,
+    withdrawal_nonces: Vec<Pubkey>,
+    utxo_registry: Vec<Pubkey>,
+    fee_address: String,
+}
+
+impl TestExchange {
+    fn new() -> Self {
+        let mut harness = Harness::new();
+        let program_state = harness.create_account();
+        let withdraw = harness.create_account();
+        let rune_receiver = harness.create_account();
+        let fee_address = wallet_address(0);
+        let program_change_address = harness.address(&harness.program_id).to_string();
+        harness.process(
+            &[meta(program_state, true, true), meta(withdraw, false, true)],
+            &ProgramInstruction::InitProgramState(InitProgramStateParams {
+                fee_account: fee_address.clone(),
+                program_change_address,
+                network_type: NetworkType::Regtest,
+            }),
+        ).unwrap();
+        harness.process(
+            &[meta(program_state, true, true), meta(rune_receiver, false, true)],
+            &ProgramInstruction::InitRuneReceiverState(),
+        ).unwrap();
+        let mut exchange = Self {
+            harness,
+            program_state,
+            withdraw,
+            rune_receiver,
+            deposit_ledgers: vec![],
+            event_logs: vec![],
+            wallet_indexes: vec![],
+            balance_shards: vec![],
+            withdrawal_limits: vec![],
+            forced_withdrawals: vec![],
+            withdrawal_nonces: vec![],
+            utxo_registry: vec![],
+            fee_address,
+        };
+        exchange.add_event_log();
+        exchange
+    }
+
+    fn add_token(&mut self, token_id: &str) -> Pubkey {
+        let token = self.harness.create_account();
+        self.harness.process(
+            &[meta(self.program_state, true, false), meta(token, false, true)],
+            &ProgramInstruction::InitTokenState(InitTokenStateParams {
+                token_id: token_id.to_string(),
+            }),
+        ).unwrap();
+        token
+    }
+
+    fn add_wallet(&mut self, token: Pubkey, address: &str) -> AddressIndex {
+        self.add_wallets(token, &[address.to_string()]).unwrap();
+        AddressIndex {
+            index: self.token_state(token).balances.len() as u32 - 1,
+            commitment: address_commitment(address),
+        }
+    }
+
+    fn add_wallets(&mut self, token: Pubkey, addresses: &[String]) -> Result<(), ProgramError> {
+        let mut accounts = vec![meta(self.program_state, true, false), meta(token, false, true)];
+        accounts.extend(self.wallet_indexes.iter().map(|index| meta(*index, false, true)));
+        accounts.extend(self.balance_shard_accounts(true));
+        self.harness.process(
+            &accounts,
+            &ProgramInstruction::InitWalletBalances(InitWalletBalancesParams {
+                token_state_setups: vec![TokenStateSetup {
+                    account_index: 1,
+                    wallet_addresses: addresses.to_vec(),
+                }],
+            }),
+        )
+    }
+
+    fn add_wallet_index(&mut self, token: Pubkey) -> Pubkey {
+        let index = self.harness.create_account();
+        self.harness.process(
+            &[meta(self.program_state, true, false), meta(token, false, true), meta(index, false, true)],
+            &ProgramInstruction::InitWalletIndex(),
+        ).unwrap();
+        self.wallet_indexes.push(index);
+        index
+    }
+
+    fn wallet_index(&self, index: Pubkey) -> WalletIndexState {
+        self.harness.decode_account(&index)
+    }
+
+    fn add_balance_shard(&mut self, token: Pubkey) -> Pubkey {
+        let balance_shard = self.harness.create_account();
+        let mut accounts = vec![meta(self.program_state, true, false), meta(token, false, true), meta(balance_shard, false, true)];
+        if self.token_state(token).num_balance_shards > 0 {
+            accounts.push(meta(self.token_state(token).last_balance_shard_account, false, true));
+        }
+        self.harness.process(&accounts, &ProgramInstruction::InitBalanceShard()).unwrap();
+        self.balance_shards.push(balance_shard);
+        balance_shard
+    }
+
+    fn balance_shard(&self, balance_shard: Pubkey) -> BalanceShardState {
+        self.harness.decode_account(&balance_shard)
+    }
+
+    fn balance_shard_accounts(&self, is_writable: bool) -> Vec<AccountMeta> {
+        self.balance_shards.iter().map(|balance_shard| meta(*balance_shard, false, is_writable)).collect()
+    }
+
+    fn deposit(&mut self, token: Pubkey, address_index: &AddressIndex, amount: u64) -> Result<(), ProgramError> {
+        self.funded_deposit(token, address_index, amount, None)
+    }
+
+    fn funded_deposit(&mut self, token: Pubkey, address_index: &AddressIndex, amount: u64, funding_outpoint: Option<FundingOutpoint>) -> Result<(), ProgramError> {
+        let mut accounts = vec![meta(self.program_state, true, true), meta(token, false, true)];
+        accounts.extend(self.deposit_ledgers.iter().map(|ledger| meta(*ledger, false, true)));
+        accounts.extend(self.event_log_accounts());
+        accounts.extend(self.balance_shard_accounts(true));
+        accounts.extend(self.utxo_registry.iter().map(|registry| meta(*registry, false, true)));
+        self.harness.process(
+            &accounts,
+            &ProgramInstruction::BatchDeposit(DepositBatchParams {
+                token_deposits: vec![TokenDeposits {
+                    account_index: 1,
+                    deposits: vec![Deposit {
+                        address_index: address_index.clone(),
+                        amount,
+                        funding_outpoint,
+                    }],
+                }],
+            }),
+        )
+    }
+
+    fn add_deposit_ledger(&mut self) -> Pubkey {
+        let ledger = self.harness.create_account();
+        let mut accounts = vec![meta(self.program_state, true, true), meta(ledger, false, true)];
+        if let Some(last_ledger) = self.deposit_ledgers.last() {
+            accounts.push(meta(*last_ledger, false, true));
+        }
+        self.harness.process(&accounts, &ProgramInstruction::InitDepositLedger()).unwrap();
+        self.deposit_ledgers.push(ledger);
+        ledger
+    }
+
+    fn add_event_log(&mut self) -> Pubkey {
+        let event_log = self.harness.create_account();
+        let mut accounts = vec![meta(self.program_state, true, true), meta(event_log, false, true)];
+        if let Some(last_event_log) = self.event_logs.last() {
+            accounts.push(meta(*last_event_log, false, true));
+        }
+        self.harness.process(&accounts, &ProgramInstruction::InitEventLog()).unwrap();
+        self.event_logs.push(event_log);
+        event_log
+    }
+
+    fn event_log_accounts(&self) -> Vec<AccountMeta> {
+        self.event_logs.iter().map(|event_log| meta(*event_log, false, true)).collect()
+    }
+
+    // the events emitted by the last instruction are the last `events_count` entries of the chain
+    fn events(&self) -> Vec<Event> {
+        let program_state = self.program_state();
+        let mut cursor = EventCursor {
+            event_log_account: program_state.first_event_log_account,
+            index: 0,
+        };
+        let mut events = vec![];
+        loop {
+            let event_log: EventLogState = self.harness.decode_account(&cursor.event_log_account);
+            let (page, next_cursor) = event_log.read_events(&cursor, 100);
+            if page.is_empty() && next_cursor == cursor {
+                break;
+            }
+            events.extend(page);
+            cursor = next_cursor;
+        }
+        events.split_off(events.len() - program_state.events_count as usize)
+    }
+
+    fn add_funding_tx(&mut self, output: Vec<TxOut>) -> Txid {
+        self.harness.add_bitcoin_tx(Transaction {
+            version: Version::TWO,
+            lock_time: LockTime::ZERO,
+            input: vec![TxIn::default()],
+            output,
+        })
+    }
+
+    // an unsigned withdrawal tx spending `num_inputs` funded outputs of the program
+    fn input_tx(&mut self, num_inputs: u8) -> Vec<u8> {
+        self.input_tx_with_values(&vec![100_000_000; num_inputs as usize])
+    }
+
+    fn input_tx_with_values(&mut self, values: &[u64]) -> Vec<u8> {
+        let script_pubkey = self.harness.script_pubkey(&self.harness.program_id);
+        let funding_txid = self.add_funding_tx(
+            values.iter().map(|value| TxOut { value: Amount::from_sat(*value), script_pubkey: script_pubkey.clone() }).collect(),
+        );
+        bitcoin::consensus::serialize(&Transaction {
+            version: Version::TWO,
+            lock_time: LockTime::ZERO,
+            input: (0..values.len()).map(|vout| TxIn {
+                previous_output: OutPoint {
+                    txid: funding_txid,
+                    vout: vout as u32,
+                },
+                script_sig: ScriptBuf::new(),
+                sequence: Sequence::MAX,
+                witness: Witness::new(),
+            }).collect(),
+            output: vec![],
+        })
+    }
+
+    fn token_state(&self, token: Pubkey) -> TokenState {
+        self.harness.decode_account(&token)
+    }
+
+    fn program_state(&self) -> ProgramState {
+        self.harness.decode_account(&self.program_state)
+    }
+
+    fn balances(&self, token: Pubkey) -> Vec<u64> {
+        self.token_state(token).balances.iter().map(|b| b.balance).collect()
+    }
+
+    fn settlement_accounts(&self, tokens: &[Pubkey], is_writable: bool) -> Vec<AccountMeta> {
+        let mut accounts = vec![meta(self.program_state, true, true)];
+        accounts.extend(tokens.iter().map(|token| meta(*token, false, is_writable)));
+        accounts.extend(self.event_log_accounts());
+        accounts.extend(self.balance_shard_accounts(is_writable));
+        accounts.extend(self.forced_withdrawals.iter().map(|registry| meta(*registry, false, false)));
+        accounts
+    }
+
+    fn close_balance_accounts(&self, token: Pubkey) -> Vec<AccountMeta> {
+        let mut accounts = vec![meta(self.program_state, true, true), meta(self.withdraw, false, false), meta(token, false, true)];
+        accounts.extend(self.event_log_accounts());
+        accounts.extend(self.wallet_indexes.iter().map(|index| meta(*index, false, true)));
+        accounts
+    }
+
+    fn prepare_withdraw_accounts(&self, tokens: &[Pubkey], has_runes: bool) -> Vec<AccountMeta> {
+        let mut accounts = vec![meta(self.program_state, true, true), meta(self.withdraw, false, true)];
+        if has_runes {
+            accounts.push(meta(self.rune_receiver, false, false));
+        }
+        accounts.extend(tokens.iter().map(|token| meta(*token, false, true)));
+        accounts.extend(self.event_log_accounts());
+        accounts.extend(self.balance_shard_accounts(true));
+        accounts.extend(self.withdrawal_limits.iter().map(|limit| meta(*limit, false, true)));
+        accounts.extend(self.withdrawal_nonces.iter().map(|nonces| meta(*nonces, false, true)));
+        accounts.extend(self.utxo_registry.iter().map(|registry| meta(*registry, false, true)));
+        accounts
+    }
+
+    fn confirm_withdrawal(&mut self, txid: Txid) -> Result<(), ProgramError> {
+        let mut accounts = vec![meta(self.program_state, true, true), meta(self.withdraw, true, true)];
+        accounts.extend(self.event_log_accounts());
+        accounts.extend(self.utxo_registry.iter().map(|registry| meta(*registry, false, true)));
+        self.harness.process(
+            &accounts,
+            &ProgramInstruction::ConfirmWithdrawBatch(ConfirmWithdrawBatchParams {
+                tx_id: txid_to_bytes(&txid),
+            }),
+        )
+    }
+
+    fn mine_blocks(&mut self, blocks: u64) {
+        self.harness.syscalls().block_height += blocks;
+    }
+
+    fn submit_withdraw_accounts(&self, tokens: &[Pubkey], has_runes: bool) -> Vec<AccountMeta> {
+        let mut accounts = vec![meta(self.program_state, true, true), meta(self.withdraw, true, true)];
+        if has_runes {
+            accounts.push(meta(self.rune_receiver, true, false));
+        }
+        accounts.extend(tokens.iter().map(|token| meta(*token, false, false)));
+        accounts.extend(self.event_log_accounts());
+        accounts.extend(self.balance_shard_accounts(false));
+        accounts.extend(self.forced_withdrawals.iter().map(|registry| meta(*registry, false, true)));
+        accounts.extend(self.utxo_registry.iter().map(|registry| meta(*registry, false, true)));
+        accounts
+    }
+}
+
+#[test]
+fn test_deposit() {
+    let mut exchange = TestExchange::new();
+    let btc = exchange.add_token("btc");
+    let wallet = wallet_address(1);
+    let wallet_index = exchange.add_wallet(btc, &wallet);
+
+    exchange.deposit(btc, &wallet_index, 10000).unwrap();
+    exchange.deposit(btc, &wallet_index, 6000).unwrap();
+
+    let token_state = exchange.token_state(btc);
+    assert_eq!(AccountType::Token, token_state.account_type);
+    assert_eq!(exchange.program_state, token_state.program_state_account);
+    assert_eq!(
+        vec![
+            Balance { address: exchange.fee_address.clone(), balance: 0 },
+            Balance { address: wallet.clone(), balance: 16000 },
+        ],
+        token_state.balances,
+    );
+    assert_eq!(
+        vec![Event::DepositCredited { sequence: 2, account_index: 1, address_index: 1, amount: 6000 }],
+        exchange.events(),
+    );
+
+    // a failed instruction leaves the account untouched
+    assert_eq!(
+        Err(ProgramError::Custom(ERROR_ADDRESS_COMMITMENT_MISMATCH)),
+        exchange.deposit(btc, &AddressIndex { index: 1, commitment: AddressCommitment::ScriptHash([0; 8]) }, 1000),
+    );
+    assert_eq!(
+        Err(ProgramError::Custom(ERROR_WALLET_LAST4_MISMATCH)),
+        exchange.deposit(btc, &AddressIndex { index: 1, commitment: AddressCommitment::Last4([0; 4]) }, 1000),
+    );
+    assert_eq!(
+        Err(ProgramError::Custom(ERROR_ARITHMETIC_OVERFLOW)),
+        exchange.deposit(btc, &wallet_index, u64::MAX),
+    );
+    assert_eq!(vec![0, 16000], exchange.balances(btc));
+
+    // address indexes of batches encoded before commitments are still checked against the last4
+    let legacy_index = AddressIndex { index: 1, commitment: AddressCommitment::Last4(wallet_last4(&wallet)) };
+    exchange.deposit(btc, &legacy_index, 1000).unwrap();
+    assert_eq!(vec![0, 17000], exchange.balances(btc));
+}
+
+#[test]
+fn test_funded_deposit() {
+    let mut exchange = TestExchange::new();
+    let btc = exchange.add_token("btc");
+    let wallet = wallet_address(1);
+    let wallet_index = exchange.add_wallet(btc, &wallet);
+    let program_script_pubkey = exchange.harness.script_pubkey(&exchange.harness.program_id);
+    let txid = exchange.add_funding_tx(vec![
+        TxOut {
+            value: Amount::from_sat(20000),
+            script_pubkey: get_bitcoin_address(&wallet, &NetworkType::Regtest).script_pubkey(),
+        },
+        TxOut {
+            value: Amount::from_sat(10000),
+            script_pubkey: program_script_pubkey,
+        },
+    ]);
+
+    exchange.funded_deposit(btc, &wallet_index, 10000, funding_outpoint(txid, 1)).unwrap();
+    assert_eq!(vec![0, 10000], exchange.balances(btc));
+
+    assert_eq!(
+        Err(ProgramError::Custom(ERROR_DEPOSIT_AMOUNT_MISMATCH)),
+        exchange.funded_deposit(btc, &wallet_index, 10001, funding_outpoint(txid, 1)),
+    );
+    // the output has to pay the program
+    assert_eq!(
+        Err(ProgramError::Custom(ERROR_DEPOSIT_OUTPUT_MISMATCH)),
+        exchange.funded_deposit(btc, &wallet_index, 1000, funding_outpoint(txid, 0)),
+    );
+    assert_eq!(
+        Err(ProgramError::Custom(ERROR_DEPOSIT_OUTPUT_MISMATCH)),
+        exchange.funded_deposit(btc, &wallet_index, 1000, funding_outpoint(txid, 2)),
+    );
+    assert_eq!(
+        Err(ProgramError::Custom(ERROR_DEPOSIT_TX_NOT_FOUND)),
+        exchange.funded_deposit(btc, &wallet_index, 1000, funding_outpoint(Txid::from_byte_array([9; 32]), 1)),
+    );
+    assert_eq!(vec![0, 10000], exchange.balances(btc));
+}
+
+#[test]
+fn test_funded_rune_deposit() {
+    let mut exchange = TestExchange::new();
+    let rune_id = RuneId::from_str("840000:3").unwrap();
+    let other_rune_id = RuneId::from_str("840000:4").unwrap();
+    let rune = exchange.add_token(&rune_id.to_string());
+    let wallet = wallet_address(1);
+    let wallet_index = exchange.add_wallet(rune, &wallet);
+    let rune_receiver_script_pubkey = exchange.harness.script_pubkey(&exchange.rune_receiver);
+    let runestone = Runestone {
+        edicts: vec![
+            Edict { id: rune_id, amount: 600, output: 1 },
+            Edict { id: other_rune_id, amount: 5000, output: 1 },
+            Edict { id: rune_id, amount: 400, output: 1 },
+            Edict { id: rune_id, amount: 0, output: 0 },
+        ],
+        etching: None,
+        mint: None,
+        pointer: None,
+    };
+    let txid = exchange.add_funding_tx(vec![
+        TxOut {
+            value: Amount::from_sat(5000),
+            script_pubkey: get_bitcoin_address(&wallet, &NetworkType::Regtest).script_pubkey(),
+        },
+        TxOut {
+            value: Amount::from_sat(DUST_THRESHOLD),
+            script_pubkey: rune_receiver_script_pubkey,
+        },
+        TxOut {
+            value: Amount::from_sat(0),
+            script_pubkey: ScriptBuf::from_bytes(runestone.encipher().to_bytes()),
+        },
+    ]);
+
+    exchange.funded_deposit(rune, &wallet_index, 1000, funding_outpoint(txid, 1)).unwrap();
+    assert_eq!(vec![1000], exchange.balances(rune));
+
+    // only edicts for the token's rune count towards the deposit
+    assert_eq!(
+        Err(ProgramError::Custom(ERROR_DEPOSIT_AMOUNT_MISMATCH)),
+        exchange.funded_deposit(rune, &wallet_index, 1001, funding_outpoint(txid, 1)),
+    );
+    // runes have to be sent to the rune receiver
+    assert_eq!(
+        Err(ProgramError::Custom(ERROR_DEPOSIT_OUTPUT_MISMATCH)),
+        exchange.funded_deposit(rune, &wallet_index, 100, funding_outpoint(txid, 0)),
+    );
+    assert_eq!(vec![1000], exchange.balances(rune));
+}
+
+#[test]
+fn test_deposit_ledger() {
+    let mut exchange = TestExchange::new();
+    let btc = exchange.add_token("btc");
+    let wallet = wallet_address(1);
+    let wallet_index = exchange.add_wallet(btc, &wallet);
+    let program_script_pubkey = exchange.harness.script_pubkey(&exchange.harness.program_id);
+    let txids: Vec<Txid> = (0..4).map(|i| exchange.add_funding_tx(vec![
+        TxOut {
+            value: Amount::from_sat(1000 * (i + 1)),
+            script_pubkey: program_script_pubkey.clone(),
+        },
+    ])).collect();
+
+    let first_ledger = exchange.add_deposit_ledger();
+    assert_eq!(
+        first_ledger.0.as_slice(),
+        &exchange.harness.account(&exchange.program_state).data[DEPOSIT_LEDGER_OFFSET..DEPOSIT_LEDGER_OFFSET + PUBKEY_SIZE],
+    );
+    exchange.funded_deposit(btc, &wallet_index, 2000, funding_outpoint(txids[1], 0)).unwrap();
+    exchange.funded_deposit(btc, &wallet_index, 1000, funding_outpoint(txids[0], 0)).unwrap();
+    assert_eq!(vec![0, 3000], exchange.balances(btc));
+
+    // a retried deposit is skipped and reported
+    exchange.funded_deposit(btc, &wallet_index, 1000, funding_outpoint(txids[0], 0)).unwrap();
+    assert_eq!(vec![0, 3000], exchange.balances(btc));
+    assert_eq!(
+        vec![Event::DuplicateDeposit {
+            account_index: 1,
+            address_index: 1,
+            amount: 1000,
+            tx_id: txid_to_bytes(&txids[0]),
+            vout: 0,
+        }],
+        exchange.events(),
+    );
+
+    // new deposits go to the last ledger, older ledgers are still checked
+    let second_ledger = exchange.add_deposit_ledger();
+    exchange.funded_deposit(btc, &wallet_index, 3000, funding_outpoint(txids[2], 0)).unwrap();
+    exchange.funded_deposit(btc, &wallet_index, 2000, funding_outpoint(txids[1], 0)).unwrap();
+    assert_eq!(vec![0, 6000], exchange.balances(btc));
+    assert_eq!(1, exchange.events().len());
+
+    let first: DepositLedgerState = exchange.harness.decode_account(&first_ledger);
+    assert_eq!(AccountType::DepositLedger, first.account_type);
+    assert_eq!(second_ledger, first.next_ledger_account);
+    let mut expected = vec![
+        FundingOutpoint { tx_id: txid_to_bytes(&txids[0]), vout: 0 },
+        FundingOutpoint { tx_id: txid_to_bytes(&txids[1]), vout: 0 },
+    ];
+    expected.sort_by(|a, b| a.tx_id.cmp(&b.tx_id));
+    assert_eq!(expected, first.deposits);
+    let second: DepositLedgerState = exchange.harness.decode_account(&second_ledger);
+    assert_eq!(vec![FundingOutpoint { tx_id: txid_to_bytes(&txids[2]), vout: 0 }], second.deposits);
+
+    // a nearly full last ledger is reported so that the next one can be chained in time
+    let num_deposits = MAX_LEDGER_DEPOSITS - LEDGER_LOW_WATERMARK - 1;
+    {
+        let ledger = exchange.harness.account_mut(&second_ledger);
+        ledger.data.resize(DEPOSITS_OFFSET + (num_deposits + 1) * DEPOSIT_OUTPOINT_SIZE, 0);
+        ledger.data[DEPOSIT_COUNT_OFFSET..DEPOSIT_COUNT_OFFSET + DEPOSIT_COUNT_SIZE]
+            .copy_from_slice(&(num_deposits as u32).to_le_bytes());
+        for i in 1..num_deposits {
+            let offset = DEPOSITS_OFFSET + i * DEPOSIT_OUTPOINT_SIZE;
+            ledger.data[offset..offset + HASH_SIZE].copy_from_slice(&[0xff; 32]);
+            ledger.data[offset + HASH_SIZE..offset + DEPOSIT_OUTPOINT_SIZE].copy_from_slice(&(i as u32).to_le_bytes());
+        }
+    }
+    exchange.funded_deposit(btc, &wallet_index, 4000, funding_outpoint(txids[3], 0)).unwrap();
+    assert_eq!(
+        Some(&Event::DepositLedgerLow { remaining_deposits: LEDGER_LOW_WATERMARK as u32 }),
+        exchange.events().last(),
+    );
+
+    // every ledger in the chain has to be passed
+    exchange.deposit_ledgers.remove(0);
+    assert_eq!(
+        Err(ProgramError::Custom(ERROR_DEPOSIT_LEDGER_MISSING)),
+        exchange.funded_deposit(btc, &wallet_index, 1000, funding_outpoint(txids[0], 0)),
+    );
+}
+
+#[test]
+fn test_update_fee_account() {
+    let mut exchange = TestExchange::new();
+    let btc = exchange.add_token("btc");
+    let rune = exchange.add_token("840000:3");
+    let wallet1 = wallet_address(1);
+    let wallet2 = wallet_address(2);
+    let wallet1_btc = exchange.add_wallet(btc, &wallet1);
+    let wallet2_btc = exchange.add_wallet(btc, &wallet2);
+    exchange.add_wallet(rune, &wallet1);
+    exchange.deposit(btc, &wallet1_btc, 10000).unwrap();
+    let params = SettlementBatchParams {
+        settlements: vec![SettlementAdjustments {
+            account_index: 1,
+            increments: vec![Adjustment { address_index: wallet2_btc.clone(), amount: 990 }],
+            decrements: vec![Adjustment { address_index: wallet1_btc.clone(), amount: 1000 }],
+            fee_amount: 10,
+        }],
+    };
+    exchange.harness.process(
+        &exchange.settlement_accounts(&[btc], false),
+        &ProgramInstruction::PrepareBatchSettlement(params.clone()),
+    ).unwrap();
+    exchange.harness.process(
+        &exchange.settlement_accounts(&[btc], true),
+        &ProgramInstruction::SubmitBatchSettlement(params),
+    ).unwrap();
+
+    let update_fee_account = |fee_account: &str| ProgramInstruction::UpdateFeeAccount(UpdateFeeAccountParams {
+        fee_account: fee_account.to_string(),
+    });
+    let accounts = vec![meta(exchange.program_state, true, true), meta(btc, false, true), meta(rune, false, true)];
+    assert_eq!(
+        Err(ProgramError::Custom(ERROR_INVALID_ADDRESS_NETWORK)),
+        exchange.harness.process(
+            &accounts,
+            &update_fee_account("bc1qhz5a7xfh5dj00u32x0j5we6jfpa8vgpqhvaqug"),
+        ),
+    );
+
+    let new_fee_address = wallet_address(9);
+    exchange.harness.process(
+        &accounts,
+        &update_fee_account(&new_fee_address),
+    ).unwrap();
+    assert_eq!(new_fee_address, exchange.program_state().fee_account_address);
+    // the fee balance moves with the address, the rune token has no fee balance
+    assert_eq!(Balance { address: new_fee_address.clone(), balance: 10 }, exchange.token_state(btc).balances[0]);
+    assert_eq!(Balance { address: wallet1.clone(), balance: 0 }, exchange.token_state(rune).balances[0]);
+
+    // fees are collected against the new address
+    let tx_hex = exchange.input_tx(1);
+    exchange.harness.process(
+        &exchange.prepare_withdraw_accounts(&[btc], false),
+        &ProgramInstruction::PrepareBatchWithdraw(WithdrawBatchParams {
+            tx_hex,
+            change_amount: 0,
+            token_withdrawals: vec![TokenWithdrawals {
+                account_index: 2,
+                withdrawals: vec![Withdrawal {
+                    address_index: wallet1_btc.clone(),
+                    amount: 5000,
+                    fee_account_index: 2,
+                    fee_address_index: wallet1_btc.clone(),
+                    fee_amount: 100,
+                    signature: None,
+                }],
+            }],
+            input_utxo_types: vec![InputUtxoType::Bitcoin],
+        }),
+    ).unwrap();
+    assert_eq!(vec![110, 4000, 990], exchange.balances(btc));
+}
+
+#[test]
+fn test_update_program_change_address() {
+    let mut exchange = TestExchange::new();
+    let program_change_address = wallet_address(9);
+    exchange.harness.process(
+        &exchange.settlement_accounts(&[], true),
+        &ProgramInstruction::UpdateProgramChangeAddress(UpdateProgramChangeAddressParams {
+            program_change_address: program_change_address.clone(),
+        }),
+    ).unwrap();
+    assert_eq!(program_change_address, exchange.program_state().program_change_address);
+
+    assert_eq!(
+        Err(ProgramError::Custom(ERROR_INVALID_ADDRESS)),
+        exchange.harness.process(
+            &exchange.settlement_accounts(&[], true),
+            &ProgramInstruction::UpdateProgramChangeAddress(UpdateProgramChangeAddressParams {
+                program_change_address: "bc1rt12345456667".to_string(),
+            }),
+        ),
+    );
+}
+
+#[test]
+fn test_update_withdraw_account() {
+    let mut exchange = TestExchange::new();
+    let btc = exchange.add_token("btc");
+    let wallet = wallet_address(1);
+    let wallet_index = exchange.add_wallet(btc, &wallet);
+    exchange.deposit(btc, &wallet_index, 10000).unwrap();
+    let params = WithdrawBatchParams {
+        tx_hex: exchange.input_tx(1),
+        change_amount: 0,
+        token_withdrawals: vec![TokenWithdrawals {
+            account_index: 2,
+            withdrawals: vec![Withdrawal {
+                address_index: wallet_index.clone(),
+                amount: 5000,
+                fee_account_index: 2,
+                fee_address_index: wallet_index.clone(),
+                fee_amount: 0,
+                signature: None,
+            }],
+        }],
+        input_utxo_types: vec![InputUtxoType::Bitcoin],
+    };
+    exchange.harness.process(
+        &exchange.prepare_withdraw_accounts(&[btc], false),
+        &ProgramInstruction::PrepareBatchWithdraw(params.clone()),
+    ).unwrap();
+
+    let old_withdraw = exchange.withdraw;
+    let new_withdraw = exchange.harness.create_account();
+    let update_accounts = [
+        meta(exchange.program_state, true, true),
+        meta(new_withdraw, false, true),
+        meta(old_withdraw, false, false),
+    ];
+    assert_eq!(
+        Err(ProgramError::Custom(ERROR_WITHDRAWAL_IN_PROGRESS)),
+        exchange.harness.process(&update_accounts, &ProgramInstruction::UpdateWithdrawAccount()),
+    );
+    exchange.harness.process(
+        &[meta(exchange.program_state, true, false), meta(old_withdraw, false, true), meta(btc, false, true)],
+        &ProgramInstruction::RollbackBatchWithdraw(RollbackWithdrawBatchParams {
+            token_withdrawals: params.token_withdrawals.clone(),
+        }),
+    ).unwrap();
+
+    exchange.harness.process(&update_accounts, &ProgramInstruction::UpdateWithdrawAccount()).unwrap();
+    assert_eq!(new_withdraw, exchange.program_state().withdraw_account);
+    let withdraw_state: WithdrawState = exchange.harness.decode_account(&new_withdraw);
+    assert_eq!(AccountType::Withdraw, withdraw_state.account_type);
+    assert_eq!(exchange.program_state, withdraw_state.program_state_account);
+
+    // the old withdraw account can no longer be used
+    assert_eq!(
+        Err(ProgramError::Custom(ERROR_STATE_ACCOUNT_MISMATCH)),
+        exchange.harness.process(
+            &exchange.prepare_withdraw_accounts(&[btc], false),
+            &ProgramInstruction::PrepareBatchWithdraw(params.clone()),
+        ),
+    );
+    exchange.withdraw = new_withdraw;
+    exchange.harness.process(
+        &exchange.prepare_withdraw_accounts(&[btc], false),
+        &ProgramInstruction::PrepareBatchWithdraw(params),
+    ).unwrap();
+    assert_eq!(vec![0, 5000], exchange.balances(btc));
+}
+
+#[test]
+fn test_pause() {
+    let mut exchange = TestExchange::new();
+    let btc = exchange.add_token("btc");
+    let wallet1 = wallet_address(1);
+    let wallet2 = wallet_address(2);
+    let wallet1_btc = exchange.add_wallet(btc, &wallet1);
+    let wallet2_btc = exchange.add_wallet(btc, &wallet2);
+    exchange.deposit(btc, &wallet1_btc, 10000).unwrap();
+    let settlement = SettlementBatchParams {
+        settlements: vec![SettlementAdjustments {
+            account_index: 1,
+            increments: vec![Adjustment { address_index: wallet2_btc.clone(), amount: 1000 }],
+            decrements: vec![Adjustment { address_index: wallet1_btc.clone(), amount: 1000 }],
+            fee_amount: 0,
+        }],
+    };
+    let withdrawal = WithdrawBatchParams {
+        tx_hex: exchange.input_tx(1),
+        change_amount: 0,
+        token_withdrawals: vec![TokenWithdrawals {
+            account_index: 2,
+            withdrawals: vec![Withdrawal {
+                address_index: wallet1_btc.clone(),
+                amount: 5000,
+                fee_account_index: 2,
+                fee_address_index: wallet1_btc.clone(),
+                fee_amount: 0,
+                signature: None,
+            }],
+        }],
+        input_utxo_types: vec![InputUtxoType::Bitcoin],
+    };
+    let set_pause_flags = |exchange: &mut TestExchange, instruction: ProgramInstruction| {
+        exchange.harness.process(&exchange.settlement_accounts(&[], true), &instruction).unwrap();
+    };
+
+    set_pause_flags(&mut exchange, ProgramInstruction::Pause(PauseParams { pause_flags: PAUSE_DEPOSITS | PAUSE_SETTLEMENTS }));
+    assert_eq!(PAUSE_DEPOSITS | PAUSE_SETTLEMENTS, exchange.program_state().pause_flags);
+    assert_eq!(
+        Err(ProgramError::Custom(ERROR_PAUSED)),
+        exchange.deposit(btc, &wallet1_btc, 1000),
+    );
+    assert_eq!(
+        Err(ProgramError::Custom(ERROR_PAUSED)),
+        exchange.harness.process(
+            &exchange.settlement_accounts(&[btc], false),
+            &ProgramInstruction::PrepareBatchSettlement(settlement.clone()),
+        ),
+    );
+    // withdrawals are paused separately
+    exchange.harness.process(
+        &exchange.prepare_withdraw_accounts(&[btc], false),
+        &ProgramInstruction::PrepareBatchWithdraw(withdrawal.clone()),
+    ).unwrap();
+
+    set_pause_flags(&mut exchange, ProgramInstruction::Pause(PauseParams { pause_flags: PAUSE_WITHDRAWALS }));
+    assert_eq!(PAUSE_ALL, exchange.program_state().pause_flags);
+    assert_eq!(
+        Err(ProgramError::Custom(ERROR_PAUSED)),
+        exchange.harness.process(
+            &exchange.submit_withdraw_accounts(&[btc], false),
+            &ProgramInstruction::SubmitBatchWithdraw(withdrawal.clone()),
+        ),
+    );
+
+    set_pause_flags(&mut exchange, ProgramInstruction::Unpause(PauseParams { pause_flags: PAUSE_DEPOSITS | PAUSE_WITHDRAWALS }));
+    assert_eq!(PAUSE_SETTLEMENTS, exchange.program_state().pause_flags);
+    exchange.harness.process(
+        &exchange.submit_withdraw_accounts(&[btc], false),
+        &ProgramInstruction::SubmitBatchWithdraw(withdrawal),
+    ).unwrap();
+    exchange.deposit(btc, &wallet1_btc, 1000).unwrap();
+    assert_eq!(vec![0, 6000, 0], exchange.balances(btc));
+
+    set_pause_flags(&mut exchange, ProgramInstruction::Unpause(PauseParams { pause_flags: PAUSE_ALL }));
+    exchange.harness.process(
+        &exchange.settlement_accounts(&[btc], false),
+        &ProgramInstruction::PrepareBatchSettlement(settlement),
+    ).unwrap();
+}
+
+#[test]
+fn test_migrate_account() {
+    let mut exchange = TestExchange::new();
+    let btc = exchange.add_token("btc");
+    let wallet1_btc = exchange.add_wallet(btc, &wallet_address(1));
+    exchange.deposit(btc, &wallet1_btc, 1000).unwrap();
+
+    // lay the program state out as version 0, which had neither pause flags nor an event sequence
+    // and kept up to 100 fixed size events inline instead of the event log keys
+    let data = &mut exchange.harness.account_mut(&exchange.program_state).data;
+    data.splice(FIRST_EVENT_LOG_OFFSET..RUNE_RECEIVER_OFFSET, vec![0u8; LEGACY_EVENT_SIZE * LEGACY_MAX_EVENTS]);
+    data[EVENTS_SIZE_OFFSET..EVENTS_SIZE_OFFSET + 2].copy_from_slice(&0u16.to_le_bytes());
+    data.drain(PAUSE_FLAGS_OFFSET..EVENTS_SIZE_OFFSET);
+    data[VERSION_OFFSET..VERSION_OFFSET + VERSION_SIZE].copy_from_slice(&0u32.to_le_bytes());
+    assert_eq!(
+        Err(ProgramError::Custom(ERROR_INVALID_ACCOUNT_VERSION)),
+        exchange.deposit(btc, &wallet1_btc, 1000),
+    );
+
+    let migrate = |exchange: &mut TestExchange, account_index: u8, accounts: &[AccountMeta]| {
+        exchange.harness.process(accounts, &ProgramInstruction::MigrateAccount(MigrateAccountParams { account_index }))
+    };
+    let accounts = exchange.settlement_accounts(&[btc], true);
+    // the token state is already current
+    assert_eq!(Err(ProgramError::Custom(ERROR_INVALID_ACCOUNT_VERSION)), migrate(&mut exchange, 1, &accounts));
+    migrate(&mut exchange, 0, &accounts).unwrap();
+    assert_eq!(1, exchange.program_state().version);
+    assert_eq!(
+        Err(ProgramError::Custom(ERROR_INVALID_ACCOUNT_VERSION)),
+        exchange.deposit(btc, &wallet1_btc, 1000),
+    );
+    migrate(&mut exchange, 0, &accounts).unwrap();
+    migrate(&mut exchange, 0, &accounts).unwrap();
+    assert_eq!(Err(ProgramError::Custom(ERROR_INVALID_ACCOUNT_VERSION)), migrate(&mut exchange, 0, &accounts));
+
+    let program_state = exchange.program_state();
+    assert_eq!(PROGRAM_STATE_VERSION, program_state.version);
+    assert_eq!(0, program_state.pause_flags);
+    assert_eq!(0, program_state.event_sequence);
+    assert_eq!(exchange.withdraw, program_state.withdraw_account);
+    assert_eq!(exchange.fee_address, program_state.fee_account_address);
+    let data = &exchange.harness.account(&exchange.program_state).data;
+    assert_eq!(exchange.rune_receiver.0[..], data[RUNE_RECEIVER_OFFSET..RUNE_RECEIVER_OFFSET + PUBKEY_SIZE]);
+    assert_eq!(0, program_state.events_count);
+    assert_eq!(Pubkey::from([0u8; 32]), program_state.event_log_account);
+
+    // deposits keep working before an event log has been initialized, their events are dropped
+    exchange.deposit(btc, &wallet1_btc, 1000).unwrap();
+    assert_eq!(vec![0, 2000], exchange.balances(btc));
+    let program_state = exchange.program_state();
+    assert_eq!(0, program_state.events_count);
+    assert_eq!(Pubkey::from([0u8; 32]), program_state.event_log_account);
+    exchange.event_logs.clear();
+    exchange.add_event_log();
+    exchange.deposit(btc, &wallet1_btc, 1000).unwrap();
+    assert_eq!(vec![0, 3000], exchange.balances(btc));
+    assert_eq!(
+        vec![Event::DepositCredited { sequence: 2, account_index: 1, address_index: wallet1_btc.index, amount: 1000 }],
+        exchange.events(),
+    );
+
+    // token state version 0 had no wallet index key, free list, balance shards, withdrawal fee
+    // policy or withdrawal limit and kept the address strings in the balances
+    let token_state_v0 = TokenState { version: 0, ..exchange.token_state(btc) };
+    exchange.harness.account_mut(&btc).data = token_state_v0.encode_to_vec().unwrap();
+    assert_eq!(0, exchange.token_state(btc).version);
+    assert_eq!(vec![0, 3000], exchange.balances(btc));
+    assert_eq!(
+        Err(ProgramError::Custom(ERROR_INVALID_ACCOUNT_VERSION)),
+        exchange.deposit(btc, &wallet1_btc, 1000),
+    );
+    let accounts = exchange.settlement_accounts(&[btc], true);
+    migrate(&mut exchange, 1, &accounts).unwrap();
+    assert_eq!(1, exchange.token_state(btc).version);
+    migrate(&mut exchange, 1, &accounts).unwrap();
+    assert_eq!(2, exchange.token_state(btc).version);
+    migrate(&mut exchange, 1, &accounts).unwrap();
+    assert_eq!(3, exchange.token_state(btc).version);
+    migrate(&mut exchange, 1, &accounts).unwrap();
+    assert_eq!(4, exchange.token_state(btc).version);
+    migrate(&mut exchange, 1, &accounts).unwrap();
+    assert_eq!(5, exchange.token_state(btc).version);
+    migrate(&mut exchange, 1, &accounts).unwrap();
+    let token_state = exchange.token_state(btc);
+    assert_eq!(TOKEN_STATE_VERSION, token_state.version);
+    assert_eq!(Pubkey::from([0u8; 32]), token_state.wallet_index_account);
+    assert_eq!(0, token_state.num_free_balances);
+    assert_eq!(0, token_state.num_balance_shards);
+    assert_eq!(None, token_state.withdrawal_fee_policy);
+    assert_eq!(Pubkey::from([0u8; 32]), token_state.withdrawal_limit_account);
+    assert_eq!(
+        vec![exchange.fee_address.clone(), wallet_address(1)],
+        token_state.balances.iter().map(|balance| balance.address.clone()).collect::<Vec<_>>(),
+    );
+    assert_eq!(BALANCES_OFFSET + 2 * BALANCE_SIZE, exchange.harness.account(&btc).data.len());
+    exchange.deposit(btc, &wallet1_btc, 1000).unwrap();
+    assert_eq!(vec![0, 4000], exchange.balances(btc));
+
+    // wallet index version 0 hashed the address strings, migrating drops the entries so the
+    // balances get indexed again
+    let index = exchange.add_wallet_index(btc);
+    let data = &mut exchange.harness.account_mut(&index).data;
+    data[VERSION_OFFSET..VERSION_OFFSET + VERSION_SIZE].copy_from_slice(&0u32.to_le_bytes());
+    let mut accounts = exchange.settlement_accounts(&[btc], true);
+    accounts.push(meta(index, false, true));
+    migrate(&mut exchange, accounts.len() as u8 - 1, &accounts).unwrap();
+    let wallet_index = exchange.wallet_index(index);
+    assert_eq!(WALLET_INDEX_STATE_VERSION, wallet_index.version);
+    assert_eq!(0, wallet_index.num_indexed_balances);
+    assert!(wallet_index.entries.is_empty());
+    exchange.harness.process(
+        &[meta(exchange.program_state, true, false), meta(btc, false, false), meta(index, false, true)],
+        &ProgramInstruction::IndexWalletBalances(),
+    ).unwrap();
+    assert_eq!(Some(1), exchange.wallet_index(index).find_balance_index(&wallet_address(1)));
+
+    // withdraw state version 0 had neither a status nor a transaction id, a batch hash meant the
+    // batch was prepared
+    let data = &mut exchange.harness.account_mut(&exchange.withdraw).data;
+    data.truncate(WITHDRAW_STATUS_OFFSET);
+    data[WITHDRAW_HASH_OFFSET..WITHDRAW_STATUS_OFFSET].copy_from_slice(&[1u8; 32]);
+    data[VERSION_OFFSET..VERSION_OFFSET + VERSION_SIZE].copy_from_slice(&0u32.to_le_bytes());
+    let accounts = [meta(exchange.program_state, true, true), meta(exchange.withdraw, false, true)];
+    migrate(&mut exchange, 1, &accounts).unwrap();
+    let withdraw_state: WithdrawState = exchange.harness.decode_account(&exchange.withdraw);
+    assert_eq!(WITHDRAW_STATE_VERSION, withdraw_state.version);
+    assert_eq!(WithdrawStatus::Prepared, withdraw_state.status);
+    assert_eq!(EMPTY_HASH, withdraw_state.tx_id);
+    assert!(withdraw_state.replaced_tx_ids.is_empty());
+    assert_eq!(WITHDRAW_ACCOUNT_SIZE, exchange.harness.account(&exchange.withdraw).data.len());
+}
+
+#[test]
+fn test_wallet_index() {
+    let mut exchange = TestExchange::new();
+    let btc = exchange.add_token("btc");
+    let wallet1 = wallet_address(1);
+    let wallet2 = wallet_address(2);
+    let wallet3 = wallet_address(3);
+    // registered twice before the token had an index
+    exchange.add_wallet(btc, &wallet1);
+    exchange.add_wallet(btc, &wallet1);
+    exchange.add_wallet(btc, &wallet2);
+
+    let index = exchange.add_wallet_index(btc);
+    assert_eq!(index, exchange.token_state(btc).wallet_index_account);
+    let wallet_index = exchange.wallet_index(index);
+    assert_eq!(4, wallet_index.num_indexed_balances);
+    assert_eq!(3, wallet_index.entries.len());
+    assert_eq!(Some(0), wallet_index.find_balance_index(&exchange.fee_address));
+    assert_eq!(Some(1), wallet_index.find_balance_index(&wallet1));
+    assert_eq!(Some(3), wallet_index.find_balance_index(&wallet2));
+    assert_eq!(None, wallet_index.find_balance_index(&wallet3));
+
+    let other_index = exchange.harness.create_account();
+    assert_eq!(
+        Err(ProgramError::Custom(ERROR_ALREADY_INITIALIZED)),
+        exchange.harness.process(
+            &[meta(exchange.program_state, true, false), meta(btc, false, true), meta(other_index, false, true)],
+            &ProgramInstruction::InitWalletIndex(),
+        ),
+    );
+
+    // the index has to be passed once the token has one
+    assert_eq!(
+        Err(ProgramError::Custom(ERROR_WALLET_INDEX_MISSING)),
+        exchange.harness.process(
+            &[meta(exchange.program_state, true, false), meta(btc, false, true)],
+            &ProgramInstruction::InitWalletBalances(InitWalletBalancesParams {
+                token_state_setups: vec![TokenStateSetup {
+                    account_index: 1,
+                    wallet_addresses: vec![wallet3.clone()],
+                }],
+            }),
+        ),
+    );
+
+    // registering is idempotent, known wallets keep their balance
+    exchange.add_wallets(btc, &[wallet1.clone(), wallet3.clone(), wallet3.clone()]).unwrap();
+    assert_eq!(5, exchange.token_state(btc).balances.len());
+    let wallet_index = exchange.wallet_index(index);
+    assert_eq!(5, wallet_index.num_indexed_balances);
+    assert_eq!(Some(1), wallet_index.find_balance_index(&wallet1));
+    assert_eq!(Some(4), wallet_index.find_balance_index(&wallet3));
+
+    // the fee balance is found by the new fee address
+    let new_fee_address = wallet_address(9);
+    exchange.harness.process(
+        &[meta(exchange.program_state, true, true), meta(btc, false, true), meta(index, false, true)],
+        &ProgramInstruction::UpdateFeeAccount(UpdateFeeAccountParams { fee_account: new_fee_address.clone() }),
+    ).unwrap();
+    let wallet_index = exchange.wallet_index(index);
+    assert_eq!(Some(0), wallet_index.find_balance_index(&new_fee_address));
+    assert_eq!(None, wallet_index.find_balance_index(&exchange.fee_address));
+
+    // a token with more balances than fit into a single instruction is indexed in several
+    let eth = exchange.add_token("eth");
+    let wallets: Vec<String> = (0..300).map(numbered_wallet_address).collect();
+    for chunk in wallets.chunks(50) {
+        exchange.add_wallets(eth, chunk).unwrap();
+    }
+    let eth_index = exchange.add_wallet_index(eth);
+    let num_indexed = exchange.wallet_index(eth_index).num_indexed_balances;
+    assert!(num_indexed > 0 && num_indexed < 301);
+    assert_eq!(
+        Err(ProgramError::Custom(ERROR_WALLET_INDEX_INCOMPLETE)),
+        exchange.add_wallets(eth, &[wallet1.clone()]),
+    );
+    let index_accounts = [meta(exchange.program_state, true, false), meta(eth, false, false), meta(eth_index, false, true)];
+    assert_eq!(
+        Err(ProgramError::Custom(ERROR_STATE_ACCOUNT_MISMATCH)),
+        exchange.harness.process(
+            &[meta(exchange.program_state, true, false), meta(btc, false, false), meta(eth_index, false, true)],
+            &ProgramInstruction::IndexWalletBalances(),
+        ),
+    );
+    exchange.harness.process(&index_accounts, &ProgramInstruction::IndexWalletBalances()).unwrap();
+    let wallet_index = exchange.wallet_index(eth_index);
+    assert_eq!(301, wallet_index.num_indexed_balances);
+    assert_eq!(301, wallet_index.entries.len());
+    assert_eq!(Some(300), wallet_index.find_balance_index(&wallets[299]));
+
+    exchange.add_wallets(eth, &[wallets[0].clone(), wallet1.clone()]).unwrap();
+    assert_eq!(302, exchange.token_state(eth).balances.len());
+    assert_eq!(Some(301), exchange.wallet_index(eth_index).find_balance_index(&wallet1));
+}
+
+#[test]
+fn test_close_wallet_balance() {
+    let mut exchange = TestExchange::new();
+    let btc = exchange.add_token("btc");
+    let wallets: Vec<String> = (1..=5).map(wallet_address).collect();
+    let indexes: Vec<AddressIndex> = wallets[..4].iter().map(|wallet| exchange.add_wallet(btc, wallet)).collect();
+    exchange.deposit(btc, &indexes[3], 1000).unwrap();
+
+    let close = |exchange: &mut TestExchange, address_indexes: &[AddressIndex]| {
+        exchange.harness.process(
+            &exchange.close_balance_accounts(btc),
+            &ProgramInstruction::CloseWalletBalance(CloseWalletBalanceParams {
+                account_index: 2,
+                address_indexes: address_indexes.to_vec(),
+            }),
+        )
+    };
+    let compact = |exchange: &mut TestExchange| {
+        exchange.harness.process(
+            &exchange.close_balance_accounts(btc),
+            &ProgramInstruction::CompactBalances(CompactBalancesParams { account_index: 2 }),
+        )
+    };
+    // only empty wallet balances can be closed
+    assert_eq!(Err(ProgramError::Custom(ERROR_BALANCE_NOT_EMPTY)), close(&mut exchange, &[indexes[3].clone()]));
+    let fee_index = AddressIndex { index: 0, commitment: address_commitment(&exchange.fee_address) };
+    assert_eq!(Err(ProgramError::Custom(ERROR_INVALID_ADDRESS_INDEX)), close(&mut exchange, &[fee_index]));
+
+    // not while a batch refers to balances by index
+    exchange.harness.process(
+        &exchange.settlement_accounts(&[btc], false),
+        &ProgramInstruction::PrepareBatchSettlement(SettlementBatchParams {
+            settlements: vec![SettlementAdjustments {
+                account_index: 1,
+                increments: vec![Adjustment { address_index: indexes[0].clone(), amount: 100 }],
+                decrements: vec![Adjustment { address_index: indexes[3].clone(), amount: 100 }],
+                fee_amount: 0,
+            }],
+        }),
+    ).unwrap();
+    assert_eq!(Err(ProgramError::Custom(ERROR_SETTLEMENT_IN_PROGRESS)), close(&mut exchange, &[indexes[0].clone()]));
+    exchange.harness.process(&[meta(exchange.program_state, true, true)], &ProgramInstruction::RollbackBatchSettlement()).unwrap();
+
+    close(&mut exchange, &[indexes[0].clone(), indexes[2].clone()]).unwrap();
+    assert_eq!(
+        vec![
+            Event::WalletBalanceClosed { sequence: 2, account_index: 2, address_index: 1 },
+            Event::WalletBalanceClosed { sequence: 3, account_index: 2, address_index: 3 },
+        ],
+        exchange.events(),
+    );
+    let token_state = exchange.token_state(btc);
+    assert_eq!(2, token_state.num_free_balances);
+    assert_eq!("", token_state.balances[1].address);
+    assert_eq!(
+        Err(ProgramError::Custom(ERROR_WALLET_LAST4_MISMATCH)),
+        exchange.deposit(btc, &indexes[0], 10),
+    );
+
+    // registering reuses the last closed balance
+    exchange.add_wallets(btc, &[wallets[4].clone()]).unwrap();
+    let token_state = exchange.token_state(btc);
+    assert_eq!(5, token_state.balances.len());
+    assert_eq!(1, token_state.num_free_balances);
+    assert_eq!(wallets[4], token_state.balances[3].address);
+
+    // the last balance moves into the free one
+    compact(&mut exchange).unwrap();
+    assert_eq!(
+        vec![Event::BalanceIndexRemapped { sequence: 4, account_index: 2, from_address_index: 4, to_address_index: 1 }],
+        exchange.events(),
+    );
+    let token_state = exchange.token_state(btc);
+    assert_eq!(0, token_state.num_free_balances);
+    assert_eq!(
+        vec![exchange.fee_address.clone(), wallets[3].clone(), wallets[1].clone(), wallets[4].clone()],
+        token_state.balances.iter().map(|balance| balance.address.clone()).collect::<Vec<_>>(),
+    );
+    assert_eq!(vec![0, 1000, 0, 0], exchange.balances(btc));
+    assert_eq!(BALANCES_OFFSET + 4 * BALANCE_SIZE, exchange.harness.account(&btc).data.len());
+    assert_eq!(Err(ProgramError::Custom(ERROR_INVALID_ADDRESS_INDEX)), exchange.deposit(btc, &indexes[3], 10));
+    let wallet4_btc = AddressIndex { index: 1, commitment: indexes[3].commitment.clone() };
+    exchange.deposit(btc, &wallet4_btc, 500).unwrap();
+    assert_eq!(vec![0, 1500, 0, 0], exchange.balances(btc));
+
+    // the wallet index follows closed and moved balances
+    let index = exchange.add_wallet_index(btc);
+    close(&mut exchange, &[AddressIndex { index: 2, commitment: address_commitment(&wallets[1]) }]).unwrap();
+    compact(&mut exchange).unwrap();
+    assert_eq!(
+        vec![Event::BalanceIndexRemapped { sequence: 7, account_index: 2, from_address_index: 3, to_address_index: 2 }],
+        exchange.events(),
+    );
+    let wallet_index = exchange.wallet_index(index);
+    assert_eq!(3, wallet_index.num_indexed_balances);
+    assert_eq!(3, wallet_index.entries.len());
+    assert_eq!(None, wallet_index.find_balance_index(&wallets[1]));
+    assert_eq!(Some(2), wallet_index.find_balance_index(&wallets[4]));
+    assert_eq!(Some(1), wallet_index.find_balance_index(&wallets[3]));
+
+    // a closed wallet can register again
+    exchange.add_wallets(btc, &[wallets[1].clone()]).unwrap();
+    assert_eq!(Some(3), exchange.wallet_index(index).find_balance_index(&wallets[1]));
+}
+
+#[test]
+fn test_balance_shards() {
+    let mut exchange = TestExchange::new();
+    let btc = exchange.add_token("btc");
+    let wallet1 = wallet_address(1);
+    let wallet2 = wallet_address(2);
+    let wallet3 = wallet_address(3);
+    let wallet1_btc = exchange.add_wallet(btc, &wallet1);
+
+    let shard1 = exchange.add_balance_shard(btc);
+    let shard2 = exchange.add_balance_shard(btc);
+    let token_state = exchange.token_state(btc);
+    assert_eq!(2, token_state.num_balance_shards);
+    assert_eq!(shard1, token_state.first_balance_shard_account);
+    assert_eq!(shard2, token_state.last_balance_shard_account);
+    assert_eq!(shard2, exchange.balance_shard(shard1).next_balance_shard_account);
+    assert_eq!(2, exchange.balance_shard(shard2).shard);
+
+    // new shards are chained to the last one
+    let other_shard = exchange.harness.create_account();
+    assert_eq!(
+        Err(ProgramError::Custom(ERROR_STATE_ACCOUNT_MISMATCH)),
+        exchange.harness.process(
+            &[meta(exchange.program_state, true, false), meta(btc, false, true), meta(other_shard, false, true), meta(shard1, false, true)],
+            &ProgramInstruction::InitBalanceShard(),
+        ),
+    );
+    // the index only covers the token state, so it cannot be added once balances are sharded
+    assert_eq!(
+        Err(ProgramError::Custom(ERROR_TOKEN_SHARDED)),
+        exchange.harness.process(
+            &[meta(exchange.program_state, true, false), meta(btc, false, true), meta(other_shard, false, true)],
+            &ProgramInstruction::InitWalletIndex(),
+        ),
+    );
+
+    // new wallets are added to the last shard
+    exchange.add_wallets(btc, &[wallet2.clone(), wallet3.clone()]).unwrap();
+    assert_eq!(2, exchange.token_state(btc).balances.len());
+    assert_eq!(
+        vec![wallet2.clone(), wallet3.clone()],
+        exchange.balance_shard(shard2).balances.iter().map(|balance| balance.address.clone()).collect::<Vec<_>>(),
+    );
+    let wallet2_btc = AddressIndex::new(2, 0, address_commitment(&wallet2));
+    let wallet3_btc = AddressIndex::new(2, 1, address_commitment(&wallet3));
+    exchange.deposit(btc, &wallet2_btc, 10000).unwrap();
+    assert_eq!(
+        vec![Event::DepositCredited { sequence: 1, account_index: 1, address_index: wallet2_btc.index, amount: 10000 }],
+        exchange.events(),
+    );
+    assert_eq!(10000, exchange.balance_shard(shard2).balances[0].balance);
+    assert_eq!(
+        Err(ProgramError::Custom(ERROR_INVALID_ADDRESS_INDEX)),
+        exchange.deposit(btc, &AddressIndex::new(1, 0, address_commitment(&wallet2)), 10),
+    );
+    assert_eq!(
+        Err(ProgramError::Custom(ERROR_BALANCE_SHARD_MISSING)),
+        exchange.deposit(btc, &AddressIndex::new(3, 0, address_commitment(&wallet2)), 10),
+    );
+
+    // the shards of another token do not stand in for the missing ones
+    let usdc = exchange.add_token("usdc");
+    let btc_shards = std::mem::take(&mut exchange.balance_shards);
+    let usdc_shard = exchange.add_balance_shard(usdc);
+    assert_eq!(
+        Err(ProgramError::Custom(ERROR_BALANCE_SHARD_MISSING)),
+        exchange.deposit(btc, &wallet2_btc, 10),
+    );
+    exchange.balance_shards.extend(btc_shards);
+    assert_eq!(usdc, exchange.balance_shard(usdc_shard).token_state_account);
+
+    // a copy of a shard is not part of the shard chain, and shards have to be owned by the program
+    let forged_shard = exchange.harness.create_account();
+    exchange.harness.account_mut(&forged_shard).data = exchange.harness.account(&shard2).data.clone();
+    let btc_shards = std::mem::replace(&mut exchange.balance_shards, vec![shard1, forged_shard]);
+    assert_eq!(
+        Err(ProgramError::Custom(ERROR_BALANCE_SHARD_MISSING)),
+        exchange.deposit(btc, &wallet2_btc, 10),
+    );
+    exchange.balance_shards = btc_shards;
+    exchange.harness.account_mut(&shard2).owner = Pubkey::new_unique();
+    assert_eq!(
+        Err(ProgramError::Custom(ERROR_STATE_ACCOUNT_MISMATCH)),
+        exchange.deposit(btc, &wallet2_btc, 10),
+    );
+    exchange.harness.account_mut(&shard2).owner = exchange.harness.program_id;
+
+    // a settlement can span the token state and its shards
+    let params = SettlementBatchParams {
+        settlements: vec![SettlementAdjustments {
+            account_index: 1,
+            increments: vec![
+                Adjustment { address_index: wallet1_btc.clone(), amount: 1000 },
+                Adjustment { address_index: wallet3_btc.clone(), amount: 990 },
+            ],
+            decrements: vec![Adjustment { address_index: wallet2_btc.clone(), amount: 2000 }],
+            fee_amount: 10,
+        }],
+    };
+    exchange.harness.process(
+        &exchange.settlement_accounts(&[btc], false),
+        &ProgramInstruction::PrepareBatchSettlement(params.clone()),
+    ).unwrap();
+    exchange.harness.process(
+        &exchange.settlement_accounts(&[btc], true),
+        &ProgramInstruction::SubmitBatchSettlement(params),
+    ).unwrap();
+    assert_eq!(vec![10, 1000], exchange.balances(btc));
+    assert_eq!(
+        vec![8000, 990],
+        exchange.balance_shard(shard2).balances.iter().map(|balance| balance.balance).collect::<Vec<_>>(),
+    );
+
+    // and so can a withdrawal
+    let params = WithdrawBatchParams {
+        tx_hex: exchange.input_tx(1),
+        change_amount: 3500,
+        token_withdrawals: vec![TokenWithdrawals {
+            account_index: 2,
+            withdrawals: vec![Withdrawal {
+                address_index: wallet2_btc.clone(),
+                amount: 5500,
+                fee_account_index: 2,
+                fee_address_index: wallet2_btc.clone(),
+                fee_amount: 500,
+                signature: None,
+            }],
+        }],
+        input_utxo_types: vec![InputUtxoType::Bitcoin],
+    };
+    exchange.harness.process(
+        &exchange.prepare_withdraw_accounts(&[btc], false),
+        &ProgramInstruction::PrepareBatchWithdraw(params.clone()),
+    ).unwrap();
+    assert_eq!(vec![510, 1000], exchange.balances(btc));
+    assert_eq!(2500, exchange.balance_shard(shard2).balances[0].balance);
+    exchange.harness.process(
+        &exchange.submit_withdraw_accounts(&[btc], false),
+        &ProgramInstruction::SubmitBatchWithdraw(params),
+    ).unwrap();
+    let tx = exchange.harness.last_signed_transaction().unwrap().transaction;
+    assert_eq!(get_bitcoin_address(&wallet2, &NetworkType::Regtest).script_pubkey(), tx.output[1].script_pubkey);
+    assert_eq!(Amount::from_sat(5000), tx.output[1].value);
+    exchange.mine_blocks(WITHDRAW_CONFIRMATION_DEPTH);
+    exchange.confirm_withdrawal(tx.compute_txid()).unwrap();
+
+    // only balances of the token state itself can be closed
+    assert_eq!(
+        Err(ProgramError::Custom(ERROR_INVALID_ADDRESS_INDEX)),
+        exchange.harness.process(
+            &exchange.close_balance_accounts(btc),
+            &ProgramInstruction::CloseWalletBalance(CloseWalletBalanceParams {
+                account_index: 2,
+                address_indexes: vec![wallet3_btc],
+            }),
+        ),
+    );
+}
+
+#[test]
+fn test_event_log() {
+    let mut exchange = TestExchange::new();
+    let btc = exchange.add_token("btc");
+    let wallet1_btc = exchange.add_wallet(btc, &wallet_address(1));
+    let deposit_batch = ProgramInstruction::BatchDeposit(DepositBatchParams {
+        token_deposits: vec![TokenDeposits {
+            account_index: 1,
+            deposits: (0..500).map(|_| Deposit {
+                address_index: wallet1_btc.clone(),
+                amount: 10,
+                funding_outpoint: None,
+            }).collect(),
+        }],
+    });
+    let deposit_accounts = |exchange: &TestExchange| {
+        let mut accounts = vec![meta(exchange.program_state, true, true), meta(btc, false, true)];
+        accounts.extend(exchange.event_log_accounts());
+        accounts
+    };
+
+    // a log can only grow by 10 KiB per instruction, which is not enough for the whole batch
+    assert_eq!(
+        Err(ProgramError::Custom(ERROR_EVENT_LOG_FULL)),
+        exchange.harness.process(&deposit_accounts(&exchange), &deposit_batch),
+    );
+
+    // the events that do not fit go to the next log of the chain
+    let first_event_log = exchange.event_logs[0];
+    let second_event_log = exchange.add_event_log();
+    exchange.harness.process(&deposit_accounts(&exchange), &deposit_batch).unwrap();
+    let program_state = exchange.program_state();
+    assert_eq!(first_event_log, program_state.first_event_log_account);
+    assert_eq!(second_event_log, program_state.event_log_account);
+    assert_eq!(500, program_state.events_count);
+    assert_eq!(500, program_state.event_sequence);
+    assert_eq!(vec![0, 5000], exchange.balances(btc));
+
+    // readers page through the whole chain from the first log
+    let mut cursor = EventCursor {
+        event_log_account: program_state.first_event_log_account,
+        index: 0,
+    };
+    let mut sequences = vec![];
+    loop {
+        let event_log: EventLogState = exchange.harness.decode_account(&cursor.event_log_account);
+        assert_eq!(exchange.program_state, event_log.program_state_account);
+        let (events, next_cursor) = event_log.read_events(&cursor, 64);
+        if events.is_empty() && next_cursor == cursor {
+            break;
+        }
+        sequences.extend(events.iter().map(|event| match event {
+            Event::DepositCredited { sequence, amount: 10, .. } => *sequence,
+            _ => panic!("unexpected event {:?}", event),
+        }));
+        cursor = next_cursor;
+    }
+    assert_eq!((1..=500).collect::<Vec<u64>>(), sequences);
+    assert_eq!(second_event_log, cursor.event_log_account);
+
+    // only the last log of the chain can be extended
+    let event_log = exchange.harness.create_account();
+    assert_eq!(
+        Err(ProgramError::Custom(ERROR_STATE_ACCOUNT_MISMATCH)),
+        exchange.harness.process(
+            &[meta(exchange.program_state, true, true), meta(event_log, false, true), meta(first_event_log, false, true)],
+            &ProgramInstruction::InitEventLog(),
+        ),
+    );
+}
+
+#[test]
+fn test_settlement() {
+    let mut exchange = TestExchange::new();
+    let btc = exchange.add_token("btc");
+    let usdc = exchange.add_token("usdc");
+    let wallet1 = wallet_address(1);
+    let wallet2 = wallet_address(2);
+    let wallet1_btc = exchange.add_wallet(btc, &wallet1);
+    let wallet2_btc = exchange.add_wallet(btc, &wallet2);
+    let wallet1_usdc = exchange.add_wallet(usdc, &wallet1);
+    let wallet2_usdc = exchange.add_wallet(usdc, &wallet2);
+    exchange.deposit(btc, &wallet1_btc, 10000).unwrap();
+    exchange.deposit(usdc, &wallet2_usdc, 50000).unwrap();
+
+    let params = SettlementBatchParams {
+        settlements: vec![
+            SettlementAdjustments {
+                account_index: 1,
+                increments: vec![Adjustment { address_index: wallet2_btc.clone(), amount: 990 }],
+                decrements: vec![Adjustment { address_index: wallet1_btc.clone(), amount: 1000 }],
+                fee_amount: 10,
+            },
+            SettlementAdjustments {
+                account_index: 2,
+                increments: vec![Adjustment { address_index: wallet1_usdc.clone(), amount: 5000 }],
+                decrements: vec![Adjustment { address_index: wallet2_usdc.clone(), amount: 5000 }],
+                fee_amount: 0,
+            },
+        ],
+    };
+
+    exchange.harness.process(
+        &exchange.settlement_accounts(&[btc, usdc], false),
+        &ProgramInstruction::PrepareBatchSettlement(params.clone()),
+    ).unwrap();
+    assert_ne!(EMPTY_HASH, exchange.program_state().settlement_batch_hash);
+    assert_eq!(vec![0, 10000, 0], exchange.balances(btc));
+
+    exchange.harness.process(
+        &exchange.settlement_accounts(&[btc, usdc], true),
+        &ProgramInstruction::SubmitBatchSettlement(params.clone()),
+    ).unwrap();
+
+    let program_state = exchange.program_state();
+    assert_eq!(EMPTY_HASH, program_state.settlement_batch_hash);
+    assert_ne!(EMPTY_HASH, program_state.last_settlement_batch_hash);
+    assert_eq!(vec![10, 9000, 990], exchange.balances(btc));
+    assert_eq!(vec![0, 5000, 45000], exchange.balances(usdc));
+    // the two deposits used up sequence numbers 1 and 2
+    assert_eq!(
+        vec![
+            Event::FeeCollected { sequence: 3, account_index: 1, amount: 10 },
+            Event::SettlementApplied {
+                sequence: 4,
+                account_index: 1,
+                settlement_hash: program_state.last_settlement_batch_hash,
+                increment_total: 990,
+                decrement_total: 1000,
+            },
+            Event::SettlementApplied {
+                sequence: 5,
+                account_index: 2,
+                settlement_hash: program_state.last_settlement_batch_hash,
+                increment_total: 5000,
+                decrement_total: 5000,
+            },
+        ],
+        exchange.events(),
+    );
+    assert_eq!(5, program_state.event_sequence);
+
+    // overdrawn wallets are reported as events and no batch is prepared
+    let params = SettlementBatchParams {
+        settlements: vec![SettlementAdjustments {
+            account_index: 1,
+            increments: vec![Adjustment { address_index: wallet1_btc.clone(), amount: 1000 }],
+            decrements: vec![Adjustment { address_index: wallet2_btc.clone(), amount: 1000 }],
+            fee_amount: 0,
+        }],
+    };
+    exchange.harness.process(
+        &exchange.settlement_accounts(&[btc], false),
+        &ProgramInstruction::PrepareBatchSettlement(params),
+    ).unwrap();
+    let program_state = exchange.program_state();
+    assert_eq!(EMPTY_HASH, program_state.settlement_batch_hash);
+    assert_eq!(
+        vec![Event::FailedSettlement {
+            account_index: 1,
+            address_index: wallet2_btc.index,
+            requested_amount: 1000,
+            balance: 990,
+            error_code: ERROR_INSUFFICIENT_BALANCE,
+        }],
+        exchange.events(),
+    );
+}
+
+#[test]
+fn test_partial_settlement() {
+    let mut exchange = TestExchange::new();
+    let btc = exchange.add_token("btc");
+    let usdc = exchange.add_token("usdc");
+    let wallets: Vec<_> = (1..=4).map(wallet_address).collect();
+    let wallets_btc: Vec<_> = wallets.iter().map(|wallet| exchange.add_wallet(btc, wallet)).collect();
+    let wallets_usdc: Vec<_> = wallets.iter().map(|wallet| exchange.add_wallet(usdc, wallet)).collect();
+    exchange.deposit(btc, &wallets_btc[0], 10000).unwrap();
+    exchange.deposit(btc, &wallets_btc[2], 10000).unwrap();
+    exchange.deposit(usdc, &wallets_usdc[3], 50000).unwrap();
+
+    // wallet1 sells 1000 btc to wallet2, which has no usdc to pay for them
+    let btc_settlement = SettlementAdjustments {
+        account_index: 1,
+        increments: vec![Adjustment { address_index: wallets_btc[1].clone(), amount: 990 }],
+        decrements: vec![Adjustment { address_index: wallets_btc[0].clone(), amount: 1000 }],
+        fee_amount: 10,
+    };
+    let usdc_settlement = SettlementAdjustments {
+        account_index: 2,
+        increments: vec![Adjustment { address_index: wallets_usdc[0].clone(), amount: 5000 }],
+        decrements: vec![Adjustment { address_index: wallets_usdc[1].clone(), amount: 5000 }],
+        fee_amount: 0,
+    };
+    let params = SettlementBatchParams {
+        settlements: vec![usdc_settlement.clone(), btc_settlement.clone()],
+    };
+
+    // the whole batch is rejected unless partial mode is asked for
+    exchange.harness.process(
+        &exchange.settlement_accounts(&[btc, usdc], false),
+        &ProgramInstruction::PrepareBatchSettlement(params.clone()),
+    ).unwrap();
+    assert_eq!(EMPTY_HASH, exchange.program_state().settlement_batch_hash);
+
+    // the btc side of the trade goes with the failing usdc side, so nothing is prepared
+    exchange.harness.process(
+        &exchange.settlement_accounts(&[btc, usdc], false),
+        &ProgramInstruction::PreparePartialBatchSettlement(params.clone()),
+    ).unwrap();
+    assert_eq!(EMPTY_HASH, exchange.program_state().settlement_batch_hash);
+    let failed_settlement = Event::FailedSettlement {
+        account_index: 2,
+        address_index: wallets_usdc[1].index,
+        requested_amount: 5000,
+        balance: 0,
+        error_code: ERROR_INSUFFICIENT_BALANCE,
+    };
+    assert_eq!(
+        vec![
+            failed_settlement.clone(),
+            Event::SettlementExcluded { account_index: 2, settlement_index: 0 },
+            Event::SettlementExcluded { account_index: 1, settlement_index: 1 },
+        ],
+        exchange.events(),
+    );
+
+    // wallet3 sells 500 btc to wallet4 in entries of their own, which are kept
+    let other_btc_settlement = SettlementAdjustments {
+        account_index: 1,
+        increments: vec![Adjustment { address_index: wallets_btc[3].clone(), amount: 495 }],
+        decrements: vec![Adjustment { address_index: wallets_btc[2].clone(), amount: 500 }],
+        fee_amount: 5,
+    };
+    let other_usdc_settlement = SettlementAdjustments {
+        account_index: 2,
+        increments: vec![Adjustment { address_index: wallets_usdc[2].clone(), amount: 2500 }],
+        decrements: vec![Adjustment { address_index: wallets_usdc[3].clone(), amount: 2500 }],
+        fee_amount: 0,
+    };
+    let params = SettlementBatchParams {
+        settlements: vec![
+            usdc_settlement.clone(),
+            other_btc_settlement.clone(),
+            btc_settlement.clone(),
+            other_usdc_settlement.clone(),
+        ],
+    };
+    exchange.harness.process(
+        &exchange.settlement_accounts(&[btc, usdc], false),
+        &ProgramInstruction::PreparePartialBatchSettlement(params.clone()),
+    ).unwrap();
+    assert_ne!(EMPTY_HASH, exchange.program_state().settlement_batch_hash);
+    assert_eq!(
+        vec![
+            failed_settlement,
+            Event::SettlementExcluded { account_index: 2, settlement_index: 0 },
+            Event::SettlementExcluded { account_index: 1, settlement_index: 2 },
+        ],
+        exchange.events(),
+    );
+
+    // only the remaining entries can be submitted
+    assert_eq!(
+        Err(ProgramError::Custom(ERROR_SETTLEMENT_BATCH_MISMATCH)),
+        exchange.harness.process(
+            &exchange.settlement_accounts(&[btc, usdc], true),
+            &ProgramInstruction::SubmitBatchSettlement(params.clone()),
+        ),
+    );
+    exchange.harness.process(
+        &exchange.settlement_accounts(&[btc, usdc], true),
+        &ProgramInstruction::SubmitBatchSettlement(SettlementBatchParams {
+            settlements: vec![other_btc_settlement, other_usdc_settlement],
+        }),
+    ).unwrap();
+    assert_eq!(vec![5, 10000, 0, 9500, 495], exchange.balances(btc));
+    assert_eq!(vec![0, 0, 0, 2500, 47500], exchange.balances(usdc));
+}
+
+#[test]
+fn test_settlement_netting() {
+    let mut exchange = TestExchange::new();
+    let btc = exchange.add_token("btc");
+    let usdc = exchange.add_token("usdc");
+    let wallet1 = wallet_address(1);
+    let wallet2 = wallet_address(2);
+    let wallet1_btc = exchange.add_wallet(btc, &wallet1);
+    let wallet2_btc = exchange.add_wallet(btc, &wallet2);
+    let wallet1_usdc = exchange.add_wallet(usdc, &wallet1);
+    let wallet2_usdc = exchange.add_wallet(usdc, &wallet2);
+    exchange.deposit(btc, &wallet1_btc, 10000).unwrap();
+    exchange.deposit(usdc, &wallet2_usdc, 50000).unwrap();
+
+    // btc is over-credited by exactly what usdc is under-credited, which used to net out
+    let btc_settlement = SettlementAdjustments {
+        account_index: 1,
+        increments: vec![Adjustment { address_index: wallet2_btc.clone(), amount: 1100 }],
+        decrements: vec![Adjustment { address_index: wallet1_btc.clone(), amount: 1000 }],
+        fee_amount: 0,
+    };
+    let usdc_settlement = SettlementAdjustments {
+        account_index: 2,
+        increments: vec![Adjustment { address_index: wallet1_usdc.clone(), amount: 4900 }],
+        decrements: vec![Adjustment { address_index: wallet2_usdc.clone(), amount: 5000 }],
+        fee_amount: 0,
+    };
+    assert_eq!(
+        Err(ProgramError::Custom(ERROR_NETTING)),
+        exchange.harness.process(
+            &exchange.settlement_accounts(&[btc, usdc], false),
+            &ProgramInstruction::PrepareBatchSettlement(SettlementBatchParams {
+                settlements: vec![btc_settlement.clone(), usdc_settlement.clone()],
+            }),
+        ),
+    );
+    assert_eq!(EMPTY_HASH, exchange.program_state().settlement_batch_hash);
+
+    // in partial mode only the token that does not net out is left out
+    let balanced_usdc_settlement = SettlementAdjustments {
+        fee_amount: 100,
+        ..usdc_settlement
+    };
+    exchange.harness.process(
+        &exchange.settlement_accounts(&[btc, usdc], false),
+        &ProgramInstruction::PreparePartialBatchSettlement(SettlementBatchParams {
+            settlements: vec![btc_settlement.clone(), balanced_usdc_settlement.clone()],
+        }),
+    ).unwrap();
+    assert_ne!(EMPTY_HASH, exchange.program_state().settlement_batch_hash);
+    assert_eq!(
+        vec![
+            Event::NettingFailed { account_index: 1, increment_total: 1100, decrement_total: 1000 },
+            Event::SettlementExcluded { account_index: 1, settlement_index: 0 },
+        ],
+        exchange.events(),
+    );
+    exchange.harness.process(
+        &exchange.settlement_accounts(&[btc, usdc], true),
+        &ProgramInstruction::SubmitBatchSettlement(SettlementBatchParams {
+            settlements: vec![balanced_usdc_settlement],
+        }),
+    ).unwrap();
+    assert_eq!(vec![100, 4900, 45000], exchange.balances(usdc));
+
+    // sums that would wrap are rejected rather than compared
+    assert_eq!(
+        Err(ProgramError::Custom(ERROR_ARITHMETIC_OVERFLOW)),
+        exchange.harness.process(
+            &exchange.settlement_accounts(&[btc], false),
+            &ProgramInstruction::PrepareBatchSettlement(SettlementBatchParams {
+                settlements: vec![SettlementAdjustments {
+                    account_index: 1,
+                    increments: vec![Adjustment { address_index: wallet2_btc.clone(), amount: u64::MAX }],
+                    decrements: vec![Adjustment { address_index: wallet1_btc.clone(), amount: 1000 }],
+                    fee_amount: 1,
+                }],
+            }),
+        ),
+    );
+    assert_eq!(EMPTY_HASH, exchange.program_state().settlement_batch_hash);
+}
+
+#[test]
+fn test_withdrawal() {
+    let mut exchange = TestExchange::new();
+    let btc = exchange.add_token("btc");
+    let wallet = wallet_address(1);
+    let wallet_index = exchange.add_wallet(btc, &wallet);
+    exchange.deposit(btc, &wallet_index, 10000).unwrap();
+
+    let params = WithdrawBatchParams {
+        tx_hex: exchange.input_tx(1),
+        change_amount: 3500,
+        token_withdrawals: vec![TokenWithdrawals {
+            account_index: 2,
+            withdrawals: vec![Withdrawal {
+                address_index: wallet_index.clone(),
+                amount: 5500,
+                fee_account_index: 2,
+                fee_address_index: wallet_index.clone(),
+                fee_amount: 500,
+                signature: None,
+            }],
+        }],
+        input_utxo_types: vec![InputUtxoType::Bitcoin],
+    };
+
+    exchange.harness.process(
+        &exchange.prepare_withdraw_accounts(&[btc], false),
+        &ProgramInstruction::PrepareBatchWithdraw(params.clone()),
+    ).unwrap();
+    assert_eq!(vec![500, 4500], exchange.balances(btc));
+    let withdraw_state: WithdrawState = exchange.harness.decode_account(&exchange.withdraw);
+    assert_ne!(EMPTY_HASH, withdraw_state.batch_hash);
+    assert_eq!(
+        vec![
+            Event::WithdrawalPrepared { sequence: 2, account_index: 2, address_index: 1, amount: 5500, fee_amount: 500 },
+            Event::FeeCollected { sequence: 3, account_index: 2, amount: 500 },
+        ],
+        exchange.events(),
+    );
+
+    let withdraw_utxo = exchange.harness.account(&exchange.withdraw).utxo.clone();
+    exchange.harness.process(
+        &exchange.submit_withdraw_accounts(&[btc], false),
+        &ProgramInstruction::SubmitBatchWithdraw(params.clone()),
+    ).unwrap();
+
+    let signed = exchange.harness.last_signed_transaction().unwrap();
+    let tx = signed.transaction;
+    assert_eq!(2, tx.input.len());
+    assert_eq!(withdraw_utxo.to_outpoint(), tx.input[0].previous_output);
+    assert_eq!(
+        vec![exchange.withdraw, exchange.harness.program_id],
+        signed.inputs_to_sign.iter().map(|i| i.signer).collect::<Vec<Pubkey>>(),
+    );
+    assert_eq!(3, tx.output.len());
+    assert_eq!(exchange.harness.script_pubkey(&exchange.withdraw), tx.output[0].script_pubkey);
+    assert_eq!(Amount::from_sat(ACCOUNT_UTXO_VALUE), tx.output[0].value);
+    assert_eq!(get_bitcoin_address(&wallet, &NetworkType::Regtest).script_pubkey(), tx.output[1].script_pubkey);
+    assert_eq!(Amount::from_sat(5000), tx.output[1].value);
+    assert_eq!(exchange.harness.script_pubkey(&exchange.harness.program_id), tx.output[2].script_pubkey);
+    assert_eq!(Amount::from_sat(3500), tx.output[2].value);
+
+    // the withdraw account now lives on the state output of the signed transaction
+    assert_eq!(
+        OutPoint { txid: tx.compute_txid(), vout: 0 },
+        exchange.harness.account(&exchange.withdraw).utxo.to_outpoint(),
+    );
+    let withdraw_state: WithdrawState = exchange.harness.decode_account(&exchange.withdraw);
+    assert_eq!(WithdrawStatus::Submitted, withdraw_state.status);
+    assert_eq!(txid_to_bytes(&tx.compute_txid()), withdraw_state.tx_id);
+    assert_eq!(
+        vec![Event::WithdrawalSubmitted { sequence: 4, tx_id: txid_to_bytes(&tx.compute_txid()), num_withdrawals: 1 }],
+        exchange.events(),
+    );
+
+    // insufficient balance is reported as an event and nothing is prepared
+    exchange.mine_blocks(WITHDRAW_CONFIRMATION_DEPTH);
+    exchange.confirm_withdrawal(tx.compute_txid()).unwrap();
+    let mut params = params.clone();
+    params.token_withdrawals[0].withdrawals[0].amount = 100000;
+    exchange.harness.process(
+        &exchange.prepare_withdraw_accounts(&[btc], false),
+        &ProgramInstruction::PrepareBatchWithdraw(params),
+    ).unwrap();
+    assert_eq!(vec![500, 4500], exchange.balances(btc));
+    assert_eq!(
+        vec![Event::FailedWithdrawal {
+            account_index: 2,
+            address_index: 1,
+            fee_account_index: 2,
+            fee_address_index: 1,
+            requested_amount: 100000,
+            fee_amount: 500,
+            balance: 4500,
+            balance_in_fee_token: 4500,
+            error_code: ERROR_INSUFFICIENT_BALANCE,
+        }],
+        exchange.events(),
+    );
+}
+
+#[test]
+fn test_confirm_withdrawal() {
+    let mut exchange = TestExchange::new();
+    let btc = exchange.add_token("btc");
+    let wallet_index = exchange.add_wallet(btc, &wallet_address(1));
+    exchange.deposit(btc, &wallet_index, 10000).unwrap();
+
+    let params = |tx_hex: Vec<u8>| WithdrawBatchParams {
+        tx_hex,
+        change_amount: 3500,
+        token_withdrawals: vec![TokenWithdrawals {
+            account_index: 2,
+            withdrawals: vec![Withdrawal {
+                address_index: wallet_index.clone(),
+                amount: 2000,
+                fee_account_index: 2,
+                fee_address_index: wallet_index.clone(),
+                fee_amount: 0,
+                signature: None,
+            }],
+        }],
+        input_utxo_types: vec![InputUtxoType::Bitcoin],
+    };
+    let withdraw_state = |exchange: &TestExchange| -> WithdrawState { exchange.harness.decode_account(&exchange.withdraw) };
+
+    let first = params(exchange.input_tx(1));
+    exchange.harness.process(
+        &exchange.prepare_withdraw_accounts(&[btc], false),
+        &ProgramInstruction::PrepareBatchWithdraw(first.clone()),
+    ).unwrap();
+    assert_eq!(WithdrawStatus::Prepared, withdraw_state(&exchange).status);
+    assert_eq!(EMPTY_HASH, withdraw_state(&exchange).tx_id);
+    assert_eq!(
+        Err(ProgramError::Custom(ERROR_NO_SUBMITTED_WITHDRAWAL)),
+        exchange.confirm_withdrawal(Txid::from_byte_array([1; 32])),
+    );
+
+    exchange.harness.syscalls().block_height = 1000;
+    exchange.harness.process(
+        &exchange.submit_withdraw_accounts(&[btc], false),
+        &ProgramInstruction::SubmitBatchWithdraw(first.clone()),
+    ).unwrap();
+    let txid = exchange.harness.last_signed_transaction().unwrap().transaction.compute_txid();
+    assert_eq!(WithdrawStatus::Submitted, withdraw_state(&exchange).status);
+    assert_eq!(txid_to_bytes(&txid), withdraw_state(&exchange).tx_id);
+    assert_eq!(1000, withdraw_state(&exchange).submitted_height);
+
+    // a submitted batch can neither be submitted again nor rolled back
+    assert_eq!(
+        Err(ProgramError::Custom(ERROR_WITHDRAWAL_BATCH_MISMATCH)),
+        exchange.harness.process(
+            &exchange.submit_withdraw_accounts(&[btc], false),
+            &ProgramInstruction::SubmitBatchWithdraw(first.clone()),
+        ),
+    );
+    assert_eq!(
+        Err(ProgramError::Custom(ERROR_WITHDRAWAL_BATCH_MISMATCH)),
+        exchange.harness.process(
+            &[meta(exchange.program_state, true, false), meta(exchange.withdraw, false, true), meta(btc, false, true)],
+            &ProgramInstruction::RollbackBatchWithdraw(RollbackWithdrawBatchParams {
+                token_withdrawals: first.token_withdrawals.clone(),
+            }),
+        ),
+    );
+    assert_eq!(vec![0, 8000], exchange.balances(btc));
+
+    // nor can the next batch start before it confirms
+    let second = params(exchange.input_tx(1));
+    assert_eq!(
+        Err(ProgramError::Custom(ERROR_WITHDRAWAL_IN_PROGRESS)),
+        exchange.harness.process(
+            &exchange.prepare_withdraw_accounts(&[btc], false),
+            &ProgramInstruction::PrepareBatchWithdraw(second.clone()),
+        ),
+    );
+    let new_withdraw = exchange.harness.create_account();
+    assert_eq!(
+        Err(ProgramError::Custom(ERROR_WITHDRAWAL_IN_PROGRESS)),
+        exchange.harness.process(
+            &[meta(exchange.program_state, true, true), meta(new_withdraw, false, true), meta(exchange.withdraw, false, false)],
+            &ProgramInstruction::UpdateWithdrawAccount(),
+        ),
+    );
+
+    // only a transaction of the batch the node knows confirms it, once enough blocks were mined
+    let other_txid = bitcoin::consensus::deserialize::<Transaction>(&first.tx_hex).unwrap().compute_txid();
+    exchange.mine_blocks(WITHDRAW_CONFIRMATION_DEPTH);
+    assert_eq!(
+        Err(ProgramError::Custom(ERROR_WITHDRAWAL_TX_NOT_FOUND)),
+        exchange.confirm_withdrawal(other_txid),
+    );
+    let tx = exchange.harness.syscalls().bitcoin_txs.remove(&txid).unwrap();
+    assert_eq!(
+        Err(ProgramError::Custom(ERROR_WITHDRAWAL_TX_NOT_FOUND)),
+        exchange.confirm_withdrawal(txid),
+    );
+    exchange.harness.add_bitcoin_tx(tx);
+    exchange.harness.syscalls().block_height = 1000 + WITHDRAW_CONFIRMATION_DEPTH - 1;
+    assert_eq!(
+        Err(ProgramError::Custom(ERROR_WITHDRAWAL_NOT_CONFIRMED)),
+        exchange.confirm_withdrawal(txid),
+    );
+    assert_eq!(WithdrawStatus::Submitted, withdraw_state(&exchange).status);
+    exchange.mine_blocks(1);
+    exchange.confirm_withdrawal(txid).unwrap();
+    assert_eq!(WithdrawStatus::Confirmed, withdraw_state(&exchange).status);
+    assert_eq!(
+        OutPoint { txid, vout: 0 },
+        exchange.harness.account(&exchange.withdraw).utxo.to_outpoint(),
+    );
+    assert_eq!(
+        vec![Event::WithdrawalConfirmed { sequence: 4, tx_id: txid_to_bytes(&txid) }],
+        exchange.events(),
+    );
+    assert_eq!(
+        Err(ProgramError::Custom(ERROR_NO_SUBMITTED_WITHDRAWAL)),
+        exchange.confirm_withdrawal(txid),
+    );
+
+    // moving the withdraw account by hand leaves the batch alone, until it confirms
+    exchange.harness.process(
+        &exchange.prepare_withdraw_accounts(&[btc], false),
+        &ProgramInstruction::PrepareBatchWithdraw(second.clone()),
+    ).unwrap();
+    exchange.harness.process(
+        &exchange.submit_withdraw_accounts(&[btc], false),
+        &ProgramInstruction::SubmitBatchWithdraw(second),
+    ).unwrap();
+    let txid = exchange.harness.last_signed_transaction().unwrap().transaction.compute_txid();
+    let update_utxo = |exchange: &mut TestExchange, tx_id: String| {
+        exchange.harness.process(
+            &[meta(exchange.program_state, true, false), meta(exchange.withdraw, true, true)],
+            &ProgramInstruction::UpdateWithdrawStateUtxo(UpdateWithdrawStateUtxoParams { tx_id, vout: 0 }),
+        )
+    };
+    assert_eq!(Err(ProgramError::InvalidInstructionData), update_utxo(&mut exchange, "not a txid".to_string()));
+    assert_eq!(Err(ProgramError::InvalidInstructionData), update_utxo(&mut exchange, "abcd".to_string()));
+    let replacement = exchange.add_funding_tx(vec![TxOut { value: Amount::from_sat(ACCOUNT_UTXO_VALUE), script_pubkey: ScriptBuf::new() }]);
+    update_utxo(&mut exchange, replacement.to_string()).unwrap();
+    assert_eq!(
+        OutPoint { txid: replacement, vout: 0 },
+        exchange.harness.account(&exchange.withdraw).utxo.to_outpoint(),
+    );
+    assert_eq!(WithdrawStatus::Submitted, withdraw_state(&exchange).status);
+    exchange.mine_blocks(WITHDRAW_CONFIRMATION_DEPTH);
+    exchange.confirm_withdrawal(txid).unwrap();
+    assert_eq!(WithdrawStatus::Confirmed, withdraw_state(&exchange).status);
+    assert_eq!(
+        OutPoint { txid, vout: 0 },
+        exchange.harness.account(&exchange.withdraw).utxo.to_outpoint(),
+    );
+    assert_eq!(vec![0, 6000], exchange.balances(btc));
+}
+
+#[test]
+fn test_bump_withdrawal() {
+    let mut exchange = TestExchange::new();
+    let btc = exchange.add_token("btc");
+    let wallet = wallet_address(1);
+    let wallet_index = exchange.add_wallet(btc, &wallet);
+    exchange.deposit(btc, &wallet_index, 10000).unwrap();
+
+    let params = WithdrawBatchParams {
+        tx_hex: exchange.input_tx_with_values(&[10000]),
+        change_amount: 6000,
+        token_withdrawals: vec![TokenWithdrawals {
+            account_index: 2,
+            withdrawals: vec![Withdrawal {
+                address_index: wallet_index.clone(),
+                amount: 3000,
+                fee_account_index: 2,
+                fee_address_index: wallet_index.clone(),
+                fee_amount: 0,
+                signature: None,
+            }],
+        }],
+        input_utxo_types: vec![InputUtxoType::Bitcoin],
+    };
+    let bump = |exchange: &mut TestExchange, withdraw_batch: &WithdrawBatchParams, change_amount: u64| {
+        exchange.harness.process(
+            &exchange.submit_withdraw_accounts(&[btc], false),
+            &ProgramInstruction::BumpWithdrawBatch(BumpWithdrawBatchParams {
+                withdraw_batch: withdraw_batch.clone(),
+                change_amount,
+            }),
+        )
+    };
+
+    // only a submitted batch can be bumped
+    exchange.harness.process(
+        &exchange.prepare_withdraw_accounts(&[btc], false),
+        &ProgramInstruction::PrepareBatchWithdraw(params.clone()),
+    ).unwrap();
+    assert_eq!(Err(ProgramError::Custom(ERROR_WITHDRAWAL_BATCH_MISMATCH)), bump(&mut exchange, &params, 5000));
+    let withdraw_utxo = exchange.harness.account(&exchange.withdraw).utxo.clone();
+    exchange.harness.process(
+        &exchange.submit_withdraw_accounts(&[btc], false),
+        &ProgramInstruction::SubmitBatchWithdraw(params.clone()),
+    ).unwrap();
+    let submitted = exchange.harness.last_signed_transaction().unwrap().transaction;
+    assert!(submitted.input.iter().all(|input| input.sequence == Sequence::ENABLE_RBF_NO_LOCKTIME));
+    assert!(submitted.is_explicitly_rbf());
+
+    // the recipients and amounts can't change and the fee has to go up
+    let mut changed = params.clone();
+    changed.token_withdrawals[0].withdrawals[0].amount = 4000;
+    assert_eq!(Err(ProgramError::Custom(ERROR_WITHDRAWAL_BATCH_MISMATCH)), bump(&mut exchange, &changed, 5000));
+    assert_eq!(Err(ProgramError::Custom(ERROR_FEE_NOT_INCREASED)), bump(&mut exchange, &params, 6000));
+    assert_eq!(Err(ProgramError::Custom(ERROR_INVALID_CHANGE)), bump(&mut exchange, &params, 500));
+
+    // the replaced transaction has to spend the withdraw account utxo and then the batch inputs
+    let submitted_txid = submitted.compute_txid();
+    let mut extra_input = submitted.clone();
+    extra_input.input.insert(0, submitted.input[0].clone());
+    let mut other_input = submitted.clone();
+    other_input.input[1].previous_output.vout += 1;
+    for replaced in [extra_input, other_input] {
+        exchange.harness.syscalls().bitcoin_txs.insert(submitted_txid, replaced);
+        assert_eq!(Err(ProgramError::Custom(ERROR_INVALID_INPUT_TX)), bump(&mut exchange, &params, 5000));
+    }
+    exchange.harness.syscalls().bitcoin_txs.insert(submitted_txid, submitted.clone());
+
+    bump(&mut exchange, &params, 5000).unwrap();
+    let signed = exchange.harness.last_signed_transaction().unwrap();
+    let tx = signed.transaction;
+    assert_eq!(
+        vec![withdraw_utxo.to_outpoint(), submitted.input[1].previous_output],
+        tx.input.iter().map(|input| input.previous_output).collect::<Vec<OutPoint>>(),
+    );
+    assert!(tx.is_explicitly_rbf());
+    assert_eq!(
+        vec![exchange.withdraw, exchange.harness.program_id],
+        signed.inputs_to_sign.iter().map(|i| i.signer).collect::<Vec<Pubkey>>(),
+    );
+    assert_eq!(submitted.output[..2], tx.output[..2]);
+    assert_eq!(exchange.harness.script_pubkey(&exchange.harness.program_id), tx.output[2].script_pubkey);
+    assert_eq!(Amount::from_sat(5000), tx.output[2].value);
+    assert_eq!(
+        OutPoint { txid: tx.compute_txid(), vout: 0 },
+        exchange.harness.account(&exchange.withdraw).utxo.to_outpoint(),
+    );
+    let withdraw_state: WithdrawState = exchange.harness.decode_account(&exchange.withdraw);
+    assert_eq!(WithdrawStatus::Replaced, withdraw_state.status);
+    assert_eq!(txid_to_bytes(&tx.compute_txid()), withdraw_state.tx_id);
+    assert_eq!(
+        vec![Event::WithdrawalBumped {
+            sequence: 4,
+            replaced_tx_id: txid_to_bytes(&submitted.compute_txid()),
+            tx_id: txid_to_bytes(&tx.compute_txid()),
+        }],
+        exchange.events(),
+    );
+    assert_eq!(vec![0, 7000], exchange.balances(btc));
+
+    // a bump has to pay more than the transaction it replaces, not just the first one, by at least
+    // the incremental relay fee of its 243 vbytes
+    assert_eq!(Err(ProgramError::Custom(ERROR_FEE_NOT_INCREASED)), bump(&mut exchange, &params, 5000));
+    assert_eq!(Err(ProgramError::Custom(ERROR_FEE_NOT_INCREASED)), bump(&mut exchange, &params, 4758));
+    bump(&mut exchange, &params, 4757).unwrap();
+    bump(&mut exchange, &params, 0).unwrap();
+    assert_eq!(2, exchange.harness.last_signed_transaction().unwrap().transaction.output.len());
+
+    // a confirmed batch can't be replaced anymore
+    let txid = exchange.harness.last_signed_transaction().unwrap().transaction.compute_txid();
+    exchange.mine_blocks(WITHDRAW_CONFIRMATION_DEPTH);
+    exchange.confirm_withdrawal(txid).unwrap();
+    assert_eq!(Err(ProgramError::Custom(ERROR_WITHDRAWAL_BATCH_MISMATCH)), bump(&mut exchange, &params, 0));
+}
+
+#[test]
+fn test_withdrawal_fee_rate() {
+    let mut exchange = TestExchange::new();
+    let btc = exchange.add_token("btc");
+    let wallet = wallet_address(1);
+    let wallet_index = exchange.add_wallet(btc, &wallet);
+    exchange.deposit(btc, &wallet_index, 10000).unwrap();
+
+    let params = |tx_hex: Vec<u8>, change_amount: u64| WithdrawBatchParams {
+        tx_hex,
+        change_amount,
+        token_withdrawals: vec![TokenWithdrawals {
+            account_index: 2,
+            withdrawals: vec![Withdrawal {
+                address_index: wallet_index.clone(),
+                amount: 5500,
+                fee_account_index: 2,
+                fee_address_index: wallet_index.clone(),
+                fee_amount: 500,
+                signature: None,
+            }],
+        }],
+        input_utxo_types: vec![InputUtxoType::Bitcoin],
+    };
+    let prepare = |exchange: &mut TestExchange, params: &WithdrawBatchParams| {
+        exchange.harness.process(
+            &exchange.prepare_withdraw_accounts(&[btc], false),
+            &ProgramInstruction::PrepareBatchWithdraw(params.clone()),
+        )
+    };
+    let set_fee_rate = |exchange: &mut TestExchange, fee_rate: Option<WithdrawalFeeRateParams>| {
+        exchange.harness.process(
+            &[meta(exchange.program_state, true, true)],
+            &ProgramInstruction::SetWithdrawalFeeRate(SetWithdrawalFeeRateParams { fee_rate }),
+        )
+    };
+
+    // the inputs must be known and owned by the program
+    let unknown_tx_hex = bitcoin::consensus::serialize(&Transaction {
+        version: Version::TWO,
+        lock_time: LockTime::ZERO,
+        input: vec![TxIn {
+            previous_output: OutPoint { txid: Txid::from_byte_array([1; 32]), vout: 0 },
+            script_sig: ScriptBuf::new(),
+            sequence: Sequence::MAX,
+            witness: Witness::new(),
+        }],
+        output: vec![],
+    });
+    assert_eq!(
+        Err(ProgramError::Custom(ERROR_PREVOUT_NOT_FOUND)),
+        prepare(&mut exchange, &params(unknown_tx_hex, 3500)),
+    );
+    let tx_hex = exchange.input_tx_with_values(&[100000]);
+    let program_id = exchange.harness.program_id;
+    exchange.harness.syscalls().validate_utxo_ownership = Some(Rc::new(move |_: &UtxoMeta, owner: &Pubkey| *owner != program_id));
+    assert_eq!(
+        Err(ProgramError::Custom(ERROR_INVALID_UTXO_OWNER)),
+        prepare(&mut exchange, &params(tx_hex.clone(), 3500)),
+    );
+    exchange.harness.syscalls().validate_utxo_ownership = None;
+
+    // the outputs and change can't exceed the inputs, and the change can't be dust
+    assert_eq!(
+        Err(ProgramError::Custom(ERROR_INVALID_CHANGE)),
+        prepare(&mut exchange, &params(tx_hex.clone(), 96000)),
+    );
+    assert_eq!(
+        Err(ProgramError::Custom(ERROR_INVALID_CHANGE)),
+        prepare(&mut exchange, &params(tx_hex.clone(), DUST_THRESHOLD - 1)),
+    );
+    assert_eq!(vec![0, 10000], exchange.balances(btc));
+
+    assert_eq!(
+        Err(ProgramError::Custom(ERROR_INVALID_FEE_RATE)),
+        set_fee_rate(&mut exchange, Some(WithdrawalFeeRateParams { min_fee_rate: 11, max_fee_rate: 10 })),
+    );
+    assert_eq!(
+        Err(ProgramError::Custom(ERROR_INVALID_FEE_RATE)),
+        set_fee_rate(&mut exchange, Some(WithdrawalFeeRateParams { min_fee_rate: 0, max_fee_rate: 0 })),
+    );
+    set_fee_rate(&mut exchange, Some(WithdrawalFeeRateParams { min_fee_rate: 2, max_fee_rate: 10 })).unwrap();
+
+    // with the state transition of the withdraw account the transaction has two key path inputs,
+    // a p2wpkh and two p2tr outputs, which is 243 vbytes, so the fee must be between 486 and
+    // 2430 sats
+    assert_eq!(
+        Err(ProgramError::Custom(ERROR_FEE_RATE_OUT_OF_BOUNDS)),
+        prepare(&mut exchange, &params(tx_hex.clone(), 94800)),
+    );
+    assert_eq!(
+        Err(ProgramError::Custom(ERROR_FEE_RATE_OUT_OF_BOUNDS)),
+        prepare(&mut exchange, &params(tx_hex.clone(), 94600)),
+    );
+    assert_eq!(
+        Err(ProgramError::Custom(ERROR_FEE_RATE_OUT_OF_BOUNDS)),
+        prepare(&mut exchange, &params(tx_hex.clone(), 90000)),
+    );
+    let params = params(tx_hex, 94000);
+    prepare(&mut exchange, &params).unwrap();
+    assert_eq!(vec![500, 4500], exchange.balances(btc));
+
+    // the bounds are checked again on submit
+    set_fee_rate(&mut exchange, Some(WithdrawalFeeRateParams { min_fee_rate: 8, max_fee_rate: 10 })).unwrap();
+    assert_eq!(
+        Err(ProgramError::Custom(ERROR_FEE_RATE_OUT_OF_BOUNDS)),
+        exchange.harness.process(
+            &exchange.submit_withdraw_accounts(&[btc], false),
+            &ProgramInstruction::SubmitBatchWithdraw(params.clone()),
+        ),
+    );
+    set_fee_rate(&mut exchange, None).unwrap();
+    exchange.harness.process(
+        &exchange.submit_withdraw_accounts(&[btc], false),
+        &ProgramInstruction::SubmitBatchWithdraw(params),
+    ).unwrap();
+    let tx = exchange.harness.last_signed_transaction().unwrap().transaction;
+    assert_eq!(Amount::from_sat(94000), tx.output[2].value);
+}
+
+#[test]
+fn test_utxo_registry() {
+    let mut exchange = TestExchange::new();
+    let btc = exchange.add_token("btc");
+    let wallet = wallet_address(1);
+    let wallet_index = exchange.add_wallet(btc, &wallet);
+    exchange.deposit(btc, &wallet_index, 10000).unwrap();
+
+    let registry = exchange.harness.create_account();
+    exchange.harness.process(
+        &[meta(exchange.program_state, true, true), meta(registry, false, true), meta(exchange.withdraw, false, false)],
+        &ProgramInstruction::InitUtxoRegistry(),
+    ).unwrap();
+    let entries = |exchange: &TestExchange| {
+        let utxo_registry: UtxoRegistryState = exchange.harness.decode_account(&registry);
+        utxo_registry.entries
+    };
+    let program_script = exchange.harness.script_pubkey(&exchange.harness.program_id);
+    let params = |outpoint: &FundingOutpoint| WithdrawBatchParams {
+        tx_hex: bitcoin::consensus::serialize(&Transaction {
+            version: Version::TWO,
+            lock_time: LockTime::ZERO,
+            input: vec![TxIn {
+                previous_output: OutPoint { txid: txid_from_bytes(&outpoint.tx_id), vout: outpoint.vout },
+                script_sig: ScriptBuf::new(),
+                sequence: Sequence::MAX,
+                witness: Witness::new(),
+            }],
+            output: vec![],
+        }),
+        change_amount: 94000,
+        token_withdrawals: vec![TokenWithdrawals {
+            account_index: 2,
+            withdrawals: vec![Withdrawal {
+                address_index: wallet_index.clone(),
+                amount: 5500,
+                fee_account_index: 2,
+                fee_address_index: wallet_index.clone(),
+                fee_amount: 500,
+                signature: None,
+            }],
+        }],
+        input_utxo_types: vec![InputUtxoType::Bitcoin],
+    };
+
+    // deposits and withdrawals need the registry once the program has one
+    let funding_txid = exchange.add_funding_tx(vec![TxOut { value: Amount::from_sat(50000), script_pubkey: program_script.clone() }]);
+    assert_eq!(
+        Err(ProgramError::Custom(ERROR_UTXO_REGISTRY_MISSING)),
+        exchange.funded_deposit(btc, &wallet_index, 50000, funding_outpoint(funding_txid, 0)),
+    );
+    exchange.utxo_registry.push(registry);
+
+    // funded deposits are registered
+    exchange.funded_deposit(btc, &wallet_index, 50000, funding_outpoint(funding_txid, 0)).unwrap();
+    let deposited = UtxoEntry { outpoint: funding_outpoint(funding_txid, 0).unwrap(), locked: false };
+    assert_eq!(vec![deposited.clone()], entries(&exchange));
+
+    // utxos of the program can be registered directly
+    let register = |exchange: &mut TestExchange, utxos: Vec<FundingOutpoint>| {
+        exchange.harness.process(
+            &[meta(exchange.program_state, true, false), meta(registry, false, true)],
+            &ProgramInstruction::RegisterUtxos(RegisterUtxosParams { utxos }),
+        )
+    };
+    let withdraw_script = exchange.harness.script_pubkey(&exchange.withdraw);
+    let funding_txid = exchange.add_funding_tx(vec![
+        TxOut { value: Amount::from_sat(100000), script_pubkey: program_script.clone() },
+        TxOut { value: Amount::from_sat(100000), script_pubkey: withdraw_script },
+    ]);
+    assert_eq!(
+        Err(ProgramError::Custom(ERROR_INVALID_UTXO_OWNER)),
+        register(&mut exchange, vec![funding_outpoint(funding_txid, 1).unwrap()]),
+    );
+    assert_eq!(
+        Err(ProgramError::Custom(ERROR_PREVOUT_NOT_FOUND)),
+        register(&mut exchange, vec![funding_outpoint(funding_txid, 2).unwrap()]),
+    );
+    let registered = funding_outpoint(funding_txid, 0).unwrap();
+    register(&mut exchange, vec![registered.clone()]).unwrap();
+    assert_eq!(2, entries(&exchange).len());
+
+    // withdrawals can only spend registered utxos
+    let unregistered = exchange.add_funding_tx(vec![TxOut { value: Amount::from_sat(100000), script_pubkey: program_script.clone() }]);
+    assert_eq!(
+        Err(ProgramError::Custom(ERROR_UTXO_NOT_REGISTERED)),
+        exchange.harness.process(
+            &exchange.prepare_withdraw_accounts(&[btc], false),
+            &ProgramInstruction::PrepareBatchWithdraw(params(&funding_outpoint(unregistered, 0).unwrap())),
+        ),
+    );
+
+    // a prepared withdrawal locks its inputs until it is rolled back
+    let prepare = |exchange: &mut TestExchange| {
+        exchange.harness.process(
+            &exchange.prepare_withdraw_accounts(&[btc], false),
+            &ProgramInstruction::PrepareBatchWithdraw(params(&registered)),
+        ).unwrap();
+    };
+    prepare(&mut exchange);
+    let locked = UtxoEntry { outpoint: registered.clone(), locked: true };
+    assert!(entries(&exchange).contains(&locked));
+    exchange.harness.process(
+        &[meta(exchange.program_state, true, false), meta(exchange.withdraw, false, true), meta(btc, false, true), meta(registry, false, true)],
+        &ProgramInstruction::RollbackBatchWithdraw(RollbackWithdrawBatchParams {
+            token_withdrawals: params(&registered).token_withdrawals,
+        }),
+    ).unwrap();
+    assert!(entries(&exchange).contains(&UtxoEntry { outpoint: registered.clone(), locked: false }));
+
+    // a submitted withdrawal spends its inputs and registers its change, reserved until the
+    // transaction confirms
+    prepare(&mut exchange);
+    exchange.harness.process(
+        &exchange.submit_withdraw_accounts(&[btc], false),
+        &ProgramInstruction::SubmitBatchWithdraw(params(&registered)),
+    ).unwrap();
+    let submitted = exchange.harness.last_signed_transaction().unwrap().transaction;
+    assert_eq!(program_script, submitted.output[2].script_pubkey);
+    let submitted_change = funding_outpoint(submitted.compute_txid(), 2).unwrap();
+    assert_eq!(2, entries(&exchange).len());
+    assert!(entries(&exchange).contains(&deposited));
+    assert!(entries(&exchange).contains(&UtxoEntry { outpoint: submitted_change.clone(), locked: true }));
+
+    // a bumped withdrawal registers the change of the replacement as well, since either
+    // transaction can still confirm
+    exchange.harness.process(
+        &exchange.submit_withdraw_accounts(&[btc], false),
+        &ProgramInstruction::BumpWithdrawBatch(BumpWithdrawBatchParams {
+            withdraw_batch: params(&registered),
+            change_amount: 93000,
+        }),
+    ).unwrap();
+    let bumped = exchange.harness.last_signed_transaction().unwrap().transaction;
+    let bumped_change = funding_outpoint(bumped.compute_txid(), 2).unwrap();
+    assert_eq!(3, entries(&exchange).len());
+    assert!(entries(&exchange).contains(&UtxoEntry { outpoint: submitted_change.clone(), locked: true }));
+    assert!(entries(&exchange).contains(&UtxoEntry { outpoint: bumped_change.clone(), locked: true }));
+    let withdraw_state: WithdrawState = exchange.harness.decode_account(&exchange.withdraw);
+    assert_eq!(vec![txid_to_bytes(&submitted.compute_txid())], withdraw_state.replaced_tx_ids);
+
+    // the replaced transaction can still win, its change is released and the replacement's dropped
+    exchange.mine_blocks(WITHDRAW_CONFIRMATION_DEPTH);
+    exchange.confirm_withdrawal(submitted.compute_txid()).unwrap();
+    let entries = entries(&exchange);
+    assert_eq!(2, entries.len());
+    assert!(entries.contains(&deposited));
+    assert!(entries.contains(&UtxoEntry { outpoint: submitted_change, locked: false }));
+    let withdraw_state: WithdrawState = exchange.harness.decode_account(&exchange.withdraw);
+    assert_eq!(WithdrawStatus::Confirmed, withdraw_state.status);
+    assert_eq!(txid_to_bytes(&submitted.compute_txid()), withdraw_state.tx_id);
+    assert!(withdraw_state.replaced_tx_ids.is_empty());
+    assert_eq!(
+        OutPoint { txid: submitted.compute_txid(), vout: 0 },
+        exchange.harness.account(&exchange.withdraw).utxo.to_outpoint(),
+    );
+}
+
+#[test]
+fn test_partial_withdrawal() {
+    let mut exchange = TestExchange::new();
+    let btc = exchange.add_token("btc");
+    let wallet1 = wallet_address(1);
+    let wallet2 = wallet_address(2);
+    let wallet1_index = exchange.add_wallet(btc, &wallet1);
+    let wallet2_index = exchange.add_wallet(btc, &wallet2);
+    exchange.deposit(btc, &wallet1_index, 10000).unwrap();
+    exchange.deposit(btc, &wallet2_index, 1000).unwrap();
+
+    let withdrawal1 = Withdrawal {
+        address_index: wallet1_index.clone(),
+        amount: 5500,
+        fee_account_index: 2,
+        fee_address_index: wallet1_index.clone(),
+        fee_amount: 500,
+        signature: None,
+    };
+    // more than wallet2 holds
+    let withdrawal2 = Withdrawal {
+        address_index: wallet2_index.clone(),
+        amount: 5000,
+        fee_account_index: 2,
+        fee_address_index: wallet2_index.clone(),
+        fee_amount: 0,
+        signature: None,
+    };
+    // a fee larger than the amount leaves nothing to pay out
+    let withdrawal3 = Withdrawal {
+        address_index: wallet2_index.clone(),
+        amount: 100,
+        fee_account_index: 2,
+        fee_address_index: wallet2_index.clone(),
+        fee_amount: 200,
+        signature: None,
+    };
+    let params = WithdrawBatchParams {
+        tx_hex: exchange.input_tx(1),
+        change_amount: 3500,
+        token_withdrawals: vec![TokenWithdrawals {
+            account_index: 2,
+            withdrawals: vec![withdrawal1.clone(), withdrawal2, withdrawal3],
+        }],
+        input_utxo_types: vec![InputUtxoType::Bitcoin],
+    };
+
+    exchange.harness.process(
+        &exchange.prepare_withdraw_accounts(&[btc], false),
+        &ProgramInstruction::PreparePartialBatchWithdraw(params.clone()),
+    ).unwrap();
+    assert_eq!(vec![500, 4500, 1000], exchange.balances(btc));
+    assert_eq!(
+        vec![
+            Event::FailedWithdrawal {
+                account_index: 2,
+                address_index: 2,
+                fee_account_index: 2,
+                fee_address_index: 2,
+                requested_amount: 5000,
+                fee_amount: 0,
+                balance: 1000,
+                balance_in_fee_token: 1000,
+                error_code: ERROR_INSUFFICIENT_BALANCE,
+            },
+            Event::FailedWithdrawal {
+                account_index: 2,
+                address_index: 2,
+                fee_account_index: 2,
+                fee_address_index: 2,
+                requested_amount: 100,
+                fee_amount: 200,
+                balance: 1000,
+                balance_in_fee_token: 1000,
+                error_code: ERROR_FEE_EXCEEDS_AMOUNT,
+            },
+            Event::WithdrawalExcluded { account_index: 2, withdrawal_index: 1 },
+            Event::WithdrawalExcluded { account_index: 2, withdrawal_index: 2 },
+            Event::WithdrawalPrepared { sequence: 3, account_index: 2, address_index: 1, amount: 5500, fee_amount: 500 },
+            Event::FeeCollected { sequence: 4, account_index: 2, amount: 500 },
+        ],
+        exchange.events(),
+    );
+
+    // the stored hash covers the remaining withdrawals, whose inputs now pay into the change
+    assert_eq!(
+        Err(ProgramError::Custom(ERROR_WITHDRAWAL_BATCH_MISMATCH)),
+        exchange.harness.process(
+            &exchange.submit_withdraw_accounts(&[btc], false),
+            &ProgramInstruction::SubmitBatchWithdraw(params.clone()),
+        ),
+    );
+    exchange.harness.process(
+        &exchange.submit_withdraw_accounts(&[btc], false),
+        &ProgramInstruction::SubmitBatchWithdraw(WithdrawBatchParams {
+            change_amount: 8500,
+            token_withdrawals: vec![TokenWithdrawals {
+                account_index: 2,
+                withdrawals: vec![withdrawal1],
+            }],
+            ..params.clone()
+        }),
+    ).unwrap();
+    let tx = exchange.harness.last_signed_transaction().unwrap().transaction;
+    assert_eq!(3, tx.output.len());
+    assert_eq!(get_bitcoin_address(&wallet1, &NetworkType::Regtest).script_pubkey(), tx.output[1].script_pubkey);
+    assert_eq!(Amount::from_sat(5000), tx.output[1].value);
+    assert_eq!(exchange.harness.script_pubkey(&exchange.harness.program_id), tx.output[2].script_pubkey);
+    assert_eq!(Amount::from_sat(8500), tx.output[2].value);
+    exchange.mine_blocks(WITHDRAW_CONFIRMATION_DEPTH);
+    exchange.confirm_withdrawal(tx.compute_txid()).unwrap();
+
+    // nothing is prepared when every withdrawal is dropped
+    exchange.harness.process(
+        &exchange.prepare_withdraw_accounts(&[btc], false),
+        &ProgramInstruction::PreparePartialBatchWithdraw(params),
+    ).unwrap();
+    let withdraw_state: WithdrawState = exchange.harness.decode_account(&exchange.withdraw);
+    assert_ne!(WithdrawStatus::Prepared, withdraw_state.status);
+    assert_eq!(vec![500, 4500, 1000], exchange.balances(btc));
+}
+
+#[test]
+fn test_withdrawal_fee_policy() {
+    let mut exchange = TestExchange::new();
+    let btc = exchange.add_token("btc");
+    let rune = exchange.add_token("840000:3");
+    let wallet_index = exchange.add_wallet(btc, &wallet_address(1));
+    exchange.deposit(btc, &wallet_index, 10000).unwrap();
+
+    let set_fee_policy = |exchange: &mut TestExchange, token: Pubkey, fee_policy: Option<WithdrawalFeePolicyParams>| {
+        let mut accounts = vec![meta(exchange.program_state, true, false), meta(token, false, true)];
+        if token != btc {
+            accounts.push(meta(btc, false, false));
+        }
+        exchange.harness.process(
+            &accounts,
+            &ProgramInstruction::SetWithdrawalFeePolicy(SetWithdrawalFeePolicyParams { account_index: 1, fee_policy }),
+        )
+    };
+    // a flat fee below the minimum could never be charged, and runes have no fee balance of their own
+    assert_eq!(
+        Err(ProgramError::Custom(ERROR_INVALID_FEE_POLICY)),
+        set_fee_policy(&mut exchange, btc, Some(WithdrawalFeePolicyParams { min_fee: 100, flat_fee: 50, fee_account_index: None })),
+    );
+    assert_eq!(
+        Err(ProgramError::Custom(ERROR_INVALID_FEE_POLICY)),
+        set_fee_policy(&mut exchange, rune, Some(WithdrawalFeePolicyParams { min_fee: 100, flat_fee: 0, fee_account_index: None })),
+    );
+    set_fee_policy(&mut exchange, rune, Some(WithdrawalFeePolicyParams { min_fee: 0, flat_fee: 300, fee_account_index: Some(2) })).unwrap();
+    assert_eq!(
+        Some(WithdrawalFeePolicy { min_fee: 0, flat_fee: 300, fee_token_account: Some(btc) }),
+        exchange.token_state(rune).withdrawal_fee_policy,
+    );
+    set_fee_policy(&mut exchange, btc, Some(WithdrawalFeePolicyParams { min_fee: 100, flat_fee: 0, fee_account_index: None })).unwrap();
+    assert_eq!(
+        Some(WithdrawalFeePolicy { min_fee: 100, flat_fee: 0, fee_token_account: None }),
+        exchange.token_state(btc).withdrawal_fee_policy,
+    );
+
+    let withdraw = |exchange: &mut TestExchange, fee_amount: u64| {
+        let tx_hex = exchange.input_tx(1);
+        exchange.harness.process(
+            &exchange.prepare_withdraw_accounts(&[btc], false),
+            &ProgramInstruction::PrepareBatchWithdraw(WithdrawBatchParams {
+                tx_hex,
+                change_amount: 3500,
+                token_withdrawals: vec![TokenWithdrawals {
+                    account_index: 2,
+                    withdrawals: vec![Withdrawal {
+                        address_index: wallet_index.clone(),
+                        amount: 5500,
+                        fee_account_index: 2,
+                        fee_address_index: wallet_index.clone(),
+                        fee_amount,
+                        signature: None,
+                    }],
+                }],
+                input_utxo_types: vec![InputUtxoType::Bitcoin],
+            }),
+        ).unwrap();
+    };
+    // a fee below the minimum is reported and nothing is prepared
+    withdraw(&mut exchange, 50);
+    assert_eq!(
+        vec![Event::FailedWithdrawal {
+            account_index: 2,
+            address_index: 1,
+            fee_account_index: 2,
+            fee_address_index: 1,
+            requested_amount: 5500,
+            fee_amount: 50,
+            balance: 10000,
+            balance_in_fee_token: 10000,
+            error_code: ERROR_WITHDRAWAL_FEE_POLICY,
+        }],
+        exchange.events(),
+    );
+    let withdraw_state: WithdrawState = exchange.harness.decode_account(&exchange.withdraw);
+    assert_eq!(EMPTY_HASH, withdraw_state.batch_hash);
+    assert_eq!(vec![0, 10000], exchange.balances(btc));
+
+    // without a policy any fee goes through
+    set_fee_policy(&mut exchange, btc, None).unwrap();
+    assert_eq!(None, exchange.token_state(btc).withdrawal_fee_policy);
+    withdraw(&mut exchange, 50);
+    assert_eq!(vec![50, 4500], exchange.balances(btc));
+}
+
+#[test]
+fn test_withdrawal_limit() {
+    let mut exchange = TestExchange::new();
+    let btc = exchange.add_token("btc");
+    let wallet1_index = exchange.add_wallet(btc, &wallet_address(1));
+    let wallet2_index = exchange.add_wallet(btc, &wallet_address(2));
+    exchange.deposit(btc, &wallet1_index, 100000).unwrap();
+    exchange.deposit(btc, &wallet2_index, 100000).unwrap();
+
+    let limit = exchange.harness.create_account();
+    let set_limit = |exchange: &mut TestExchange, limit_params: Option<WithdrawalLimitParams>| {
+        exchange.harness.process(
+            &[meta(exchange.program_state, true, false), meta(btc, false, true), meta(limit, false, true)],
+            &ProgramInstruction::SetWithdrawalLimit(SetWithdrawalLimitParams { limit: limit_params }),
+        )
+    };
+    assert_eq!(
+        Err(ProgramError::Custom(ERROR_INVALID_WITHDRAWAL_LIMIT)),
+        set_limit(&mut exchange, Some(WithdrawalLimitParams { max_amount: 50000, window_blocks: 0 })),
+    );
+    set_limit(&mut exchange, Some(WithdrawalLimitParams { max_amount: 50000, window_blocks: 144 })).unwrap();
+    assert_eq!(limit, exchange.token_state(btc).withdrawal_limit_account);
+
+    let withdrawal = |address_index: &AddressIndex, amount: u64| Withdrawal {
+        address_index: address_index.clone(),
+        amount,
+        fee_account_index: 2,
+        fee_address_index: address_index.clone(),
+        fee_amount: 0,
+        signature: None,
+    };
+    let tx_hex = exchange.input_tx(1);
+    let params = |withdrawals: Vec<Withdrawal>| WithdrawBatchParams {
+        tx_hex: tx_hex.clone(),
+        change_amount: 3500,
+        token_withdrawals: vec![TokenWithdrawals { account_index: 2, withdrawals }],
+        input_utxo_types: vec![InputUtxoType::Bitcoin],
+    };
+    let rollback = |exchange: &mut TestExchange, withdrawals: Vec<Withdrawal>| {
+        exchange.harness.process(
+            &[meta(exchange.program_state, true, false), meta(exchange.withdraw, false, true), meta(btc, false, true)],
+            &ProgramInstruction::RollbackBatchWithdraw(RollbackWithdrawBatchParams {
+                token_withdrawals: params(withdrawals).token_withdrawals,
+            }),
+        ).unwrap();
+    };
+
+    // the limit account has to be passed once the token has a limit
+    exchange.harness.syscalls().block_height = 1000;
+    assert_eq!(
+        Err(ProgramError::Custom(ERROR_WITHDRAWAL_LIMIT_MISSING)),
+        exchange.harness.process(
+            &exchange.prepare_withdraw_accounts(&[btc], false),
+            &ProgramInstruction::PrepareBatchWithdraw(params(vec![withdrawal(&wallet1_index, 1000)])),
+        ),
+    );
+    exchange.withdrawal_limits.push(limit);
+
+    // withdrawals earlier in the batch count against the limit of their wallet
+    exchange.harness.process(
+        &exchange.prepare_withdraw_accounts(&[btc], false),
+        &ProgramInstruction::PreparePartialBatchWithdraw(params(vec![
+            withdrawal(&wallet1_index, 30000),
+            withdrawal(&wallet1_index, 20000),
+            withdrawal(&wallet1_index, 10000),
+            withdrawal(&wallet2_index, 40000),
+        ])),
+    ).unwrap();
+    assert_eq!(
+        vec![
+            Event::FailedWithdrawal {
+                account_index: 2,
+                address_index: 1,
+                fee_account_index: 2,
+                fee_address_index: 1,
+                requested_amount: 10000,
+                fee_amount: 0,
+                balance: 100000,
+                balance_in_fee_token: 100000,
+                error_code: ERROR_WITHDRAWAL_LIMIT_EXCEEDED,
+            },
+            Event::WithdrawalExcluded { account_index: 2, withdrawal_index: 2 },
+        ],
+        exchange.events()[..2],
+    );
+    assert_eq!(vec![0, 50000, 60000], exchange.balances(btc));
+    let limit_state: WithdrawalLimitState = exchange.harness.decode_account(&limit);
+    assert_eq!(1000 - 1000 % 144, limit_state.window_start);
+    let withdrawn = |limit_state: &WithdrawalLimitState, address: &str| {
+        let address_hash = WalletAddress::from_address(address).unwrap().hash();
+        limit_state.entries.iter().find(|entry| entry.address_hash == address_hash).map(|entry| entry.amount)
+    };
+    assert_eq!(Some(50000), withdrawn(&limit_state, &wallet_address(1)));
+    assert_eq!(Some(40000), withdrawn(&limit_state, &wallet_address(2)));
+
+    // rolled back withdrawals keep counting until the window is over
+    rollback(&mut exchange, vec![
+        withdrawal(&wallet1_index, 30000),
+        withdrawal(&wallet1_index, 20000),
+        withdrawal(&wallet2_index, 40000),
+    ]);
+    assert_eq!(vec![0, 100000, 100000], exchange.balances(btc));
+    exchange.harness.syscalls().block_height = 1007;
+    exchange.harness.process(
+        &exchange.prepare_withdraw_accounts(&[btc], false),
+        &ProgramInstruction::PrepareBatchWithdraw(params(vec![withdrawal(&wallet1_index, 1000)])),
+    ).unwrap();
+    assert_eq!(ERROR_WITHDRAWAL_LIMIT_EXCEEDED, match exchange.events().as_slice() {
+        [Event::FailedWithdrawal { error_code, .. }] => *error_code,
+        _ => panic!("expected a single failed withdrawal"),
+    });
+    let withdraw_state: WithdrawState = exchange.harness.decode_account(&exchange.withdraw);
+    assert_eq!(EMPTY_HASH, withdraw_state.batch_hash);
+
+    // the next window starts from scratch
+    exchange.harness.syscalls().block_height = 1008;
+    exchange.harness.process(
+        &exchange.prepare_withdraw_accounts(&[btc], false),
+        &ProgramInstruction::PrepareBatchWithdraw(params(vec![withdrawal(&wallet1_index, 50000)])),
+    ).unwrap();
+    assert_eq!(vec![0, 50000, 100000], exchange.balances(btc));
+    let limit_state: WithdrawalLimitState = exchange.harness.decode_account(&limit);
+    assert_eq!(1008, limit_state.window_start);
+    assert_eq!(Some(50000), withdrawn(&limit_state, &wallet_address(1)));
+    assert_eq!(None, withdrawn(&limit_state, &wallet_address(2)));
+    rollback(&mut exchange, vec![withdrawal(&wallet1_index, 50000)]);
+
+    // a token listed twice in the batch still counts against a single limit
+    exchange.harness.syscalls().block_height = 1152;
+    exchange.harness.process(
+        &exchange.prepare_withdraw_accounts(&[btc], false),
+        &ProgramInstruction::PreparePartialBatchWithdraw(WithdrawBatchParams {
+            token_withdrawals: vec![
+                TokenWithdrawals { account_index: 2, withdrawals: vec![withdrawal(&wallet1_index, 30000)] },
+                TokenWithdrawals { account_index: 2, withdrawals: vec![withdrawal(&wallet1_index, 30000)] },
+            ],
+            ..params(vec![])
+        }),
+    ).unwrap();
+    assert_eq!(
+        vec![
+            Event::FailedWithdrawal {
+                account_index: 2,
+                address_index: 1,
+                fee_account_index: 2,
+                fee_address_index: 1,
+                requested_amount: 30000,
+                fee_amount: 0,
+                balance: 100000,
+                balance_in_fee_token: 100000,
+                error_code: ERROR_WITHDRAWAL_LIMIT_EXCEEDED,
+            },
+            Event::WithdrawalExcluded { account_index: 2, withdrawal_index: 0 },
+        ],
+        exchange.events()[..2],
+    );
+    assert_eq!(vec![0, 70000, 100000], exchange.balances(btc));
+    let limit_state: WithdrawalLimitState = exchange.harness.decode_account(&limit);
+    assert_eq!(Some(30000), withdrawn(&limit_state, &wallet_address(1)));
+    rollback(&mut exchange, vec![withdrawal(&wallet1_index, 30000)]);
+
+    // without a limit only the balance counts
+    set_limit(&mut exchange, None).unwrap();
+    assert_eq!(Pubkey::from([0u8; 32]), exchange.token_state(btc).withdrawal_limit_account);
+    exchange.harness.process(
+        &exchange.prepare_withdraw_accounts(&[btc], false),
+        &ProgramInstruction::PrepareBatchWithdraw(params(vec![withdrawal(&wallet1_index, 100000)])),
+    ).unwrap();
+    assert_eq!(vec![0, 0, 100000], exchange.balances(btc));
+}
+
+#[test]
+fn test_forced_withdrawal() {
+    let mut exchange = TestExchange::new();
+    let btc = exchange.add_token("btc");
+    let secp = bitcoin::secp256k1::Secp256k1::new();
+    let keypair = bitcoin::key::Keypair::from_seckey_slice(&secp, &[1; 32]).unwrap();
+    let wallet = Address::p2tr(&secp, keypair.x_only_public_key().0, None, Network::Regtest).to_string();
+    let wallet_index = exchange.add_wallet(btc, &wallet);
+    let other_wallet_index = exchange.add_wallet(btc, &wallet_address(2));
+    exchange.deposit(btc, &wallet_index, 100000).unwrap();
+    exchange.deposit(btc, &other_wallet_index, 100000).unwrap();
+
+    let registry = exchange.harness.create_account();
+    let request_params = |exchange: &TestExchange, amount: u64, nonce: u64, signed_amount: u64| {
+        let mut params = RequestForcedWithdrawalParams {
+            account_index: 1,
+            address_index: wallet_index.clone(),
+            amount: signed_amount,
+            nonce,
+            signature: vec![],
+        };
+        let message = params.message(&exchange.program_state, &btc, &wallet);
+        let signature = bip322::sign_message_bip322(&keypair, message.as_bytes(), Network::Regtest);
+        params.amount = amount;
+        params.signature = bitcoin::consensus::serialize(&Witness::from_slice(&[signature.to_vec()]));
+        params
+    };
+    let request = |exchange: &mut TestExchange, amount: u64, nonce: u64, signed_amount: u64| {
+        let params = request_params(exchange, amount, nonce, signed_amount);
+        let mut accounts = vec![meta(exchange.program_state, false, true), meta(btc, false, false), meta(registry, false, true)];
+        accounts.extend(exchange.event_log_accounts());
+        exchange.harness.process(&accounts, &ProgramInstruction::RequestForcedWithdrawal(params))
+    };
+    // the wallet signs the network fee of its first request
+    let forced_withdraw = |exchange: &mut TestExchange, tx_hex: Vec<u8>, signed_network_fee: u64| {
+        let mut params = ForcedWithdrawParams {
+            account_index: 1,
+            address_index: wallet_index.clone(),
+            tx_hex,
+            network_fee: signed_network_fee,
+            signature: vec![],
+        };
+        let message = params.message(&exchange.program_state, &btc, &wallet, 60000, 1);
+        let signature = bip322::sign_message_bip322(&keypair, message.as_bytes(), Network::Regtest);
+        params.network_fee = 500;
+        params.signature = bitcoin::consensus::serialize(&Witness::from_slice(&[signature.to_vec()]));
+        let mut accounts = vec![
+            meta(exchange.program_state, false, true),
+            meta(btc, false, true),
+            meta(registry, false, true),
+            meta(exchange.withdraw, false, false),
+        ];
+        accounts.extend(exchange.event_log_accounts());
+        exchange.harness.process(&accounts, &ProgramInstruction::ForcedWithdraw(params))
+    };
+    let settle = |exchange: &mut TestExchange| {
+        exchange.harness.process(
+            &exchange.settlement_accounts(&[btc], false),
+            &ProgramInstruction::PrepareBatchSettlement(SettlementBatchParams {
+                settlements: vec![SettlementAdjustments {
+                    account_index: 1,
+                    increments: vec![Adjustment { address_index: other_wallet_index.clone(), amount: 1000 }],
+                    decrements: vec![Adjustment { address_index: wallet_index.clone(), amount: 1000 }],
+                    fee_amount: 0,
+                }],
+            }),
+        ).unwrap();
+        exchange.harness.process(&[meta(exchange.program_state, true, true)], &ProgramInstruction::RollbackBatchSettlement()).unwrap();
+    };
+
+    assert_eq!(
+        Err(ProgramError::Custom(ERROR_FORCED_WITHDRAWALS_MISSING)),
+        request(&mut exchange, 60000, 1, 60000),
+    );
+    exchange.harness.process(
+        &[meta(exchange.program_state, true, true), meta(registry, false, true)],
+        &ProgramInstruction::InitForcedWithdrawals(InitForcedWithdrawalsParams { delay_blocks: 6 }),
+    ).unwrap();
+    exchange.forced_withdrawals.push(registry);
+
+    // the signature has to cover the request
+    exchange.harness.syscalls().block_height = 1000;
+    assert_eq!(
+        Err(ProgramError::Custom(ERROR_INVALID_SIGNATURE)),
+        request(&mut exchange, 60000, 1, 50000),
+    );
+    assert_eq!(
+        Err(ProgramError::Custom(ERROR_INSUFFICIENT_BALANCE)),
+        request(&mut exchange, 100001, 1, 100001),
+    );
+    request(&mut exchange, 60000, 1, 60000).unwrap();
+    assert_eq!(
+        vec![Event::ForcedWithdrawalRequested { sequence: 3, account_index: 1, address_index: 1, amount: 60000, due_height: 1006 }],
+        exchange.events(),
+    );
+    assert_eq!(
+        Err(ProgramError::Custom(ERROR_FORCED_WITHDRAWAL_PENDING)),
+        request(&mut exchange, 60000, 2, 60000),
+    );
+
+    // the operator has until the due height, after that the wallet is frozen
+    let program_script_pubkey = exchange.harness.script_pubkey(&exchange.harness.program_id);
+    let funding_txid = exchange.add_funding_tx(vec![
+        TxOut { value: Amount::from_sat(70000), script_pubkey: program_script_pubkey.clone() },
+        TxOut { value: Amount::from_sat(70000), script_pubkey: get_bitcoin_address(&wallet, &NetworkType::Regtest).script_pubkey() },
+    ]);
+    let spending_tx = |vout: u32| bitcoin::consensus::serialize(&Transaction {
+        version: Version::TWO,
+        lock_time: LockTime::ZERO,
+        input: vec![TxIn {
+            previous_output: OutPoint { txid: funding_txid, vout },
+            script_sig: ScriptBuf::new(),
+            sequence: Sequence::MAX,
+            witness: Witness::new(),
+        }],
+        output: vec![],
+    });
+    exchange.harness.syscalls().block_height = 1005;
+    assert_eq!(
+        Err(ProgramError::Custom(ERROR_FORCED_WITHDRAWAL_NOT_DUE)),
+        forced_withdraw(&mut exchange, spending_tx(0), 500),
+    );
+    settle(&mut exchange);
+    assert!(exchange.events().is_empty());
+
+    exchange.harness.syscalls().block_height = 1006;
+    settle(&mut exchange);
+    assert_eq!(
+        vec![Event::FailedSettlement {
+            account_index: 1,
+            address_index: wallet_index.index,
+            requested_amount: 1000,
+            balance: 100000,
+            error_code: ERROR_WALLET_FROZEN,
+        }],
+        exchange.events(),
+    );
+
+    // a prepared settlement may count on the balance, so it has to be submitted or rolled back first
+    exchange.harness.process(
+        &exchange.settlement_accounts(&[btc], false),
+        &ProgramInstruction::PrepareBatchSettlement(SettlementBatchParams {
+            settlements: vec![SettlementAdjustments {
+                account_index: 1,
+                increments: vec![Adjustment { address_index: other_wallet_index.clone(), amount: 1000 }],
+                decrements: vec![Adjustment { address_index: other_wallet_index.clone(), amount: 1000 }],
+                fee_amount: 0,
+            }],
+        }),
+    ).unwrap();
+    assert_eq!(
+        Err(ProgramError::Custom(ERROR_SETTLEMENT_IN_PROGRESS)),
+        forced_withdraw(&mut exchange, spending_tx(0), 500),
+    );
+    exchange.harness.process(&[meta(exchange.program_state, true, true)], &ProgramInstruction::RollbackBatchSettlement()).unwrap();
+
+    // only utxos of the program can be spent
+    assert_eq!(
+        Err(ProgramError::Custom(ERROR_INVALID_FORCED_WITHDRAWAL_TX)),
+        forced_withdraw(&mut exchange, spending_tx(1), 500),
+    );
+    // the network fee is the one the wallet signed
+    assert_eq!(
+        Err(ProgramError::Custom(ERROR_INVALID_SIGNATURE)),
+        forced_withdraw(&mut exchange, spending_tx(0), 400),
+    );
+    forced_withdraw(&mut exchange, spending_tx(0), 500).unwrap();
+    let signed = exchange.harness.last_signed_transaction().unwrap();
+    assert_eq!(
+        vec![exchange.harness.program_id],
+        signed.inputs_to_sign.iter().map(|i| i.signer).collect::<Vec<Pubkey>>(),
+    );
+    let tx = signed.transaction;
+    assert_eq!(2, tx.output.len());
+    assert_eq!(get_bitcoin_address(&wallet, &NetworkType::Regtest).script_pubkey(), tx.output[0].script_pubkey);
+    assert_eq!(Amount::from_sat(59500), tx.output[0].value);
+    assert_eq!(program_script_pubkey, tx.output[1].script_pubkey);
+    assert_eq!(Amount::from_sat(10000), tx.output[1].value);
+    assert_eq!(vec![0, 40000, 100000], exchange.balances(btc));
+    assert_eq!(
+        vec![Event::ForcedWithdrawalExecuted {
+            sequence: 4,
+            account_index: 1,
+            address_index: 1,
+            amount: 60000,
+            tx_id: txid_to_bytes(&tx.compute_txid()),
+        }],
+        exchange.events(),
+    );
+    settle(&mut exchange);
+    assert!(exchange.events().is_empty());
+
+    // a withdrawal by the operator serves the request
+    assert_eq!(
+        Err(ProgramError::Custom(ERROR_NONCE_USED)),
+        request(&mut exchange, 40000, 1, 40000),
+    );
+    request(&mut exchange, 40000, 2, 40000).unwrap();
+    let params = WithdrawBatchParams {
+        tx_hex: exchange.input_tx(1),
+        change_amount: 3500,
+        token_withdrawals: vec![TokenWithdrawals {
+            account_index: 2,
+            withdrawals: vec![Withdrawal {
+                address_index: wallet_index.clone(),
+                amount: 40000,
+                fee_account_index: 2,
+                fee_address_index: wallet_index.clone(),
+                fee_amount: 0,
+                signature: None,
+            }],
+        }],
+        input_utxo_types: vec![InputUtxoType::Bitcoin],
+    };
+    exchange.harness.process(
+        &exchange.prepare_withdraw_accounts(&[btc], false),
+        &ProgramInstruction::PrepareBatchWithdraw(params.clone()),
+    ).unwrap();
+    exchange.harness.process(
+        &exchange.submit_withdraw_accounts(&[btc], false),
+        &ProgramInstruction::SubmitBatchWithdraw(params),
+    ).unwrap();
+    // the submitted batch may spend the same utxos, so forced withdrawals wait for it
+    exchange.harness.syscalls().block_height = 1012;
+    assert_eq!(
+        Err(ProgramError::Custom(ERROR_WITHDRAWAL_IN_PROGRESS)),
+        forced_withdraw(&mut exchange, spending_tx(0), 500),
+    );
+    let tx = exchange.harness.last_signed_transaction().unwrap().transaction;
+    exchange.mine_blocks(WITHDRAW_CONFIRMATION_DEPTH);
+    exchange.confirm_withdrawal(tx.compute_txid()).unwrap();
+    let registry_state: ForcedWithdrawalState = exchange.harness.decode_account(&registry);
+    let key = ForcedWithdrawalState::entry_key(&btc, &WalletAddress::from_address(&wallet).unwrap());
+    assert_eq!(
+        vec![ForcedWithdrawalEntry { key, amount: 0, nonce: 2, due_height: 1012, pending: false }],
+        registry_state.entries,
+    );
+    assert_eq!(
+        Err(ProgramError::Custom(ERROR_NO_FORCED_WITHDRAWAL)),
+        forced_withdraw(&mut exchange, spending_tx(0), 500),
+    );
+}
+
+#[test]
+fn test_signed_withdrawal() {
+    let mut exchange = TestExchange::new();
+    let btc = exchange.add_token("btc");
+    let secp = bitcoin::secp256k1::Secp256k1::new();
+    let keypair = bitcoin::key::Keypair::from_seckey_slice(&secp, &[1; 32]).unwrap();
+    let taproot_wallet = Address::p2tr(&secp, keypair.x_only_public_key().0, None, Network::Regtest).to_string();
+    let private_key = bitcoin::PrivateKey::new(bitcoin::secp256k1::SecretKey::from_slice(&[2; 32]).unwrap(), Network::Regtest);
+    let segwit_wallet = Address::p2wpkh(
+        &bitcoin::CompressedPublicKey::from_private_key(&secp, &private_key).unwrap(),
+        Network::Regtest,
+    ).to_string();
+    let taproot_index = exchange.add_wallet(btc, &taproot_wallet);
+    let segwit_index = exchange.add_wallet(btc, &segwit_wallet);
+    exchange.deposit(btc, &taproot_index, 100000).unwrap();
+    exchange.deposit(btc, &segwit_index, 100000).unwrap();
+
+    let unsigned = |address_index: &AddressIndex, amount: u64| Withdrawal {
+        address_index: address_index.clone(),
+        amount,
+        fee_account_index: 2,
+        fee_address_index: address_index.clone(),
+        fee_amount: 500,
+        signature: None,
+    };
+    let signed = |exchange: &TestExchange, address_index: &AddressIndex, amount: u64, nonce: u64, expiry: u64| {
+        let mut withdrawal = unsigned(address_index, amount);
+        let witness = if *address_index == taproot_index {
+            let message = withdrawal.message(&exchange.program_state, &btc, &btc, &taproot_wallet, nonce, expiry);
+            let signature = bip322::sign_message_bip322(&keypair, message.as_bytes(), Network::Regtest);
+            Witness::from_slice(&[signature.to_vec()])
+        } else {
+            let message = withdrawal.message(&exchange.program_state, &btc, &btc, &segwit_wallet, nonce, expiry);
+            bip322::sign_message_bip322_p2wpkh(&private_key, message.as_bytes(), Network::Regtest)
+        };
+        withdrawal.signature = Some(WithdrawalSignature {
+            nonce,
+            expiry,
+            witness: bitcoin::consensus::serialize(&witness),
+        });
+        withdrawal
+    };
+    let tx_hex = exchange.input_tx(1);
+    let params = |withdrawals: Vec<Withdrawal>| WithdrawBatchParams {
+        tx_hex: tx_hex.clone(),
+        change_amount: 3500,
+        token_withdrawals: vec![TokenWithdrawals { account_index: 2, withdrawals }],
+        input_utxo_types: vec![InputUtxoType::Bitcoin],
+    };
+    let error_codes = |exchange: &TestExchange| {
+        exchange.events().iter().filter_map(|event| match event {
+            Event::FailedWithdrawal { error_code, .. } => Some(*error_code),
+            _ => None,
+        }).collect::<Vec<u32>>()
+    };
+    let nonce = |exchange: &TestExchange, address: &str| {
+        let nonce_state: WithdrawalNonceState = exchange.harness.decode_account(&exchange.withdrawal_nonces[0]);
+        let address_hash = WalletAddress::from_address(address).unwrap().hash();
+        nonce_state.entries.iter().find(|entry| entry.address_hash == address_hash).map(|entry| entry.nonce)
+    };
+
+    // signed withdrawals need the nonce account of the program
+    exchange.harness.syscalls().block_height = 1000;
+    let withdrawal = signed(&exchange, &taproot_index, 10000, 1, 1010);
+    assert_eq!(
+        Err(ProgramError::Custom(ERROR_WITHDRAWAL_NONCES_MISSING)),
+        exchange.harness.process(
+            &exchange.prepare_withdraw_accounts(&[btc], false),
+            &ProgramInstruction::PrepareBatchWithdraw(params(vec![withdrawal.clone()])),
+        ),
+    );
+    let nonces = exchange.harness.create_account();
+    exchange.harness.process(
+        &[meta(exchange.program_state, true, true), meta(nonces, false, true)],
+        &ProgramInstruction::InitWithdrawalNonces(),
+    ).unwrap();
+    exchange.withdrawal_nonces.push(nonces);
+
+    // expired, tampered and replayed withdrawals are excluded
+    let mut tampered = signed(&exchange, &segwit_index, 10000, 1, 1010);
+    tampered.amount = 20000;
+    let accepted = vec![
+        withdrawal.clone(),
+        signed(&exchange, &segwit_index, 20000, 2, 1000),
+        unsigned(&segwit_index, 1000),
+    ];
+    exchange.harness.process(
+        &exchange.prepare_withdraw_accounts(&[btc], false),
+        &ProgramInstruction::PreparePartialBatchWithdraw(params(vec![
+            accepted[0].clone(),
+            signed(&exchange, &segwit_index, 10000, 1, 999),
+            tampered,
+            signed(&exchange, &taproot_index, 5000, 1, 1010),
+            accepted[1].clone(),
+            accepted[2].clone(),
+        ])),
+    ).unwrap();
+    assert_eq!(
+        vec![ERROR_WITHDRAWAL_EXPIRED, ERROR_INVALID_SIGNATURE, ERROR_NONCE_USED],
+        error_codes(&exchange),
+    );
+    assert_eq!(vec![1500, 90000, 79000], exchange.balances(btc));
+    assert_eq!(Some(1), nonce(&exchange, &taproot_wallet));
+    assert_eq!(Some(2), nonce(&exchange, &segwit_wallet));
+
+    // nonces stay used once the batch is rolled back
+    exchange.harness.process(
+        &[meta(exchange.program_state, true, false), meta(exchange.withdraw, false, true), meta(btc, false, true)],
+        &ProgramInstruction::RollbackBatchWithdraw(RollbackWithdrawBatchParams {
+            token_withdrawals: params(accepted).token_withdrawals,
+        }),
+    ).unwrap();
+    assert_eq!(vec![0, 100000, 100000], exchange.balances(btc));
+    exchange.harness.process(
+        &exchange.prepare_withdraw_accounts(&[btc], false),
+        &ProgramInstruction::PrepareBatchWithdraw(params(vec![withdrawal])),
+    ).unwrap();
+    assert_eq!(vec![ERROR_NONCE_USED], error_codes(&exchange));
+    assert_eq!(vec![0, 100000, 100000], exchange.balances(btc));
+
+    exchange.harness.process(
+        &exchange.prepare_withdraw_accounts(&[btc], false),
+        &ProgramInstruction::PrepareBatchWithdraw(params(vec![signed(&exchange, &taproot_index, 10000, 2, 1010)])),
+    ).unwrap();
+    assert_eq!(vec![500, 90000, 100000], exchange.balances(btc));
+    assert_eq!(Some(2), nonce(&exchange, &taproot_wallet));
+
+    // a withdrawal that fails a later check does not use up its nonce
+    exchange.harness.process(
+        &[meta(exchange.program_state, true, false), meta(exchange.withdraw, false, true), meta(btc, false, true)],
+        &ProgramInstruction::RollbackBatchWithdraw(RollbackWithdrawBatchParams {
+            token_withdrawals: params(vec![signed(&exchange, &taproot_index, 10000, 2, 1010)]).token_withdrawals,
+        }),
+    ).unwrap();
+    let limit = exchange.harness.create_account();
+    exchange.harness.process(
+        &[meta(exchange.program_state, true, false), meta(btc, false, true), meta(limit, false, true)],
+        &ProgramInstruction::SetWithdrawalLimit(SetWithdrawalLimitParams {
+            limit: Some(WithdrawalLimitParams { max_amount: 15000, window_blocks: 144 }),
+        }),
+    ).unwrap();
+    exchange.withdrawal_limits.push(limit);
+    exchange.harness.process(
+        &exchange.prepare_withdraw_accounts(&[btc], false),
+        &ProgramInstruction::PreparePartialBatchWithdraw(params(vec![
+            signed(&exchange, &taproot_index, 20000, 3, 1010),
+            signed(&exchange, &taproot_index, 5000, 3, 1010),
+        ])),
+    ).unwrap();
+    assert_eq!(vec![ERROR_WITHDRAWAL_LIMIT_EXCEEDED], error_codes(&exchange));
+    assert_eq!(vec![500, 95000, 100000], exchange.balances(btc));
+    assert_eq!(Some(3), nonce(&exchange, &taproot_wallet));
+}
+
+#[test]
+fn test_rune_withdrawal() {
+    let mut exchange = TestExchange::new();
+    let rune_id = RuneId::from_str("840000:3").unwrap();
+    let rune = exchange.add_token(&rune_id.to_string());
+    let btc = exchange.add_token("btc");
+    let wallet = wallet_address(1);
+    let wallet_rune_index = exchange.add_wallet(rune, &wallet);
+    let wallet_btc_index = exchange.add_wallet(btc, &wallet);
+    exchange.deposit(rune, &wallet_rune_index, 1000).unwrap();
+    exchange.deposit(btc, &wallet_btc_index, 2000).unwrap();
+
+    let params = WithdrawBatchParams {
+        tx_hex: exchange.input_tx(2),
+        change_amount: 1200,
+        token_withdrawals: vec![TokenWithdrawals {
+            account_index: 3,
+            withdrawals: vec![Withdrawal {
+                address_index: wallet_rune_index.clone(),
+                amount: 400,
+                fee_account_index: 4,
+                fee_address_index: wallet_btc_index.clone(),
+                fee_amount: 300,
+                signature: None,
+            }],
+        }],
+        input_utxo_types: vec![InputUtxoType::Rune, InputUtxoType::Bitcoin],
+    };
+
+    // the rune receiver is required to return rune change
+    assert_eq!(
+        Err(ProgramError::Custom(ERROR_NO_RUNE_RECEIVER)),
+        exchange.harness.process(
+            &exchange.prepare_withdraw_accounts(&[rune, btc], false),
+            &ProgramInstruction::PrepareBatchWithdraw(WithdrawBatchParams {
+                token_withdrawals: vec![TokenWithdrawals {
+                    account_index: 2,
+                    withdrawals: vec![Withdrawal {
+                        fee_account_index: 3,
+                        ..params.token_withdrawals[0].withdrawals[0].clone()
+                    }],
+                }],
+                ..params.clone()
+            }),
+        ),
+    );
+    assert_eq!(vec![1000], exchange.balances(rune));
+
+    exchange.harness.process(
+        &exchange.prepare_withdraw_accounts(&[rune, btc], true),
+        &ProgramInstruction::PrepareBatchWithdraw(params.clone()),
+    ).unwrap();
+    assert_eq!(vec![600], exchange.balances(rune));
+    assert_eq!(vec![300, 1700], exchange.balances(btc));
+
+    exchange.harness.process(
+        &exchange.submit_withdraw_accounts(&[rune, btc], true),
+        &ProgramInstruction::SubmitBatchWithdraw(params),
+    ).unwrap();
+
+    let signed = exchange.harness.last_signed_transaction().unwrap();
+    assert_eq!(
+        vec![exchange.withdraw, exchange.rune_receiver, exchange.harness.program_id],
+        signed.inputs_to_sign.iter().map(|i| i.signer).collect::<Vec<Pubkey>>(),
+    );
+    let tx = signed.transaction;
+    assert_eq!(5, tx.output.len());
+    assert_eq!(get_bitcoin_address(&wallet, &NetworkType::Regtest).script_pubkey(), tx.output[1].script_pubkey);
+    assert_eq!(Amount::from_sat(DUST_THRESHOLD), tx.output[1].value);
+    assert_eq!(Amount::from_sat(1200), tx.output[2].value);
+    assert_eq!(exchange.harness.script_pubkey(&exchange.rune_receiver), tx.output[3].script_pubkey);
+    match Runestone::decipher(&tx) {
+        Some(Artifact::Runestone(runestone)) => assert_eq!(
+            vec![
+                Edict { id: rune_id, amount: 400, output: 1 },
+                Edict { id: rune_id, amount: 0, output: 3 },
+            ],
+            runestone.edicts,
+        ),
+        other => panic!("expected a runestone, got {:?}", other),
+    }
+}
@@ -24,7 +24,7 @@ mod tests {
     use ordinals::{Etching, Rune, RuneId, SpacedRune};
 
     fn cleanup_account_keys() {
-        for file in vec![WALLET1_FILE_PATH, WALLET2_FILE_PATH, WALLET3_FILE_PATH, SUBMITTER_FILE_PATH, WITHDRAW_ACCOUNT_FILE_PATH, RUNE_RECEIVER_ACCOUNT_FILE_PATH, FEE_ACCOUNT_FILE_PATH] {
+        for file in vec![WALLET1_FILE_PATH, WALLET2_FILE_PATH, WALLET3_FILE_PATH, SUBMITTER_FILE_PATH, WITHDRAW_ACCOUNT_FILE_PATH, RUNE_RECEIVER_ACCOUNT_FILE_PATH, EVENT_LOG_ACCOUNT_FILE_PATH, FEE_ACCOUNT_FILE_PATH] {
             delete_secret_file(file);
         }
         for file in TOKEN_FILE_PATHS {
@@ -370,15 +370,16 @@ mod tests {
                 withdrawals: vec![Withdrawal {
                     address_index: AddressIndex {
                         index: 1,
-                        last4: wallet_last4(&wallet.address.to_string()),
+                        commitment: address_commitment(&wallet.address.to_string()),
                     },
                     amount: 5500,
                     fee_account_index: 2,
                     fee_address_index: AddressIndex {
                         index: 1,
-                        last4: wallet_last4(&wallet.address.to_string()),
+                        commitment: address_commitment(&wallet.address.to_string()),
                     },
                     fee_amount: 500,
+                    signature: None,
                 }],
             }],
             change_amount,
@@ -387,9 +388,17 @@ mod tests {
         };
         let expected = TokenState {
             account_type: AccountType::Token,
-            version: 0,
+            version: TOKEN_STATE_VERSION,
             program_state_account: accounts[0],
             token_id: "btc".to_string(),
+            wallet_index_account: Pubkey::from([0u8; 32]),
+            num_free_balances: 0,
+            first_free_balance: 0,
+            num_balance_shards: 0,
+            first_balance_shard_account: Pubkey::from([0u8; 32]),
+            last_balance_shard_account: Pubkey::from([0u8; 32]),
+            withdrawal_fee_policy: None,
+            withdrawal_limit_account: Pubkey::from([0u8; 32]),
             balances: vec![
                 Balance {
                     address: fee_account.address.to_string().clone(),
@@ -415,15 +424,16 @@ mod tests {
                 withdrawals: vec![Withdrawal {
                     address_index: AddressIndex {
                         index: 1,
-                        last4: wallet_last4(&wallet.address.to_string()),
+                        commitment: address_commitment(&wallet.address.to_string()),
                     },
                     amount: 100000,
                     fee_account_index: 2,
                     fee_address_index: AddressIndex {
                         index: 1,
-                        last4: wallet_last4(&wallet.address.to_string()),
+                        commitment: address_commitment(&wallet.address.to_string()),
                     },
                     fee_amount: 500,
+                    signature: None,
                 }],
             }],
             change_amount,
@@ -551,41 +561,44 @@ mod tests {
                     Withdrawal {
                         address_index: AddressIndex {
                             index: 1,
-                            last4: wallet_last4(&wallet1.address.to_string()),
+                            commitment: address_commitment(&wallet1.address.to_string()),
                         },
                         amount: 10000,
                         fee_account_index: 2,
                         fee_address_index: AddressIndex {
                             index: 1,
-                            last4: wallet_last4(&wallet1.address.to_string()),
+                            commitment: address_commitment(&wallet1.address.to_string()),
                         },
                         fee_amount: 500,
+                        signature: None,
                     },
                     Withdrawal {
                         address_index: AddressIndex {
                             index: 2,
-                            last4: wallet_last4(&wallet2.address.to_string()),
+                            commitment: address_commitment(&wallet2.address.to_string()),
                         },
                         amount: 12000,
                         fee_account_index: 2,
                         fee_address_index: AddressIndex {
                             index: 2,
-                            last4: wallet_last4(&wallet2.address.to_string()),
+                            commitment: address_commitment(&wallet2.address.to_string()),
                         },
                         fee_amount: 500,
+                        signature: None,
                     },
                     Withdrawal {
                         address_index: AddressIndex {
                             index: 3,
-                            last4: wallet_last4(&wallet3.address.to_string()),
+                            commitment: address_commitment(&wallet3.address.to_string()),
                         },
                         amount: 12500,
                         fee_account_index: 2,
                         fee_address_index: AddressIndex {
                             index: 3,
-                            last4: wallet_last4(&wallet3.address.to_string()),
+                            commitment: address_commitment(&wallet3.address.to_string()),
                         },
                         fee_amount: 500,
+                        signature: None,
                     },
                 ],
             }],
@@ -595,9 +608,17 @@ mod tests {
         };
         let expected = TokenState {
             account_type: AccountType::Token,
-            version: 0,
+            version: TOKEN_STATE_VERSION,
             program_state_account: accounts[0],
             token_id: "btc".to_string(),
+            wallet_index_account: Pubkey::from([0u8; 32]),
+            num_free_balances: 0,
+            first_free_balance: 0,
+            num_balance_shards: 0,
+            first_balance_shard_account: Pubkey::from([0u8; 32]),
+            last_balance_shard_account: Pubkey::from([0u8; 32]),
+            withdrawal_fee_policy: None,
+            withdrawal_limit_account: Pubkey::from([0u8; 32]),
             balances: vec![
                 Balance {
                     address: fee_account.address.to_string().clone(),
@@ -702,15 +723,16 @@ mod tests {
                     withdrawals: vec![Withdrawal {
                         address_index: AddressIndex {
                             index: 1,
-                            last4: wallet_last4(&mainnet_address.clone()),
+                            commitment: address_commitment(&mainnet_address.clone()),
                         },
                         amount: 100000,
                         fee_account_index: 2,
                         fee_address_index: AddressIndex {
                             index: 1,
-                            last4: wallet_last4(&mainnet_address.clone()),
+                            commitment: address_commitment(&mainnet_address.clone()),
                         },
                         fee_amount: 500,
+                        signature: None,
                     }],
                 }],
                 change_amount,
@@ -720,9 +742,17 @@ mod tests {
             vec![
                 TokenState {
                     account_type: AccountType::Token,
-                    version: 0,
+                    version: TOKEN_STATE_VERSION,
                     program_state_account: accounts[0],
                     token_id: "btc".to_string(),
+                    wallet_index_account: Pubkey::from([0u8; 32]),
+                    num_free_balances: 0,
+                    first_free_balance: 0,
+                    num_balance_shards: 0,
+                    first_balance_shard_account: Pubkey::from([0u8; 32]),
+                    last_balance_shard_account: Pubkey::from([0u8; 32]),
+                    withdrawal_fee_policy: None,
+                    withdrawal_limit_account: Pubkey::from([0u8; 32]),
                     balances: vec![
                         Balance {
                             address: fee_account.address.to_string().clone(),
@@ -860,9 +890,17 @@ mod tests {
         assert_eq!(
             TokenState {
                 account_type: AccountType::Token,
-                version: 0,
+                version: TOKEN_STATE_VERSION,
                 program_state_account: accounts[0],
                 token_id: token1.to_string(),
+                wallet_index_account: Pubkey::from([0u8; 32]),
+                num_free_balances: 0,
+                first_free_balance: 0,
+                num_balance_shards: 0,
+                first_balance_shard_account: Pubkey::from([0u8; 32]),
+                last_balance_shard_account: Pubkey::from([0u8; 32]),
+                withdrawal_fee_policy: None,
+                withdrawal_limit_account: Pubkey::from([0u8; 32]),
                 balances: vec![
                     Balance {
                         address: fee_account.address.to_string(),
@@ -886,9 +924,17 @@ mod tests {
         assert_eq!(
             TokenState {
                 account_type: AccountType::Token,
-                version: 0,
+                version: TOKEN_STATE_VERSION,
                 program_state_account: accounts[0],
                 token_id: token2.to_string(),
+                wallet_index_account: Pubkey::from([0u8; 32]),
+                num_free_balances: 0,
+                first_free_balance: 0,
+                num_balance_shards: 0,
+                first_balance_shard_account: Pubkey::from([0u8; 32]),
+                last_balance_shard_account: Pubkey::from([0u8; 32]),
+                withdrawal_fee_policy: None,
+                withdrawal_limit_account: Pubkey::from([0u8; 32]),
                 balances: vec![
                     Balance {
                         address: fee_account.address.to_string(),
@@ -1161,15 +1207,16 @@ mod tests {
             )
         }
 
-        let mut adjustments: Vec<Adjustment> = vec![];
+        let mut deposits: Vec<Deposit> = vec![];
         for index in 0..100 {
-            adjustments.push(
-                Adjustment {
+            deposits.push(
+                Deposit {
                     address_index: AddressIndex {
                         index: index + 1,
-                        last4: wallet_last4(&wallets[index as usize]),
+                        commitment: address_commitment(&wallets[index as usize]),
                     },
                     amount: 10000,
+                    funding_outpoint: None,
                 }
             )
         }
@@ -1180,20 +1227,21 @@ mod tests {
                 AccountMeta {
                     pubkey: submitter_pubkey,
                     is_signer: true,
-                    is_writable: false,
+                    is_writable: true,
                 },
                 AccountMeta {
                     pubkey: token_account,
                     is_signer: false,
                     is_writable: true,
                 },
+                event_log_account_meta(),
             ],
             ProgramInstruction::BatchDeposit(
                 DepositBatchParams {
                     token_deposits: vec![
                         TokenDeposits {
                             account_index: 1,
-                            deposits: adjustments,
+                            deposits,
                         }
                     ],
                 }
@@ -1252,15 +1300,16 @@ mod tests {
                     Withdrawal {
                         address_index: AddressIndex {
                             index: (num_withdrawals_per_batch * index + i + 1) as u32,
-                            last4: wallet_last4(&wallets[num_withdrawals_per_batch * index + i]),
+                            commitment: address_commitment(&wallets[num_withdrawals_per_batch * index + i]),
                         },
                         amount: 6000,
                         fee_account_index: 2,
                         fee_address_index: AddressIndex {
                             index: (num_withdrawals_per_batch * index + i + 1) as u32,
-                            last4: wallet_last4(&wallets[num_withdrawals_per_batch * index + i]),
+                            commitment: address_commitment(&wallets[num_withdrawals_per_batch * index + i]),
                         },
                         fee_amount: 0,
+                        signature: None,
                     }
                 )
             }
@@ -1292,6 +1341,7 @@ mod tests {
                         is_signer: false,
                         is_writable: true,
                     },
+                    event_log_account_meta(),
                 ],
                 ProgramInstruction::PrepareBatchWithdraw(
                     withdraw_batch_params.clone()
@@ -1304,7 +1354,7 @@ mod tests {
                     AccountMeta {
                         pubkey: submitter_pubkey,
                         is_signer: true,
-                        is_writable: false,
+                        is_writable: true,
                     },
                     AccountMeta {
                         pubkey: withdraw_pubkey,
@@ -1316,6 +1366,7 @@ mod tests {
                         is_signer: false,
                         is_writable: false,
                     },
+                    event_log_account_meta(),
                 ],
                 ProgramInstruction::SubmitBatchWithdraw(
                     withdraw_batch_params
@@ -1323,7 +1374,8 @@ mod tests {
                 vec![submitter_keypair, withdraw_keypair],
             );
             assert_ne!(processed_tx.bitcoin_txid, None);
-            debug!("processed tx = {:?}", processed_tx.bitcoin_txid)
+            debug!("processed tx = {:?}", processed_tx.bitcoin_txid);
+            assert_confirm_withdrawal(&processed_tx.bitcoin_txid.unwrap());
         }
 
         let account_info = read_account_info(NODE1_ADDRESS, token_account.clone()).unwrap();
@@ -1455,20 +1507,24 @@ mod tests {
         }
 
         // batch deposit into the first wallet in each account
+        let mut deposit_accounts = accounts.clone();
+        deposit_accounts[0].is_writable = true;
+        deposit_accounts.push(event_log_account_meta());
         sign_and_send_instruction_success(
-            accounts.clone(),
+            deposit_accounts,
             ProgramInstruction::BatchDeposit(
                 DepositBatchParams {
                     token_deposits: (0..num_accounts)
                         .map(|idx| TokenDeposits {
                             account_index: idx as u8 + 1,
                             deposits: vec![
-                                Adjustment {
+                                Deposit {
                                     address_index: AddressIndex {
                                         index: 0,
-                                        last4: wallet_last4(&wallets[idx * num_wallets_per_account]),
+                                        commitment: address_commitment(&wallets[idx * num_wallets_per_account]),
                                     },
                                     amount: 10000,
+                                    funding_outpoint: None,
                                 }
                             ],
                         })
@@ -1486,21 +1542,21 @@ mod tests {
                         Adjustment {
                             address_index: AddressIndex {
                                 index: 1,
-                                last4: wallet_last4(&wallets[(idx * num_wallets_per_account + 1) as usize]),
+                                commitment: address_commitment(&wallets[(idx * num_wallets_per_account + 1) as usize]),
                             },
                             amount: 4000,
                         },
                         Adjustment {
                             address_index: AddressIndex {
                                 index: 2,
-                                last4: wallet_last4(&wallets[(idx * num_wallets_per_account + 2) as usize]),
+                                commitment: address_commitment(&wallets[(idx * num_wallets_per_account + 2) as usize]),
                             },
                             amount: 3500,
                         },
                         Adjustment {
                             address_index: AddressIndex {
                                 index: 3,
-                                last4: wallet_last4(&wallets[(idx * num_wallets_per_account + 3) as usize]),
+                                commitment: address_commitment(&wallets[(idx * num_wallets_per_account + 3) as usize]),
                             },
                             amount: 1000,
                         },
@@ -1509,7 +1565,7 @@ mod tests {
                         Adjustment {
                             address_index: AddressIndex {
                                 index: 0,
-                                last4: wallet_last4(&wallets[(idx * num_wallets_per_account) as usize]),
+                                commitment: address_commitment(&wallets[(idx * num_wallets_per_account) as usize]),
                             },
                             amount: 8500,
                         }
@@ -1524,7 +1580,7 @@ mod tests {
                 pubkey: a.pubkey,
                 is_writable: a.is_signer,
                 is_signer: a.is_signer,
-            }).collect::<Vec<AccountMeta>>(),
+            }).chain([event_log_account_meta()]).collect::<Vec<AccountMeta>>(),
             ProgramInstruction::PrepareBatchSettlement(
                 settlement_batch_params.clone()
             ).encode_to_vec().unwrap(),
@@ -1536,7 +1592,7 @@ mod tests {
                 pubkey: a.pubkey,
                 is_writable: true,
                 is_signer: a.is_signer,
-            }).collect::<Vec<AccountMeta>>(),
+            }).chain([event_log_account_meta()]).collect::<Vec<AccountMeta>>(),
             ProgramInstruction::SubmitBatchSettlement(
                 settlement_batch_params
             ).encode_to_vec().unwrap(),
@@ -1604,15 +1660,16 @@ mod tests {
             withdrawals: vec![Withdrawal {
                 address_index: AddressIndex {
                     index: 1,
-                    last4: wallet_last4(&wallet.address.to_string()),
+                    commitment: address_commitment(&wallet.address.to_string()),
                 },
                 amount: 5500,
                 fee_account_index: 2,
                 fee_address_index: AddressIndex {
                     index: 1,
-                    last4: wallet_last4(&wallet.address.to_string()),
+                    commitment: address_commitment(&wallet.address.to_string()),
                 },
                 fee_amount: 500,
+                signature: None,
             }],
         }];
 
@@ -1627,9 +1684,17 @@ mod tests {
             },
             vec![TokenState {
                 account_type: AccountType::Token,
-                version: 0,
+                version: TOKEN_STATE_VERSION,
                 program_state_account: accounts[0],
                 token_id: "btc".to_string(),
+                wallet_index_account: Pubkey::from([0u8; 32]),
+                num_free_balances: 0,
+                first_free_balance: 0,
+                num_balance_shards: 0,
+                first_balance_shard_account: Pubkey::from([0u8; 32]),
+                last_balance_shard_account: Pubkey::from([0u8; 32]),
+                withdrawal_fee_policy: None,
+                withdrawal_limit_account: Pubkey::from([0u8; 32]),
                 balances: vec![
                     Balance {
                         address: fee_account.address.to_string().clone(),
@@ -1653,9 +1718,17 @@ mod tests {
             vec![
                 TokenState {
                     account_type: AccountType::Token,
-                    version: 0,
+                    version: TOKEN_STATE_VERSION,
                     program_state_account: accounts[0],
                     token_id: "btc".to_string(),
+                    wallet_index_account: Pubkey::from([0u8; 32]),
+                    num_free_balances: 0,
+                    first_free_balance: 0,
+                    num_balance_shards: 0,
+                    first_balance_shard_account: Pubkey::from([0u8; 32]),
+                    last_balance_shard_account: Pubkey::from([0u8; 32]),
+                    withdrawal_fee_policy: None,
+                    withdrawal_limit_account: Pubkey::from([0u8; 32]),
                     balances: balances_after_deposit,
                 }
             ],
@@ -1753,17 +1826,21 @@ mod tests {
         );
 
         // invalid address index
+        let mut writable_program_and_token_acct = program_and_token_acct.clone();
+        writable_program_and_token_acct[0].is_writable = true;
+        writable_program_and_token_acct.push(event_log_account_meta());
         test_error_condition(
-            program_and_token_acct.clone(),
+            writable_program_and_token_acct.clone(),
             ProgramInstruction::BatchDeposit(
                 DepositBatchParams {
                     token_deposits: vec![
                         TokenDeposits {
                             account_index: 1,
                             deposits: vec![
-                                Adjustment {
-                                    address_index: AddressIndex { index: 1000, last4: [0; 4] },
+                                Deposit {
+                                    address_index: AddressIndex { index: 1000, commitment: AddressCommitment::ScriptHash([0; 8]) },
                                     amount: 0,
+                                    funding_outpoint: None,
                                 }
                             ],
                         }
@@ -1774,18 +1851,19 @@ mod tests {
             ERROR_INVALID_ADDRESS_INDEX,
         );
 
-        // invalid address last 4
+        // invalid address commitment
         test_error_condition(
-            program_and_token_acct.clone(),
+            writable_program_and_token_acct.clone(),
             ProgramInstruction::BatchDeposit(
                 DepositBatchParams {
                     token_deposits: vec![
                         TokenDeposits {
                             account_index: 1,
                             deposits: vec![
-                                Adjustment {
-                                    address_index: AddressIndex { index: 0, last4: [0; 4] },
+                                Deposit {
+                                    address_index: AddressIndex { index: 0, commitment: AddressCommitment::ScriptHash([0; 8]) },
                                     amount: 0,
+                                    funding_outpoint: None,
                                 }
                             ],
                         }
@@ -1793,7 +1871,7 @@ mod tests {
 
                 }
             ),
-            ERROR_WALLET_LAST4_MISMATCH,
+            ERROR_ADDRESS_COMMITMENT_MISMATCH,
         );
 
         // signer account is not the program account
@@ -1858,11 +1936,12 @@ mod tests {
                     account_index: 1,
                     withdrawals: vec![
                         Withdrawal {
-                            address_index: AddressIndex { index: 0, last4: wallet_last4(&fee_account.address.to_string()) },
+                            address_index: AddressIndex { index: 0, commitment: address_commitment(&fee_account.address.to_string()) },
                             amount: 100000000,
                             fee_account_index: 1,
-                            fee_address_index: AddressIndex { index: 0, last4: wallet_last4(&fee_account.address.to_string()) },
+                            fee_address_index: AddressIndex { index: 0, commitment: address_commitment(&fee_account.address.to_string()) },
                             fee_amount: 0,
+                            signature: None,
                         }
                     ],
                 }
@@ -1886,6 +1965,7 @@ mod tests {
                 is_signer: false,
                 is_writable: true,
             },
+            event_log_account_meta(),
         ];
 
         // withdraw but send in no input txs
@@ -1937,6 +2017,7 @@ mod tests {
                 is_signer: false,
                 is_writable: false,
             },
+            event_log_account_meta(),
         ];
 
         // netting error
@@ -1957,6 +2038,29 @@ mod tests {
             ERROR_NETTING,
         );
 
+        // netting sums that overflow
+        test_error_condition(
+            settlement_accounts.clone(),
+            ProgramInstruction::PrepareBatchSettlement(
+                SettlementBatchParams {
+                    settlements: vec![
+                        SettlementAdjustments {
+                            account_index: 1,
+                            increments: vec![
+                                Adjustment {
+                                    address_index: AddressIndex { index: 0, commitment: address_commitment(&fee_account.address.to_string()) },
+                                    amount: u64::MAX,
+                                }
+                            ],
+                            decrements: vec![],
+                            fee_amount: 2,
+                        }
+                    ],
+                }
+            ),
+            ERROR_ARITHMETIC_OVERFLOW,
+        );
+
         // cannot submit if not prepared
         test_error_condition(
             settlement_accounts.clone(),
@@ -2182,15 +2286,16 @@ mod tests {
                     withdrawals: vec![Withdrawal {
                         address_index: AddressIndex {
                             index: 0,
-                            last4: wallet_last4(&wallet.address.to_string()),
+                            commitment: address_commitment(&wallet.address.to_string()),
                         },
                         amount: withdraw_amount,
                         fee_account_index: 4,
                         fee_address_index: AddressIndex {
                             index: 1,
-                            last4: wallet_last4(&wallet.address.to_string()),
+                            commitment: address_commitment(&wallet.address.to_string()),
                         },
                         fee_amount: 500,
+                        signature: None,
                     }],
                 }
             ],
@@ -2200,9 +2305,17 @@ mod tests {
         };
         let expected_rune_account = TokenState {
             account_type: AccountType::Token,
-            version: 0,
+            version: TOKEN_STATE_VERSION,
             program_state_account: accounts[0],
             token_id: rune_id.to_string(),
+            wallet_index_account: Pubkey::from([0u8; 32]),
+            num_free_balances: 0,
+            first_free_balance: 0,
+            num_balance_shards: 0,
+            first_balance_shard_account: Pubkey::from([0u8; 32]),
+            last_balance_shard_account: Pubkey::from([0u8; 32]),
+            withdrawal_fee_policy: None,
+            withdrawal_limit_account: Pubkey::from([0u8; 32]),
             balances: vec![
                 Balance {
                     address: wallet.address.to_string().clone(),
@@ -2212,9 +2325,17 @@ mod tests {
         };
         let expected_btc_account = TokenState {
             account_type: AccountType::Token,
-            version: 0,
+            version: TOKEN_STATE_VERSION,
             program_state_account: accounts[0],
             token_id: "btc".to_string(),
+            wallet_index_account: Pubkey::from([0u8; 32]),
+            num_free_balances: 0,
+            first_free_balance: 0,
+            num_balance_shards: 0,
+            first_balance_shard_account: Pubkey::from([0u8; 32]),
+            last_balance_shard_account: Pubkey::from([0u8; 32]),
+            withdrawal_fee_policy: None,
+            withdrawal_limit_account: Pubkey::from([0u8; 32]),
             balances: vec![
                 Balance {
                     address: fee_account.address.to_string().clone(),
@@ -2282,15 +2403,16 @@ mod tests {
                     withdrawals: vec![Withdrawal {
                         address_index: AddressIndex {
                             index: 0,
-                            last4: wallet_last4(&wallet.address.to_string()),
+                            commitment: address_commitment(&wallet.address.to_string()),
                         },
                         amount: 10000,
                         fee_account_index: 2,
                         fee_address_index: AddressIndex {
                             index: 1,
-                            last4: wallet_last4(&wallet.address.to_string()),
+                            commitment: address_commitment(&wallet.address.to_string()),
                         },
                         fee_amount: 0,
+                        signature: None,
                     }],
                 }],
                 change_amount: 0,
@@ -2544,28 +2666,30 @@ mod tests {
                         Withdrawal {
                             address_index: AddressIndex {
                                 index: 1,
-                                last4: wallet_last4(&wallet1.address.to_string()),
+                                commitment: address_commitment(&wallet1.address.to_string()),
                             },
                             amount: btc_withdraw_amount,
                             fee_account_index: 5,
                             fee_address_index: AddressIndex {
                                 index: 1,
-                                last4: wallet_last4(&wallet1.address.to_string()),
+                                commitment: address_commitment(&wallet1.address.to_string()),
                             },
                             fee_amount: 500,
+                            signature: None,
                         },
                         Withdrawal {
                             address_index: AddressIndex {
                                 index: 2,
-                                last4: wallet_last4(&wallet2.address.to_string()),
+                                commitment: address_commitment(&wallet2.address.to_string()),
                             },
                             amount: btc_withdraw_amount2,
                             fee_account_index: 5,
                             fee_address_index: AddressIndex {
                                 index: 2,
-                                last4: wallet_last4(&wallet2.address.to_string()),
+                                commitment: address_commitment(&wallet2.address.to_string()),
                             },
                             fee_amount: 500,
+                            signature: None,
                         },
                     ],
                 },
@@ -2575,28 +2699,30 @@ mod tests {
                         Withdrawal {
                             address_index: AddressIndex {
                                 index: 0,
-                                last4: wallet_last4(&wallet1.address.to_string()),
+                                commitment: address_commitment(&wallet1.address.to_string()),
                             },
                             amount: rune_withdraw_base_amount,
                             fee_account_index: 5,
                             fee_address_index: AddressIndex {
                                 index: 1,
-                                last4: wallet_last4(&wallet1.address.to_string()),
+                                commitment: address_commitment(&wallet1.address.to_string()),
                             },
                             fee_amount: 500,
+                            signature: None,
                         },
                         Withdrawal {
                             address_index: AddressIndex {
                                 index: 1,
-                                last4: wallet_last4(&wallet2.address.to_string()),
+                                commitment: address_commitment(&wallet2.address.to_string()),
                             },
                             amount: rune_withdraw_base_amount2,
                             fee_account_index: 5,
                             fee_address_index: AddressIndex {
                                 index: 2,
-                                last4: wallet_last4(&wallet2.address.to_string()),
+                                commitment: address_commitment(&wallet2.address.to_string()),
                             },
                             fee_amount: 500,
+                            signature: None,
                         },
                     ],
                 },
@@ -2606,28 +2732,30 @@ mod tests {
                         Withdrawal {
                             address_index: AddressIndex {
                                 index: 0,
-                                last4: wallet_last4(&wallet1.address.to_string()),
+                                commitment: address_commitment(&wallet1.address.to_string()),
                             },
                             amount: rune_withdraw_base_amount + 2000000,
                             fee_account_index: 5,
                             fee_address_index: AddressIndex {
                                 index: 1,
-                                last4: wallet_last4(&wallet1.address.to_string()),
+                                commitment: address_commitment(&wallet1.address.to_string()),
                             },
                             fee_amount: 500,
+                            signature: None,
                         },
                         Withdrawal {
                             address_index: AddressIndex {
                                 index: 1,
-                                last4: wallet_last4(&wallet2.address.to_string()),
+                                commitment: address_commitment(&wallet2.address.to_string()),
                             },
                             amount: rune_withdraw_base_amount2 + 20000,
                             fee_account_index: 5,
                             fee_address_index: AddressIndex {
                                 index: 2,
-                                last4: wallet_last4(&wallet2.address.to_string()),
+                                commitment: address_commitment(&wallet2.address.to_string()),
                             },
                             fee_amount: 500,
+                            signature: None,
                         },
                     ],
                 },
@@ -2638,9 +2766,17 @@ mod tests {
         };
         let expected_rune1_account = TokenState {
             account_type: AccountType::Token,
-            version: 0,
+            version: TOKEN_STATE_VERSION,
             program_state_account: accounts[0],
             token_id: rune_ids[0].to_string(),
+            wallet_index_account: Pubkey::from([0u8; 32]),
+            num_free_balances: 0,
+            first_free_balance: 0,
+            num_balance_shards: 0,
+            first_balance_shard_account: Pubkey::from([0u8; 32]),
+            last_balance_shard_account: Pubkey::from([0u8; 32]),
+            withdrawal_fee_policy: None,
+            withdrawal_limit_account: Pubkey::from([0u8; 32]),
             balances: vec![
                 Balance {
                     address: wallet1.address.to_string().clone(),
@@ -2654,9 +2790,17 @@ mod tests {
         };
         let expected_rune2_account = TokenState {
             account_type: AccountType::Token,
-            version: 0,
+            version: TOKEN_STATE_VERSION,
             program_state_account: accounts[0],
             token_id: rune_ids[1].to_string(),
+            wallet_index_account: Pubkey::from([0u8; 32]),
+            num_free_balances: 0,
+            first_free_balance: 0,
+            num_balance_shards: 0,
+            first_balance_shard_account: Pubkey::from([0u8; 32]),
+            last_balance_shard_account: Pubkey::from([0u8; 32]),
+            withdrawal_fee_policy: None,
+            withdrawal_limit_account: Pubkey::from([0u8; 32]),
             balances: vec![
                 Balance {
                     address: wallet1.address.to_string().clone(),
@@ -2670,9 +2814,17 @@ mod tests {
         };
         let expected_btc_account = TokenState {
             account_type: AccountType::Token,
-            version: 0,
+            version: TOKEN_STATE_VERSION,
             program_state_account: accounts[0],
             token_id: "btc".to_string(),
+            wallet_index_account: Pubkey::from([0u8; 32]),
+            num_free_balances: 0,
+            first_free_balance: 0,
+            num_balance_shards: 0,
+            first_balance_shard_account: Pubkey::from([0u8; 32]),
+            last_balance_shard_account: Pubkey::from([0u8; 32]),
+            withdrawal_fee_policy: None,
+            withdrawal_limit_account: Pubkey::from([0u8; 32]),
             balances: vec![
                 Balance {
                     address: fee_account.address.to_string().clone(),
@@ -2726,9 +2878,17 @@ mod tests {
             vec![
                 TokenState {
                     account_type: AccountType::Token,
-                    version: 0,
+                    version: TOKEN_STATE_VERSION,
                     program_state_account: accounts[0],
                     token_id: rune_ids[0].to_string(),
+                    wallet_index_account: Pubkey::from([0u8; 32]),
+                    num_free_balances: 0,
+                    first_free_balance: 0,
+                    num_balance_shards: 0,
+                    first_balance_shard_account: Pubkey::from([0u8; 32]),
+                    last_balance_shard_account: Pubkey::from([0u8; 32]),
+                    withdrawal_fee_policy: None,
+                    withdrawal_limit_account: Pubkey::from([0u8; 32]),
                     balances: vec![
                         Balance {
                             address: wallet1.address.to_string().clone(),
@@ -2742,9 +2902,17 @@ mod tests {
                 },
                 TokenState {
                     account_type: AccountType::Token,
-                    version: 0,
+                    version: TOKEN_STATE_VERSION,
                     program_state_account: accounts[0],
                     token_id: rune_ids[1].to_string(),
+                    wallet_index_account: Pubkey::from([0u8; 32]),
+                    num_free_balances: 0,
+                    first_free_balance: 0,
+                    num_balance_shards: 0,
+                    first_balance_shard_account: Pubkey::from([0u8; 32]),
+                    last_balance_shard_account: Pubkey::from([0u8; 32]),
+                    withdrawal_fee_policy: None,
+                    withdrawal_limit_account: Pubkey::from([0u8; 32]),
                     balances: vec![
                         Balance {
                             address: wallet1.address.to_string().clone(),
@@ -2758,9 +2926,17 @@ mod tests {
                 },
                 TokenState {
                     account_type: AccountType::Token,
-                    version: 0,
+                    version: TOKEN_STATE_VERSION,
                     program_state_account: accounts[0],
                     token_id: "btc".to_string(),
+                    wallet_index_account: Pubkey::from([0u8; 32]),
+                    num_free_balances: 0,
+                    first_free_balance: 0,
+                    num_balance_shards: 0,
+                    first_balance_shard_account: Pubkey::from([0u8; 32]),
+                    last_balance_shard_account: Pubkey::from([0u8; 32]),
+                    withdrawal_fee_policy: None,
+                    withdrawal_limit_account: Pubkey::from([0u8; 32]),
                     balances: expected_btc_balances_after_deposit.clone(),
                 },
             ],
@@ -176,7 +176,12 @@ pub fn get_bitcoin_tx(txid: [u8; 32]) -> Option<Vec<u8>> {
 
 pub fn get_network_xonly_pubkey() -> [u8; 32] {
     let mut buf = [0u8; 32];
+
+    #[cfg(target_os = "solana")]
     let _ = unsafe { crate::syscalls::arch_get_network_xonly_pubkey(buf.as_mut_ptr()) };
+
+    #[cfg(not(target_os = "solana"))]
+    crate::program_stubs::arch_get_network_xonly_pubkey(buf.as_mut_ptr());
     buf
 }
 
@@ -203,7 +208,13 @@ pub fn get_account_script_pubkey(pubkey: &Pubkey) -> [u8; 34] {
 }
 
 pub fn get_bitcoin_block_height() -> u64 {
-    unsafe { crate::syscalls::arch_get_bitcoin_block_height() }
+    #[cfg(target_os = "solana")]
+    unsafe {
+        crate::syscalls::arch_get_bitcoin_block_height()
+    }
+
+    #[cfg(not(target_os = "solana"))]
+    crate::program_stubs::arch_get_bitcoin_block_height()
 }
 
 pub fn get_clock() -> Clock {
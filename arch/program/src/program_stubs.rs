@@ -1,16 +1,70 @@
 //! Implementations of syscalls used when `arch-program` is built for non-SBF targets.
+//!
+//! By default every syscall is unavailable. Host-side test harnesses can plug in
+//! their own behaviour for the current thread with [`set_syscall_stubs`].
 
 #![cfg(not(target_os = "solana"))]
 #![allow(dead_code)]
 
 pub const UNIMPLEMENTED: u64 = 0;
+use std::cell::RefCell;
+
 use crate::{
     account::AccountInfo, entrypoint::ProgramResult, instruction::Instruction, pubkey::Pubkey,
     utxo::UtxoMeta,
 };
 
+/// Host-side implementation of the Arch syscalls.
+///
+/// Every method has a default that mirrors the behaviour of the runtime being
+/// absent, so implementors only need to override the syscalls they care about.
+pub trait SyscallStubs {
+    fn sol_log(&self, message: &str) {
+        println!("{message}");
+    }
+    fn arch_set_transaction_to_sign(&self, _transaction_to_sign: &[u8]) -> u64 {
+        self.sol_log("UNAVAILABLE");
+        UNIMPLEMENTED
+    }
+    fn arch_get_bitcoin_tx(&self, _txid: &[u8; 32]) -> Option<Vec<u8>> {
+        self.sol_log("UNAVAILABLE");
+        None
+    }
+    fn arch_get_network_xonly_pubkey(&self) -> [u8; 32] {
+        self.sol_log("UNAVAILABLE");
+        [0u8; 32]
+    }
+    fn arch_validate_utxo_ownership(&self, _utxo: &UtxoMeta, _owner: &Pubkey) -> bool {
+        self.sol_log("UNAVAILABLE");
+        false
+    }
+    fn arch_get_account_script_pubkey(&self, _pubkey: &Pubkey) -> [u8; 34] {
+        [0u8; 34]
+    }
+    fn arch_get_bitcoin_block_height(&self) -> u64 {
+        self.sol_log("UNAVAILABLE");
+        0
+    }
+}
+
+struct DefaultSyscallStubs {}
+impl SyscallStubs for DefaultSyscallStubs {}
+
+thread_local! {
+    static SYSCALL_STUBS: RefCell<Box<dyn SyscallStubs>> = RefCell::new(Box::new(DefaultSyscallStubs {}));
+}
+
+/// Installs `syscall_stubs` for the current thread and returns the previous stubs.
+pub fn set_syscall_stubs(syscall_stubs: Box<dyn SyscallStubs>) -> Box<dyn SyscallStubs> {
+    SYSCALL_STUBS.with(|stubs| stubs.replace(syscall_stubs))
+}
+
+fn with_stubs<T>(f: impl FnOnce(&dyn SyscallStubs) -> T) -> T {
+    SYSCALL_STUBS.with(|stubs| f(stubs.borrow().as_ref()))
+}
+
 pub(crate) fn sol_log(message: &str) {
-    println!("{message}");
+    with_stubs(|stubs| stubs.sol_log(message))
 }
 pub(crate) fn sol_log_64_(arg1: u64, arg2: u64, arg3: u64, arg4: u64, arg5: u64) {
     sol_log(&format!("{arg1:?}, {arg2:?},{arg3:?},{arg4:?},{arg5:?}"))
@@ -28,23 +82,35 @@ pub(crate) fn sol_get_return_data(_data: *mut u8, _length: u64, _program_id: *mu
     sol_log("UNAVAILABLE");
     UNIMPLEMENTED
 }
-pub(crate) fn arch_set_transaction_to_sign(_transaction_to_sign: *const u8, _length: usize) -> u64 {
-    sol_log("UNAVAILABLE");
-    UNIMPLEMENTED
+pub(crate) fn arch_set_transaction_to_sign(transaction_to_sign: *const u8, length: usize) -> u64 {
+    let transaction_to_sign = unsafe { std::slice::from_raw_parts(transaction_to_sign, length) };
+    with_stubs(|stubs| stubs.arch_set_transaction_to_sign(transaction_to_sign))
 }
-pub(crate) fn arch_get_bitcoin_tx(_buf: *const u8, _buf_len: usize, _txid: &[u8; 32]) -> u64 {
-    sol_log("UNAVAILABLE");
-    UNIMPLEMENTED
+pub(crate) fn arch_get_bitcoin_tx(buf: *mut u8, buf_len: usize, txid: &[u8; 32]) -> u64 {
+    match with_stubs(|stubs| stubs.arch_get_bitcoin_tx(txid)) {
+        Some(tx) => {
+            let size = tx.len().min(buf_len);
+            unsafe { std::ptr::copy_nonoverlapping(tx.as_ptr(), buf, size) };
+            size as u64
+        }
+        None => UNIMPLEMENTED,
+    }
 }
-pub(crate) fn arch_get_network_xonly_pubkey(_data: *mut u8) -> u64 {
-    sol_log("UNAVAILABLE");
+pub(crate) fn arch_get_network_xonly_pubkey(data: *mut u8) -> u64 {
+    let pubkey = with_stubs(|stubs| stubs.arch_get_network_xonly_pubkey());
+    unsafe { std::ptr::copy_nonoverlapping(pubkey.as_ptr(), data, pubkey.len()) };
     UNIMPLEMENTED
 }
-pub(crate) fn arch_validate_utxo_ownership(_utxo: *const UtxoMeta, _owner: *const Pubkey) -> u64 {
-    sol_log("UNAVAILABLE");
-    UNIMPLEMENTED
+pub(crate) fn arch_validate_utxo_ownership(utxo: *const UtxoMeta, owner: *const Pubkey) -> u64 {
+    let (utxo, owner) = unsafe { (&*utxo, &*owner) };
+    with_stubs(|stubs| stubs.arch_validate_utxo_ownership(utxo, owner)) as u64
+}
+pub(crate) fn arch_get_account_script_pubkey(buf: &mut [u8; 34], pubkey: &Pubkey) {
+    *buf = with_stubs(|stubs| stubs.arch_get_account_script_pubkey(pubkey));
+}
+pub(crate) fn arch_get_bitcoin_block_height() -> u64 {
+    with_stubs(|stubs| stubs.arch_get_bitcoin_block_height())
 }
-pub(crate) fn arch_get_account_script_pubkey(_buf: &mut [u8; 34], _pubkey: &Pubkey) {}
 
 pub(crate) fn sol_invoke_signed_rust(
     _instruction_addr: &Instruction,
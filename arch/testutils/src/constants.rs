@@ -3,6 +3,7 @@ pub const TOKEN_FILE_PATHS: &'static [&'static str] = &["../../data/token1.json"
 pub const SUBMITTER_FILE_PATH: &str = "../../data/submitter.json";
 pub const WITHDRAW_ACCOUNT_FILE_PATH: &str = "../../data/withdraw.json";
 pub const RUNE_RECEIVER_ACCOUNT_FILE_PATH: &str = "../../data/rune_receiver.json";
+pub const EVENT_LOG_ACCOUNT_FILE_PATH: &str = "../../data/event_log.json";
 pub const WALLET1_FILE_PATH: &str = "../../data/wallet1.json";
 pub const WALLET2_FILE_PATH: &str = "../../data/wallet2.json";
 pub const WALLET3_FILE_PATH: &str = "../../data/wallet3.json";
@@ -8,7 +8,7 @@ use common::helper::{deploy_program_txs, get_account_address, get_processed_tran
 use common::models::CallerInfo;
 use common::processed_transaction::{ProcessedTransaction, Status};
 use crate::bitcoin::mine;
-use crate::constants::{FEE_ACCOUNT_FILE_PATH, RUNE_RECEIVER_ACCOUNT_FILE_PATH, SUBMITTER_FILE_PATH, TOKEN_FILE_PATHS, WALLET1_FILE_PATH, WITHDRAW_ACCOUNT_FILE_PATH};
+use crate::constants::{EVENT_LOG_ACCOUNT_FILE_PATH, FEE_ACCOUNT_FILE_PATH, RUNE_RECEIVER_ACCOUNT_FILE_PATH, SUBMITTER_FILE_PATH, TOKEN_FILE_PATHS, WALLET1_FILE_PATH, WITHDRAW_ACCOUNT_FILE_PATH};
 use crate::utils::hash;
 use log::debug;
 use model::state::*;
@@ -72,6 +72,7 @@ pub fn sign_and_send_token_instruction_success(
             },
         );
     }
+    accounts.push(event_log_account_meta());
     let (txid, _) = sign_and_send_instruction(
         Instruction {
             program_id: program_pubkey,
@@ -159,14 +160,18 @@ pub fn onboard_state_accounts(tokens: Vec<&str>) -> Vec<Pubkey> {
         },
         ProgramState {
             account_type: AccountType::Program,
-            version: 0,
+            version: PROGRAM_STATE_VERSION,
             withdraw_account: withdraw_account_pubkey,
             fee_account_address: fee_account.address.to_string(),
             program_change_address,
             network_type: NetworkType::Regtest,
             settlement_batch_hash: EMPTY_HASH,
             last_settlement_batch_hash: EMPTY_HASH,
-            events: vec![],
+            pause_flags: 0,
+            event_sequence: 0,
+            events_count: 0,
+            first_event_log_account: Pubkey::from([0u8; 32]),
+            event_log_account: Pubkey::from([0u8; 32]),
         },
     );
     debug!("Initialized program state");
@@ -185,9 +190,17 @@ pub fn onboard_state_accounts(tokens: Vec<&str>) -> Vec<Pubkey> {
             token_pubkey,
             TokenState {
                 account_type: AccountType::Token,
-                version: 0,
+                version: TOKEN_STATE_VERSION,
                 program_state_account: submitter_pubkey,
                 token_id: token.to_string(),
+                wallet_index_account: Pubkey::from([0u8; 32]),
+                num_free_balances: 0,
+                first_free_balance: 0,
+                num_balance_shards: 0,
+                first_balance_shard_account: Pubkey::from([0u8; 32]),
+                last_balance_shard_account: Pubkey::from([0u8; 32]),
+                withdrawal_fee_policy: None,
+                withdrawal_limit_account: Pubkey::from([0u8; 32]),
                 balances: if !TokenState::is_rune_id(token) {
                     vec![Balance {
                         address: fee_account.address.to_string(),
@@ -213,9 +226,34 @@ pub fn onboard_state_accounts(tokens: Vec<&str>) -> Vec<Pubkey> {
         },
     );
     debug!("Initialized token state account");
+
+    let (event_log_account_keypair, event_log_account_pubkey) = create_new_account(EVENT_LOG_ACCOUNT_FILE_PATH);
+    assign_ownership(event_log_account_keypair, event_log_account_pubkey, program_pubkey.clone());
+    debug!("Created event log account");
+    init_event_log_account(event_log_account_pubkey);
+    debug!("Initialized event log account");
     accounts
 }
 
+pub fn event_log_account_meta() -> AccountMeta {
+    let (_, event_log_pubkey) = with_secret_key_file(EVENT_LOG_ACCOUNT_FILE_PATH).unwrap();
+    AccountMeta {
+        pubkey: event_log_pubkey,
+        is_signer: false,
+        is_writable: true,
+    }
+}
+
+/// Returns the events emitted by the last instruction, which are the last entries of the event log.
+pub fn get_emitted_events() -> Vec<Event> {
+    let (_, submitter_pubkey) = with_secret_key_file(SUBMITTER_FILE_PATH).unwrap();
+    let state_account = read_account_info(NODE1_ADDRESS, submitter_pubkey).unwrap();
+    let program_state = ProgramState::decode_from_slice(&state_account.data).unwrap();
+    let event_log_account = read_account_info(NODE1_ADDRESS, program_state.event_log_account).unwrap();
+    let mut events = EventLogState::decode_from_slice(&event_log_account.data).unwrap().events;
+    events.split_off(events.len() - program_state.events_count as usize)
+}
+
 pub fn create_new_account(file_path: &str) -> (UntweakedKeypair, Pubkey) {
     let (keypair, pubkey) = with_secret_key_file(file_path)
         .expect("getting caller info should not fail");
@@ -398,6 +436,43 @@ pub fn init_rune_receiver_state_account(
     )
 }
 
+pub fn init_event_log_account(event_log_account: Pubkey) {
+    debug!("Invoking contract to init event log");
+    let (submitter_keypair, submitter_pubkey) = with_secret_key_file(SUBMITTER_FILE_PATH).unwrap();
+    sign_and_send_instruction_success(
+        vec![
+            AccountMeta {
+                pubkey: submitter_pubkey,
+                is_signer: true,
+                is_writable: true,
+            },
+            AccountMeta {
+                pubkey: event_log_account,
+                is_signer: false,
+                is_writable: true,
+            },
+        ],
+        ProgramInstruction::InitEventLog().encode_to_vec().unwrap(),
+        vec![submitter_keypair],
+    );
+
+    let state_account = read_account_info(NODE1_ADDRESS, submitter_pubkey.clone()).unwrap();
+    let program_state = ProgramState::decode_from_slice(&state_account.data).unwrap();
+    assert_eq!(event_log_account, program_state.first_event_log_account);
+    assert_eq!(event_log_account, program_state.event_log_account);
+    let account = read_account_info(NODE1_ADDRESS, event_log_account.clone()).unwrap();
+    let expected = EventLogState {
+        account_type: AccountType::EventLog,
+        version: EVENT_LOG_STATE_VERSION,
+        program_state_account: submitter_pubkey,
+        next_event_log_account: Pubkey::from([0u8; 32]),
+        events: vec![],
+    };
+    assert_eq!(
+        expected.encode_to_vec().unwrap(), EventLogState::decode_from_slice(account.data.as_slice()).unwrap().encode_to_vec().unwrap()
+    )
+}
+
 pub fn deposit(
     address: String,
     token: &str,
@@ -412,9 +487,10 @@ pub fn deposit(
             TokenDeposits {
                 account_index: 1,
                 deposits: vec![
-                    Adjustment {
+                    Deposit {
                         address_index: get_or_create_balance_index(address.clone(), token_account),
                         amount,
+                        funding_outpoint: None,
                     }
                 ],
             }
@@ -422,9 +498,17 @@ pub fn deposit(
     };
     let expected = TokenState {
         account_type: AccountType::Token,
-        version: 0,
+        version: TOKEN_STATE_VERSION,
         program_state_account: submitter_pubkey,
         token_id: token.to_string(),
+        wallet_index_account: Pubkey::from([0u8; 32]),
+        num_free_balances: 0,
+        first_free_balance: 0,
+        num_balance_shards: 0,
+        first_balance_shard_account: Pubkey::from([0u8; 32]),
+        last_balance_shard_account: Pubkey::from([0u8; 32]),
+        withdrawal_fee_policy: None,
+        withdrawal_limit_account: Pubkey::from([0u8; 32]),
         balances: expected_balances,
     };
     assert_send_and_sign_deposit(
@@ -444,7 +528,7 @@ pub fn assert_send_and_sign_deposit(
         Some(token_account),
         ProgramInstruction::BatchDeposit(params.clone()),
         None,
-        false,
+        true,
     );
 
     let token_account = read_account_info(NODE1_ADDRESS, token_account.clone()).unwrap();
@@ -483,7 +567,7 @@ pub fn get_or_create_balance_index(
     let token_balances: TokenState = TokenState::decode_from_slice(&account_info.data).unwrap();
     AddressIndex {
         index: token_balances.balances.into_iter().position(|r| r.address == address).unwrap() as u32,
-        last4: wallet_last4(&address),
+        commitment: address_commitment(&address),
     }
 }
 
@@ -538,7 +622,7 @@ pub fn assert_send_and_sign_withdrawal(
             is_writable: true,
         })
     );
-
+    accounts.push(event_log_account_meta());
 
     let processed_tx = sign_and_send_instruction_success(
         accounts,
@@ -563,10 +647,8 @@ pub fn assert_send_and_sign_withdrawal(
     let withdraw_utxo_before = withdraw_account_info.utxo;
 
     if let Some(events) = expected_events {
-        let state_account = read_account_info(NODE1_ADDRESS, submitter_pubkey.clone()).unwrap();
-        let program_state: ProgramState = ProgramState::decode_from_slice(&state_account.data).unwrap();
         assert_eq!(
-            program_state.events,
+            get_emitted_events(),
             events
         );
         assert_eq!(
@@ -585,7 +667,7 @@ pub fn assert_send_and_sign_withdrawal(
         AccountMeta {
             pubkey: submitter_pubkey,
             is_signer: true,
-            is_writable: false,
+            is_writable: true,
         },
         AccountMeta {
             pubkey: withdraw_pubkey,
@@ -611,6 +693,7 @@ pub fn assert_send_and_sign_withdrawal(
             }
         )
     );
+    accounts.push(event_log_account_meta());
 
     let processed_tx = sign_and_send_instruction_success(
         accounts,
@@ -631,14 +714,14 @@ pub fn assert_send_and_sign_withdrawal(
     let withdraw_account_info = read_account_info(NODE1_ADDRESS, withdraw_pubkey).unwrap();
     let withdraw_state = WithdrawState::decode_from_slice(withdraw_account_info.data.as_slice()).unwrap();
     assert_eq!(
-        withdraw_state.batch_hash,
-        EMPTY_HASH
+        withdraw_state.status,
+        WithdrawStatus::Submitted
     );
     assert_ne!(withdraw_account_info.utxo, withdraw_utxo_before);
 
 
     if let Some(expected_change_amount) = expected_change_amount {
-        let bitcoin_txid = match processed_tx.bitcoin_txid {
+        let bitcoin_txid = match processed_tx.bitcoin_txid.clone() {
             Some(x) => Txid::from_str(&x).unwrap(),
             None => Txid::from_str("").unwrap(),
         };
@@ -692,6 +775,48 @@ pub fn assert_send_and_sign_withdrawal(
         );
         debug!("Wallet amount is {}, Change amount is {}", wallet_amount, change_amount)
     }
+
+    assert_confirm_withdrawal(&processed_tx.bitcoin_txid.expect("a submitted batch should have a bitcoin tx"));
+}
+
+/// Mines enough blocks on top of a submitted withdrawal batch and confirms it, so the next batch can be prepared.
+pub fn assert_confirm_withdrawal(bitcoin_txid: &str) {
+    debug!("Confirming Withdrawal {}", bitcoin_txid);
+    let (withdraw_keypair, withdraw_pubkey) = with_secret_key_file(WITHDRAW_ACCOUNT_FILE_PATH).unwrap();
+    let (submitter_keypair, submitter_pubkey) = with_secret_key_file(SUBMITTER_FILE_PATH).unwrap();
+
+    mine(WITHDRAW_CONFIRMATION_DEPTH);
+
+    sign_and_send_instruction_success(
+        vec![
+            AccountMeta {
+                pubkey: submitter_pubkey,
+                is_signer: true,
+                is_writable: true,
+            },
+            AccountMeta {
+                pubkey: withdraw_pubkey,
+                is_signer: true,
+                is_writable: true,
+            },
+            event_log_account_meta(),
+        ],
+        ProgramInstruction::ConfirmWithdrawBatch(ConfirmWithdrawBatchParams {
+            tx_id: hex::decode(bitcoin_txid).unwrap().try_into().unwrap(),
+        }).encode_to_vec().unwrap(),
+        vec![submitter_keypair, withdraw_keypair],
+    );
+
+    let withdraw_account_info = read_account_info(NODE1_ADDRESS, withdraw_pubkey).unwrap();
+    let withdraw_state = WithdrawState::decode_from_slice(withdraw_account_info.data.as_slice()).unwrap();
+    assert_eq!(
+        withdraw_state.status,
+        WithdrawStatus::Confirmed
+    );
+    assert_eq!(
+        withdraw_account_info.utxo,
+        format!("{}:0", bitcoin_txid)
+    );
 }
 
 pub fn assert_send_and_sign_withdrawal_rollback(
@@ -782,6 +907,7 @@ pub fn assert_send_and_sign_prepare_settlement(
                     is_signer: false,
                     is_writable: false,
                 },
+                event_log_account_meta(),
             ],
             data: ProgramInstruction::PrepareBatchSettlement(params.clone()).encode_to_vec().unwrap(),
         },
@@ -801,7 +927,7 @@ pub fn assert_send_and_sign_prepare_settlement(
                 EMPTY_HASH,
             );
         assert_eq!(
-            program_state.events,
+            get_emitted_events(),
             events
         )
     } else {
@@ -863,6 +989,7 @@ pub fn assert_send_and_sign_submit_settlement(
                     is_signer: false,
                     is_writable: true,
                 },
+                event_log_account_meta(),
             ],
             data: ProgramInstruction::SubmitBatchSettlement(params.clone()).encode_to_vec().unwrap(),
         },